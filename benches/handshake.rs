@@ -0,0 +1,112 @@
+//! Connection establishment benchmark
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use ntex::codec::Encoder;
+use ntex::io::{testing::IoTest, Io, IoBoxed};
+use ntex::service::{Pipeline, Service, ServiceFactory};
+use ntex::util::{select, Bytes, BytesMut, Ready};
+use ntex_mqtt::{v3, v5};
+
+struct St;
+
+#[derive(Debug)]
+struct BenchError;
+
+impl From<()> for BenchError {
+    fn from(_: ()) -> Self {
+        BenchError
+    }
+}
+
+impl TryFrom<BenchError> for v5::PublishAck {
+    type Error = BenchError;
+
+    fn try_from(err: BenchError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+fn v3_connect() -> Bytes {
+    let mut buf = BytesMut::new();
+    v3::codec::Codec::default()
+        .encode(
+            v3::codec::Packet::Connect(v3::codec::Connect::default().client_id("bench").into()),
+            &mut buf,
+        )
+        .unwrap();
+    buf.freeze()
+}
+
+fn v5_connect() -> Bytes {
+    let mut buf = BytesMut::new();
+    v5::codec::Codec::default()
+        .encode(
+            v5::codec::Packet::Connect(Box::new(
+                v5::codec::Connect::default().client_id("bench"),
+            )),
+            &mut buf,
+        )
+        .unwrap();
+    buf.freeze()
+}
+
+/// Run handshake and wait for CONNACK
+async fn handshake<S>(srv: &Pipeline<S>, connect: &Bytes)
+where
+    S: Service<IoBoxed, Response = ()>,
+{
+    let (client, server) = IoTest::create();
+    client.remote_buffer_cap(1024);
+    client.write(connect);
+
+    let _ = select(srv.call(IoBoxed::from(Io::new(server))), client.read()).await;
+    client.close().await;
+}
+
+async fn run<F>(factory: F, connect: Bytes, iters: u64) -> Duration
+where
+    F: ServiceFactory<IoBoxed, Response = ()>,
+    F::InitError: std::fmt::Debug,
+{
+    let srv = factory.pipeline(()).await.unwrap();
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        handshake(&srv, &connect).await;
+    }
+    start.elapsed()
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+
+    group.bench_function("v3", |b| {
+        b.iter_custom(|iters| {
+            ntex::rt::System::new("bench").block_on(run(
+                v3::MqttServer::new(|hs: v3::Handshake| Ready::Ok::<_, ()>(hs.ack(St, false)))
+                    .publish(|_| Ready::Ok::<_, ()>(()))
+                    .finish(),
+                v3_connect(),
+                iters,
+            ))
+        })
+    });
+
+    group.bench_function("v5", |b| {
+        b.iter_custom(|iters| {
+            ntex::rt::System::new("bench").block_on(run(
+                v5::MqttServer::new(|hs: v5::Handshake| Ready::Ok::<_, BenchError>(hs.ack(St)))
+                    .publish(|p: v5::Publish| Ready::Ok::<_, BenchError>(p.ack()))
+                    .finish(),
+                v5_connect(),
+                iters,
+            ))
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_handshake);
+criterion_main!(benches);
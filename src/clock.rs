@@ -0,0 +1,41 @@
+//! Timer source for protocol timeouts
+use std::{future::Future, pin::Pin};
+
+use ntex_util::future::{select, Either};
+use ntex_util::time::{sleep, Millis};
+
+/// Source of timers for handshake timeouts.
+///
+/// Clock drives connect and protocol version timeouts and authentication
+/// failure delay. Keep-alive and frame read rate timers are managed by io
+/// layer, dispatcher timers use ntex timer, both do not use clock.
+pub trait Clock: 'static {
+    /// Returns future that resolves when `dur` elapses
+    fn sleep(&self, dur: Millis) -> Pin<Box<dyn Future<Output = ()>>>;
+}
+
+/// Clock based on ntex timer
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn sleep(&self, dur: Millis) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(sleep(dur))
+    }
+}
+
+/// Run future with timeout, zero timeout disables timer
+pub(crate) async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    dur: Millis,
+    fut: F,
+) -> Result<F::Output, ()> {
+    if dur.is_zero() {
+        Ok(fut.await)
+    } else {
+        match select(clock.sleep(dur), fut).await {
+            Either::Left(_) => Err(()),
+            Either::Right(res) => Ok(res),
+        }
+    }
+}
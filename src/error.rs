@@ -1,8 +1,9 @@
 use std::{fmt, io, num::NonZeroU16};
 
+use ntex_bytes::ByteString;
 use ntex_util::future::Either;
 
-use crate::v5::codec::DisconnectReasonCode;
+use crate::v5::codec::{DisconnectReasonCode, PublishAckReason, SubscribeAckReason};
 
 /// Errors which can occur when attempting to handle mqtt connection.
 #[derive(Debug, thiserror::Error)]
@@ -173,11 +174,62 @@ pub enum SendPacketError {
     /// Provided packet id is in use
     #[error("Provided packet id is in use")]
     PacketIdInUse(NonZeroU16),
+    /// Outbound queue is full
+    #[error("Outbound queue is full")]
+    QueueFull,
+    /// Publish is rejected by peer with failure reason code
+    #[error("Publish is rejected by peer: {:?}", _0)]
+    PublishRejected(PublishAckReason),
+    /// Peer disconnected
+    #[error("Peer is disconnected")]
+    Disconnected,
+    /// Publish is cancelled before acknowledgement
+    #[error("Publish is cancelled")]
+    Cancelled,
+    /// Acknowledgement is not received in time
+    #[error("Ack timeout")]
+    Timeout,
+    /// Publish is not sent before its expiration
+    #[error("Publish is expired")]
+    Expired,
+    /// Payload transform could not be applied to streamed payload
+    #[error("Payload transform is not supported for streamed payload")]
+    TransformUnsupported,
+}
+
+/// Errors which can occur during request/response exchange.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, thiserror::Error)]
+pub enum RpcError {
+    /// Subscribe or publish packet send error
+    #[error("Send error: {}", _0)]
+    Send(#[from] SendPacketError),
+    /// Server rejected subscription for response topic
+    #[error("Response topic subscription is rejected: {:?}", _0)]
+    Subscribe(SubscribeAckReason),
+    /// Response is not received in time
+    #[error("Response timeout")]
+    Timeout,
     /// Peer disconnected
     #[error("Peer is disconnected")]
     Disconnected,
 }
 
+/// Errors which can occur when decoding publish payload.
+#[derive(Debug, thiserror::Error)]
+pub enum PayloadError {
+    /// Json deserialization error
+    #[error("Cannot decode json payload for {:?}: {}", topic, err)]
+    Json { topic: ByteString, err: serde_json::Error },
+    /// Cbor deserialization error
+    #[cfg(feature = "cbor")]
+    #[error("Cannot decode cbor payload for {:?}: {}", topic, err)]
+    Cbor { topic: ByteString, err: ciborium::de::Error<io::Error> },
+    /// MessagePack deserialization error
+    #[cfg(feature = "msgpack")]
+    #[error("Cannot decode msgpack payload for {:?}: {}", topic, err)]
+    MsgPack { topic: ByteString, err: rmp_serde::decode::Error },
+}
+
 /// Errors which can occur when attempting to handle mqtt client connection.
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError<T: fmt::Debug> {
@@ -196,6 +248,22 @@ pub enum ClientError<T: fmt::Debug> {
     /// Connect error
     #[error("Connect error: {}", _0)]
     Connect(#[from] ntex_net::connect::ConnectError),
+    /// Proxy handshake error
+    #[error("Proxy error: {}", _0)]
+    Proxy(&'static str),
+}
+
+impl ClientError<Box<crate::v5::codec::ConnectAck>> {
+    /// Server reference of rejected v5 connect
+    ///
+    /// Server may redirect client to another server with `UseAnotherServer`
+    /// or `ServerMoved` reason code.
+    pub fn server_reference(&self) -> Option<&ByteString> {
+        match self {
+            ClientError::Ack(pkt) => pkt.server_reference.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 impl<T: fmt::Debug> From<EncodeError> for ClientError<T> {
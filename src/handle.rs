@@ -0,0 +1,224 @@
+//! Server connections registry
+use std::collections::HashMap;
+use std::sync::{atomic::AtomicUsize, atomic::Ordering, Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::{fmt, future::poll_fn, net::SocketAddr, time::SystemTime};
+
+use ntex_bytes::ByteString;
+use ntex_io::{types::PeerAddr, IoRef};
+use ntex_util::future::{select, Either};
+
+use crate::error::DecodeError;
+
+/// Handle to active server connections
+///
+/// Handle is shared between all server workers. Registered connections could be
+/// inspected and disconnected from any thread.
+///
+/// Server does not maintain subscription store, so `$SYS` topics are not
+/// published by the server itself. Brokers that expose `$SYS/broker/*` statistics
+/// could use handle as a source of connection metrics, i.e. `len()` for
+/// `$SYS/broker/clients/connected`, and publish them through own fan-out.
+#[derive(Clone, Default)]
+pub struct ServerHandle(Arc<Mutex<Registry>>);
+
+#[derive(Default)]
+struct Registry {
+    idx: u64,
+    connections: HashMap<u64, Entry>,
+    decode_errors: HashMap<DecodeError, u64>,
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    signal: Arc<Mutex<Signal>>,
+    subscriptions: Arc<AtomicUsize>,
+}
+
+/// Number of active subscriptions of registered connection
+#[derive(Clone, Debug)]
+pub(crate) struct SubscriptionsCount(Arc<AtomicUsize>);
+
+impl SubscriptionsCount {
+    pub(crate) fn set(&self, val: usize) {
+        self.0.store(val, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Signal {
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+/// Information about active connection
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    client_id: ByteString,
+    peer_addr: Option<SocketAddr>,
+    connected_at: SystemTime,
+    subscriptions: usize,
+}
+
+impl ConnectionInfo {
+    #[inline]
+    /// Client identifier
+    pub fn client_id(&self) -> &ByteString {
+        &self.client_id
+    }
+
+    #[inline]
+    /// Remote peer address
+    ///
+    /// Returns `None` for transports without socket address, i.e. Unix domain sockets.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    #[inline]
+    /// Time when connection got established
+    pub fn connected_at(&self) -> SystemTime {
+        self.connected_at
+    }
+
+    #[inline]
+    /// Number of topic filters successfully subscribed by the connection
+    ///
+    /// Subscription is counted if control service confirms it in SUBACK,
+    /// unsubscribe removes topic filter regardless of control service result.
+    pub fn subscriptions(&self) -> usize {
+        self.subscriptions
+    }
+}
+
+impl ServerHandle {
+    /// Create new server handle
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get list of active connections
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.0
+            .lock()
+            .unwrap()
+            .connections
+            .values()
+            .map(|entry| ConnectionInfo {
+                subscriptions: entry.subscriptions.load(Ordering::Relaxed),
+                ..entry.info.clone()
+            })
+            .collect()
+    }
+
+    /// Number of active connections
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().connections.len()
+    }
+
+    /// Check if there are no active connections
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of codec decode errors by error type
+    ///
+    /// Counters are shared by all connections of the server, including
+    /// connections that failed during handshake. Counters are updated only
+    /// when decoder returns an error, so there is no overhead for valid traffic.
+    pub fn decode_errors(&self) -> HashMap<DecodeError, u64> {
+        self.0.lock().unwrap().decode_errors.clone()
+    }
+
+    /// Number of decode errors of specified type
+    pub fn decode_error_count(&self, err: DecodeError) -> u64 {
+        self.0.lock().unwrap().decode_errors.get(&err).copied().unwrap_or(0)
+    }
+
+    /// Force close all connections with specified client id
+    ///
+    /// Returns `true` if at least one connection is found.
+    pub fn disconnect(&self, client_id: &str) -> bool {
+        let registry = self.0.lock().unwrap();
+
+        let mut found = false;
+        for entry in registry.connections.values() {
+            if entry.info.client_id.as_str() == client_id {
+                let mut signal = entry.signal.lock().unwrap();
+                signal.fired = true;
+                if let Some(waker) = signal.waker.take() {
+                    waker.wake();
+                }
+                found = true;
+            }
+        }
+        found
+    }
+
+    pub(crate) fn record_decode_error(&self, err: DecodeError) {
+        *self.0.lock().unwrap().decode_errors.entry(err).or_default() += 1;
+    }
+
+    /// Register new connection
+    ///
+    /// Connection stays registered until io stream get disconnected.
+    /// `on_disconnect` is called if connection get closed via server handle.
+    /// Returned counter is used by dispatcher to report active subscriptions.
+    pub(crate) fn register<F>(
+        &self,
+        client_id: ByteString,
+        io: IoRef,
+        on_disconnect: F,
+    ) -> SubscriptionsCount
+    where
+        F: FnOnce() + 'static,
+    {
+        let info = ConnectionInfo {
+            client_id,
+            peer_addr: io.query::<PeerAddr>().get().map(|addr| addr.0),
+            connected_at: SystemTime::now(),
+            subscriptions: 0,
+        };
+        let signal = Arc::new(Mutex::new(Signal::default()));
+        let subscriptions = Arc::new(AtomicUsize::new(0));
+
+        let id = {
+            let mut registry = self.0.lock().unwrap();
+            registry.idx += 1;
+            let id = registry.idx;
+            registry.connections.insert(
+                id,
+                Entry { info, signal: signal.clone(), subscriptions: subscriptions.clone() },
+            );
+            id
+        };
+
+        // watcher task is detached, it completes when connection get closed
+        let handle = self.clone();
+        ntex_util::spawn(async move {
+            let kicked = poll_fn(|cx| {
+                let mut signal = signal.lock().unwrap();
+                if signal.fired {
+                    Poll::Ready(())
+                } else {
+                    signal.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            });
+
+            if let Either::Left(_) = select(kicked, io.on_disconnect()).await {
+                log::trace!("Connection is closed via server handle");
+                on_disconnect();
+            }
+            handle.0.lock().unwrap().connections.remove(&id);
+        });
+
+        SubscriptionsCount(subscriptions)
+    }
+}
+
+impl fmt::Debug for ServerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerHandle").field("connections", &self.len()).finish()
+    }
+}
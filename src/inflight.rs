@@ -1,6 +1,7 @@
 //! Service that limits number of in-flight async requests.
 use std::{cell::Cell, future::poll_fn, rc::Rc, task::Context, task::Poll};
 
+use ntex_io::IoBoxed;
 use ntex_service::{Service, ServiceCtx};
 use ntex_util::task::LocalWaker;
 
@@ -8,6 +9,12 @@ pub(crate) trait SizedRequest {
     fn size(&self) -> u32;
 }
 
+impl SizedRequest for IoBoxed {
+    fn size(&self) -> u32 {
+        0
+    }
+}
+
 pub(crate) struct InFlightService<S> {
     count: Counter,
     service: S,
@@ -1,6 +1,6 @@
 //! Framed transport dispatcher
 use std::task::{ready, Context, Poll};
-use std::{cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc};
 
 use ntex_bytes::Pool;
 use ntex_codec::{Decoder, Encoder};
@@ -30,11 +30,26 @@ pin_project_lite::pin_project! {
 bitflags::bitflags! {
     #[derive(Copy, Clone, Eq, PartialEq, Debug)]
     struct Flags: u8  {
-        const READY_ERR     = 0b00001;
-        const IO_ERR        = 0b00010;
-        const KA_ENABLED    = 0b00100;
-        const KA_TIMEOUT    = 0b01000;
-        const READ_TIMEOUT  = 0b10000;
+        const READY_ERR     = 0b0001;
+        const IO_ERR        = 0b0010;
+        const KA_TIMEOUT    = 0b0100;
+        const READ_TIMEOUT  = 0b1000;
+    }
+}
+
+/// Keep-alive timeout shared between dispatcher and connection session
+///
+/// Updated value is used next time keep-alive timer starts.
+#[derive(Clone, Debug, Default)]
+pub struct KeepAlive(Rc<Cell<Seconds>>);
+
+impl KeepAlive {
+    pub(crate) fn get(&self) -> Seconds {
+        self.0.get()
+    }
+
+    pub(crate) fn set(&self, timeout: Seconds) {
+        self.0.set(timeout)
     }
 }
 
@@ -49,7 +64,7 @@ struct DispatcherInner<S: Service<DispatchItem<U>>, U: Encoder + Decoder + 'stat
     read_remains: u32,
     read_remains_prev: u32,
     read_max_timeout: Seconds,
-    keepalive_timeout: Seconds,
+    keepalive_timeout: KeepAlive,
 
     response: Option<PipelineCall<S, DispatchItem<U>>>,
     response_idx: usize,
@@ -123,7 +138,8 @@ where
             queue: VecDeque::new(),
         }));
         let pool = io.memory_pool().pool();
-        let keepalive_timeout = config.keepalive_timeout();
+        let keepalive_timeout = KeepAlive::default();
+        keepalive_timeout.set(config.keepalive_timeout());
 
         Dispatcher {
             pool,
@@ -132,11 +148,7 @@ where
                 codec,
                 state,
                 keepalive_timeout,
-                flags: if keepalive_timeout.is_zero() {
-                    Flags::KA_ENABLED
-                } else {
-                    Flags::empty()
-                },
+                flags: Flags::empty(),
                 service: Pipeline::new(service.into_service()).bind(),
                 config: config.clone(),
                 st: IoDispatcherState::Processing,
@@ -149,18 +161,9 @@ where
         }
     }
 
-    /// Set keep-alive timeout in seconds.
-    ///
-    /// To disable timeout set value to 0.
-    ///
-    /// By default keep-alive timeout is set to 30 seconds.
-    pub(crate) fn keepalive_timeout(mut self, timeout: Seconds) -> Self {
-        self.inner.keepalive_timeout = timeout;
-        if timeout.is_zero() {
-            self.inner.flags.remove(Flags::KA_ENABLED);
-        } else {
-            self.inner.flags.insert(Flags::KA_ENABLED);
-        }
+    /// Use keep-alive timeout shared with connection session.
+    pub(crate) fn keepalive(mut self, keepalive: KeepAlive) -> Self {
+        self.inner.keepalive_timeout = keepalive;
         self
     }
 }
@@ -519,15 +522,11 @@ where
             self.read_remains = decoded.remains as u32;
         } else if self.read_remains == 0 && decoded.remains == 0 {
             // no new data, start keep-alive timer
-            if self.flags.contains(Flags::KA_ENABLED) && !self.flags.contains(Flags::KA_TIMEOUT)
-            {
-                log::debug!(
-                    "{}: Start keep-alive timer {:?}",
-                    self.io.tag(),
-                    self.keepalive_timeout
-                );
+            let timeout = self.keepalive_timeout.get();
+            if !timeout.is_zero() && !self.flags.contains(Flags::KA_TIMEOUT) {
+                log::debug!("{}: Start keep-alive timer {:?}", self.io.tag(), timeout);
                 self.flags.insert(Flags::KA_TIMEOUT);
-                self.io.start_timer(self.keepalive_timeout);
+                self.io.start_timer(timeout);
             }
         } else if let Some((timeout, max, _)) = self.config.frame_read_rate() {
             // we got new data but not enough to parse single frame
@@ -618,7 +617,8 @@ mod tests {
             config: DispatcherConfig,
             service: F,
         ) -> (Self, nio::IoRef) {
-            let keepalive_timeout = config.keepalive_timeout();
+            let keepalive_timeout = KeepAlive::default();
+            keepalive_timeout.set(config.keepalive_timeout());
             let rio = io.get_ref();
 
             let state = Rc::new(RefCell::new(DispatcherState {
@@ -640,11 +640,7 @@ mod tests {
                         response_idx: 0,
                         io: IoBoxed::from(io),
                         st: IoDispatcherState::Processing,
-                        flags: if keepalive_timeout.is_zero() {
-                            Flags::empty()
-                        } else {
-                            Flags::KA_ENABLED
-                        },
+                        flags: Flags::empty(),
                         read_remains: 0,
                         read_remains_prev: 0,
                         read_max_timeout: Seconds::ZERO,
@@ -1001,8 +997,10 @@ mod tests {
                 }
             }),
         );
+        let keepalive = KeepAlive::default();
+        keepalive.set(Seconds(2));
         ntex_util::spawn(async move {
-            let _ = disp.keepalive_timeout(Seconds(2)).await;
+            let _ = disp.keepalive(keepalive).await;
         });
 
         client.write("1");
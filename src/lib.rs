@@ -8,22 +8,29 @@ mod topic;
 mod utils;
 
 pub mod error;
+pub mod test;
 pub mod v3;
 pub mod v5;
 
+mod clock;
+mod handle;
 mod inflight;
 mod io;
 mod server;
 mod service;
 mod session;
+mod socks;
 mod types;
 mod version;
 
+pub use self::clock::{Clock, SystemClock};
 pub use self::error::{HandshakeError, MqttError, ProtocolError};
+pub use self::handle::{ConnectionInfo, ServerHandle};
 pub use self::server::MqttServer;
 pub use self::session::Session;
 pub use self::topic::{TopicFilter, TopicFilterError, TopicFilterLevel};
-pub use types::QoS;
+pub use self::version::ProtocolVersion;
+pub use types::{ControlErrorPolicy, ErrorAction, FilterDecision, PacketIdStrategy, QoS};
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
 pub const TCP_PORT: u16 = 1883;
@@ -1,18 +1,28 @@
-use std::{fmt, io, marker};
+use std::{fmt, io, marker, rc::Rc};
 
 use ntex_io::{Filter, Io, IoBoxed};
 use ntex_service::{Service, ServiceCtx, ServiceFactory};
-use ntex_util::future::{join, select, Either};
-use ntex_util::time::{Deadline, Millis, Seconds};
+use ntex_util::future::{join, Either};
+use ntex_util::time::{Millis, Seconds};
 
+use crate::clock::{self, Clock, SystemClock};
 use crate::version::{ProtocolVersion, VersionCodec};
 use crate::{error::HandshakeError, error::MqttError, v3, v5};
 
 /// Mqtt Server
+///
+/// Server works with `Io` objects created by ntex server, socket level options
+/// are not managed by mqtt server. ntex runtime enables `TCP_NODELAY` for all
+/// accepted tcp connections. To enable `SO_KEEPALIVE`, configure listening socket
+/// and pass it to `ntex::server::build().listen()`, accepted sockets inherit
+/// the option from the listener.
 pub struct MqttServer<V3, V5, Err, InitErr> {
     v3: V3,
     v5: V5,
     connect_timeout: Millis,
+    clock: Rc<dyn Clock>,
+    versions: Rc<[ProtocolVersion]>,
+    min_version: Option<ProtocolVersion>,
     _t: marker::PhantomData<(Err, InitErr)>,
 }
 
@@ -30,6 +40,9 @@ impl<Err, InitErr>
             v3: DefaultProtocolServer::new(ProtocolVersion::MQTT3),
             v5: DefaultProtocolServer::new(ProtocolVersion::MQTT5),
             connect_timeout: Millis(5_000),
+            clock: Rc::new(SystemClock),
+            versions: Rc::new([ProtocolVersion::MQTT3, ProtocolVersion::MQTT5]),
+            min_version: None,
             _t: marker::PhantomData,
         }
     }
@@ -60,6 +73,38 @@ impl<V3, V5, Err, InitErr> MqttServer<V3, V5, Err, InitErr> {
         self.connect_timeout = timeout.into();
         self
     }
+
+    /// Set clock for protocol version timeout.
+    ///
+    /// Clock drives only the protocol version read timeout. Connect timeouts
+    /// use clocks of v3 and v5 server builders, see `v3::MqttServer::clock()`.
+    ///
+    /// By default `SystemClock` is used.
+    pub fn protocol_version_clock<T: Clock>(mut self, clock: T) -> Self {
+        self.clock = Rc::new(clock);
+        self
+    }
+
+    /// Set allowed protocol versions.
+    ///
+    /// Protocol version is checked right after it is read from `Connect` packet.
+    /// Client with version that is not allowed gets `ConnectAck` with unsupported
+    /// protocol version reason code and connection is closed.
+    ///
+    /// By default all versions are allowed.
+    pub fn allowed_versions(mut self, versions: &[ProtocolVersion]) -> Self {
+        self.versions = versions.into();
+        self
+    }
+
+    /// Set minimum allowed protocol version.
+    ///
+    /// Versions lower than `version` are rejected, see `allowed_versions()`.
+    /// Check is applied in addition to allowed versions, regardless of call order.
+    pub fn min_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.min_version = Some(version);
+        self
+    }
 }
 
 impl<V3, V5, Err, InitErr> MqttServer<V3, V5, Err, InitErr>
@@ -87,7 +132,8 @@ where
             > + 'static,
         Cn: ServiceFactory<v3::Control<Err>, v3::Session<St>, Response = v3::ControlAck>
             + 'static,
-        P: ServiceFactory<v3::Publish, v3::Session<St>, Response = ()> + 'static,
+        P: ServiceFactory<v3::Publish, v3::Session<St>> + 'static,
+        P::Response: Into<v3::PublishResult>,
         C::Error: From<Cn::Error>
             + From<Cn::InitError>
             + From<P::Error>
@@ -98,6 +144,9 @@ where
             v3: service.finish(),
             v5: self.v5,
             connect_timeout: self.connect_timeout,
+            clock: self.clock,
+            versions: self.versions,
+            min_version: self.min_version,
             _t: marker::PhantomData,
         }
     }
@@ -135,6 +184,9 @@ where
             v3: self.v3,
             v5: service.finish(),
             connect_timeout: self.connect_timeout,
+            clock: self.clock,
+            versions: self.versions,
+            min_version: self.min_version,
             _t: marker::PhantomData,
         }
     }
@@ -154,6 +206,9 @@ where
         Ok(MqttServerImpl {
             handlers: (v3, v5),
             connect_timeout: self.connect_timeout,
+            clock: self.clock.clone(),
+            versions: self.versions.clone(),
+            min_version: self.min_version,
             _t: marker::PhantomData,
         })
     }
@@ -202,9 +257,56 @@ where
 pub struct MqttServerImpl<V3, V5, Err> {
     handlers: (V3, V5),
     connect_timeout: Millis,
+    clock: Rc<dyn Clock>,
+    versions: Rc<[ProtocolVersion]>,
+    min_version: Option<ProtocolVersion>,
     _t: marker::PhantomData<Err>,
 }
 
+impl<V3, V5, Err> MqttServerImpl<V3, V5, Err>
+where
+    V3: Service<IoBoxed, Response = (), Error = MqttError<Err>>,
+    V5: Service<IoBoxed, Response = (), Error = MqttError<Err>>,
+{
+    async fn dispatch(
+        &self,
+        ver: ProtocolVersion,
+        io: IoBoxed,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<(), MqttError<Err>> {
+        if !self.versions.contains(&ver) || self.min_version.is_some_and(|min| ver < min) {
+            log::trace!("Protocol version is not allowed: {:?}", ver);
+            return reject_version(io, ver).await;
+        }
+        match ver {
+            ProtocolVersion::MQTT3 => ctx.call(&self.handlers.0, io).await,
+            ProtocolVersion::MQTT5 => ctx.call(&self.handlers.1, io).await,
+        }
+    }
+}
+
+/// Send `ConnectAck` with unsupported protocol version reason and close connection
+async fn reject_version<Err>(io: IoBoxed, ver: ProtocolVersion) -> Result<(), MqttError<Err>> {
+    match ver {
+        ProtocolVersion::MQTT3 => io.encode(
+            v3::codec::Packet::ConnectAck(v3::codec::ConnectAck {
+                session_present: false,
+                return_code: v3::codec::ConnectAckReason::UnacceptableProtocolVersion,
+            }),
+            &v3::codec::Codec::default(),
+        )?,
+        ProtocolVersion::MQTT5 => io.encode(
+            v5::codec::Packet::ConnectAck(Box::new(v5::codec::ConnectAck {
+                reason_code: v5::codec::ConnectAckReason::UnsupportedProtocolVersion,
+                ..Default::default()
+            })),
+            &v5::codec::Codec::default(),
+        )?,
+    }
+    let _ = io.shutdown().await;
+    Err(MqttError::Handshake(HandshakeError::Disconnected(None)))
+}
+
 impl<V3, V5, Err> Service<IoBoxed> for MqttServerImpl<V3, V5, Err>
 where
     V3: Service<IoBoxed, Response = (), Error = MqttError<Err>>,
@@ -238,10 +340,7 @@ where
             .decode(&VersionCodec)
             .map_err(|e| MqttError::Handshake(HandshakeError::Protocol(e.into())))?;
         if let Some(ver) = res {
-            match ver {
-                ProtocolVersion::MQTT3 => ctx.call(&self.handlers.0, io).await,
-                ProtocolVersion::MQTT5 => ctx.call(&self.handlers.1, io).await,
-            }
+            self.dispatch(ver, io, ctx).await
         } else {
             let fut = async {
                 match io.recv(&VersionCodec).await {
@@ -255,16 +354,11 @@ where
                 }
             };
 
-            match select(&mut Deadline::new(self.connect_timeout), fut).await {
-                Either::Left(_) => Err(MqttError::Handshake(HandshakeError::Timeout)),
-                Either::Right(Ok(Some(ver))) => match ver {
-                    ProtocolVersion::MQTT3 => ctx.call(&self.handlers.0, io).await,
-                    ProtocolVersion::MQTT5 => ctx.call(&self.handlers.1, io).await,
-                },
-                Either::Right(Ok(None)) => {
-                    Err(MqttError::Handshake(HandshakeError::Disconnected(None)))
-                }
-                Either::Right(Err(e)) => Err(e),
+            match clock::timeout(&*self.clock, self.connect_timeout, fut).await {
+                Err(_) => Err(MqttError::Handshake(HandshakeError::Timeout)),
+                Ok(Ok(Some(ver))) => self.dispatch(ver, io, ctx).await,
+                Ok(Ok(None)) => Err(MqttError::Handshake(HandshakeError::Disconnected(None))),
+                Ok(Err(e)) => Err(e),
             }
         }
     }
@@ -327,9 +421,10 @@ impl<Err, InitErr> Service<IoBoxed> for DefaultProtocolServer<Err, InitErr> {
 
     async fn call(
         &self,
-        _: IoBoxed,
+        io: IoBoxed,
         _: ServiceCtx<'_, Self>,
     ) -> Result<Self::Response, Self::Error> {
+        let _ = reject_version::<Err>(io, self.ver).await;
         Err(MqttError::Handshake(HandshakeError::Disconnected(Some(io::Error::new(
             io::ErrorKind::Other,
             format!("Protocol is not supported: {:?}", self.ver),
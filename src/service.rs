@@ -1,11 +1,13 @@
-use std::{fmt, marker::PhantomData, rc::Rc};
+use std::{cell::Cell, fmt, marker::PhantomData, rc::Rc};
 
 use ntex_codec::{Decoder, Encoder};
+#[cfg(feature = "tracing")]
+use ntex_io::types::PeerAddr;
 use ntex_io::{DispatchItem, DispatcherConfig, Filter, Io, IoBoxed};
 use ntex_service::{Service, ServiceCtx, ServiceFactory};
 use ntex_util::time::Seconds;
 
-use crate::io::Dispatcher;
+use crate::io::{Dispatcher, KeepAlive};
 
 type ResponseItem<U> = Option<<U as Encoder>::Item>;
 
@@ -13,18 +15,31 @@ pub struct MqttServer<St, C, T, Codec> {
     connect: C,
     handler: Rc<T>,
     config: DispatcherConfig,
+    dead_peer_timeout: Seconds,
     _t: PhantomData<(St, Codec)>,
 }
 
 impl<St, C, T, Codec> MqttServer<St, C, T, Codec> {
     pub(crate) fn new(connect: C, service: T, config: DispatcherConfig) -> Self {
-        MqttServer { connect, config, handler: Rc::new(service), _t: PhantomData }
+        MqttServer {
+            connect,
+            config,
+            handler: Rc::new(service),
+            dead_peer_timeout: Seconds::ZERO,
+            _t: PhantomData,
+        }
+    }
+
+    /// Set max idle time for connection regardless of negotiated keep-alive
+    pub(crate) fn dead_peer_timeout(mut self, timeout: Seconds) -> Self {
+        self.dead_peer_timeout = timeout;
+        self
     }
 }
 
 impl<St, C, T, Codec> MqttServer<St, C, T, Codec>
 where
-    C: ServiceFactory<IoBoxed, Response = (IoBoxed, Codec, St, Seconds)>,
+    C: ServiceFactory<IoBoxed, Response = (IoBoxed, Codec, St, KeepAlive)>,
 {
     async fn create_service(
         &self,
@@ -33,6 +48,7 @@ where
         Ok(MqttHandler {
             config: self.config.clone(),
             handler: self.handler.clone(),
+            dead_peer_timeout: self.dead_peer_timeout,
             connect: self.connect.create(()).await?,
             _t: PhantomData,
         })
@@ -42,7 +58,7 @@ where
 impl<St, C, T, Codec> ServiceFactory<IoBoxed> for MqttServer<St, C, T, Codec>
 where
     St: 'static,
-    C: ServiceFactory<IoBoxed, Response = (IoBoxed, Codec, St, Seconds)> + 'static,
+    C: ServiceFactory<IoBoxed, Response = (IoBoxed, Codec, St, KeepAlive)> + 'static,
     C::Error: fmt::Debug,
     T: ServiceFactory<
             DispatchItem<Codec>,
@@ -67,7 +83,7 @@ impl<F, St, C, T, Codec> ServiceFactory<Io<F>> for MqttServer<St, C, T, Codec>
 where
     F: Filter,
     St: 'static,
-    C: ServiceFactory<IoBoxed, Response = (IoBoxed, Codec, St, Seconds)> + 'static,
+    C: ServiceFactory<IoBoxed, Response = (IoBoxed, Codec, St, KeepAlive)> + 'static,
     C::Error: fmt::Debug,
     T: ServiceFactory<
             DispatchItem<Codec>,
@@ -92,13 +108,14 @@ pub struct MqttHandler<St, C, T, Codec> {
     connect: C,
     handler: Rc<T>,
     config: DispatcherConfig,
+    dead_peer_timeout: Seconds,
     _t: PhantomData<(St, Codec)>,
 }
 
 impl<St, C, T, Codec> Service<IoBoxed> for MqttHandler<St, C, T, Codec>
 where
     St: 'static,
-    C: Service<IoBoxed, Response = (IoBoxed, Codec, St, Seconds)> + 'static,
+    C: Service<IoBoxed, Response = (IoBoxed, Codec, St, KeepAlive)> + 'static,
     C::Error: fmt::Debug,
     T: ServiceFactory<
             DispatchItem<Codec>,
@@ -116,19 +133,40 @@ where
     ntex_service::forward_shutdown!(connect);
 
     async fn call(&self, req: IoBoxed, ctx: ServiceCtx<'_, Self>) -> Result<(), Self::Error> {
-        let tag = req.tag();
-        let handshake = ctx.call(&self.connect, req).await;
-
-        let (io, codec, session, keepalive) = handshake.map_err(|e| {
-            log::trace!("{}: Connection handshake failed: {:?}", tag, e);
-            e
-        })?;
-        log::trace!("{}: Connection handshake succeeded", tag);
-
-        let handler = self.handler.create(session).await?;
-        log::trace!("{}: Connection handler is created, starting dispatcher", tag);
-
-        Dispatcher::new(io, codec, handler, &self.config).keepalive_timeout(keepalive).await
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "mqtt.connection",
+            peer_addr = ?req.query::<PeerAddr>().get().map(|addr| addr.0),
+            client_id = tracing::field::Empty,
+        );
+
+        let fut = async move {
+            let tag = req.tag();
+            let handshake = ctx.call(&self.connect, req).await;
+
+            let (io, codec, session, keepalive) = handshake.map_err(|e| {
+                log::trace!("{}: Connection handshake failed: {:?}", tag, e);
+                e
+            })?;
+            log::trace!("{}: Connection handshake succeeded", tag);
+
+            // dead peer timeout is a hard limit, client keep-alive cannot extend it
+            let timeout = keepalive.get();
+            if !self.dead_peer_timeout.is_zero()
+                && (timeout.is_zero() || timeout > self.dead_peer_timeout)
+            {
+                keepalive.set(self.dead_peer_timeout);
+            }
+
+            let handler = self.handler.create(session).await?;
+            log::trace!("{}: Connection handler is created, starting dispatcher", tag);
+
+            Dispatcher::new(io, codec, handler, &self.config).keepalive(keepalive).await
+        };
+
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument(fut, span);
+        fut.await
     }
 }
 
@@ -136,7 +174,7 @@ impl<F, St, C, T, Codec> Service<Io<F>> for MqttHandler<St, C, T, Codec>
 where
     F: Filter,
     St: 'static,
-    C: Service<IoBoxed, Response = (IoBoxed, Codec, St, Seconds)> + 'static,
+    C: Service<IoBoxed, Response = (IoBoxed, Codec, St, KeepAlive)> + 'static,
     C::Error: fmt::Debug,
     T: ServiceFactory<
             DispatchItem<Codec>,
@@ -158,3 +196,46 @@ where
         Service::<IoBoxed>::call(self, IoBoxed::from(io), ctx).await
     }
 }
+
+/// Handshake io object
+///
+/// If slot is set, io object is returned to the slot when handshake
+/// gets dropped without acknowledgement.
+pub(crate) struct HandshakeIo {
+    io: Option<IoBoxed>,
+    slot: Option<Rc<Cell<Option<IoBoxed>>>>,
+}
+
+impl HandshakeIo {
+    pub(crate) fn new(io: IoBoxed) -> Self {
+        HandshakeIo { io: Some(io), slot: None }
+    }
+
+    pub(crate) fn set_slot(&mut self, slot: Rc<Cell<Option<IoBoxed>>>) {
+        self.slot = Some(slot);
+    }
+
+    pub(crate) fn get(&self) -> &IoBoxed {
+        self.io.as_ref().unwrap()
+    }
+
+    pub(crate) fn take_io(&mut self) -> IoBoxed {
+        self.io.take().unwrap()
+    }
+
+    pub(crate) fn set_io(&mut self, io: IoBoxed) {
+        self.io = Some(io);
+    }
+
+    pub(crate) fn take(mut self) -> IoBoxed {
+        self.io.take().unwrap()
+    }
+}
+
+impl Drop for HandshakeIo {
+    fn drop(&mut self) {
+        if let (Some(io), Some(slot)) = (self.io.take(), self.slot.take()) {
+            slot.set(Some(io));
+        }
+    }
+}
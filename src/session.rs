@@ -2,6 +2,15 @@ use std::ops::Deref;
 use std::rc::Rc;
 
 /// Mqtt connection session
+///
+/// Session lives as long as connection. Server does not persist sessions and
+/// does not queue messages for offline clients, so nothing gets replayed on
+/// reconnect by the server itself. Applications that restore persistent sessions
+/// in handshake service should measure restore time and replayed messages there.
+///
+/// Established connection cannot be handed over to another server instance.
+/// In-flight packet ids, topic aliases and codec state stay with the dispatcher,
+/// client has to reconnect and send CONNECT to the new instance.
 pub struct Session<T, St>(Rc<SessionInner<T, St>>);
 
 struct SessionInner<T, St> {
@@ -0,0 +1,227 @@
+//! SOCKS5 proxy handshake (RFC 1928, RFC 1929)
+use std::{io, net::IpAddr};
+
+use ntex_bytes::{BufMut, ByteString, Bytes, BytesMut};
+use ntex_codec::{Decoder, Encoder};
+use ntex_io::IoBoxed;
+use ntex_net::connect::Address;
+
+use crate::error::ClientError;
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const DEFAULT_PORT: u16 = 1883;
+
+/// SOCKS5 proxy configuration
+#[derive(Debug, Clone)]
+pub(crate) struct Socks5<A> {
+    pub(crate) addr: A,
+    pub(crate) auth: Option<(ByteString, ByteString)>,
+}
+
+/// Split address to host and port
+pub(crate) fn host_port<A: Address>(addr: &A) -> (&str, u16) {
+    let host = addr.host();
+    let port = addr.port();
+    match host.rsplit_once(':') {
+        // ipv6 address without port
+        Some((h, _)) if h.contains(':') && !h.ends_with(']') => {
+            (host, port.unwrap_or(DEFAULT_PORT))
+        }
+        Some((h, p)) => match p.parse::<u16>() {
+            Ok(p) => (h.trim_start_matches('[').trim_end_matches(']'), port.unwrap_or(p)),
+            Err(_) => (host, port.unwrap_or(DEFAULT_PORT)),
+        },
+        None => (host, port.unwrap_or(DEFAULT_PORT)),
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Reply {
+    Method,
+    Auth,
+    Connect,
+}
+
+/// Codec for SOCKS5 handshake messages
+struct Codec(Reply);
+
+impl Decoder for Codec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let len = match self.0 {
+            Reply::Method | Reply::Auth => 2,
+            Reply::Connect => {
+                if src.len() < 5 {
+                    return Ok(None);
+                }
+                // ver, rep, rsv, atyp, bound address, port
+                match src[3] {
+                    ATYP_IPV4 => 4 + 4 + 2,
+                    ATYP_IPV6 => 4 + 16 + 2,
+                    ATYP_DOMAIN => 4 + 1 + src[4] as usize + 2,
+                    _ => return Err(invalid("Unknown address type in proxy reply")),
+                }
+            }
+        };
+        if src.len() < len {
+            Ok(None)
+        } else {
+            Ok(Some(src.split_to(len).freeze()))
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+async fn request(io: &IoBoxed, reply: Reply, req: Bytes) -> io::Result<Bytes> {
+    let codec = Codec(reply);
+    io.send(req, &codec).await.map_err(|e| e.into_inner())?;
+    match io.recv(&codec).await {
+        Ok(Some(res)) => Ok(res),
+        Ok(None) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Proxy is disconnected")),
+        Err(e) => Err(e.into_inner()),
+    }
+}
+
+/// Establish tunnel to `host:port` through SOCKS5 proxy
+///
+/// Domain names are resolved by the proxy.
+pub(crate) async fn handshake<T: std::fmt::Debug>(
+    io: &IoBoxed,
+    host: &str,
+    port: u16,
+    auth: Option<&(ByteString, ByteString)>,
+) -> Result<(), ClientError<T>> {
+    let greeting = if auth.is_some() {
+        Bytes::from_static(&[VERSION, 2, METHOD_NO_AUTH, METHOD_PASSWORD])
+    } else {
+        Bytes::from_static(&[VERSION, 1, METHOD_NO_AUTH])
+    };
+    let res = request(io, Reply::Method, greeting).await.map_err(proxy_io)?;
+    if res[0] != VERSION {
+        return Err(ClientError::Proxy("Unsupported proxy version"));
+    }
+
+    match (res[1], auth) {
+        (METHOD_NO_AUTH, _) => (),
+        (METHOD_PASSWORD, Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(ClientError::Proxy("Proxy username or password is too long"));
+            }
+            let mut buf = BytesMut::with_capacity(3 + user.len() + pass.len());
+            buf.put_u8(AUTH_VERSION);
+            buf.put_u8(user.len() as u8);
+            buf.extend_from_slice(user.as_bytes());
+            buf.put_u8(pass.len() as u8);
+            buf.extend_from_slice(pass.as_bytes());
+
+            let res = request(io, Reply::Auth, buf.freeze()).await.map_err(proxy_io)?;
+            if res[1] != 0 {
+                return Err(ClientError::Proxy("Proxy authentication failed"));
+            }
+        }
+        _ => return Err(ClientError::Proxy("No acceptable proxy authentication method")),
+    }
+
+    let mut buf = BytesMut::with_capacity(7 + host.len());
+    buf.extend_from_slice(&[VERSION, CMD_CONNECT, 0]);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            buf.put_u8(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            buf.put_u8(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(ClientError::Proxy("Host name is too long"));
+            }
+            buf.put_u8(ATYP_DOMAIN);
+            buf.put_u8(host.len() as u8);
+            buf.extend_from_slice(host.as_bytes());
+        }
+    }
+    buf.put_u16(port);
+
+    let res = request(io, Reply::Connect, buf.freeze()).await.map_err(proxy_io)?;
+    if res[1] != 0 {
+        log::trace!("Proxy connect failed, reply code: {}", res[1]);
+        return Err(ClientError::Proxy(match res[1] {
+            0x02 => "Connection not allowed by ruleset",
+            0x03 => "Network unreachable",
+            0x04 => "Host unreachable",
+            0x05 => "Connection refused",
+            0x08 => "Address type not supported",
+            _ => "Proxy connect failed",
+        }));
+    }
+    Ok(())
+}
+
+fn proxy_io<T: std::fmt::Debug>(err: io::Error) -> ClientError<T> {
+    ClientError::Disconnected(Some(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_port() {
+        assert_eq!(host_port(&"broker.local:8883".to_string()), ("broker.local", 8883));
+        assert_eq!(host_port(&"broker.local".to_string()), ("broker.local", 1883));
+        assert_eq!(host_port(&"[::1]:1884".to_string()), ("::1", 1884));
+        assert_eq!(host_port(&"::1".to_string()), ("::1", 1883));
+    }
+
+    #[test]
+    fn test_decode_connect_reply() {
+        let codec = Codec(Reply::Connect);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[5, 0, 0, ATYP_DOMAIN, 4, b'h', b'o']);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&[b's', b't', 0x07, 0x5b]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Bytes::from_static(&[
+                5,
+                0,
+                0,
+                ATYP_DOMAIN,
+                4,
+                b'h',
+                b'o',
+                b's',
+                b't',
+                0x07,
+                0x5b
+            ]))
+        );
+
+        buf.extend_from_slice(&[5, 0, 0, 9, 0]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! Testing utilities
+use std::fmt;
+
+use ntex_bytes::BytesMut;
+use ntex_io::{testing::IoTest, Io, IoBoxed};
+use ntex_service::ServiceFactory;
+use ntex_util::future::select;
+use ntex_util::time::{timeout_checked, Millis};
+
+use crate::error::ProtocolError;
+use crate::{v3, v5};
+
+/// Server is considered idle if it does not write anything within this period
+const REPLAY_IDLE: Millis = Millis(100);
+
+/// Packet type that could be collected by [`replay`]
+pub trait ReplayPacket: Sized {
+    /// Decode single packet from bytes slice
+    fn decode(src: &[u8]) -> Result<Option<(Self, usize)>, ProtocolError>;
+}
+
+impl ReplayPacket for v3::codec::Packet {
+    fn decode(src: &[u8]) -> Result<Option<(Self, usize)>, ProtocolError> {
+        v3::codec::decode_packet_slice(src)
+    }
+}
+
+impl ReplayPacket for v5::codec::Packet {
+    fn decode(src: &[u8]) -> Result<Option<(Self, usize)>, ProtocolError> {
+        v5::codec::decode_packet_slice(src)
+    }
+}
+
+/// Replay captured byte stream through the server.
+///
+/// Server is driven over in-memory io seeded with `bytes`, all frames
+/// written by the server are collected and decoded. Replay completes when
+/// server closes connection or stays idle for 100 millis.
+///
+/// ```rust,ignore
+/// let packets: Vec<v3::codec::Packet> =
+///     test::replay(&captured, v3::MqttServer::new(handshake).finish()).await;
+/// ```
+///
+/// # Panics
+///
+/// Panics if server service could not be created or if server output
+/// could not be decoded.
+pub async fn replay<F, P>(bytes: &[u8], factory: F) -> Vec<P>
+where
+    F: ServiceFactory<IoBoxed, Response = ()>,
+    F::InitError: fmt::Debug,
+    P: ReplayPacket,
+{
+    let (client, server) = IoTest::create();
+    client.remote_buffer_cap(1024 * 1024);
+    client.write(bytes);
+
+    let srv = factory.pipeline(()).await.expect("Cannot create server service");
+
+    let mut buf = BytesMut::new();
+    let collect = async {
+        while let Ok(Ok(data)) = timeout_checked(REPLAY_IDLE, client.read()).await {
+            buf.extend_from_slice(&data);
+        }
+    };
+    let _ = select(srv.call(IoBoxed::from(Io::new(server))), collect).await;
+    buf.extend_from_slice(&client.read_any());
+    client.close().await;
+
+    let mut packets = Vec::new();
+    let mut src = &buf[..];
+    while !src.is_empty() {
+        match P::decode(src) {
+            Ok(Some((pkt, size))) => {
+                packets.push(pkt);
+                src = &src[size..];
+            }
+            Ok(None) => panic!("Incomplete packet in server output: {:?}", src),
+            Err(err) => panic!("Cannot decode server output: {:?}", err),
+        }
+    }
+    packets
+}
@@ -2,8 +2,18 @@ use std::{fmt, fmt::Write, io};
 
 use ntex_bytes::ByteString;
 
-pub(crate) fn is_valid(topic: &str) -> bool {
-    if topic.is_empty() {
+/// Check if topic filter contains wildcards
+pub(crate) fn has_wildcards(topic: &str) -> bool {
+    topic.contains(['#', '+'])
+}
+
+/// Check if topic filter is valid
+///
+/// Topic filter must not be empty or longer than 65535 bytes and must not
+/// contain null characters. `+` wildcard must occupy an entire level and
+/// `#` wildcard must be the last level of the filter.
+pub fn is_valid_topic_filter(topic: &str) -> bool {
+    if topic.is_empty() || topic.len() > u16::MAX as usize || topic.contains('\0') {
         false
     } else {
         enum PrevState {
@@ -30,6 +40,14 @@ pub(crate) fn is_valid(topic: &str) -> bool {
     }
 }
 
+/// Check if topic name is valid
+///
+/// Topic name must not be empty or longer than 65535 bytes and must not
+/// contain wildcard or null characters.
+pub fn is_valid_topic_name(topic: &str) -> bool {
+    !topic.is_empty() && topic.len() <= u16::MAX as usize && !topic.contains(['#', '+', '\0'])
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TopicFilterError {
     InvalidTopic,
@@ -335,8 +353,41 @@ mod tests {
     #[test_case("a/#b" => false; "fail_hash4")]
     #[test_case("a/##" => false; "fail_hash5")]
     #[test_case("a/#+" => false; "fail_hash6")]
+    #[test_case("sport/tennis/player1/#" => true; "pass_spec1")]
+    #[test_case("sport/#" => true; "pass_spec2")]
+    #[test_case("sport/tennis/#" => true; "pass_spec3")]
+    #[test_case("sport/+/player1" => true; "pass_spec4")]
+    #[test_case("/finance" => true; "pass_spec5")]
+    #[test_case("$SYS/#" => true; "pass_spec6")]
+    #[test_case("sport/tennis#" => false; "fail_spec1")]
+    #[test_case("sport/tennis/#/ranking" => false; "fail_spec2")]
+    #[test_case("sport+" => false; "fail_spec3")]
+    #[test_case("" => false; "fail_empty")]
+    #[test_case("a/\0/b" => false; "fail_null")]
     fn check_is_valid(topic_filter: &'static str) -> bool {
-        is_valid(topic_filter)
+        is_valid_topic_filter(topic_filter)
+    }
+
+    #[test_case("sport/tennis/player1" => true; "pass_name1")]
+    #[test_case("/" => true; "pass_name2")]
+    #[test_case("$SYS/monitor/Clients" => true; "pass_name3")]
+    #[test_case("" => false; "fail_name_empty")]
+    #[test_case("sport/+" => false; "fail_name_plus")]
+    #[test_case("sport/#" => false; "fail_name_hash")]
+    #[test_case("sport\0" => false; "fail_name_null")]
+    fn check_is_valid_name(topic: &'static str) -> bool {
+        is_valid_topic_name(topic)
+    }
+
+    #[test]
+    fn check_max_len() {
+        let topic = "a".repeat(u16::MAX as usize);
+        assert!(is_valid_topic_filter(&topic));
+        assert!(is_valid_topic_name(&topic));
+
+        let topic = "a".repeat(u16::MAX as usize + 1);
+        assert!(!is_valid_topic_filter(&topic));
+        assert!(!is_valid_topic_name(&topic));
     }
 
     fn lvl_normal<T: AsRef<str>>(s: T) -> TopicFilterLevel {
@@ -1,3 +1,7 @@
+use std::{cell::Cell, num::NonZeroU16};
+
+use ntex_util::time::Millis;
+
 pub(crate) const MQTT: &[u8] = b"MQTT";
 pub(crate) const MQTT_LEVEL_3: u8 = 4;
 pub(crate) const MQTT_LEVEL_5: u8 = 5;
@@ -31,6 +35,133 @@ prim_enum! {
     }
 }
 
+/// Server side subscription policy
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SubscribePolicy {
+    /// Max number of subscriptions per connection, `0` means unlimited
+    pub(crate) max_subscriptions: usize,
+    /// Max QoS granted in SUBACK
+    pub(crate) max_granted_qos: QoS,
+    /// Reject topic filters with wildcards
+    pub(crate) deny_wildcards: bool,
+    /// Max number of topic filters per SUBSCRIBE packet, `0` means unlimited
+    pub(crate) max_filters: usize,
+}
+
+impl Default for SubscribePolicy {
+    fn default() -> Self {
+        Self {
+            max_subscriptions: 0,
+            max_granted_qos: QoS::ExactlyOnce,
+            deny_wildcards: false,
+            max_filters: 1024,
+        }
+    }
+}
+
+/// Action for publish service errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Pass error to control service, by default connection gets closed
+    Disconnect,
+    /// Drop error and acknowledge publish packet
+    Ignore,
+    /// Acknowledge publish packet and stop reading inbound packets for specified period
+    PauseReads(Millis),
+}
+
+/// Handling of control service errors
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ControlErrorPolicy {
+    /// Pass error to control service as `Control::Error` message
+    #[default]
+    Escalate,
+    /// Close connection
+    Close,
+    /// Log error, connection stays open. Control packet is not acknowledged
+    Ignore,
+}
+
+/// Packet id allocation strategy for outbound packets
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PacketIdStrategy {
+    /// Increment packet id for every new packet
+    #[default]
+    Monotonic,
+    /// Reuse lowest packet id that is not in-flight
+    LowestFree,
+}
+
+/// Egress filter decision for outbound packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision<T> {
+    /// Encode packet, including changes made by filter
+    Pass,
+    /// Do not send packet
+    Drop,
+    /// Encode provided packet instead
+    Replace(T),
+}
+
+/// Bit set over packet id space
+pub(crate) struct PacketIdSet(Box<[u64]>);
+
+impl PacketIdSet {
+    pub(crate) fn new() -> Self {
+        PacketIdSet(vec![0; 1024].into_boxed_slice())
+    }
+
+    pub(crate) fn insert(&mut self, id: NonZeroU16) {
+        let id = id.get() as usize;
+        self.0[id >> 6] |= 1 << (id & 63);
+    }
+
+    pub(crate) fn remove(&mut self, id: NonZeroU16) {
+        let id = id.get() as usize;
+        self.0[id >> 6] &= !(1 << (id & 63));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.iter_mut().for_each(|w| *w = 0);
+    }
+
+    /// Insert lowest id that is not in the set
+    pub(crate) fn insert_lowest(&mut self) -> Option<NonZeroU16> {
+        for (idx, word) in self.0.iter_mut().enumerate() {
+            // id 0 is not valid packet id
+            let free = if idx == 0 { !*word & !1 } else { !*word };
+            if free != 0 {
+                let bit = free.trailing_zeros() as usize;
+                *word |= 1 << bit;
+                return NonZeroU16::new((idx * 64 + bit) as u16);
+            }
+        }
+        None
+    }
+}
+
+/// Per-connection inbound traffic counters
+#[derive(Default)]
+pub(crate) struct StatsCounter {
+    publishes: Cell<u64>,
+    bytes: Cell<u64>,
+}
+
+impl StatsCounter {
+    pub(crate) fn packet(&self, size: u32) {
+        self.bytes.set(self.bytes.get() + u64::from(size));
+    }
+
+    pub(crate) fn publish(&self) {
+        self.publishes.set(self.publishes.get() + 1);
+    }
+
+    /// Take counters, returns number of publishes and bytes
+    pub(crate) fn take(&self) -> (u64, u64) {
+        (self.publishes.take(), self.bytes.take())
+    }
+}
+
 bitflags::bitflags! {
     #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct ConnectFlags: u8 {
@@ -1,6 +1,9 @@
-use std::{io::Cursor, num::NonZeroU16, num::NonZeroU32};
+use std::cell::Cell;
+use std::hash::{BuildHasher, Hasher};
+use std::{collections::hash_map::RandomState, io::Cursor, num::NonZeroU16, num::NonZeroU32};
 
 use ntex_bytes::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex_util::channel::condition::Condition;
 
 use crate::error::{DecodeError, EncodeError};
 
@@ -269,6 +272,12 @@ impl<'a> Encode for &'a [u8] {
     }
 }
 
+/// Generate client id for connection without client id
+pub(crate) fn generate_client_id() -> ByteString {
+    let token = RandomState::new().build_hasher().finish();
+    ByteString::from(format!("auto-{:016x}", token))
+}
+
 pub(crate) fn write_variable_length(len: u32, dst: &mut BytesMut) {
     match len {
         0..=127 => dst.put_u8(len as u8),
@@ -294,6 +303,47 @@ pub(crate) fn write_variable_length(len: u32, dst: &mut BytesMut) {
     }
 }
 
+/// Counter of in-progress operations
+///
+/// Allows to wait until all in-progress operations complete.
+pub(crate) struct InProgress {
+    count: Cell<usize>,
+    done: Condition,
+}
+
+impl Default for InProgress {
+    fn default() -> Self {
+        Self { count: Cell::new(0), done: Condition::new() }
+    }
+}
+
+impl InProgress {
+    /// Start new operation, operation completes when guard gets dropped
+    pub(crate) fn enter(&self) -> InProgressGuard<'_> {
+        self.count.set(self.count.get() + 1);
+        InProgressGuard(self)
+    }
+
+    /// Wait until there are no in-progress operations
+    pub(crate) async fn wait(&self) {
+        while self.count.get() != 0 {
+            self.done.wait().await;
+        }
+    }
+}
+
+pub(crate) struct InProgressGuard<'a>(&'a InProgress);
+
+impl Drop for InProgressGuard<'_> {
+    fn drop(&mut self) {
+        let count = self.0.count.get() - 1;
+        self.0.count.set(count);
+        if count == 0 {
+            self.0.done.notify();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
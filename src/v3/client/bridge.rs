@@ -0,0 +1,317 @@
+//! Topic forwarding between two mqtt connections
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+use std::{cell::RefCell, collections::hash_map::RandomState, collections::VecDeque, rc::Rc};
+
+use ntex_bytes::{ByteString, Bytes};
+use ntex_io::IoBoxed;
+use ntex_net::connect::{self, Address, Connect};
+use ntex_service::{fn_service, Service};
+use ntex_util::future::select;
+use ntex_util::time::{now, sleep, Millis};
+
+use super::{codec, control::Control, Client, MqttConnector, MqttSink};
+use crate::{error::SendPacketError, types::QoS};
+
+/// Number of forwarded messages remembered for loop prevention
+const RECENT_SIZE: usize = 256;
+
+/// Bridge options
+#[derive(Debug, Clone)]
+pub struct BridgeOptions {
+    /// Max QoS of forwarded publishes
+    ///
+    /// Forwarded publish is sent with `min(source QoS, max_qos)`. Client sink
+    /// does not send QoS 2 publishes, QoS 2 is downgraded to QoS 1.
+    /// By default max QoS is `QoS::AtLeastOnce`.
+    pub max_qos: QoS,
+    /// Forward retain flag of source publishes, enabled by default
+    pub retain: bool,
+    /// Delay before reconnecting, 1 second by default
+    pub reconnect_delay: Millis,
+    /// Loop detection window, used by [`bridge_pair`] only
+    ///
+    /// Message forwarded to a server is expected to come back from the same server
+    /// within the window, if the opposite direction subscription matches it.
+    /// Zero window disables loop detection. By default window is 1 second.
+    pub loop_window: Millis,
+}
+
+impl Default for BridgeOptions {
+    fn default() -> Self {
+        Self {
+            max_qos: QoS::AtLeastOnce,
+            retain: true,
+            reconnect_delay: Millis(1_000),
+            loop_window: Millis(1_000),
+        }
+    }
+}
+
+/// Forward publishes matching `filters` from `source` server to `dest` server
+///
+/// Bridge connects to both servers, subscribes to `filters` on source connection
+/// and republishes received messages to the same topic on destination connection.
+/// Source QoS 1 publish is acknowledged after destination server acknowledges
+/// forwarded publish. If either connection is lost or subscription is rejected,
+/// both connections get closed and bridge reconnects after `reconnect_delay`.
+///
+/// One way bridge does not detect loops. Use [`bridge_pair`] to forward messages
+/// in both directions, two `bridge()` futures with overlapping filters in opposite
+/// directions forward the same message back and forth. Both connectors must not
+/// point to the same server for the same reason.
+///
+/// Returned future never completes, drop it to stop the bridge.
+pub async fn bridge<A1, T1, A2, T2>(
+    source: MqttConnector<A1, T1>,
+    dest: MqttConnector<A2, T2>,
+    filters: Vec<(ByteString, QoS)>,
+    options: BridgeOptions,
+) where
+    A1: Address + Clone,
+    T1: Service<Connect<A1>, Error = connect::ConnectError>,
+    IoBoxed: From<T1::Response>,
+    A2: Address + Clone,
+    T2: Service<Connect<A2>, Error = connect::ConnectError>,
+    IoBoxed: From<T2::Response>,
+{
+    loop {
+        let dest_client = match dest.connect().await {
+            Ok(client) => client,
+            Err(err) => {
+                log::trace!("Cannot connect to bridge destination: {:?}", err);
+                sleep(options.reconnect_delay).await;
+                continue;
+            }
+        };
+        let source_client = match source.connect().await {
+            Ok(client) => client,
+            Err(err) => {
+                log::trace!("Cannot connect to bridge source: {:?}", err);
+                dest_client.sink().close();
+                sleep(options.reconnect_delay).await;
+                continue;
+            }
+        };
+        let dest_sink = dest_client.sink();
+        let source_sink = source_client.sink();
+
+        let _ = select(
+            dest_client.start_default(),
+            select(
+                run_source(source_client, dest_sink.clone(), None, options.clone()),
+                subscribe(source_sink.clone(), &filters),
+            ),
+        )
+        .await;
+
+        log::trace!("Bridge connection is lost, reconnecting");
+        source_sink.close();
+        dest_sink.close();
+        sleep(options.reconnect_delay).await;
+    }
+}
+
+/// Forward publishes between `a` and `b` servers in both directions
+///
+/// Bridge keeps one connection to each server. Publishes matching `a_filters` are
+/// forwarded from `a` to `b`, publishes matching `b_filters` are forwarded from
+/// `b` to `a`. Acknowledgement, QoS and reconnect handling is the same as for
+/// [`bridge`]. Empty filters list disables forwarding in that direction.
+///
+/// Loops are detected by origin. Bridge remembers messages it publishes to
+/// each server, message received from a server that matches (by topic and payload)
+/// a message bridge published to that server within `loop_window` is treated as
+/// the echo of bridge's own publish and is not forwarded. Each published message
+/// suppresses at most one echo. Server does not report the origin of a publish,
+/// so if another client publishes identical message to the same server before
+/// the echo arrives, that message is dropped and the echo is forwarded once.
+/// At most 256 messages per direction are remembered.
+///
+/// Returned future never completes, drop it to stop the bridge.
+pub async fn bridge_pair<A1, T1, A2, T2>(
+    a: MqttConnector<A1, T1>,
+    b: MqttConnector<A2, T2>,
+    a_filters: Vec<(ByteString, QoS)>,
+    b_filters: Vec<(ByteString, QoS)>,
+    options: BridgeOptions,
+) where
+    A1: Address + Clone,
+    T1: Service<Connect<A1>, Error = connect::ConnectError>,
+    IoBoxed: From<T1::Response>,
+    A2: Address + Clone,
+    T2: Service<Connect<A2>, Error = connect::ConnectError>,
+    IoBoxed: From<T2::Response>,
+{
+    // messages published by bridge, per server
+    let sent_to_a = Rc::new(Recent::default());
+    let sent_to_b = Rc::new(Recent::default());
+
+    loop {
+        let a_client = match a.connect().await {
+            Ok(client) => client,
+            Err(err) => {
+                log::trace!("Cannot connect to bridge server: {:?}", err);
+                sleep(options.reconnect_delay).await;
+                continue;
+            }
+        };
+        let b_client = match b.connect().await {
+            Ok(client) => client,
+            Err(err) => {
+                log::trace!("Cannot connect to bridge server: {:?}", err);
+                a_client.sink().close();
+                sleep(options.reconnect_delay).await;
+                continue;
+            }
+        };
+        let a_sink = a_client.sink();
+        let b_sink = b_client.sink();
+
+        let a_loops = Loops { received: sent_to_a.clone(), sent: sent_to_b.clone() };
+        let b_loops = Loops { received: sent_to_b.clone(), sent: sent_to_a.clone() };
+        let _ = select(
+            select(
+                run_source(a_client, b_sink.clone(), Some(a_loops), options.clone()),
+                subscribe(a_sink.clone(), &a_filters),
+            ),
+            select(
+                run_source(b_client, a_sink.clone(), Some(b_loops), options.clone()),
+                subscribe(b_sink.clone(), &b_filters),
+            ),
+        )
+        .await;
+
+        log::trace!("Bridge connection is lost, reconnecting");
+        a_sink.close();
+        b_sink.close();
+        sleep(options.reconnect_delay).await;
+    }
+}
+
+/// Loop detection state of one direction
+#[derive(Clone)]
+struct Loops {
+    /// Messages bridge published to source server
+    received: Rc<Recent>,
+    /// Messages bridge publishes to destination server
+    sent: Rc<Recent>,
+}
+
+async fn run_source(
+    client: Client,
+    dest: MqttSink,
+    loops: Option<Loops>,
+    options: BridgeOptions,
+) {
+    let service = fn_service(move |msg: Control<SendPacketError>| {
+        let dest = dest.clone();
+        let loops = loops.clone();
+        let options = options.clone();
+        async move {
+            match msg {
+                Control::Publish(publish) => {
+                    let (ack, pkt) = publish.into_inner();
+                    forward(&dest, loops.as_ref(), &options, pkt).await?;
+                    Ok::<_, SendPacketError>(ack)
+                }
+                msg => Ok(msg.disconnect()),
+            }
+        }
+    });
+    let _ = client.start(service).await;
+}
+
+/// Subscribe to filters, close connection if subscription is rejected
+async fn subscribe(sink: MqttSink, filters: &[(ByteString, QoS)]) {
+    if filters.is_empty() {
+        return std::future::pending::<()>().await;
+    }
+    let mut builder = sink.subscribe();
+    for (filter, qos) in filters {
+        builder = builder.topic_filter(filter.clone(), *qos);
+    }
+    match builder.send().await {
+        Ok(codes) if codes.iter().all(|c| *c != codec::SubscribeReturnCode::Failure) => {
+            std::future::pending::<()>().await
+        }
+        res => {
+            log::error!("Bridge subscription failed: {:?}", res);
+            sink.close();
+        }
+    }
+}
+
+async fn forward(
+    dest: &MqttSink,
+    loops: Option<&Loops>,
+    options: &BridgeOptions,
+    pkt: codec::Publish,
+) -> Result<(), SendPacketError> {
+    if let Some(loops) = loops.filter(|_| !options.loop_window.is_zero()) {
+        // do not forward echo of the message bridge published to source
+        if loops.received.take(&pkt.topic, &pkt.payload, options.loop_window) {
+            log::trace!("Skip looped publish to {:?}", pkt.topic);
+            return Ok(());
+        }
+        loops.sent.insert(&pkt.topic, &pkt.payload);
+    }
+
+    let qos = pkt.qos.min(options.max_qos);
+    let mut builder = dest.publish(pkt.topic, pkt.payload);
+    if options.retain && pkt.retain {
+        builder = builder.retain();
+    }
+    if qos == QoS::AtMostOnce {
+        builder.send_at_most_once()
+    } else {
+        builder.send_at_least_once().await
+    }
+}
+
+/// Hashes of recently published messages
+#[derive(Default)]
+struct Recent {
+    state: RandomState,
+    hashes: RefCell<VecDeque<(u64, Instant)>>,
+}
+
+impl Recent {
+    fn hash(&self, topic: &str, payload: &Bytes) -> u64 {
+        // length prefix keeps topic and payload boundary
+        let mut hasher = self.state.build_hasher();
+        hasher.write_usize(topic.len());
+        hasher.write(topic.as_bytes());
+        hasher.write(payload);
+        hasher.finish()
+    }
+
+    fn insert(&self, topic: &str, payload: &Bytes) {
+        let hash = self.hash(topic, payload);
+        let mut hashes = self.hashes.borrow_mut();
+        if hashes.len() == RECENT_SIZE {
+            hashes.pop_front();
+        }
+        hashes.push_back((hash, now()));
+    }
+
+    /// Remove message from recent list, returns `true` if message is found
+    fn take(&self, topic: &str, payload: &Bytes, window: Millis) -> bool {
+        let hash = self.hash(topic, payload);
+        let mut hashes = self.hashes.borrow_mut();
+
+        // drop expired entries
+        let now = now();
+        let window = Duration::from(window);
+        while hashes.front().map(|(_, t)| now.duration_since(*t) > window).unwrap_or(false) {
+            hashes.pop_front();
+        }
+
+        if let Some(pos) = hashes.iter().position(|(h, _)| *h == hash) {
+            hashes.remove(pos);
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -1,18 +1,27 @@
 #![allow(clippy::let_underscore_future)]
-use std::{fmt, marker::PhantomData, rc::Rc};
+use std::{cell::Cell, fmt, future::Future, marker::PhantomData, rc::Rc};
 
+use ntex_bytes::ByteString;
 use ntex_io::{DispatcherConfig, IoBoxed};
 use ntex_router::{IntoPattern, Router, RouterBuilder};
 use ntex_service::{boxed, fn_service, IntoService, Pipeline, Service};
+use ntex_util::channel::oneshot;
 use ntex_util::future::{Either, Ready};
 use ntex_util::time::{sleep, Millis, Seconds};
 
+use crate::error::{MqttError, SendPacketError};
 use crate::v3::{codec, shared::MqttShared, sink::MqttSink, ControlAck, Publish};
-use crate::{error::MqttError, io::Dispatcher};
+use crate::{io::Dispatcher, types::QoS};
 
 use super::{control::Control, dispatcher::create_dispatcher};
 
 /// Mqtt client
+///
+/// Use client's sink to publish, subscribe or unsubscribe. Inbound publishes
+/// are dispatched to services registered with `resource()`, publishes that do not
+/// match any resource are passed to control service as `Control::Publish` message.
+/// Note, `start_default()` closes connection on any control message, including
+/// unmatched publishes.
 pub struct Client {
     io: IoBoxed,
     shared: Rc<MqttShared>,
@@ -20,8 +29,11 @@ pub struct Client {
     session_present: bool,
     max_receive: usize,
     config: DispatcherConfig,
+    ready: Cell<Option<oneshot::Receiver<SubscribeResult>>>,
 }
 
+type SubscribeResult = Result<Vec<codec::SubscribeReturnCode>, SendPacketError>;
+
 impl fmt::Debug for Client {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("v3::Client")
@@ -42,7 +54,22 @@ impl Client {
         keepalive_timeout: Seconds,
         max_receive: usize,
         config: DispatcherConfig,
+        subscriptions: &[(ByteString, QoS)],
     ) -> Self {
+        let ready = if subscriptions.is_empty() {
+            None
+        } else {
+            let mut builder = MqttSink::new(shared.clone()).subscribe();
+            for (filter, qos) in subscriptions {
+                builder = builder.topic_filter(filter.clone(), *qos);
+            }
+            let (tx, rx) = oneshot::channel();
+            ntex_util::spawn(async move {
+                let _ = tx.send(builder.send().await);
+            });
+            Some(rx)
+        };
+
         Client {
             io,
             shared,
@@ -50,6 +77,7 @@ impl Client {
             max_receive,
             config,
             keepalive: keepalive_timeout,
+            ready: Cell::new(ready),
         }
     }
 }
@@ -67,7 +95,29 @@ impl Client {
         self.session_present
     }
 
+    /// Wait until initial subscriptions are acknowledged
+    ///
+    /// Resolves with return codes of subscriptions registered with
+    /// `MqttConnector::subscribe()`, or with empty list if connector has no
+    /// subscriptions. SUBACK is handled by client's dispatcher, so returned
+    /// future must be obtained before client is started and awaited after.
+    /// Only first call waits for SUBACK, subsequent calls resolve immediately.
+    pub fn ready(&self) -> impl Future<Output = SubscribeResult> {
+        let rx = self.ready.take();
+        async move {
+            match rx {
+                Some(rx) => rx.await.unwrap_or(Err(SendPacketError::Disconnected)),
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+
     /// Configure mqtt resource for a specific topic
+    ///
+    /// Inbound publish packets with matching topic are handled by `service`.
+    /// `address` is a router pattern, not mqtt topic filter: use `{name}`
+    /// for a single topic level (e.g. `topic/{id}`) and `{name}*` or `*` for
+    /// remaining levels; mqtt wildcards `+` and `#` match literally.
     pub fn resource<T, F, U>(self, address: T, service: F) -> ClientRouter<U::Error, U::Error>
     where
         T: IntoPattern,
@@ -167,6 +217,11 @@ where
     PErr: 'static,
 {
     /// Configure mqtt resource for a specific topic
+    ///
+    /// Inbound publish packets with matching topic are handled by `service`.
+    /// `address` is a router pattern, not mqtt topic filter: use `{name}`
+    /// for a single topic level (e.g. `topic/{id}`) and `{name}*` or `*` for
+    /// remaining levels; mqtt wildcards `+` and `#` match literally.
     pub fn resource<T, F, S>(mut self, address: T, service: F) -> Self
     where
         T: IntoPattern,
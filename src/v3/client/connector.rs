@@ -1,4 +1,4 @@
-use std::rc::Rc;
+use std::{num::NonZeroU16, rc::Rc};
 
 use ntex_bytes::{ByteString, Bytes, PoolId};
 use ntex_io::{DispatcherConfig, IoBoxed};
@@ -6,7 +6,9 @@ use ntex_net::connect::{self, Address, Connect, Connector};
 use ntex_service::{IntoService, Pipeline, Service};
 use ntex_util::time::{timeout_checked, Seconds};
 
-use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
+use super::{codec, connection::Client, error::ClientError, error::ProtocolError, MqttSink};
+use crate::socks::{self, Socks5};
+use crate::types::QoS;
 use crate::v3::shared::{MqttShared, MqttSinkPool};
 
 /// Mqtt client connector
@@ -18,10 +20,79 @@ pub struct MqttConnector<A, T> {
     max_send: usize,
     max_receive: usize,
     handshake_timeout: Seconds,
+    initial_packet_id: Option<NonZeroU16>,
+    retransmit_interval: Seconds,
+    proxy: Option<Socks5<A>>,
+    subscriptions: Vec<(ByteString, QoS)>,
     config: DispatcherConfig,
     pool: Rc<MqttSinkPool>,
 }
 
+/// Mqtt client connection options
+///
+/// Plain representation of connector configuration, can be deserialized
+/// from configuration file and applied with `MqttConnector::with_options()`.
+/// Tls is configured with custom connector, see `MqttConnector::connector()`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ConnectOptions {
+    /// Client identifier
+    pub client_id: String,
+    /// Keep-alive interval in seconds
+    pub keep_alive: u16,
+    /// Start new session
+    pub clean_session: bool,
+    /// Username for authentication
+    pub username: Option<String>,
+    /// Password for authentication
+    pub password: Option<String>,
+    /// Will message
+    pub last_will: Option<LastWillOptions>,
+    /// Max incoming packet size, `0` disables limit
+    pub max_size: u32,
+    /// Number of in-flight outgoing publish packets
+    pub max_send: u16,
+    /// Number of inbound in-flight concurrent messages
+    pub max_receive: u16,
+    /// Handshake timeout in seconds, `0` disables timeout
+    pub handshake_timeout: u16,
+    /// Disconnect timeout in seconds, `0` disables timeout
+    pub disconnect_timeout: u16,
+}
+
+/// Will message options
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LastWillOptions {
+    /// Will topic
+    pub topic: String,
+    /// Will message payload
+    #[serde(default)]
+    pub message: String,
+    /// QoS level of will message
+    pub qos: QoS,
+    /// Retain will message
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            keep_alive: 0,
+            clean_session: false,
+            username: None,
+            password: None,
+            last_will: None,
+            max_size: 64 * 1024,
+            max_send: 16,
+            max_receive: 16,
+            handshake_timeout: 0,
+            disconnect_timeout: 3,
+        }
+    }
+}
+
 impl<A> MqttConnector<A, ()>
 where
     A: Address + Clone,
@@ -41,6 +112,10 @@ where
             max_send: 16,
             max_receive: 16,
             handshake_timeout: Seconds::ZERO,
+            initial_packet_id: None,
+            retransmit_interval: Seconds::ZERO,
+            proxy: None,
+            subscriptions: Vec::new(),
             pool: Rc::new(MqttSinkPool::default()),
         }
     }
@@ -137,6 +212,29 @@ where
         self
     }
 
+    /// Apply connection options
+    ///
+    /// Options override values previously set with builder methods.
+    pub fn with_options(mut self, opts: ConnectOptions) -> Self {
+        self.pkt.client_id = opts.client_id.into();
+        self.pkt.keep_alive = opts.keep_alive;
+        self.pkt.clean_session = opts.clean_session;
+        self.pkt.username = opts.username.map(ByteString::from);
+        self.pkt.password = opts.password.map(Bytes::from);
+        self.pkt.last_will = opts.last_will.map(|will| codec::LastWill {
+            qos: will.qos,
+            retain: will.retain,
+            topic: will.topic.into(),
+            message: will.message.into(),
+        });
+        self.max_size = opts.max_size;
+        self.max_send = opts.max_send as usize;
+        self.max_receive = opts.max_receive as usize;
+        self.handshake_timeout = Seconds(opts.handshake_timeout);
+        self.config.set_disconnect_timeout(Seconds(opts.disconnect_timeout));
+        self
+    }
+
     #[inline]
     /// Update connect packet
     pub fn packet<F>(mut self, f: F) -> Self
@@ -156,6 +254,30 @@ where
         self
     }
 
+    /// Set initial packet id.
+    ///
+    /// Sink allocates packet ids starting from `id`. Allocated ids wrap
+    /// around and skip ids of in-flight packets.
+    ///
+    /// By default packet ids start from 1.
+    pub fn initial_packet_id(mut self, id: NonZeroU16) -> Self {
+        self.initial_packet_id = Some(id);
+        self
+    }
+
+    /// Set publish retransmit interval.
+    ///
+    /// If PUBACK packet is not received within interval, sink re-sends
+    /// the PUBLISH packet with DUP flag set on the same connection. Retransmit
+    /// happens while `send_at_least_once()` future is awaited, publishes sent
+    /// with `send_at_least_once_no_block()` are not retransmitted.
+    ///
+    /// By default retransmit is disabled.
+    pub fn retransmit_interval(mut self, interval: Option<Seconds>) -> Self {
+        self.retransmit_interval = interval.unwrap_or(Seconds::ZERO);
+        self
+    }
+
     /// Set client connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -178,7 +300,37 @@ where
         self
     }
 
+    /// Connect through SOCKS5 proxy
+    ///
+    /// Connector establishes connection to the proxy and requests a tunnel
+    /// to the server address, server host name is resolved by the proxy.
+    /// `auth` is an optional username and password pair. Proxy handshake runs
+    /// on top of io object returned by connector, connections that require TLS
+    /// to the server are not supported.
+    pub fn socks5_proxy(mut self, addr: A, auth: Option<(ByteString, ByteString)>) -> Self {
+        self.proxy = Some(Socks5 { addr, auth });
+        self
+    }
+
+    /// Subscribe to topic filters after connect
+    ///
+    /// Connector sends SUBSCRIBE packet for all registered filters right after
+    /// CONNACK is received, use `Client::ready()` to wait for SUBACK.
+    /// Filters are subscribed on every successful connect.
+    pub fn subscribe<I>(mut self, filters: I) -> Self
+    where
+        I: IntoIterator<Item = (ByteString, QoS)>,
+    {
+        self.subscriptions.extend(filters);
+        self
+    }
+
     /// Use custom connector
+    ///
+    /// Socket level options (`TCP_NODELAY`, `SO_KEEPALIVE`, etc) are not managed by
+    /// the connector, ntex runtime enables `TCP_NODELAY` for all tcp connections.
+    /// To use different socket options, provide custom connector that configures
+    /// the socket before converting it to `Io` object.
     pub fn connector<U, F>(self, connector: F) -> MqttConnector<A, U>
     where
         F: IntoService<U, Connect<A>>,
@@ -194,6 +346,10 @@ where
             max_send: self.max_send,
             max_receive: self.max_receive,
             handshake_timeout: self.handshake_timeout,
+            initial_packet_id: self.initial_packet_id,
+            retransmit_interval: self.retransmit_interval,
+            proxy: self.proxy,
+            subscriptions: self.subscriptions,
             pool: self.pool,
         }
     }
@@ -207,14 +363,44 @@ where
 {
     /// Connect to mqtt server
     pub async fn connect(&self) -> Result<Client, ClientError<codec::ConnectAck>> {
-        match timeout_checked(self.handshake_timeout, self._connect()).await {
+        match timeout_checked(self.handshake_timeout, self._connect(None)).await {
             Ok(res) => res.map_err(From::from),
             Err(_) => Err(ClientError::HandshakeTimeout),
         }
     }
 
-    async fn _connect(&self) -> Result<Client, ClientError<codec::ConnectAck>> {
-        let io: IoBoxed = self.connector.call(Connect::new(self.address.clone())).await?.into();
+    /// Reconnect to mqtt server and resume session of previous connection
+    ///
+    /// Unless connect packet requests clean session, unacknowledged QoS 1 publishes
+    /// of `sink` are re-sent with DUP flag and the same packet ids, in original
+    /// send order, before any packet of the new connection. Publish futures of
+    /// previous connection resolve with `SendPacketError::Disconnected`, re-sent
+    /// publishes are tracked as in-flight packets of the new sink.
+    ///
+    /// Unacknowledged publishes are kept if connect fails.
+    pub async fn reconnect(
+        &self,
+        sink: &MqttSink,
+    ) -> Result<Client, ClientError<codec::ConnectAck>> {
+        match timeout_checked(self.handshake_timeout, self._connect(Some(sink))).await {
+            Ok(res) => res,
+            Err(_) => Err(ClientError::HandshakeTimeout),
+        }
+    }
+
+    async fn _connect(
+        &self,
+        prev: Option<&MqttSink>,
+    ) -> Result<Client, ClientError<codec::ConnectAck>> {
+        let io: IoBoxed = if let Some(ref proxy) = self.proxy {
+            let io: IoBoxed =
+                self.connector.call(Connect::new(proxy.addr.clone())).await?.into();
+            let (host, port) = socks::host_port(&self.address);
+            socks::handshake(&io, host, port, proxy.auth.as_ref()).await?;
+            io
+        } else {
+            self.connector.call(Connect::new(self.address.clone())).await?.into()
+        };
         let pkt = self.pkt.clone();
         let max_send = self.max_send;
         let max_receive = self.max_receive;
@@ -232,12 +418,22 @@ where
         })?;
 
         let shared = Rc::new(MqttShared::new(io.get_ref(), codec, true, pool));
+        shared.set_ack_timeout(self.handshake_timeout.into());
+        if let Some(id) = self.initial_packet_id {
+            shared.set_initial_packet_id(id);
+        }
+        shared.set_retransmit_interval(self.retransmit_interval);
 
         match packet {
             (codec::Packet::ConnectAck(pkt), _) => {
                 log::trace!("Connect ack response from server: session: present: {:?}, return code: {:?}", pkt.session_present, pkt.return_code);
                 if pkt.return_code == codec::ConnectAckReason::ConnectionAccepted {
                     shared.set_cap(max_send);
+                    if let Some(prev) = prev {
+                        if !self.pkt.clean_session {
+                            shared.resend_unacked(prev.shared().take_unacked());
+                        }
+                    }
                     Ok(Client::new(
                         io,
                         shared,
@@ -245,6 +441,7 @@ where
                         Seconds(keepalive_timeout),
                         max_receive,
                         config,
+                        &self.subscriptions,
                     ))
                 } else {
                     Err(ClientError::Ack(pkt))
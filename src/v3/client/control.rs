@@ -36,7 +36,7 @@ impl<E> Control<E> {
     }
 
     pub(super) fn peer_gone(err: Option<io::Error>) -> Self {
-        Control::PeerGone(PeerGone(err))
+        Control::PeerGone(PeerGone(err, false))
     }
 
     /// Initiate clean disconnect
@@ -1,11 +1,12 @@
 //! MQTT 3.1.1 client
+pub mod bridge;
 mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
 
 pub use self::connection::{Client, ClientRouter};
-pub use self::connector::MqttConnector;
+pub use self::connector::{ConnectOptions, LastWillOptions, MqttConnector};
 pub use self::control::{Control, ControlAck};
 
 pub use crate::topic::{TopicFilter, TopicFilterError};
@@ -1,39 +1,130 @@
-use std::cell::Cell;
+use std::{cell::Cell, cell::RefCell, fmt, rc::Rc};
 
-use ntex_bytes::{Buf, BytesMut};
+use ntex_bytes::{Buf, Bytes, BytesMut};
 use ntex_codec::{Decoder, Encoder};
 
 use super::{decode, encode, Packet, Publish};
-use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, QoS};
+use crate::error::{DecodeError, EncodeError, ProtocolError};
+use crate::types::{FilterDecision, FixedHeader, QoS};
 use crate::utils::decode_variable_length;
+use crate::ServerHandle;
 
 #[derive(Debug, Clone)]
 /// Mqtt v3.1.1 protocol codec
 pub struct Codec {
     state: Cell<DecodeState>,
     max_size: Cell<u32>,
+    oversize: RefCell<OversizePolicy>,
+    passthrough: Cell<bool>,
+    metrics: RefCell<Option<ServerHandle>>,
+    egress: RefCell<Option<EgressFilter>>,
+}
+
+#[derive(Clone)]
+struct EgressFilter(Rc<dyn Fn(&mut Packet) -> FilterDecision<Packet>>);
+
+impl fmt::Debug for EgressFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EgressFilter")
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum DecodeState {
     FrameHeader,
     Frame(FixedHeader),
+    Skip(u32),
+}
+
+#[derive(Clone, Default)]
+/// Oversized inbound packet handling policy
+pub enum OversizePolicy {
+    /// Fail with `DecodeError::MaxSizeExceeded` error
+    #[default]
+    Close,
+    /// Discard oversized packet and continue decoding
+    SkipPacket,
+    /// Discard oversized packet and call provided function.
+    ///
+    /// Function receives packet type and declared remaining length of the packet.
+    Callback(Rc<dyn Fn(u8, u32)>),
+}
+
+impl OversizePolicy {
+    /// Create `Callback` policy
+    pub fn callback<F>(f: F) -> Self
+    where
+        F: Fn(u8, u32) + 'static,
+    {
+        OversizePolicy::Callback(Rc::new(f))
+    }
+}
+
+impl fmt::Debug for OversizePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OversizePolicy::Close => write!(f, "OversizePolicy::Close"),
+            OversizePolicy::SkipPacket => write!(f, "OversizePolicy::SkipPacket"),
+            OversizePolicy::Callback(_) => write!(f, "OversizePolicy::Callback"),
+        }
+    }
 }
 
 impl Codec {
     /// Create `Codec` instance
     pub fn new() -> Self {
-        Codec { state: Cell::new(DecodeState::FrameHeader), max_size: Cell::new(0) }
+        Codec {
+            state: Cell::new(DecodeState::FrameHeader),
+            max_size: Cell::new(0),
+            oversize: RefCell::new(OversizePolicy::Close),
+            passthrough: Cell::new(false),
+            metrics: RefCell::new(None),
+            egress: RefCell::new(None),
+        }
     }
 
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
+    /// Limit is checked against frame's fixed header, so it is safe to change max size
+    /// on established connection, new limit applies starting from next frame.
     /// By default max size is set to `0`
     pub fn set_max_size(&self, size: u32) {
         self.max_size.set(size);
     }
+
+    /// Set oversized inbound packet handling policy.
+    ///
+    /// With `SkipPacket` or `Callback` policy, oversized packet is not buffered,
+    /// its bytes get discarded as they arrive.
+    /// By default policy is set to `OversizePolicy::Close`
+    pub fn set_oversize_policy(&self, policy: OversizePolicy) {
+        *self.oversize.borrow_mut() = policy;
+    }
+
+    /// Pass through packets with unrecognized fixed header.
+    ///
+    /// Unrecognized packets are decoded as `Packet::Unknown` instead of
+    /// failing with `DecodeError::UnsupportedPacketType`, so they could be
+    /// relayed verbatim, i.e. by a proxy. By default passthrough is disabled.
+    /// Known packet types with invalid fixed header flags are rejected
+    /// with `DecodeError::MalformedPacket`.
+    pub fn set_passthrough_unknown(&self, val: bool) {
+        self.passthrough.set(val);
+    }
+
+    /// Count decode errors in server handle
+    pub(crate) fn set_metrics(&self, handle: Option<ServerHandle>) {
+        *self.metrics.borrow_mut() = handle;
+    }
+
+    /// Set filter for outbound packets, filter is called before packet encoding
+    pub(crate) fn set_egress_filter(
+        &self,
+        filter: Option<Rc<dyn Fn(&mut Packet) -> FilterDecision<Packet>>>,
+    ) {
+        *self.egress.borrow_mut() = filter.map(EgressFilter);
+    }
 }
 
 impl Default for Codec {
@@ -42,11 +133,8 @@ impl Default for Codec {
     }
 }
 
-impl Decoder for Codec {
-    type Item = (Packet, u32);
-    type Error = DecodeError;
-
-    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+impl Codec {
+    fn decode_frame(&self, src: &mut BytesMut) -> Result<Option<(Packet, u32)>, DecodeError> {
         loop {
             match self.state.get() {
                 DecodeState::FrameHeader => {
@@ -60,7 +148,23 @@ impl Decoder for Codec {
                             // check max message size
                             let max_size = self.max_size.get();
                             if max_size != 0 && max_size < remaining_length {
-                                return Err(DecodeError::MaxSizeExceeded);
+                                match &*self.oversize.borrow() {
+                                    OversizePolicy::Close => {
+                                        return Err(DecodeError::MaxSizeExceeded)
+                                    }
+                                    OversizePolicy::SkipPacket => (),
+                                    OversizePolicy::Callback(f) => {
+                                        f(first_byte >> 4, remaining_length)
+                                    }
+                                }
+                                log::trace!(
+                                    "Skip oversized packet, max-size: {}, remaining: {}",
+                                    max_size,
+                                    remaining_length
+                                );
+                                src.advance(consumed + 1);
+                                self.state.set(DecodeState::Skip(remaining_length));
+                                continue;
                             }
                             src.advance(consumed + 1);
                             self.state.set(DecodeState::Frame(FixedHeader {
@@ -84,22 +188,65 @@ impl Decoder for Codec {
                     if src.len() < fixed.remaining_length as usize {
                         return Ok(None);
                     }
-                    let packet_buf = src.split_to(fixed.remaining_length as usize);
-                    let packet = decode::decode_packet(packet_buf.freeze(), fixed.first_byte)?;
+                    let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
+                    let packet =
+                        match decode::decode_packet(packet_buf.clone(), fixed.first_byte) {
+                            Err(DecodeError::UnsupportedPacketType)
+                                if self.passthrough.get() =>
+                            {
+                                Packet::Unknown {
+                                    packet_type: fixed.first_byte >> 4,
+                                    flags: fixed.first_byte & 0b0000_1111,
+                                    payload: packet_buf,
+                                }
+                            }
+                            res => res?,
+                        };
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(2);
                     return Ok(Some((packet, fixed.remaining_length)));
                 }
+                DecodeState::Skip(remaining) => {
+                    let size = std::cmp::min(src.len(), remaining as usize);
+                    src.advance(size);
+                    if size < remaining as usize {
+                        self.state.set(DecodeState::Skip(remaining - size as u32));
+                        return Ok(None);
+                    }
+                    self.state.set(DecodeState::FrameHeader);
+                }
             }
         }
     }
 }
 
+impl Decoder for Codec {
+    type Item = (Packet, u32);
+    type Error = DecodeError;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        let result = self.decode_frame(src);
+        if let Err(err) = result {
+            if let Some(ref handle) = *self.metrics.borrow() {
+                handle.record_decode_error(err);
+            }
+        }
+        result
+    }
+}
+
 impl Encoder for Codec {
     type Item = Packet;
     type Error = EncodeError;
 
-    fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
+    fn encode(&self, mut item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        if let Some(ref filter) = *self.egress.borrow() {
+            match (filter.0)(&mut item) {
+                FilterDecision::Pass => (),
+                FilterDecision::Drop => return Ok(()),
+                FilterDecision::Replace(pkt) => item = pkt,
+            }
+        }
         if let Packet::Publish(Publish { qos, packet_id, .. }) = item {
             if (qos == QoS::AtLeastOnce || qos == QoS::ExactlyOnce) && packet_id.is_none() {
                 return Err(EncodeError::PacketIdRequired);
@@ -112,10 +259,35 @@ impl Encoder for Codec {
     }
 }
 
+/// Decode single packet from bytes slice.
+///
+/// Returns `Ok(None)` if slice does not contain complete packet, otherwise returns
+/// decoded packet and number of consumed bytes. Function does not panic on
+/// arbitrary input, so it could be used as a fuzz target.
+pub fn decode_packet_slice(src: &[u8]) -> Result<Option<(Packet, usize)>, ProtocolError> {
+    if src.len() < 2 {
+        return Ok(None);
+    }
+    let first_byte = src[0];
+    if let Some((remaining_length, consumed)) = decode_variable_length(&src[1..])? {
+        let start = consumed + 1;
+        let end = start + remaining_length as usize;
+        if src.len() < end {
+            return Ok(None);
+        }
+        let packet =
+            decode::decode_packet(Bytes::copy_from_slice(&src[start..end]), first_byte)?;
+        Ok(Some((packet, end)))
+    } else {
+        Ok(None)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ntex_bytes::{ByteString, Bytes};
+    use ntex_bytes::ByteString;
+    use std::num::NonZeroU16;
 
     #[test]
     fn test_max_size() {
@@ -127,6 +299,67 @@ mod tests {
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
 
+    #[test]
+    fn test_max_size_change() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\x08\x00\x01t");
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+
+        // partially received frame is not affected
+        codec.set_max_size(4);
+        buf.extend_from_slice(b"hello");
+        assert!(matches!(codec.decode(&mut buf), Ok(Some((Packet::Publish(_), 8)))));
+
+        buf.extend_from_slice(b"\x30\x08\x00\x01thello");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+    }
+
+    #[test]
+    fn test_passthrough_unknown() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x03\x02ab");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::UnsupportedPacketType));
+
+        let codec = Codec::new();
+        codec.set_passthrough_unknown(true);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x03\x02ab\xc0\0");
+        let pkt =
+            Packet::Unknown { packet_type: 0, flags: 3, payload: Bytes::from_static(b"ab") };
+        assert_eq!(codec.decode(&mut buf), Ok(Some((pkt.clone(), 2))));
+        assert_eq!(codec.decode(&mut buf), Ok(Some((Packet::PingRequest, 0))));
+
+        // relayed verbatim
+        codec.encode(pkt, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"\x03\x02ab");
+    }
+
+    #[test]
+    fn test_oversize_skip() {
+        let codec = Codec::new();
+        codec.set_max_size(5);
+        codec.set_oversize_policy(OversizePolicy::SkipPacket);
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\x0a\0\x01");
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+        assert!(buf.is_empty());
+        buf.extend_from_slice(b"tpayload\xc0\0");
+        assert_eq!(codec.decode(&mut buf), Ok(Some((Packet::PingRequest, 0))));
+        assert!(buf.is_empty());
+
+        let skipped = Rc::new(Cell::new(None));
+        let skipped2 = skipped.clone();
+        codec.set_oversize_policy(OversizePolicy::callback(move |tp, size| {
+            skipped2.set(Some((tp, size)))
+        }));
+        buf.extend_from_slice(b"\x30\x0a\0\x01tpayload\xc0\0");
+        assert_eq!(codec.decode(&mut buf), Ok(Some((Packet::PingRequest, 0))));
+        assert_eq!(skipped.get(), Some((3, 10)));
+    }
+
     #[test]
     fn test_packet() {
         let codec = Codec::new();
@@ -149,4 +382,40 @@ mod tests {
         };
         assert_eq!(pkt, pkt2);
     }
+
+    #[test]
+    fn test_decode_packet_slice() {
+        let pkt = Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from_static("topic"), QoS::AtLeastOnce)],
+        };
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+        let len = buf.len();
+        buf.extend_from_slice(b"\xc0\0");
+
+        assert_eq!(decode_packet_slice(&buf[..len]).unwrap(), Some((pkt.clone(), len)));
+        assert_eq!(decode_packet_slice(&buf).unwrap(), Some((pkt.clone(), len)));
+        assert_eq!(decode_packet_slice(&buf[len..]).unwrap(), Some((Packet::PingRequest, 2)));
+        for idx in 0..len {
+            assert_eq!(decode_packet_slice(&buf[..idx]).unwrap(), None);
+        }
+        assert!(decode_packet_slice(b"\x00\x00").is_err());
+        assert!(decode_packet_slice(b"\x10\xff\xff\xff\xff\x7f").is_err());
+
+        // arbitrary input must not panic
+        let mut seed = 0x2545_f491_u32;
+        for _ in 0..10_000 {
+            let mut data = [0u8; 32];
+            for b in data.iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                *b = seed as u8;
+            }
+            data[1] &= 0x1f;
+            let _ = decode_packet_slice(&data);
+        }
+    }
 }
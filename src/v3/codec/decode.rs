@@ -35,6 +35,8 @@ pub(crate) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, De
         packet_type::PINGREQ => Ok(Packet::PingRequest),
         packet_type::PINGRESP => Ok(Packet::PingResponse),
         packet_type::DISCONNECT => Ok(Packet::Disconnect),
+        // known packet type with invalid fixed header flags [MQTT-2.2.2-2]
+        _ if (1..=14).contains(&(first_byte >> 4)) => Err(DecodeError::MalformedPacket),
         _ => Err(DecodeError::UnsupportedPacketType),
     }
 }
@@ -86,6 +88,8 @@ fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
     };
     let password =
         if flags.contains(ConnectFlags::PASSWORD) { Some(Bytes::decode(src)?) } else { None };
+    // remaining length must match decoded fields
+    ensure!(!src.has_remaining(), DecodeError::MalformedPacket);
     Ok(Connect {
         clean_session: flags.contains(ConnectFlags::CLEAN_START),
         keep_alive,
@@ -165,6 +169,8 @@ fn decode_unsubscribe_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
 
 #[cfg(test)]
 mod tests {
+    use test_case::test_case;
+
     use super::*;
     use crate::utils::decode_variable_length;
     use crate::v3::codec::ConnectAckReason;
@@ -266,6 +272,12 @@ mod tests {
         assert_decode_packet!(b"\xe0\x00", Packet::Disconnect);
     }
 
+    #[test_case(b"\x00\x04MQTT\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass\x01\x02\x03", DecodeError::MalformedPacket; "trailing bytes")]
+    #[test_case(b"\x00\x04MQTT\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pa", DecodeError::InvalidLength; "truncated")]
+    fn test_decode_connect_length_mismatch(bytes: &'static [u8], err: DecodeError) {
+        assert_eq!(decode_connect_packet(&mut Bytes::from_static(bytes)), Err(err));
+    }
+
     #[test]
     fn test_decode_publish_packets() {
         //assert_eq!(
@@ -377,4 +389,43 @@ mod tests {
         assert_decode_packet!(b"\xc0\x00", Packet::PingRequest);
         assert_decode_packet!(b"\xd0\x00", Packet::PingResponse);
     }
+
+    #[test_case(b"\x11\x00"; "connect")]
+    #[test_case(b"\x21\x00"; "connack")]
+    #[test_case(b"\x41\x00"; "puback")]
+    #[test_case(b"\x51\x00"; "pubrec")]
+    #[test_case(b"\x60\x00"; "pubrel")]
+    #[test_case(b"\x71\x00"; "pubcomp")]
+    #[test_case(b"\x80\x00"; "subscribe")]
+    #[test_case(b"\x91\x00"; "suback")]
+    #[test_case(b"\xa0\x00"; "unsubscribe")]
+    #[test_case(b"\xb1\x00"; "unsuback")]
+    #[test_case(b"\xc1\x00"; "pingreq")]
+    #[test_case(b"\xd1\x00"; "pingresp")]
+    #[test_case(b"\xe1\x00"; "disconnect")]
+    fn test_decode_invalid_flags(bytes: &[u8]) {
+        assert_eq!(
+            decode_packet(Bytes::copy_from_slice(&bytes[2..]), bytes[0]),
+            Err(DecodeError::MalformedPacket)
+        );
+    }
+
+    #[test]
+    fn test_packet_flags() {
+        assert_eq!(Packet::PingRequest.flags(), 0);
+        assert_eq!(Packet::PublishRelease { packet_id: packet_id(1) }.flags(), 0b0010);
+        assert_eq!(
+            Packet::Unsubscribe { packet_id: packet_id(1), topic_filters: vec![] }.flags(),
+            0b0010
+        );
+        let publish = Publish {
+            dup: true,
+            retain: true,
+            qos: QoS::ExactlyOnce,
+            topic: ByteString::from_static("a"),
+            packet_id: Some(packet_id(1)),
+            payload: Bytes::new(),
+        };
+        assert_eq!(Packet::Publish(publish).flags(), 0b1101);
+    }
 }
@@ -63,6 +63,7 @@ pub(crate) fn get_encoded_size(packet: &Packet) -> usize {
         Packet::Unsubscribe { ref topic_filters, .. } => get_encoded_unsubscribe_size(topic_filters),
 
         Packet::PingRequest | Packet::PingResponse | Packet::Disconnect => 0,
+        Packet::Unknown { ref payload, .. } => payload.len(),
     }
 }
 
@@ -161,6 +162,11 @@ pub(crate) fn encode(
         Packet::PingRequest => dst.put_slice(&[packet_type::PINGREQ, 0]),
         Packet::PingResponse => dst.put_slice(&[packet_type::PINGRESP, 0]),
         Packet::Disconnect => dst.put_slice(&[packet_type::DISCONNECT, 0]),
+        Packet::Unknown { packet_type, flags, payload } => {
+            dst.put_u8((packet_type << 4) | (flags & 0b0000_1111));
+            write_variable_length(content_size, dst);
+            dst.put_slice(payload);
+        }
     }
 
     Ok(())
@@ -6,8 +6,9 @@ mod decode;
 pub(crate) mod encode;
 mod packet;
 
-pub use self::codec::Codec;
+pub use self::codec::{decode_packet_slice, Codec, OversizePolicy};
 pub use self::packet::{
     Connect, ConnectAck, ConnectAckReason, LastWill, Packet, Publish, SubscribeReturnCode,
 };
+pub use crate::topic::{is_valid_topic_filter, is_valid_topic_name};
 pub use crate::types::{ConnectAckFlags, ConnectFlags, QoS};
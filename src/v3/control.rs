@@ -1,4 +1,5 @@
 use ntex_bytes::ByteString;
+use ntex_util::time::Seconds;
 use std::{io, marker::PhantomData, num::NonZeroU16};
 
 use super::codec;
@@ -25,6 +26,10 @@ pub enum Control<E> {
     ProtocolError(ProtocolError),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Periodic connection statistics
+    Stats(Stats),
+    /// Client did not publish or subscribe within first action timeout
+    ActionTimeout(ActionTimeout),
 }
 
 #[derive(Debug)]
@@ -84,9 +89,17 @@ impl<E> Control<E> {
         Control::ProtocolError(ProtocolError::new(err))
     }
 
+    pub(super) fn stats(stats: Stats) -> Self {
+        Control::Stats(stats)
+    }
+
+    pub(super) fn action_timeout(timeout: Seconds) -> Self {
+        Control::ActionTimeout(ActionTimeout(timeout))
+    }
+
     /// Create a new `Control` message from DISCONNECT packet.
-    pub(super) fn peer_gone(err: Option<io::Error>) -> Self {
-        Control::PeerGone(PeerGone(err))
+    pub(super) fn peer_gone(err: Option<io::Error>, clean: bool) -> Self {
+        Control::PeerGone(PeerGone(err, clean))
     }
 
     /// Disconnects the client by sending DISCONNECT packet.
@@ -112,6 +125,8 @@ impl<E> Control<E> {
             Control::Error(msg) => msg.ack(),
             Control::ProtocolError(msg) => msg.ack(),
             Control::PeerGone(msg) => msg.ack(),
+            Control::Stats(msg) => msg.ack(),
+            Control::ActionTimeout(msg) => msg.ack(),
         }
     }
 }
@@ -333,6 +348,11 @@ impl<'a> Subscription<'a> {
 }
 
 /// Unsubscribe message
+///
+/// UNSUBACK is sent only after control service acknowledges the message, so
+/// subscription store must be updated before returning the ack. Deliveries that
+/// are already in flight for the removed filters complete normally, new
+/// deliveries must not be started once ack is returned [MQTT-3.10.4-2].
 #[derive(Debug)]
 pub struct Unsubscribe {
     packet_id: NonZeroU16,
@@ -406,7 +426,7 @@ impl Closed {
 }
 
 #[derive(Debug)]
-pub struct PeerGone(pub(super) Option<io::Error>);
+pub struct PeerGone(pub(super) Option<io::Error>, pub(super) bool);
 
 impl PeerGone {
     /// Returns error reference
@@ -419,7 +439,102 @@ impl PeerGone {
         self.0.take()
     }
 
+    /// Connection is closed after DISCONNECT packet
+    ///
+    /// `false` means peer is gone without sending DISCONNECT packet
+    /// (read EOF or io error), in that case will message must be published.
+    pub fn is_clean(&self) -> bool {
+        self.1
+    }
+
     pub fn ack(self) -> ControlAck {
         ControlAck { result: ControlAckKind::Nothing }
     }
 }
+
+/// Connection statistics message
+///
+/// Delivered every `stats_interval`, counters cover the period
+/// since previous message.
+#[derive(Debug)]
+pub struct Stats {
+    pub(super) interval: Seconds,
+    pub(super) publishes: u64,
+    pub(super) bytes: u64,
+    pub(super) inflight: usize,
+}
+
+impl Stats {
+    #[inline]
+    /// Statistics period
+    pub fn interval(&self) -> Seconds {
+        self.interval
+    }
+
+    #[inline]
+    /// Number of received publish packets
+    pub fn publishes(&self) -> u64 {
+        self.publishes
+    }
+
+    #[inline]
+    /// Number of received bytes
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Received publish packets per second
+    pub fn publishes_per_sec(&self) -> f64 {
+        self.publishes as f64 / self.interval.seconds().max(1) as f64
+    }
+
+    /// Received bytes per second
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.interval.seconds().max(1) as f64
+    }
+
+    #[inline]
+    /// Number of in-flight inbound packets
+    pub fn inflight(&self) -> usize {
+        self.inflight
+    }
+
+    #[inline]
+    /// Ack stats message, connection stays open
+    pub fn ack(self) -> ControlAck {
+        ControlAck { result: ControlAckKind::Nothing }
+    }
+
+    #[inline]
+    /// Close connection
+    pub fn disconnect(self) -> ControlAck {
+        ControlAck { result: ControlAckKind::Disconnect }
+    }
+}
+
+/// First action timeout message
+///
+/// Client did not send PUBLISH or SUBSCRIBE packet within configured
+/// timeout after connection got established.
+#[derive(Debug)]
+pub struct ActionTimeout(Seconds);
+
+impl ActionTimeout {
+    #[inline]
+    /// Configured first action timeout
+    pub fn timeout(&self) -> Seconds {
+        self.0
+    }
+
+    #[inline]
+    /// Close connection
+    pub fn ack(self) -> ControlAck {
+        ControlAck { result: ControlAckKind::Disconnect }
+    }
+
+    #[inline]
+    /// Keep connection open
+    pub fn keep(self) -> ControlAck {
+        ControlAck { result: ControlAckKind::Nothing }
+    }
+}
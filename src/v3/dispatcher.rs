@@ -1,25 +1,75 @@
-use std::{cell::RefCell, marker::PhantomData, num::NonZeroU16, rc::Rc};
+use std::{cell::Cell, cell::RefCell, marker::PhantomData, num::NonZeroU16, rc::Rc};
 
+use ntex_bytes::ByteString;
 use ntex_io::DispatchItem;
 use ntex_service::{Pipeline, Service, ServiceCtx, ServiceFactory};
+use ntex_util::future::{join, join_all, select, Either};
 use ntex_util::services::buffer::{BufferService, BufferServiceError};
 use ntex_util::services::inflight::InFlightService;
-use ntex_util::{future::join, HashSet};
+use ntex_util::{time::sleep, time::Seconds, HashSet};
 
 use crate::error::{HandshakeError, MqttError, ProtocolError};
-use crate::types::QoS;
+use crate::handle::SubscriptionsCount;
+use crate::topic::is_valid_topic_filter;
+use crate::types::{packet_type, ControlErrorPolicy, QoS, StatsCounter, SubscribePolicy};
+use crate::utils::InProgress;
+
+use super::control::{Control, ControlAck, ControlAckKind, Stats, Subscribe, Unsubscribe};
+use super::publish::{Publish, PublishResult};
+use super::{codec, shared::Ack, shared::MqttShared, MqttSink, Session, WillInfo};
+
+/// mqtt3 dispatcher settings
+#[derive(Clone)]
+pub(super) struct DispatcherConfig {
+    /// Max number of in-flight publishes
+    pub(super) inbound: u16,
+    /// Max size of in-flight publishes
+    pub(super) inbound_size: usize,
+    /// Inbound queue depth, overrides `inbound` if non zero
+    pub(super) inbound_depth: usize,
+    pub(super) max_qos: QoS,
+    pub(super) handle_qos_after_disconnect: Option<QoS>,
+    pub(super) subscribe_policy: SubscribePolicy,
+    pub(super) immediate_ack: bool,
+    pub(super) strict_packet_ids: bool,
+    pub(super) deny_dollar_publish: bool,
+    pub(super) ordered_subscribe: bool,
+    pub(super) stats_interval: Seconds,
+    pub(super) first_action_timeout: Seconds,
+    pub(super) control_error_policy: ControlErrorPolicy,
+    pub(super) publish_filter: Option<Rc<dyn Fn(&Publish) -> bool>>,
+    pub(super) topic_size_limits: Option<Rc<dyn Fn(&str) -> Option<u32>>>,
+}
 
-use super::control::{Control, ControlAck, ControlAckKind, Subscribe, Unsubscribe};
-use super::{codec, publish::Publish, shared::Ack, shared::MqttShared, Session};
+impl Default for DispatcherConfig {
+    fn default() -> Self {
+        Self {
+            inbound: 16,
+            inbound_size: 65535,
+            inbound_depth: 0,
+            max_qos: QoS::AtLeastOnce,
+            handle_qos_after_disconnect: None,
+            subscribe_policy: SubscribePolicy::default(),
+            immediate_ack: false,
+            strict_packet_ids: true,
+            deny_dollar_publish: false,
+            ordered_subscribe: false,
+            stats_interval: Seconds::ZERO,
+            first_action_timeout: Seconds::ZERO,
+            control_error_policy: ControlErrorPolicy::Escalate,
+            publish_filter: None,
+            topic_size_limits: None,
+        }
+    }
+}
 
 /// mqtt3 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
-    inbound: u16,
-    inbound_size: usize,
-    max_qos: QoS,
-    handle_qos_after_disconnect: Option<QoS>,
+    config: DispatcherConfig,
+    on_connected: Option<Rc<dyn Fn(Session<St>)>>,
+    on_will: Option<Rc<dyn Fn(WillInfo<'_>, Session<St>)>>,
 ) -> impl ServiceFactory<
     DispatchItem<Rc<MqttShared>>,
     Session<St>,
@@ -29,24 +79,46 @@ pub(super) fn factory<St, T, C, E>(
 >
 where
     St: 'static,
-    T: ServiceFactory<Publish, Session<St>, Response = ()> + 'static,
+    T: ServiceFactory<Publish, Session<St>> + 'static,
+    T::Response: Into<PublishResult>,
     C: ServiceFactory<Control<E>, Session<St>, Response = ControlAck> + 'static,
     E: From<C::Error> + From<C::InitError> + From<T::Error> + From<T::InitError> + 'static,
 {
     let factories = Rc::new((publish, control));
+    // queue depth overrides number of in-flight messages
+    let inbound = if config.inbound_depth == 0 {
+        config.inbound
+    } else {
+        u16::try_from(config.inbound_depth).unwrap_or(u16::MAX)
+    };
 
     ntex_service::fn_factory_with_config(move |session: Session<St>| {
         let factories = factories.clone();
+        let on_connected = on_connected.clone();
+        let on_will = on_will.clone();
+        let config = config.clone();
 
         async move {
             // create services
             let sink = session.sink().shared();
-            let fut = join(factories.0.create(session.clone()), factories.1.create(session));
+            let fut =
+                join(factories.0.create(session.clone()), factories.1.create(session.clone()));
             let (publish, control) = fut.await;
 
             let publish = publish.map_err(|e| MqttError::Service(e.into()))?;
             let control = control.map_err(|e| MqttError::Service(e.into()))?;
 
+            let on_will = on_will.map(|f| {
+                let session = session.clone();
+                Box::new(move |will: WillInfo<'_>| (*f)(will, session.clone()))
+                    as Box<dyn Fn(WillInfo<'_>)>
+            });
+
+            // connection is established
+            if let Some(f) = on_connected {
+                (*f)(session);
+            }
+
             let control = BufferService::new(
                 16,
                 // limit number of in-flight messages
@@ -59,19 +131,16 @@ where
                 }
             });
 
+            let (inbound_size, stats_interval, first_action_timeout) =
+                (config.inbound_size, config.stats_interval, config.first_action_timeout);
+            let dispatcher =
+                Dispatcher::<_, _, E>::new(sink, publish, control, config, on_will);
+            dispatcher.start_stats(stats_interval);
+            dispatcher.start_action_timeout(first_action_timeout);
+
             Ok(
                 // limit number of in-flight messages
-                crate::inflight::InFlightService::new(
-                    inbound,
-                    inbound_size,
-                    Dispatcher::<_, _, E>::new(
-                        sink,
-                        publish,
-                        control,
-                        max_qos,
-                        handle_qos_after_disconnect,
-                    ),
-                ),
+                crate::inflight::InFlightService::new(inbound, inbound_size, dispatcher),
             )
         }
     })
@@ -92,6 +161,14 @@ pub(crate) struct Dispatcher<T, C: Service<Control<E>>, E> {
     publish: T,
     max_qos: QoS,
     handle_qos_after_disconnect: Option<QoS>,
+    subscribe_policy: SubscribePolicy,
+    immediate_ack: bool,
+    strict_packet_ids: bool,
+    deny_dollar_publish: bool,
+    ordered_subscribe: bool,
+    on_will: Option<Box<dyn Fn(WillInfo<'_>)>>,
+    publish_filter: Option<Rc<dyn Fn(&Publish) -> bool>>,
+    topic_size_limits: Option<Rc<dyn Fn(&str) -> Option<u32>>>,
     inner: Rc<Inner<C>>,
     _t: PhantomData<(E,)>,
 }
@@ -100,35 +177,148 @@ struct Inner<C> {
     control: C,
     sink: Rc<MqttShared>,
     inflight: RefCell<HashSet<NonZeroU16>>,
+    subscriptions: RefCell<HashSet<ByteString>>,
+    subscriptions_count: Option<SubscriptionsCount>,
+    subscribing: InProgress,
+    stats: StatsCounter,
+    disconnect_received: Cell<bool>,
+    first_action: Cell<bool>,
+    control_error_policy: ControlErrorPolicy,
+}
+
+impl<C> Inner<C> {
+    /// Subscriptions are tracked for limit check and for server handle
+    fn track_subscriptions(&self, policy: &SubscribePolicy) -> bool {
+        policy.max_subscriptions != 0 || self.subscriptions_count.is_some()
+    }
+
+    fn update_subscriptions_count(&self) {
+        if let Some(ref count) = self.subscriptions_count {
+            count.set(self.subscriptions.borrow().len());
+        }
+    }
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
 where
     E: From<T::Error>,
-    T: Service<Publish, Response = ()>,
+    T: Service<Publish>,
+    T::Response: Into<PublishResult>,
     C: Service<Control<E>, Response = ControlAck, Error = MqttError<E>>,
 {
     pub(crate) fn new(
         sink: Rc<MqttShared>,
         publish: T,
         control: C,
-        max_qos: QoS,
-        handle_qos_after_disconnect: Option<QoS>,
+        config: DispatcherConfig,
+        on_will: Option<Box<dyn Fn(WillInfo<'_>)>>,
     ) -> Self {
+        let subscriptions_count = sink.take_subscriptions_count();
         Self {
             publish,
-            max_qos,
-            handle_qos_after_disconnect,
-            inner: Rc::new(Inner { sink, control, inflight: RefCell::new(HashSet::default()) }),
+            max_qos: config.max_qos,
+            handle_qos_after_disconnect: config.handle_qos_after_disconnect,
+            subscribe_policy: config.subscribe_policy,
+            immediate_ack: config.immediate_ack,
+            strict_packet_ids: config.strict_packet_ids,
+            deny_dollar_publish: config.deny_dollar_publish,
+            ordered_subscribe: config.ordered_subscribe,
+            on_will,
+            publish_filter: config.publish_filter,
+            topic_size_limits: config.topic_size_limits,
+            inner: Rc::new(Inner {
+                sink,
+                control,
+                inflight: RefCell::new(HashSet::default()),
+                subscriptions: RefCell::new(HashSet::default()),
+                subscriptions_count,
+                subscribing: InProgress::default(),
+                stats: StatsCounter::default(),
+                disconnect_received: Cell::new(false),
+                first_action: Cell::new(false),
+                control_error_policy: config.control_error_policy,
+            }),
             _t: PhantomData,
         }
     }
 }
 
+impl<T, C, E> Dispatcher<T, C, E>
+where
+    C: Service<Control<E>, Response = ControlAck, Error = MqttError<E>> + 'static,
+    E: 'static,
+{
+    /// Deliver connection stats to control service every `interval`
+    fn start_stats(&self, interval: Seconds) {
+        if interval.is_zero() {
+            return;
+        }
+        let inner = self.inner.clone();
+
+        ntex_util::spawn(async move {
+            loop {
+                let on_disconnect = inner.sink.on_disconnect();
+                if let Either::Right(_) = select(sleep(interval), on_disconnect).await {
+                    break;
+                }
+                if inner.sink.is_closed() {
+                    break;
+                }
+
+                let (publishes, bytes) = inner.stats.take();
+                let stats = Stats {
+                    interval,
+                    publishes,
+                    bytes,
+                    inflight: inner.inflight.borrow().len(),
+                };
+                match Pipeline::new(&inner.control).call(Control::stats(stats)).await {
+                    Ok(ack) => {
+                        if let ControlAckKind::Disconnect = ack.result {
+                            inner.sink.close();
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+
+    /// Notify control service if client does not publish or subscribe
+    /// within `timeout` after connection is established
+    fn start_action_timeout(&self, timeout: Seconds) {
+        if timeout.is_zero() {
+            return;
+        }
+        let inner = self.inner.clone();
+
+        ntex_util::spawn(async move {
+            let on_disconnect = inner.sink.on_disconnect();
+            if let Either::Right(_) = select(sleep(timeout), on_disconnect).await {
+                return;
+            }
+            if inner.sink.is_closed() || inner.first_action.get() {
+                return;
+            }
+
+            log::trace!("Client did not publish or subscribe within {:?}", timeout);
+            if let Ok(ack) =
+                Pipeline::new(&inner.control).call(Control::action_timeout(timeout)).await
+            {
+                if let ControlAckKind::Disconnect = ack.result {
+                    inner.sink.close();
+                }
+            }
+        });
+    }
+}
+
 impl<T, C, E> Service<DispatchItem<Rc<MqttShared>>> for Dispatcher<T, C, E>
 where
     E: From<T::Error> + 'static,
-    T: Service<Publish, Response = ()>,
+    T: Service<Publish>,
+    T::Response: Into<PublishResult>,
     C: Service<Control<E>, Response = ControlAck, Error = MqttError<E>> + 'static,
 {
     type Response = Option<codec::Packet>;
@@ -143,6 +333,12 @@ where
 
     async fn shutdown(&self) {
         self.inner.sink.close();
+        // connection is closed without DISCONNECT, publish will message
+        if let Some(ref f) = self.on_will {
+            if let Some(will) = self.inner.sink.take_will() {
+                f(WillInfo(&will));
+            }
+        }
         let _ = Pipeline::new(&self.inner.control).call(Control::closed()).await;
 
         self.publish.shutdown().await;
@@ -156,8 +352,15 @@ where
     ) -> Result<Self::Response, Self::Error> {
         log::trace!("Dispatch v3 packet: {:#?}", req);
 
+        if let DispatchItem::Item((_, size)) = &req {
+            self.inner.stats.packet(*size);
+        }
+
         match req {
             DispatchItem::Item((codec::Packet::Publish(publish), size)) => {
+                self.inner.stats.publish();
+                self.inner.first_action.set(true);
+
                 if publish.topic.contains(['#', '+']) {
                     return control(
                         Control::proto_error(
@@ -170,12 +373,52 @@ where
                     ).await;
                 }
 
+                // clients are not allowed to publish to reserved topics
+                if self.deny_dollar_publish && publish.topic.starts_with('$') {
+                    log::trace!("Reject publish to reserved topic: {:?}", publish.topic);
+                    return control(
+                        Control::proto_error(ProtocolError::generic_violation(
+                            "PUBLISH packet's topic name starts with reserved `$` character",
+                        )),
+                        &self.inner,
+                        ctx,
+                    )
+                    .await;
+                }
+
+                // check per-topic payload size limit
+                if let Some(ref f) = self.topic_size_limits {
+                    if let Some(limit) = (*f)(&publish.topic) {
+                        if publish.payload.len() > limit as usize {
+                            log::trace!(
+                                "Publish payload size limit exceeded for {:?}, limit {}, size {}",
+                                publish.topic,
+                                limit,
+                                publish.payload.len()
+                            );
+                            return control(
+                                Control::proto_error(ProtocolError::generic_violation(
+                                    "PUBLISH payload exceeds topic size limit",
+                                )),
+                                &self.inner,
+                                ctx,
+                            )
+                            .await;
+                        }
+                    }
+                }
+
                 let inner = self.inner.as_ref();
                 let packet_id = publish.packet_id;
 
                 // check for duplicated packet id
                 if let Some(pid) = packet_id {
                     if !inner.inflight.borrow_mut().insert(pid) {
+                        // re-delivery of in-flight publish, original packet gets acked
+                        if publish.dup || !self.strict_packet_ids {
+                            log::trace!("Drop publish packet with in-use packet id: {:?}", pid);
+                            return Ok(None);
+                        }
                         log::trace!("Duplicated packet id for publish packet: {:?}", pid);
                         return control(
                             Control::proto_error(
@@ -217,10 +460,44 @@ where
                     return Ok(None);
                 }
 
-                publish_fn(&self.publish, Publish::new(publish, size), packet_id, inner, ctx)
-                    .await
+                #[cfg(feature = "tracing")]
+                let span = tracing::debug_span!(
+                    "mqtt.publish",
+                    packet_id = packet_id.map(|id| id.get()),
+                    topic = %publish.topic,
+                );
+
+                // wait for in-progress subscribe and unsubscribe handling
+                if self.ordered_subscribe {
+                    self.inner.subscribing.wait().await;
+                }
+
+                let publish = Publish::new(publish, size);
+                if let Some(ref f) = self.publish_filter {
+                    if !(*f)(&publish) {
+                        log::trace!("Publish to {:?} is skipped", publish.publish_topic());
+                        return Ok(packet_id.map(|packet_id| {
+                            inner.inflight.borrow_mut().remove(&packet_id);
+                            codec::Packet::PublishAck { packet_id }
+                        }));
+                    }
+                }
+
+                let fut = publish_fn(
+                    &self.publish,
+                    publish,
+                    packet_id,
+                    self.immediate_ack,
+                    inner,
+                    ctx,
+                );
+                #[cfg(feature = "tracing")]
+                let fut = tracing::Instrument::instrument(fut, span);
+                fut.await
             }
             DispatchItem::Item((codec::Packet::PublishAck { packet_id }, _)) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(packet_id = packet_id.get(), "PUBACK received");
                 if let Err(e) = self.inner.sink.pkt_ack(Ack::Publish(packet_id)) {
                     control(Control::proto_error(e), &self.inner, ctx).await
                 } else {
@@ -234,11 +511,31 @@ where
                 codec::Packet::Subscribe { packet_id, topic_filters },
                 size,
             )) => {
+                // publishes wait for subscription side effects
+                let _guard = self.inner.subscribing.enter();
+                self.inner.first_action.set(true);
                 if self.inner.sink.is_closed() {
                     return Ok(None);
                 }
 
-                if topic_filters.iter().any(|(tf, _)| !crate::topic::is_valid(tf)) {
+                let max_filters = self.subscribe_policy.max_filters;
+                if max_filters != 0 && topic_filters.len() > max_filters {
+                    log::trace!(
+                        "Max topic filters per subscribe is exceeded: max {} provided {}",
+                        max_filters,
+                        topic_filters.len()
+                    );
+                    return control(
+                        Control::proto_error(ProtocolError::generic_violation(
+                            "Number of topic filters in SUBSCRIBE exceeds set maximum",
+                        )),
+                        &self.inner,
+                        ctx,
+                    )
+                    .await;
+                }
+
+                if topic_filters.iter().any(|(tf, _)| !is_valid_topic_filter(tf)) {
                     return control(
                         Control::proto_error(ProtocolError::generic_violation(
                             "Topic filter is malformed [MQTT-4.7.1-*]",
@@ -260,22 +557,116 @@ where
                     ).await;
                 }
 
-                control(
-                    Control::subscribe(Subscribe::new(packet_id, size, topic_filters)),
-                    &self.inner,
-                    ctx,
-                )
-                .await
+                let count = topic_filters.len();
+                let policy = self.subscribe_policy;
+                let mut result = if policy.max_subscriptions == 0
+                    && !policy.deny_wildcards
+                    && self.inner.subscriptions_count.is_none()
+                {
+                    control(
+                        Control::subscribe(Subscribe::new(packet_id, size, topic_filters)),
+                        &self.inner,
+                        ctx,
+                    )
+                    .await?
+                } else {
+                    // check wildcards and subscriptions limit,
+                    // already existing subscriptions do not count
+                    let mut rejected = Vec::new();
+                    let mut allowed = Vec::with_capacity(topic_filters.len());
+                    {
+                        let subs = self.inner.subscriptions.borrow();
+                        let mut count = subs.len();
+                        for (idx, item) in topic_filters.into_iter().enumerate() {
+                            if policy.deny_wildcards && crate::topic::has_wildcards(&item.0) {
+                                log::trace!(
+                                    "Wildcard subscriptions are denied, reject {:?}",
+                                    item.0
+                                );
+                                rejected.push(idx);
+                            } else if policy.max_subscriptions == 0 || subs.contains(&item.0) {
+                                allowed.push(item);
+                            } else if count < policy.max_subscriptions {
+                                count += 1;
+                                allowed.push(item);
+                            } else {
+                                log::trace!(
+                                    "Subscriptions limit is reached, reject {:?}",
+                                    item.0
+                                );
+                                rejected.push(idx);
+                            }
+                        }
+                    }
+
+                    if allowed.is_empty() {
+                        self.inner.inflight.borrow_mut().remove(&packet_id);
+                        return Ok(Some(codec::Packet::SubscribeAck {
+                            packet_id,
+                            status: vec![codec::SubscribeReturnCode::Failure; rejected.len()],
+                        }));
+                    }
+
+                    let filters: Vec<_> = allowed.iter().map(|(tf, _)| tf.clone()).collect();
+                    let allowed_count = filters.len();
+                    let mut result = control(
+                        Control::subscribe(Subscribe::new(packet_id, size, allowed)),
+                        &self.inner,
+                        ctx,
+                    )
+                    .await?;
+
+                    if let Some(codec::Packet::SubscribeAck { ref mut status, .. }) = result {
+                        status.resize(allowed_count, codec::SubscribeReturnCode::Failure);
+                        if self.inner.track_subscriptions(&policy) {
+                            let mut subs = self.inner.subscriptions.borrow_mut();
+                            for (tf, code) in filters.into_iter().zip(status.iter()) {
+                                if matches!(code, codec::SubscribeReturnCode::Success(_)) {
+                                    subs.insert(tf);
+                                }
+                            }
+                        }
+                        self.inner.update_subscriptions_count();
+                        for idx in rejected {
+                            status.insert(idx, codec::SubscribeReturnCode::Failure);
+                        }
+                    }
+                    result
+                };
+
+                // cap granted qos by server max qos and subscribe policy,
+                // applied after control service
+                if let Some(codec::Packet::SubscribeAck { ref mut status, .. }) = result {
+                    // SUBACK must contain return code for each topic filter [MQTT-3.8.4-5]
+                    if status.len() != count {
+                        log::error!(
+                            "SUBACK contains {} return codes for {} topic filters",
+                            status.len(),
+                            count
+                        );
+                        status.resize(count, codec::SubscribeReturnCode::Failure);
+                    }
+
+                    let max = policy.max_granted_qos.min(self.max_qos);
+                    for code in status.iter_mut() {
+                        if let codec::SubscribeReturnCode::Success(ref mut qos) = code {
+                            *qos = (*qos).min(max);
+                        }
+                    }
+                }
+                Ok(result)
             }
             DispatchItem::Item((
                 codec::Packet::Unsubscribe { packet_id, topic_filters },
                 size,
             )) => {
+                // publishes wait for subscription side effects
+                let _guard = self.inner.subscribing.enter();
                 if self.inner.sink.is_closed() {
                     return Ok(None);
                 }
 
-                if topic_filters.iter().any(|tf| !crate::topic::is_valid(tf)) {
+                if topic_filters.iter().any(|tf| !is_valid_topic_filter(tf)) {
                     return control(
                         Control::proto_error(ProtocolError::generic_violation(
                             "Topic filter is malformed [MQTT-4.7.1-*]",
@@ -297,6 +688,15 @@ where
                     ).await;
                 }
 
+                if self.inner.track_subscriptions(&self.subscribe_policy) {
+                    let mut subs = self.inner.subscriptions.borrow_mut();
+                    topic_filters.iter().for_each(|tf| {
+                        subs.remove(tf);
+                    });
+                    drop(subs);
+                    self.inner.update_subscriptions_count();
+                }
+
                 control(
                     Control::unsubscribe(Unsubscribe::new(packet_id, size, topic_filters)),
                     &self.inner,
@@ -305,8 +705,23 @@ where
                 .await
             }
             DispatchItem::Item((codec::Packet::Disconnect, _)) => {
+                self.inner.disconnect_received.set(true);
+                // [MQTT-3.1.2-10] will message must be removed on DISCONNECT
+                self.inner.sink.take_will();
                 control(Control::remote_disconnect(), &self.inner, ctx).await
             }
+            DispatchItem::Item((codec::Packet::Connect(_), _)) => {
+                log::trace!("Unexpected CONNECT packet on established connection");
+                control(
+                    Control::proto_error(ProtocolError::unexpected_packet(
+                        packet_type::CONNECT,
+                        "CONNECT packet can be sent only once [MQTT-3.1.0-2]",
+                    )),
+                    &self.inner,
+                    ctx,
+                )
+                .await
+            }
             DispatchItem::Item(_) => Ok(None),
             DispatchItem::EncoderError(err) => {
                 control(Control::proto_error(ProtocolError::Encode(err)), &self.inner, ctx)
@@ -325,7 +740,8 @@ where
                     .await
             }
             DispatchItem::Disconnect(err) => {
-                control(Control::peer_gone(err), &self.inner, ctx).await
+                let clean = self.inner.disconnect_received.get();
+                control(Control::peer_gone(err, clean), &self.inner, ctx).await
             }
             DispatchItem::WBackPressureEnabled => {
                 self.inner.sink.enable_wr_backpressure();
@@ -344,21 +760,41 @@ async fn publish_fn<'f, T, C, E>(
     svc: &'f T,
     pkt: Publish,
     packet_id: Option<NonZeroU16>,
+    immediate_ack: bool,
     inner: &'f Inner<C>,
     ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
 ) -> Result<Option<codec::Packet>, MqttError<E>>
 where
     E: From<T::Error>,
-    T: Service<Publish, Response = ()>,
+    T: Service<Publish>,
+    T::Response: Into<PublishResult>,
     C: Service<Control<E>, Response = ControlAck, Error = MqttError<E>>,
 {
     match ctx.call(svc, pkt).await {
-        Ok(_) => {
+        Ok(res) => {
             log::trace!("Publish result for packet {:?} is ready", packet_id);
 
-            if let Some(packet_id) = packet_id {
+            if let PublishResult::Reply(replies) = res.into() {
+                // replies task starts after dispatcher writes the ack
+                send_replies(replies, &inner.sink);
+                if let Some(packet_id) = packet_id {
+                    inner.inflight.borrow_mut().remove(&packet_id);
+                    Ok(Some(codec::Packet::PublishAck { packet_id }))
+                } else {
+                    Ok(None)
+                }
+            } else if let Some(packet_id) = packet_id {
                 inner.inflight.borrow_mut().remove(&packet_id);
-                Ok(Some(codec::Packet::PublishAck { packet_id }))
+                if immediate_ack {
+                    // do not wait for responses of previous packets
+                    let pkt = codec::Packet::PublishAck { packet_id };
+                    if let Err(err) = inner.sink.encode_packet(pkt) {
+                        log::error!("Cannot encode publish ack {:?}: {:?}", packet_id, err);
+                    }
+                    Ok(None)
+                } else {
+                    Ok(Some(codec::Packet::PublishAck { packet_id }))
+                }
             } else {
                 Ok(None)
             }
@@ -367,6 +803,40 @@ where
     }
 }
 
+/// Send publish replies in order
+///
+/// Replies are written one after another, each reply waits for sink readiness.
+fn send_replies(replies: Vec<codec::Publish>, sink: &Rc<MqttShared>) {
+    if replies.is_empty() {
+        return;
+    }
+    let sink = MqttSink::new(sink.clone());
+    ntex_util::spawn(async move {
+        let mut acks = Vec::new();
+        for pkt in replies {
+            if !sink.ready().await {
+                log::warn!("Connection is closed, drop publish reply to {:?}", pkt.topic);
+                break;
+            }
+            let qos = pkt.qos;
+            let builder = sink.publish_pkt(pkt);
+            if qos == QoS::AtMostOnce {
+                if let Err(e) = builder.send_at_most_once() {
+                    log::warn!("Cannot send publish reply: {:?}", e);
+                }
+            } else {
+                // exactly once replies are downgraded to at least once
+                acks.push(builder.send_at_least_once());
+            }
+        }
+        for res in join_all(acks).await {
+            if let Err(e) = res {
+                log::warn!("Cannot send publish reply: {:?}", e);
+            }
+        }
+    });
+}
+
 async fn control<'f, T, C, E>(
     mut pkt: Control<E>,
     inner: &'f Inner<C>,
@@ -409,11 +879,18 @@ where
                 } else {
                     // handle error from control service
                     match err {
-                        MqttError::Service(err) => {
-                            error = true;
-                            pkt = Control::error(err);
-                            continue;
-                        }
+                        MqttError::Service(err) => match inner.control_error_policy {
+                            ControlErrorPolicy::Escalate => {
+                                error = true;
+                                pkt = Control::error(err);
+                                continue;
+                            }
+                            ControlErrorPolicy::Close => Err(MqttError::Service(err)),
+                            ControlErrorPolicy::Ignore => {
+                                log::error!("Control service error is ignored");
+                                Ok(None)
+                            }
+                        },
                         _ => Err(err),
                     }
                 };
@@ -455,7 +932,7 @@ mod tests {
                 }
                 Ready::Ok(ControlAck { result: ControlAckKind::Nothing })
             }),
-            QoS::AtLeastOnce,
+            DispatcherConfig::default(),
             None,
         ));
 
@@ -498,7 +975,7 @@ mod tests {
             shared.clone(),
             fn_service(|_| Ready::Ok(())),
             fn_service(|_| Ready::Ok(ControlAck { result: ControlAckKind::Nothing })),
-            QoS::AtLeastOnce,
+            DispatcherConfig::default(),
             None,
         ));
 
@@ -1,15 +1,18 @@
-use std::{fmt, rc::Rc};
+use std::{cell::Cell, fmt, future::Future, rc::Rc};
 
+use ntex_bytes::{ByteString, Bytes};
 use ntex_io::IoBoxed;
+use ntex_service::{Service, ServiceCtx, ServiceFactory};
 use ntex_util::time::Seconds;
 
 use super::{codec as mqtt, shared::MqttShared, sink::MqttSink};
+use crate::{service::HandshakeIo, version::ProtocolVersion};
 
 const DEFAULT_KEEPALIVE: Seconds = Seconds(30);
 
 /// Connect message
 pub struct Handshake {
-    io: IoBoxed,
+    io: HandshakeIo,
     pkt: Box<mqtt::Connect>,
     pkt_size: u32,
     shared: Rc<MqttShared>,
@@ -22,7 +25,7 @@ impl Handshake {
         io: IoBoxed,
         shared: Rc<MqttShared>,
     ) -> Self {
-        Self { io, pkt, pkt_size, shared }
+        Self { io: HandshakeIo::new(io), pkt, pkt_size, shared }
     }
 
     #[inline]
@@ -40,9 +43,15 @@ impl Handshake {
         self.pkt_size
     }
 
+    #[inline]
+    /// Protocol version of the connection
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        ProtocolVersion::MQTT3
+    }
+
     #[inline]
     pub fn io(&self) -> &IoBoxed {
-        &self.io
+        self.io.get()
     }
 
     /// Returns mqtt server sink
@@ -50,9 +59,27 @@ impl Handshake {
         MqttSink::new(self.shared.clone())
     }
 
+    /// Upgrade handshake io object, for example to negotiate TLS after CONNECT.
+    ///
+    /// Upgrade must happen before handshake is acknowledged. Returned io must be
+    /// derived from the provided one (i.e. with `Io::add_filter()`), so mqtt sink
+    /// keeps writing into the same connection. Bytes that are already read from
+    /// the peer stay in io read buffer and are processed by the new filter.
+    pub async fn upgrade_io<F, Fut, E>(mut self, f: F) -> Result<Self, E>
+    where
+        F: FnOnce(IoBoxed) -> Fut,
+        Fut: Future<Output = Result<IoBoxed, E>>,
+    {
+        let io = self.io.take_io();
+        self.io.set_io(f(io).await?);
+        Ok(self)
+    }
+
     /// Ack handshake message and set state
     pub fn ack<St>(self, st: St, session_present: bool) -> HandshakeAck<St> {
-        let Handshake { io, shared, pkt, .. } = self;
+        let Handshake { io, shared, mut pkt, .. } = self;
+        shared.set_will(pkt.last_will.take());
+        let io = io.take();
         // [MQTT-3.1.2-24].
         let keepalive = if pkt.keep_alive != 0 {
             Seconds((pkt.keep_alive >> 1).checked_add(pkt.keep_alive).unwrap_or(u16::MAX))
@@ -73,7 +100,7 @@ impl Handshake {
     /// Create connect ack object with `identifier rejected` return code
     pub fn identifier_rejected<St>(self) -> HandshakeAck<St> {
         HandshakeAck {
-            io: self.io,
+            io: self.io.take(),
             shared: self.shared,
             session: None,
             session_present: false,
@@ -86,7 +113,7 @@ impl Handshake {
     /// Create connect ack object with `bad user name or password` return code
     pub fn bad_username_or_pwd<St>(self) -> HandshakeAck<St> {
         HandshakeAck {
-            io: self.io,
+            io: self.io.take(),
             shared: self.shared,
             session: None,
             session_present: false,
@@ -99,7 +126,7 @@ impl Handshake {
     /// Create connect ack object with `not authorized` return code
     pub fn not_authorized<St>(self) -> HandshakeAck<St> {
         HandshakeAck {
-            io: self.io,
+            io: self.io.take(),
             shared: self.shared,
             session: None,
             session_present: false,
@@ -112,7 +139,7 @@ impl Handshake {
     /// Create connect ack object with `service unavailable` return code
     pub fn service_unavailable<St>(self) -> HandshakeAck<St> {
         HandshakeAck {
-            io: self.io,
+            io: self.io.take(),
             shared: self.shared,
             session: None,
             session_present: false,
@@ -129,6 +156,82 @@ impl fmt::Debug for Handshake {
     }
 }
 
+/// Handshake service factory that maps handshake errors to CONNACK packet.
+///
+/// Created by `MqttServer::map_handshake_error()`.
+pub struct MapHandshakeError<H, F> {
+    factory: H,
+    f: Rc<F>,
+}
+
+impl<H, F> MapHandshakeError<H, F> {
+    pub(super) fn new(factory: H, f: F) -> Self {
+        Self { factory, f: Rc::new(f) }
+    }
+}
+
+impl<H, F> ServiceFactory<Handshake> for MapHandshakeError<H, F>
+where
+    H: ServiceFactory<Handshake>,
+    F: Fn(&H::Error) -> mqtt::ConnectAckReason,
+{
+    type Response = H::Response;
+    type Error = H::Error;
+    type Service = MapHandshakeErrorService<H::Service, F>;
+    type InitError = H::InitError;
+
+    async fn create(&self, cfg: ()) -> Result<Self::Service, Self::InitError> {
+        let service = self.factory.create(cfg).await?;
+        Ok(MapHandshakeErrorService { service, f: self.f.clone() })
+    }
+}
+
+/// Handshake service that maps handshake errors to CONNACK packet
+pub struct MapHandshakeErrorService<S, F> {
+    service: S,
+    f: Rc<F>,
+}
+
+impl<S, F> Service<Handshake> for MapHandshakeErrorService<S, F>
+where
+    S: Service<Handshake>,
+    F: Fn(&S::Error) -> mqtt::ConnectAckReason,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    ntex_service::forward_ready!(service);
+    ntex_service::forward_shutdown!(service);
+
+    async fn call(
+        &self,
+        mut req: Handshake,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        // keep io object if handshake service drops handshake message
+        let slot = Rc::new(Cell::new(None));
+        let shared = req.shared.clone();
+        req.io.set_slot(slot.clone());
+
+        match ctx.call(&self.service, req).await {
+            Ok(ack) => Ok(ack),
+            Err(err) => {
+                if let Some(io) = slot.take() {
+                    let pkt = mqtt::ConnectAck {
+                        session_present: false,
+                        return_code: (self.f)(&err),
+                    };
+                    log::trace!("Sending failed handshake ack: {:#?}", pkt);
+                    if io.encode(mqtt::Packet::ConnectAck(pkt), &shared.codec).is_ok() {
+                        let _ = io.shutdown().await;
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
 /// Ack connect message
 pub struct HandshakeAck<St> {
     pub(crate) io: IoBoxed,
@@ -143,7 +246,9 @@ pub struct HandshakeAck<St> {
 impl<St> HandshakeAck<St> {
     /// Set idle time-out for the connection in seconds
     ///
-    /// By default idle time-out is set to 30 seconds.
+    /// Time-out is set per connection, so it could be computed from the client's
+    /// connect packet. By default idle time-out is one and a half times the client's
+    /// keep-alive, or 30 seconds if client's keep-alive is zero.
     pub fn idle_timeout(mut self, timeout: Seconds) -> Self {
         self.keepalive = timeout;
         self
@@ -164,3 +269,49 @@ impl<St> HandshakeAck<St> {
         self
     }
 }
+
+/// Last will message of connect packet
+#[derive(Copy, Clone)]
+pub struct WillInfo<'a>(pub(super) &'a mqtt::LastWill);
+
+impl<'a> WillInfo<'a> {
+    #[inline]
+    /// Will topic
+    pub fn topic(&self) -> &'a ByteString {
+        &self.0.topic
+    }
+
+    #[inline]
+    /// Will message payload
+    pub fn payload(&self) -> &'a Bytes {
+        &self.0.message
+    }
+
+    #[inline]
+    /// QoS level to be used when publishing will message
+    pub fn qos(&self) -> mqtt::QoS {
+        self.0.qos
+    }
+
+    #[inline]
+    /// Will message is to be retained when it is published
+    pub fn retain(&self) -> bool {
+        self.0.retain
+    }
+
+    #[inline]
+    /// Get reference to last will packet
+    pub fn packet(&self) -> &'a mqtt::LastWill {
+        self.0
+    }
+}
+
+impl fmt::Debug for WillInfo<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WillInfo")
+            .field("topic", &self.0.topic)
+            .field("qos", &self.0.qos)
+            .field("retain", &self.0.retain)
+            .finish()
+    }
+}
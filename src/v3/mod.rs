@@ -15,12 +15,18 @@ mod sink;
 pub type Session<St> = crate::Session<MqttSink, St>;
 
 pub use self::control::{Control, ControlAck};
-pub use self::handshake::{Handshake, HandshakeAck};
-pub use self::publish::Publish;
+pub use self::handshake::{
+    Handshake, HandshakeAck, MapHandshakeError, MapHandshakeErrorService, WillInfo,
+};
+pub use self::publish::{
+    Publish, PublishErrorHandler, PublishErrorHandlerService, PublishResult,
+};
 pub use self::router::Router;
 pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
+pub use self::sink::{
+    MqttSink, PublishBuilder, QueueFullPolicy, SubscribeBuilder, UnsubscribeBuilder,
+};
 
 pub use crate::error::{self, MqttError};
 pub use crate::topic::{TopicFilter, TopicFilterError};
-pub use crate::types::QoS;
+pub use crate::types::{FilterDecision, PacketIdStrategy, QoS};
@@ -1,10 +1,14 @@
-use std::{mem, num::NonZeroU16};
+use std::{cell::Cell, mem, num::NonZeroU16, rc::Rc};
 
 use ntex_bytes::{ByteString, Bytes};
 use ntex_router::Path;
+use ntex_service::{Service, ServiceCtx, ServiceFactory};
+use ntex_util::time::{sleep, Millis};
 use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
 
+use crate::{error::PayloadError, ErrorAction};
+
 use crate::v3::codec;
 
 #[derive(Clone)]
@@ -34,6 +38,16 @@ impl Publish {
         self.pkt.retain
     }
 
+    #[inline]
+    /// Check if publish removes retained message of the topic.
+    ///
+    /// Retained publish with empty payload removes retained message and must
+    /// not be stored [MQTT-3.3.1-11]. It is still passed to publish service and
+    /// must be delivered to matching subscribers as a normal message [MQTT-3.3.1-10].
+    pub fn clears_retained(&self) -> bool {
+        self.pkt.retain && self.pkt.payload.is_empty()
+    }
+
     #[inline]
     /// the level of assurance for delivery of an Application Message.
     pub fn qos(&self) -> codec::QoS {
@@ -94,11 +108,129 @@ impl Publish {
         serde_json::from_slice(&self.pkt.payload)
     }
 
+    /// Loads and parse `application/json` encoded body, error contains packet topic.
+    pub fn decode_json<T: DeserializeOwned>(&mut self) -> Result<T, PayloadError> {
+        self.json().map_err(|err| PayloadError::Json { topic: self.pkt.topic.clone(), err })
+    }
+
+    #[cfg(feature = "cbor")]
+    /// Loads and parse `application/cbor` encoded body.
+    pub fn cbor<T: DeserializeOwned>(&mut self) -> Result<T, PayloadError> {
+        ciborium::de::from_reader(&self.pkt.payload[..])
+            .map_err(|err| PayloadError::Cbor { topic: self.pkt.topic.clone(), err })
+    }
+
+    #[cfg(feature = "msgpack")]
+    /// Loads and parse `application/msgpack` encoded body.
+    pub fn msgpack<T: DeserializeOwned>(&mut self) -> Result<T, PayloadError> {
+        rmp_serde::from_slice(&self.pkt.payload)
+            .map_err(|err| PayloadError::MsgPack { topic: self.pkt.topic.clone(), err })
+    }
+
     pub(super) fn into_inner(self) -> codec::Publish {
         self.pkt
     }
 }
 
+/// Publish service result
+#[derive(Debug, Clone)]
+pub enum PublishResult {
+    /// Acknowledge publish packet
+    Ack,
+    /// Acknowledge publish packet and send publish packets back to the peer.
+    ///
+    /// Replies are queued after the acknowledgement.
+    /// Replies with `QoS::ExactlyOnce` are delivered with `QoS::AtLeastOnce`.
+    Reply(Vec<codec::Publish>),
+}
+
+impl From<()> for PublishResult {
+    fn from(_: ()) -> Self {
+        PublishResult::Ack
+    }
+}
+
+/// Publish service factory with error handler
+///
+/// Handler decides how publish service errors are processed,
+/// see [`ErrorAction`](crate::ErrorAction).
+pub struct PublishErrorHandler<S, F> {
+    factory: S,
+    f: Rc<F>,
+}
+
+impl<S, F> PublishErrorHandler<S, F> {
+    pub(crate) fn new(factory: S, f: F) -> Self {
+        Self { factory, f: Rc::new(f) }
+    }
+}
+
+impl<S, F, Cfg> ServiceFactory<Publish, Cfg> for PublishErrorHandler<S, F>
+where
+    S: ServiceFactory<Publish, Cfg>,
+    S::Response: Into<PublishResult>,
+    F: Fn(&S::Error) -> ErrorAction,
+{
+    type Response = PublishResult;
+    type Error = S::Error;
+    type Service = PublishErrorHandlerService<S::Service, F>;
+    type InitError = S::InitError;
+
+    async fn create(&self, cfg: Cfg) -> Result<Self::Service, Self::InitError> {
+        let service = self.factory.create(cfg).await?;
+        Ok(PublishErrorHandlerService { service, f: self.f.clone(), pause: Cell::new(None) })
+    }
+}
+
+/// Publish service with error handler
+pub struct PublishErrorHandlerService<S, F> {
+    service: S,
+    f: Rc<F>,
+    pause: Cell<Option<Millis>>,
+}
+
+impl<S, F> Service<Publish> for PublishErrorHandlerService<S, F>
+where
+    S: Service<Publish>,
+    S::Response: Into<PublishResult>,
+    F: Fn(&S::Error) -> ErrorAction,
+{
+    type Response = PublishResult;
+    type Error = S::Error;
+
+    ntex_service::forward_shutdown!(service);
+
+    async fn ready(&self, ctx: ServiceCtx<'_, Self>) -> Result<(), Self::Error> {
+        // service is not ready while paused, dispatcher stops reading
+        if let Some(dur) = self.pause.take() {
+            log::trace!("Pause reading inbound packets for {:?}", dur);
+            sleep(dur).await;
+        }
+        ctx.ready(&self.service).await
+    }
+
+    async fn call(
+        &self,
+        req: Publish,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        match ctx.call(&self.service, req).await {
+            Ok(res) => Ok(res.into()),
+            Err(err) => match (self.f)(&err) {
+                ErrorAction::Disconnect => Err(err),
+                ErrorAction::Ignore => {
+                    log::trace!("Publish service error is ignored");
+                    Ok(PublishResult::Ack)
+                }
+                ErrorAction::PauseReads(dur) => {
+                    self.pause.set(Some(dur));
+                    Ok(PublishResult::Ack)
+                }
+            },
+        }
+    }
+}
+
 impl std::fmt::Debug for Publish {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.pkt.fmt(f)
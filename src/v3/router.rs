@@ -1,10 +1,11 @@
-use std::rc::Rc;
+use std::{marker::PhantomData, rc::Rc};
 
 use ntex_router::{IntoPattern, RouterBuilder};
 use ntex_service::boxed::{self, BoxService, BoxServiceFactory};
 use ntex_service::{IntoServiceFactory, Service, ServiceCtx, ServiceFactory};
 
-use super::{publish::Publish, Session};
+use super::{publish::Publish, PublishResult, Session};
+use crate::types::QoS;
 
 type Handler<S, E> = BoxServiceFactory<Session<S>, Publish, (), E, E>;
 type HandlerService<E> = BoxService<Publish, (), E>;
@@ -128,3 +129,74 @@ impl<Err> Service<Publish> for RouterService<Err> {
         }
     }
 }
+
+/// Publish service factory that routes publish packets by QoS
+pub struct QosPublish<A, B, Err> {
+    qos0: A,
+    reliable: B,
+    _t: PhantomData<Err>,
+}
+
+impl<A, B, Err> QosPublish<A, B, Err> {
+    pub(super) fn new(qos0: A, reliable: B) -> Self {
+        Self { qos0, reliable, _t: PhantomData }
+    }
+}
+
+impl<S, A, B, Err> ServiceFactory<Publish, Session<S>> for QosPublish<A, B, Err>
+where
+    S: 'static,
+    A: ServiceFactory<Publish, Session<S>>,
+    A::Response: Into<PublishResult>,
+    B: ServiceFactory<Publish, Session<S>>,
+    B::Response: Into<PublishResult>,
+    Err: From<A::Error> + From<A::InitError> + From<B::Error> + From<B::InitError>,
+{
+    type Response = PublishResult;
+    type Error = Err;
+    type InitError = Err;
+    type Service = QosPublishService<A::Service, B::Service, Err>;
+
+    async fn create(&self, session: Session<S>) -> Result<Self::Service, Self::InitError> {
+        let qos0 = self.qos0.create(session.clone()).await?;
+        let reliable = self.reliable.create(session).await?;
+        Ok(QosPublishService { qos0, reliable, _t: PhantomData })
+    }
+}
+
+pub struct QosPublishService<A, B, Err> {
+    qos0: A,
+    reliable: B,
+    _t: PhantomData<Err>,
+}
+
+impl<A, B, Err> Service<Publish> for QosPublishService<A, B, Err>
+where
+    A: Service<Publish>,
+    A::Response: Into<PublishResult>,
+    B: Service<Publish>,
+    B::Response: Into<PublishResult>,
+    Err: From<A::Error> + From<B::Error>,
+{
+    type Response = PublishResult;
+    type Error = Err;
+
+    #[inline]
+    async fn ready(&self, ctx: ServiceCtx<'_, Self>) -> Result<(), Self::Error> {
+        ctx.ready(&self.qos0).await?;
+        ctx.ready(&self.reliable).await?;
+        Ok(())
+    }
+
+    async fn call(
+        &self,
+        req: Publish,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        if req.qos() == QoS::AtMostOnce {
+            Ok(ctx.call(&self.qos0, req).await?.into())
+        } else {
+            Ok(ctx.call(&self.reliable, req).await?.into())
+        }
+    }
+}
@@ -1,17 +1,24 @@
-use std::{fmt, marker::PhantomData, rc::Rc};
+use std::{cell::OnceCell, fmt, marker::PhantomData, rc::Rc, time::Duration};
 
 use ntex_io::{DispatchItem, DispatcherConfig, IoBoxed};
 use ntex_service::{IntoServiceFactory, Service, ServiceCtx, ServiceFactory};
-use ntex_util::time::{timeout_checked, Millis, Seconds};
+use ntex_util::{future::Either, time::Millis, time::Seconds};
 
-use crate::error::{HandshakeError, MqttError, ProtocolError};
-use crate::{service, types::QoS};
+use crate::clock::{self, Clock, SystemClock};
+use crate::error::{DecodeError, HandshakeError, MqttError, ProtocolError};
+use crate::inflight::InFlightService;
+use crate::io::KeepAlive;
+use crate::types::{ControlErrorPolicy, FilterDecision, QoS, SubscribePolicy};
+use crate::utils::generate_client_id;
+use crate::{service, ErrorAction, ServerHandle};
 
 use super::control::{Control, ControlAck};
 use super::default::{DefaultControlService, DefaultPublishService};
-use super::handshake::{Handshake, HandshakeAck};
+use super::handshake::{Handshake, HandshakeAck, MapHandshakeError, WillInfo};
+use super::publish::PublishErrorHandler;
+use super::router::QosPublish;
 use super::shared::{MqttShared, MqttSinkPool};
-use super::{codec as mqtt, dispatcher::factory, MqttSink, Publish, Session};
+use super::{codec as mqtt, dispatcher::factory, MqttSink, Publish, PublishResult, Session};
 
 /// Mqtt v3.1.1 server
 ///
@@ -48,10 +55,32 @@ pub struct MqttServer<St, H, C, P> {
     max_size: u32,
     max_receive: u16,
     max_receive_size: usize,
+    inbound_queue_depth: usize,
     max_send: u16,
     max_send_size: (u32, u32),
     handle_qos_after_disconnect: Option<QoS>,
+    subscribe_policy: SubscribePolicy,
+    immediate_ack: bool,
+    strict_packet_ids: bool,
+    deny_dollar_publish: bool,
+    ordered_subscribe: bool,
+    stats_interval: Seconds,
+    first_action_timeout: Seconds,
+    control_error_policy: ControlErrorPolicy,
+    max_handshakes: u16,
+    read_params: Option<(u32, u32)>,
+    on_connected: Option<Rc<dyn Fn(Session<St>)>>,
+    on_will: Option<Rc<dyn Fn(WillInfo<'_>, Session<St>)>>,
+    publish_filter: Option<Rc<dyn Fn(&Publish) -> bool>>,
+    topic_size_limits: Option<Rc<dyn Fn(&str) -> Option<u32>>>,
     connect_timeout: Seconds,
+    auth_failure_delay: Millis,
+    overload_check: Option<Rc<dyn Fn() -> bool>>,
+    egress_filter: Option<Rc<dyn Fn(&mut mqtt::Packet) -> FilterDecision<mqtt::Packet>>>,
+    clock: Rc<dyn Clock>,
+    dead_peer_timeout: Seconds,
+    handle: OnceCell<ServerHandle>,
+    oversize: mqtt::OversizePolicy,
     config: DispatcherConfig,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
@@ -81,10 +110,32 @@ where
             max_size: 0,
             max_receive: 16,
             max_receive_size: 65535,
+            inbound_queue_depth: 0,
             max_send: 16,
             max_send_size: (65535, 512),
             handle_qos_after_disconnect: None,
+            subscribe_policy: SubscribePolicy::default(),
+            immediate_ack: false,
+            strict_packet_ids: true,
+            deny_dollar_publish: false,
+            ordered_subscribe: false,
+            stats_interval: Seconds::ZERO,
+            first_action_timeout: Seconds::ZERO,
+            control_error_policy: ControlErrorPolicy::Escalate,
+            max_handshakes: 0,
+            read_params: None,
+            on_connected: None,
+            on_will: None,
+            publish_filter: None,
+            topic_size_limits: None,
             connect_timeout: Seconds::ZERO,
+            auth_failure_delay: Millis::ZERO,
+            overload_check: None,
+            egress_filter: None,
+            clock: Rc::new(SystemClock),
+            dead_peer_timeout: Seconds::ZERO,
+            handle: OnceCell::new(),
+            oversize: mqtt::OversizePolicy::Close,
             pool: Default::default(),
             _t: PhantomData,
         }
@@ -96,10 +147,36 @@ where
     St: 'static,
     H: ServiceFactory<Handshake, Response = HandshakeAck<St>> + 'static,
     C: ServiceFactory<Control<H::Error>, Session<St>, Response = ControlAck> + 'static,
-    P: ServiceFactory<Publish, Session<St>, Response = ()> + 'static,
+    P: ServiceFactory<Publish, Session<St>> + 'static,
+    P::Response: Into<PublishResult>,
     H::Error:
         From<C::Error> + From<C::InitError> + From<P::Error> + From<P::InitError> + fmt::Debug,
 {
+    /// Set dead peer timeout.
+    ///
+    /// Connection is closed if no packets arrive within this time, regardless of
+    /// keep-alive negotiated with the client. Control service receives
+    /// `ProtocolError::KeepAliveTimeout` error.
+    ///
+    /// By default dead peer timeout is disabled.
+    pub fn dead_peer_timeout(mut self, timeout: Seconds) -> Self {
+        self.dead_peer_timeout = timeout;
+        self
+    }
+
+    /// Set clock for handshake timers.
+    ///
+    /// Clock drives `Connect` frame timeout and authentication failure delay,
+    /// custom clock could be used for deterministic timeout testing.
+    /// Keep-alive, read rate and dispatcher timers use ntex timer and
+    /// ignore the clock.
+    ///
+    /// By default `SystemClock` is used.
+    pub fn clock<T: Clock>(mut self, clock: T) -> Self {
+        self.clock = Rc::new(clock);
+        self
+    }
+
     /// Set client timeout for first `Connect` frame.
     ///
     /// Defines a timeout for reading `Connect` frame. If a client does not transmit
@@ -112,6 +189,50 @@ where
         self
     }
 
+    /// Set delay before sending rejected handshake response.
+    ///
+    /// Slows down credential guessing, server waits for specified time
+    /// before sending failed `ConnectAck` packet. Delay applies only to
+    /// the connection being rejected.
+    ///
+    /// By default delay is disabled.
+    pub fn auth_failure_delay(mut self, delay: Duration) -> Self {
+        self.auth_failure_delay = delay.into();
+        self
+    }
+
+    /// Set server overload check.
+    ///
+    /// Function is called for every decoded `Connect` packet before handshake
+    /// service. If it returns `true`, connection is rejected with
+    /// `ServiceUnavailable` reason and closed, so clients could back off.
+    ///
+    /// By default overload check is not set.
+    pub fn overload_check<F>(mut self, check: F) -> Self
+    where
+        F: Fn() -> bool + 'static,
+    {
+        self.overload_check = Some(Rc::new(check));
+        self
+    }
+
+    /// Set filter for outbound packets.
+    ///
+    /// Filter is called for every packet sent to the client, including `ConnectAck`,
+    /// just before packet encoding. Filter could modify packet in place and return
+    /// `FilterDecision::Pass`, replace packet or drop it. Dropped packets are not
+    /// tracked by the server, i.e. dropped qos1 `Publish` stays in-flight
+    /// until connection is closed.
+    ///
+    /// By default egress filter is not set.
+    pub fn egress_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&mut mqtt::Packet) -> FilterDecision<mqtt::Packet> + 'static,
+    {
+        self.egress_filter = Some(Rc::new(filter));
+        self
+    }
+
     /// Set server connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -140,6 +261,7 @@ where
     /// Set max allowed QoS.
     ///
     /// If peer sends publish with higher qos then ProtocolError::MaxQoSViolated(..)
+    /// Granted qos in SUBACK is downgraded to this value.
     /// By default max qos is set to `ExactlyOnce`.
     pub fn max_qos(mut self, qos: QoS) -> Self {
         self.max_qos = qos;
@@ -185,6 +307,18 @@ where
         self
     }
 
+    /// Max number of inbound packets queued for processing.
+    ///
+    /// Dispatcher does not read next packet from the socket until queue has
+    /// free capacity, so slow publish service applies tcp backpressure
+    /// to the peer instead of buffering inbound packets in memory.
+    ///
+    /// By default queue depth is equal to `max_receive`.
+    pub fn inbound_queue_depth(mut self, depth: usize) -> Self {
+        self.inbound_queue_depth = depth;
+        self
+    }
+
     /// Number of outgoing concurrent messages.
     ///
     /// By default outgoing is set to 16 messages
@@ -227,6 +361,270 @@ where
         self
     }
 
+    /// Set max number of subscriptions per connection.
+    ///
+    /// Topic filters that exceed the limit get `SubscribeReturnCode::Failure`
+    /// in SUBACK, connection stays open. Wildcard filter counts as one subscription,
+    /// UNSUBSCRIBE releases subscription slots.
+    ///
+    /// If max subscriptions is set to `0`, number of subscriptions is unlimited.
+    /// By default max subscriptions is set to `0`
+    pub fn max_subscriptions(mut self, val: usize) -> Self {
+        self.subscribe_policy.max_subscriptions = val;
+        self
+    }
+
+    /// Set max granted QoS for subscriptions.
+    ///
+    /// QoS granted in SUBACK is capped to this value, policy is applied
+    /// after control service handled subscribe message.
+    ///
+    /// By default max granted QoS is set to `ExactlyOnce`
+    pub fn max_granted_qos(mut self, qos: QoS) -> Self {
+        self.subscribe_policy.max_granted_qos = qos;
+        self
+    }
+
+    /// Deny subscriptions with wildcard topic filters.
+    ///
+    /// Topic filters that contain `#` or `+` get `SubscribeReturnCode::Failure` in SUBACK
+    /// and are not passed to control service.
+    ///
+    /// By default wildcard subscriptions are allowed.
+    pub fn deny_wildcard_subscriptions(mut self, val: bool) -> Self {
+        self.subscribe_policy.deny_wildcards = val;
+        self
+    }
+
+    /// Set max number of topic filters per SUBSCRIBE packet.
+    ///
+    /// SUBSCRIBE packet with more topic filters is treated as protocol
+    /// violation and connection gets closed, control service does not
+    /// receive subscribe message.
+    ///
+    /// If max filters is set to `0`, number of filters is unlimited.
+    /// By default max filters is set to `1024`
+    pub fn max_filters_per_subscribe(mut self, val: usize) -> Self {
+        self.subscribe_policy.max_filters = val;
+        self
+    }
+
+    /// Send publish acks immediately.
+    ///
+    /// If immediate ack is enabled, PUBACK is written to the connection
+    /// directly as soon as publish service completes, instead of being returned
+    /// to io dispatcher as service response. Acks could be sent out of order
+    /// which relaxes message ordering requirement [MQTT-4.6.0-2].
+    /// Outbound QoS 0 publishes are not affected by this option.
+    ///
+    /// Latency difference between both modes is not measured.
+    ///
+    /// By default immediate ack is disabled.
+    pub fn immediate_ack(mut self, val: bool) -> Self {
+        self.immediate_ack = val;
+        self
+    }
+
+    /// Set strict inbound packet id check
+    ///
+    /// If enabled, PUBLISH packet with packet id that is already in use
+    /// is treated as protocol violation and connection gets closed.
+    /// Otherwise such packet is dropped. Re-delivered packet with
+    /// DUP flag set is always dropped, original packet gets acknowledged.
+    ///
+    /// By default strict check is enabled.
+    pub fn strict_packet_ids(mut self, val: bool) -> Self {
+        self.strict_packet_ids = val;
+        self
+    }
+
+    /// Reject PUBLISH packets to reserved `$` topics
+    ///
+    /// If enabled, PUBLISH packet with topic name that starts with `$` character,
+    /// i.e. `$SYS/`, is treated as protocol violation and connection gets closed.
+    /// Publish service is not called for such packets. Subscriptions to `$`
+    /// topics are not affected.
+    ///
+    /// By default `$` topics are allowed.
+    pub fn deny_dollar_publish(mut self, val: bool) -> Self {
+        self.deny_dollar_publish = val;
+        self
+    }
+
+    /// Process publishes after in-progress subscribe handling
+    ///
+    /// Control service handles SUBSCRIBE and UNSUBSCRIBE packets concurrently
+    /// with publish service. If enabled, PUBLISH packets received after
+    /// SUBSCRIBE or UNSUBSCRIBE packet are passed to publish service only after
+    /// control service completes subscription handling, so subscription side
+    /// effects are visible to subsequent publishes of the same client.
+    ///
+    /// By default ordering is disabled.
+    pub fn ordered_subscribe(mut self, val: bool) -> Self {
+        self.ordered_subscribe = val;
+        self
+    }
+
+    /// Set connection stats interval
+    ///
+    /// Control service receives `Control::Stats` message every `interval`
+    /// with received publishes and bytes since previous message and number of
+    /// in-flight inbound packets. Returning `disconnect()` ack closes connection.
+    ///
+    /// By default stats are disabled.
+    pub fn stats_interval(mut self, interval: Seconds) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// Set first action timeout
+    ///
+    /// If client does not send PUBLISH or SUBSCRIBE packet within `timeout`
+    /// after connection is established, control service receives
+    /// `Control::ActionTimeout` message. Default ack closes connection,
+    /// `keep()` leaves connection open. PINGREQ packets are not counted.
+    ///
+    /// By default timeout is disabled.
+    pub fn first_action_timeout(mut self, timeout: Seconds) -> Self {
+        self.first_action_timeout = timeout;
+        self
+    }
+
+    /// Set control service error handling policy
+    ///
+    /// Policy is applied to errors returned by control service for
+    /// control messages. Errors returned for `Control::Error` and
+    /// `Control::ProtocolError` messages always close connection.
+    ///
+    /// By default error is passed to control service as `Control::Error` message.
+    pub fn control_error_policy(mut self, policy: ControlErrorPolicy) -> Self {
+        self.control_error_policy = policy;
+        self
+    }
+
+    /// Set max number of connections in handshake phase
+    ///
+    /// Limit is applied per worker thread. Once limit is reached, server
+    /// stops accepting new connections until one of in-progress handshakes
+    /// completes. Established connections do not count towards the limit.
+    ///
+    /// By default handshakes are not limited, zero value disables limit.
+    pub fn max_concurrent_handshakes(mut self, val: u16) -> Self {
+        self.max_handshakes = val;
+        self
+    }
+
+    /// Set read buffer parameters
+    ///
+    /// `hi` is the size of read buffer, `lo` is minimal free space in read
+    /// buffer before buffer gets extended, same order as ntex's
+    /// `MemoryPool::set_read_params()`. Large buffer reduces number of read
+    /// syscalls for high-throughput connections but increases memory usage
+    /// of idle connections. Parameters are applied to memory pool of
+    /// connection io, all connections that use same pool share parameters.
+    ///
+    /// By default memory pool parameters are not changed.
+    pub fn read_buffer_params(mut self, hi: u32, lo: u32) -> Self {
+        self.read_params = Some((hi, lo));
+        self
+    }
+
+    /// Set callback for established connections
+    ///
+    /// Callback is called once per connection, after CONNACK packet is written
+    /// to connection and before first inbound packet is dispatched. Packets sent
+    /// from callback are delivered to client after CONNACK.
+    ///
+    /// By default callback is not set.
+    pub fn on_connected<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Session<St>) + 'static,
+    {
+        self.on_connected = Some(Rc::new(f));
+        self
+    }
+
+    /// Set callback for will messages
+    ///
+    /// Callback is called once per connection with stored will message if
+    /// connection gets closed without DISCONNECT packet, for example
+    /// if peer is gone or connection is closed because of protocol error.
+    /// Callback is responsible for publishing will message.
+    ///
+    /// By default callback is not set and will messages are dropped.
+    pub fn on_will<F>(mut self, f: F) -> Self
+    where
+        F: Fn(WillInfo<'_>, Session<St>) + 'static,
+    {
+        self.on_will = Some(Rc::new(f));
+        self
+    }
+
+    /// Set publish filter
+    ///
+    /// Filter is called for every inbound publish before publish service.
+    /// If filter returns `false`, publish service is not called and packet is
+    /// acknowledged according to its QoS level.
+    ///
+    /// By default all publishes are passed to publish service.
+    pub fn publish_filter<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Publish) -> bool + 'static,
+    {
+        self.publish_filter = Some(Rc::new(f));
+        self
+    }
+
+    /// Set per-topic payload size limits
+    ///
+    /// Function is called for every inbound publish with publish topic and returns
+    /// max allowed payload size for the topic, `None` means no limit. Publish with
+    /// larger payload is rejected with protocol error before publish service is called,
+    /// connection gets closed.
+    /// Limit is checked against decoded payload length, it complements global `max_size`.
+    ///
+    /// By default per-topic limits are not set.
+    pub fn topic_size_limits<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> Option<u32> + 'static,
+    {
+        self.topic_size_limits = Some(Rc::new(f));
+        self
+    }
+
+    /// Set oversized packet handling policy
+    ///
+    /// Policy is applied to inbound packets that exceed `max_size` limit.
+    /// With `OversizePolicy::SkipPacket` or `OversizePolicy::Callback` policies
+    /// oversized packet is discarded and connection stays open.
+    ///
+    /// By default policy is set to `OversizePolicy::Close`
+    pub fn on_oversize(mut self, policy: mqtt::OversizePolicy) -> Self {
+        self.oversize = policy;
+        self
+    }
+
+    /// Get server handle
+    ///
+    /// All established connections get registered in the handle, active
+    /// connections could be listed and disconnected via handle. Codec decode
+    /// errors are counted in the handle by error type.
+    ///
+    /// Server builder is created per worker, each builder creates its own handle.
+    /// Use `with_handle()` to share one handle between workers.
+    /// Connections are registered only if handle is requested or set.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.get_or_init(ServerHandle::new).clone()
+    }
+
+    /// Set server handle
+    ///
+    /// Connections of all builders with the same handle get registered in it.
+    pub fn with_handle(mut self, handle: ServerHandle) -> Self {
+        self.handle = OnceCell::from(handle);
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max number of buffered
@@ -246,10 +644,32 @@ where
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_receive_size: self.max_receive_size,
+            inbound_queue_depth: self.inbound_queue_depth,
             max_send: self.max_send,
             max_send_size: self.max_send_size,
             handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+            subscribe_policy: self.subscribe_policy,
+            immediate_ack: self.immediate_ack,
+            strict_packet_ids: self.strict_packet_ids,
+            deny_dollar_publish: self.deny_dollar_publish,
+            ordered_subscribe: self.ordered_subscribe,
+            stats_interval: self.stats_interval,
+            first_action_timeout: self.first_action_timeout,
+            control_error_policy: self.control_error_policy,
+            max_handshakes: self.max_handshakes,
+            read_params: self.read_params,
+            on_connected: self.on_connected,
+            on_will: self.on_will,
+            publish_filter: self.publish_filter,
+            topic_size_limits: self.topic_size_limits,
             connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check,
+            egress_filter: self.egress_filter,
+            clock: self.clock,
+            dead_peer_timeout: self.dead_peer_timeout,
+            handle: self.handle,
+            oversize: self.oversize,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -259,22 +679,191 @@ where
     pub fn publish<F, Srv>(self, publish: F) -> MqttServer<St, H, C, Srv>
     where
         F: IntoServiceFactory<Srv, Publish, Session<St>>,
-        Srv: ServiceFactory<Publish, Session<St>, Response = ()> + 'static,
+        Srv: ServiceFactory<Publish, Session<St>> + 'static,
+        Srv::Response: Into<PublishResult>,
         H::Error: From<Srv::Error> + From<Srv::InitError> + fmt::Debug,
+    {
+        self.map_publish(|_| publish.into_factory())
+    }
+
+    /// Set service to handle QoS 0 publish packets
+    ///
+    /// Publish packets with QoS 1 are handled by the service set with `publish()`
+    /// or `publish_reliable()`. If only `publish()` is set, all publish packets
+    /// are handled by that service.
+    pub fn publish_qos0<F, Srv>(
+        self,
+        publish: F,
+    ) -> MqttServer<St, H, C, QosPublish<Srv, P, H::Error>>
+    where
+        F: IntoServiceFactory<Srv, Publish, Session<St>>,
+        Srv: ServiceFactory<Publish, Session<St>> + 'static,
+        Srv::Response: Into<PublishResult>,
+        H::Error: From<Srv::Error> + From<Srv::InitError>,
+    {
+        let publish = publish.into_factory();
+        self.map_publish(|p| QosPublish::new(publish, p))
+    }
+
+    /// Set service to handle QoS 1 and QoS 2 publish packets
+    ///
+    /// Publish packets with QoS 0 are handled by the service set with `publish()`
+    /// or `publish_qos0()`.
+    pub fn publish_reliable<F, Srv>(
+        self,
+        publish: F,
+    ) -> MqttServer<St, H, C, QosPublish<P, Srv, H::Error>>
+    where
+        F: IntoServiceFactory<Srv, Publish, Session<St>>,
+        Srv: ServiceFactory<Publish, Session<St>> + 'static,
+        Srv::Response: Into<PublishResult>,
+        H::Error: From<Srv::Error> + From<Srv::InitError>,
+    {
+        let publish = publish.into_factory();
+        self.map_publish(|p| QosPublish::new(p, publish))
+    }
+
+    fn map_publish<Srv, F>(self, f: F) -> MqttServer<St, H, C, Srv>
+    where
+        F: FnOnce(P) -> Srv,
     {
         MqttServer {
             handshake: self.handshake,
-            publish: publish.into_factory(),
+            publish: f(self.publish),
             control: self.control,
             config: self.config,
             max_qos: self.max_qos,
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_receive_size: self.max_receive_size,
+            inbound_queue_depth: self.inbound_queue_depth,
             max_send: self.max_send,
             max_send_size: self.max_send_size,
             handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+            subscribe_policy: self.subscribe_policy,
+            immediate_ack: self.immediate_ack,
+            strict_packet_ids: self.strict_packet_ids,
+            deny_dollar_publish: self.deny_dollar_publish,
+            ordered_subscribe: self.ordered_subscribe,
+            stats_interval: self.stats_interval,
+            first_action_timeout: self.first_action_timeout,
+            control_error_policy: self.control_error_policy,
+            max_handshakes: self.max_handshakes,
+            read_params: self.read_params,
+            on_connected: self.on_connected,
+            on_will: self.on_will,
+            publish_filter: self.publish_filter,
+            topic_size_limits: self.topic_size_limits,
             connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check,
+            egress_filter: self.egress_filter,
+            clock: self.clock,
+            dead_peer_timeout: self.dead_peer_timeout,
+            handle: self.handle,
+            oversize: self.oversize,
+            pool: self.pool,
+            _t: PhantomData,
+        }
+    }
+
+    /// Map handshake service errors to CONNACK return code.
+    ///
+    /// By default connection is dropped if handshake service returns error.
+    /// With error mapping, CONNACK with mapped return code is sent to the client
+    /// before connection gets closed.
+    pub fn map_handshake_error<F>(self, f: F) -> MqttServer<St, MapHandshakeError<H, F>, C, P>
+    where
+        F: Fn(&H::Error) -> mqtt::ConnectAckReason + 'static,
+    {
+        MqttServer {
+            handshake: MapHandshakeError::new(self.handshake, f),
+            publish: self.publish,
+            control: self.control,
+            config: self.config,
+            max_qos: self.max_qos,
+            max_size: self.max_size,
+            max_receive: self.max_receive,
+            max_receive_size: self.max_receive_size,
+            inbound_queue_depth: self.inbound_queue_depth,
+            max_send: self.max_send,
+            max_send_size: self.max_send_size,
+            handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+            subscribe_policy: self.subscribe_policy,
+            immediate_ack: self.immediate_ack,
+            strict_packet_ids: self.strict_packet_ids,
+            deny_dollar_publish: self.deny_dollar_publish,
+            ordered_subscribe: self.ordered_subscribe,
+            stats_interval: self.stats_interval,
+            first_action_timeout: self.first_action_timeout,
+            control_error_policy: self.control_error_policy,
+            max_handshakes: self.max_handshakes,
+            read_params: self.read_params,
+            on_connected: self.on_connected,
+            on_will: self.on_will,
+            publish_filter: self.publish_filter,
+            topic_size_limits: self.topic_size_limits,
+            connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check,
+            egress_filter: self.egress_filter,
+            clock: self.clock,
+            dead_peer_timeout: self.dead_peer_timeout,
+            handle: self.handle,
+            oversize: self.oversize,
+            pool: self.pool,
+            _t: PhantomData,
+        }
+    }
+
+    /// Set publish service error handler
+    ///
+    /// Handler is called for every publish service error and decides whether
+    /// error is passed to control service, ignored, or reading of inbound packets
+    /// gets paused for a cooldown period. Ignored publish is acknowledged.
+    /// Handler applies to publish service that is set at the time of the call.
+    ///
+    /// By default all errors are passed to control service.
+    pub fn on_handler_error<F>(self, f: F) -> MqttServer<St, H, C, PublishErrorHandler<P, F>>
+    where
+        P: ServiceFactory<Publish, Session<St>>,
+        F: Fn(&P::Error) -> ErrorAction + 'static,
+    {
+        MqttServer {
+            handshake: self.handshake,
+            publish: PublishErrorHandler::new(self.publish, f),
+            control: self.control,
+            config: self.config,
+            max_qos: self.max_qos,
+            max_size: self.max_size,
+            max_receive: self.max_receive,
+            max_receive_size: self.max_receive_size,
+            inbound_queue_depth: self.inbound_queue_depth,
+            max_send: self.max_send,
+            max_send_size: self.max_send_size,
+            handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+            subscribe_policy: self.subscribe_policy,
+            immediate_ack: self.immediate_ack,
+            strict_packet_ids: self.strict_packet_ids,
+            deny_dollar_publish: self.deny_dollar_publish,
+            ordered_subscribe: self.ordered_subscribe,
+            stats_interval: self.stats_interval,
+            first_action_timeout: self.first_action_timeout,
+            control_error_policy: self.control_error_policy,
+            max_handshakes: self.max_handshakes,
+            read_params: self.read_params,
+            on_connected: self.on_connected,
+            on_will: self.on_will,
+            publish_filter: self.publish_filter,
+            topic_size_limits: self.topic_size_limits,
+            connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check,
+            egress_filter: self.egress_filter,
+            clock: self.clock,
+            dead_peer_timeout: self.dead_peer_timeout,
+            handle: self.handle,
+            oversize: self.oversize,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -287,7 +876,7 @@ where
         Session<St>,
         impl ServiceFactory<
             IoBoxed,
-            Response = (IoBoxed, Rc<MqttShared>, Session<St>, Seconds),
+            Response = (IoBoxed, Rc<MqttShared>, Session<St>, KeepAlive),
             Error = MqttError<H::Error>,
             InitError = H::InitError,
         >,
@@ -303,32 +892,64 @@ where
         service::MqttServer::new(
             HandshakeFactory {
                 factory: self.handshake,
+                max_handshakes: self.max_handshakes,
+                read_params: self.read_params,
                 max_size: self.max_size,
                 max_send: self.max_send,
                 max_send_size: self.max_send_size,
                 connect_timeout: self.connect_timeout,
+                auth_failure_delay: self.auth_failure_delay,
+                overload_check: self.overload_check,
+                egress_filter: self.egress_filter,
+                clock: self.clock,
+                handle: self.handle.into_inner(),
+                oversize: self.oversize,
                 pool: self.pool.clone(),
                 _t: PhantomData,
             },
             factory(
                 self.publish,
                 self.control,
-                self.max_receive,
-                self.max_receive_size,
-                self.max_qos,
-                self.handle_qos_after_disconnect,
+                super::dispatcher::DispatcherConfig {
+                    inbound: self.max_receive,
+                    inbound_size: self.max_receive_size,
+                    inbound_depth: self.inbound_queue_depth,
+                    max_qos: self.max_qos,
+                    handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+                    subscribe_policy: self.subscribe_policy,
+                    immediate_ack: self.immediate_ack,
+                    strict_packet_ids: self.strict_packet_ids,
+                    deny_dollar_publish: self.deny_dollar_publish,
+                    ordered_subscribe: self.ordered_subscribe,
+                    stats_interval: self.stats_interval,
+                    first_action_timeout: self.first_action_timeout,
+                    control_error_policy: self.control_error_policy,
+                    publish_filter: self.publish_filter,
+                    topic_size_limits: self.topic_size_limits,
+                },
+                self.on_connected,
+                self.on_will,
             ),
             self.config,
         )
+        .dead_peer_timeout(self.dead_peer_timeout)
     }
 }
 
 struct HandshakeFactory<St, H> {
     factory: H,
+    max_handshakes: u16,
+    read_params: Option<(u32, u32)>,
     max_size: u32,
     max_send: u16,
     max_send_size: (u32, u32),
     connect_timeout: Seconds,
+    auth_failure_delay: Millis,
+    overload_check: Option<Rc<dyn Fn() -> bool>>,
+    egress_filter: Option<Rc<dyn Fn(&mut mqtt::Packet) -> FilterDecision<mqtt::Packet>>>,
+    clock: Rc<dyn Clock>,
+    handle: Option<ServerHandle>,
+    oversize: mqtt::OversizePolicy,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -338,32 +959,47 @@ where
     H: ServiceFactory<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
 {
-    type Response = (IoBoxed, Rc<MqttShared>, Session<St>, Seconds);
+    type Response = (IoBoxed, Rc<MqttShared>, Session<St>, KeepAlive);
     type Error = MqttError<H::Error>;
 
-    type Service = HandshakeService<St, H::Service>;
+    type Service = InFlightService<HandshakeService<St, H::Service>>;
     type InitError = H::InitError;
 
     async fn create(&self, _: ()) -> Result<Self::Service, Self::InitError> {
-        Ok(HandshakeService {
+        let service = HandshakeService {
+            read_params: self.read_params,
             max_size: self.max_size,
             max_send: self.max_send,
             max_send_size: self.max_send_size,
             pool: self.pool.clone(),
             service: self.factory.create(()).await?,
             connect_timeout: self.connect_timeout.into(),
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check.clone(),
+            egress_filter: self.egress_filter.clone(),
+            clock: self.clock.clone(),
+            handle: self.handle.clone(),
+            oversize: self.oversize.clone(),
             _t: PhantomData,
-        })
+        };
+        Ok(InFlightService::new(self.max_handshakes, 0, service))
     }
 }
 
 struct HandshakeService<St, H> {
     service: H,
+    read_params: Option<(u32, u32)>,
     max_size: u32,
     max_send: u16,
     max_send_size: (u32, u32),
     pool: Rc<MqttSinkPool>,
     connect_timeout: Millis,
+    auth_failure_delay: Millis,
+    overload_check: Option<Rc<dyn Fn() -> bool>>,
+    egress_filter: Option<Rc<dyn Fn(&mut mqtt::Packet) -> FilterDecision<mqtt::Packet>>>,
+    clock: Rc<dyn Clock>,
+    handle: Option<ServerHandle>,
+    oversize: mqtt::OversizePolicy,
     _t: PhantomData<St>,
 }
 
@@ -372,7 +1008,7 @@ where
     H: Service<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
 {
-    type Response = (IoBoxed, Rc<MqttShared>, Session<St>, Seconds);
+    type Response = (IoBoxed, Rc<MqttShared>, Session<St>, KeepAlive);
     type Error = MqttError<H::Error>;
 
     ntex_service::forward_ready!(service, MqttError::Service);
@@ -385,33 +1021,87 @@ where
     ) -> Result<Self::Response, Self::Error> {
         log::trace!("Starting mqtt v3 handshake");
 
+        if let Some((h, l)) = self.read_params {
+            io.memory_pool().set_read_params(h, l);
+        }
+
         let (h, l) = self.max_send_size;
         io.memory_pool().set_write_params(h, l);
 
         let codec = mqtt::Codec::default();
         codec.set_max_size(self.max_size);
+        codec.set_oversize_policy(self.oversize.clone());
+        codec.set_metrics(self.handle.clone());
+        codec.set_egress_filter(self.egress_filter.clone());
         let shared = Rc::new(MqttShared::new(io.get_ref(), codec, false, self.pool.clone()));
 
         // read first packet
-        let packet = timeout_checked(self.connect_timeout, io.recv(&shared.codec))
-            .await
-            .map_err(|_| MqttError::Handshake(HandshakeError::Timeout))?
-            .map_err(|err| {
-                log::trace!("Error is received during mqtt handshake: {:?}", err);
-                MqttError::Handshake(HandshakeError::from(err))
-            })?
-            .ok_or_else(|| {
-                log::trace!("Server mqtt is disconnected during handshake");
-                MqttError::Handshake(HandshakeError::Disconnected(None))
-            })?;
+        let packet =
+            match clock::timeout(&*self.clock, self.connect_timeout, io.recv(&shared.codec))
+                .await
+                .map_err(|_| MqttError::Handshake(HandshakeError::Timeout))?
+            {
+                Err(Either::Left(DecodeError::InvalidClientId)) => {
+                    // [MQTT-3.1.3-8] empty client id requires clean session
+                    log::trace!("MQTT-3.1.3-8: Empty client id with clean session set to 0");
+                    let pkt = mqtt::Packet::ConnectAck(mqtt::ConnectAck {
+                        session_present: false,
+                        return_code: mqtt::ConnectAckReason::IdentifierRejected,
+                    });
+                    io.encode(pkt, &shared.codec)?;
+                    let _ = io.shutdown().await;
+
+                    return Err(MqttError::Handshake(HandshakeError::Protocol(
+                        ProtocolError::Decode(DecodeError::InvalidClientId),
+                    )));
+                }
+                res => res
+                    .map_err(|err| {
+                        log::trace!("Error is received during mqtt handshake: {:?}", err);
+                        MqttError::Handshake(HandshakeError::from(err))
+                    })?
+                    .ok_or_else(|| {
+                        log::trace!("Server mqtt is disconnected during handshake");
+                        MqttError::Handshake(HandshakeError::Disconnected(None))
+                    })?,
+            };
 
         match packet {
-            (mqtt::Packet::Connect(connect), size) => {
-                // authenticate mqtt connection
-                let ack = ctx
-                    .call(&self.service, Handshake::new(connect, size, io, shared))
-                    .await
-                    .map_err(MqttError::Service)?;
+            (mqtt::Packet::Connect(mut connect), size) => {
+                if let Some(ref check) = self.overload_check {
+                    if check() {
+                        log::trace!("Server is overloaded, reject connection");
+                        let pkt = mqtt::Packet::ConnectAck(mqtt::ConnectAck {
+                            session_present: false,
+                            return_code: mqtt::ConnectAckReason::ServiceUnavailable,
+                        });
+                        io.encode(pkt, &shared.codec)?;
+                        let _ = io.shutdown().await;
+                        return Err(MqttError::Handshake(HandshakeError::Disconnected(None)));
+                    }
+                }
+
+                // [MQTT-3.1.3-6] assign unique client id
+                if connect.client_id.is_empty() {
+                    connect.client_id = generate_client_id();
+                    log::trace!("Assign client id: {:?}", connect.client_id);
+                }
+                let client_id = connect.client_id.clone();
+                shared.set_client_id(client_id.clone());
+                #[cfg(feature = "tracing")]
+                tracing::Span::current()
+                    .record("client_id", tracing::field::display(&client_id));
+
+                // authenticate mqtt connection, readiness is checked by InFlightService,
+                // waiting for it here would block on pending handshakes limit
+                let fut =
+                    ctx.call_nowait(&self.service, Handshake::new(connect, size, io, shared));
+                #[cfg(feature = "tracing")]
+                let fut = tracing::Instrument::instrument(
+                    fut,
+                    tracing::info_span!("mqtt.handshake", client_id = %client_id),
+                );
+                let ack = fut.await.map_err(MqttError::Service)?;
 
                 match ack.session {
                     Some(session) => {
@@ -424,12 +1114,19 @@ where
 
                         ack.shared.set_cap(ack.max_send.unwrap_or(self.max_send) as usize);
                         ack.io.encode(pkt, &ack.shared.codec)?;
-                        Ok((
-                            ack.io,
-                            ack.shared.clone(),
-                            Session::new(session, MqttSink::new(ack.shared)),
-                            ack.keepalive,
-                        ))
+
+                        let sink = MqttSink::new(ack.shared.clone());
+                        if let Some(ref handle) = self.handle {
+                            let sink = sink.clone();
+                            let count =
+                                handle.register(client_id, ack.io.get_ref(), move || {
+                                    sink.force_close()
+                                });
+                            ack.shared.set_subscriptions_count(count);
+                        }
+                        ack.shared.keepalive().set(ack.keepalive);
+                        let keepalive = ack.shared.keepalive().clone();
+                        Ok((ack.io, ack.shared, Session::new(session, sink), keepalive))
                     }
                     None => {
                         let pkt = mqtt::Packet::ConnectAck(mqtt::ConnectAck {
@@ -438,6 +1135,9 @@ where
                         });
 
                         log::trace!("Sending failed handshake ack: {:#?}", pkt);
+                        if !self.auth_failure_delay.is_zero() {
+                            self.clock.sleep(self.auth_failure_delay).await;
+                        }
                         ack.io.encode(pkt, &ack.shared.codec)?;
                         let _ = ack.io.shutdown().await;
 
@@ -1,17 +1,31 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc::Rc};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::time::{Duration, Instant};
+use std::{collections::VecDeque, num::NonZeroU16, rc::Rc};
 
-use ntex_bytes::{BytesMut, PoolId, PoolRef};
+use ntex_bytes::{ByteString, Bytes, BytesMut, PoolId, PoolRef};
 use ntex_codec::{Decoder, Encoder};
-use ntex_io::IoRef;
-use ntex_util::{channel::pool, HashSet};
+use ntex_io::{IoRef, OnDisconnect};
+use ntex_util::time::{now, sleep, Millis, Seconds};
+use ntex_util::{channel::pool, future::stream_recv, future::OnDropFn, Stream};
+use ntex_util::{HashMap, HashSet};
 
 use crate::error::{DecodeError, EncodeError, ProtocolError, SendPacketError};
-use crate::{types::packet_type, v3::codec};
+use crate::handle::SubscriptionsCount;
+use crate::io::KeepAlive;
+use crate::types::{packet_type, PacketIdSet, PacketIdStrategy, MAX_PACKET_SIZE};
+use crate::utils::{decode_variable_length, write_variable_length};
+use crate::v3::codec;
+
+use super::sink::QueueFullPolicy;
+
+/// Interval for checking io write buffer in `flush()`
+const FLUSH_POLL_INTERVAL: Millis = Millis(1);
 
 pub(super) enum Ack {
     Publish(NonZeroU16),
     Subscribe { packet_id: NonZeroU16, status: Vec<codec::SubscribeReturnCode> },
     Unsubscribe(NonZeroU16),
+    Cancelled(NonZeroU16),
 }
 
 #[derive(Copy, Clone)]
@@ -43,6 +57,9 @@ bitflags::bitflags! {
         const CLIENT         = 0b1000_0000;
         const WRB_ENABLED    = 0b0100_0000; // write-backpressure
         const ON_PUBLISH_ACK = 0b0010_0000; // on-publish-ack callback
+        const ACTIVITY       = 0b0001_0000; // outbound activity
+        const STREAMING      = 0b0000_1000; // streamed publish payload is being written
+        const ACK_TIMED      = 0b0000_0100; // measure publish ack latency
     }
 }
 
@@ -53,14 +70,43 @@ pub struct MqttShared {
     inflight_idx: Cell<u16>,
     pool: Rc<MqttSinkPool>,
     flags: Cell<Flags>,
-    on_publish_ack: Cell<Option<Box<dyn Fn(NonZeroU16, bool)>>>,
+    on_publish_ack: Cell<Option<Box<dyn Fn(NonZeroU16, bool, Duration)>>>,
+    limit_qos0: Cell<(usize, QueueFullPolicy)>,
+    limit_qos1: Cell<(usize, QueueFullPolicy)>,
+    queued: RefCell<BytesMut>,
+    subscriptions_count: Cell<Option<SubscriptionsCount>>,
+    ack_timeout: Cell<Millis>,
+    retransmit_interval: Cell<Seconds>,
+    client_id: OnceCell<ByteString>,
+    keepalive: KeepAlive,
+    will: Cell<Option<codec::LastWill>>,
     pub(super) codec: codec::Codec,
 }
 
 struct MqttSharedQueues {
     inflight: VecDeque<(NonZeroU16, Option<pool::Sender<Ack>>, AckType)>,
     inflight_ids: HashSet<NonZeroU16>,
+    ids: Option<PacketIdSet>,
+    cancelled: HashSet<NonZeroU16>,
+    expired: HashSet<NonZeroU16>,
+    sent: HashMap<NonZeroU16, Instant>,
     waiters: VecDeque<pool::Sender<()>>,
+    pending: VecDeque<(codec::Publish, Option<Instant>)>,
+    drain_waiters: Vec<pool::Sender<()>>,
+    // streamed publish writers waiting for current stream to finish
+    stream_waiters: Vec<pool::Sender<()>>,
+    // streamed publish writer waiting for write back-pressure to be disabled
+    wrb_waiters: Vec<pool::Sender<()>>,
+    // client only, unacknowledged QoS 1 publishes in send order
+    unacked: VecDeque<codec::Publish>,
+}
+
+impl MqttSharedQueues {
+    fn remove_unacked(&mut self, id: NonZeroU16) {
+        if let Some(pos) = self.unacked.iter().position(|pkt| pkt.packet_id == Some(id)) {
+            self.unacked.remove(pos);
+        }
+    }
 }
 
 impl MqttShared {
@@ -77,16 +123,35 @@ impl MqttShared {
             cap: Cell::new(0),
             flags: Cell::new(if client { Flags::CLIENT } else { Flags::empty() }),
             queues: RefCell::new(MqttSharedQueues {
-                inflight: VecDeque::with_capacity(8),
+                inflight: VecDeque::new(),
                 inflight_ids: HashSet::default(),
+                ids: None,
+                cancelled: HashSet::default(),
+                expired: HashSet::default(),
+                sent: HashMap::default(),
                 waiters: VecDeque::new(),
+                pending: VecDeque::new(),
+                drain_waiters: Vec::new(),
+                stream_waiters: Vec::new(),
+                wrb_waiters: Vec::new(),
+                unacked: VecDeque::new(),
             }),
             inflight_idx: Cell::new(0),
             on_publish_ack: Cell::new(None),
+            queued: RefCell::new(BytesMut::new()),
+            subscriptions_count: Cell::new(None),
+            ack_timeout: Cell::new(Millis::ZERO),
+            retransmit_interval: Cell::new(Seconds::ZERO),
+            client_id: OnceCell::new(),
+            keepalive: KeepAlive::default(),
+            will: Cell::new(None),
+            limit_qos0: Cell::new((0, QueueFullPolicy::Block)),
+            limit_qos1: Cell::new((0, QueueFullPolicy::Block)),
         }
     }
 
     pub(super) fn close(&self) {
+        self.flush_queued();
         if self.flags.get().contains(Flags::CLIENT) {
             let _ = self.encode_packet(codec::Packet::Disconnect);
         }
@@ -103,6 +168,17 @@ impl MqttShared {
         self.io.is_closed()
     }
 
+    pub(super) fn on_disconnect(&self) -> OnDisconnect {
+        self.io.on_disconnect()
+    }
+
+    /// Check and reset outbound activity flag
+    pub(super) fn take_activity(&self) -> bool {
+        let flags = self.flags.get();
+        self.flags.set(flags - Flags::ACTIVITY);
+        flags.contains(Flags::ACTIVITY)
+    }
+
     pub(super) fn is_ready(&self) -> bool {
         self.credit() > 0 && !self.flags.get().contains(Flags::WRB_ENABLED)
     }
@@ -112,15 +188,40 @@ impl MqttShared {
     }
 
     pub(super) fn next_id(&self) -> NonZeroU16 {
-        let idx = self.inflight_idx.get() + 1;
-        let idx = if idx == u16::MAX {
-            self.inflight_idx.set(0);
-            u16::MAX
-        } else {
-            self.inflight_idx.set(idx);
-            idx
+        let mut queues = self.queues.borrow_mut();
+        if let Some(ref mut ids) = queues.ids {
+            if let Some(id) = ids.insert_lowest() {
+                return id;
+            }
+        }
+
+        // skip ids of in-flight packets
+        let start = self.inflight_idx.get();
+        let mut idx = start;
+        loop {
+            idx = idx.wrapping_add(1).max(1);
+            let id = NonZeroU16::new(idx).unwrap();
+            if !queues.inflight_ids.contains(&id) || idx == start {
+                self.inflight_idx.set(idx);
+                return id;
+            }
+        }
+    }
+
+    pub(super) fn set_initial_packet_id(&self, id: NonZeroU16) {
+        self.inflight_idx.set(id.get() - 1);
+    }
+
+    pub(super) fn set_packet_id_strategy(&self, strategy: PacketIdStrategy) {
+        let mut queues = self.queues.borrow_mut();
+        queues.ids = match strategy {
+            PacketIdStrategy::Monotonic => None,
+            PacketIdStrategy::LowestFree => {
+                let mut ids = PacketIdSet::new();
+                queues.inflight_ids.iter().for_each(|id| ids.insert(*id));
+                Some(ids)
+            }
         };
-        NonZeroU16::new(idx).unwrap()
     }
 
     pub(super) fn set_cap(&self, cap: usize) {
@@ -138,25 +239,281 @@ impl MqttShared {
         self.cap.set(cap);
     }
 
-    pub(super) fn set_publish_ack(&self, f: Box<dyn Fn(NonZeroU16, bool)>) {
+    pub(super) fn set_publish_ack(
+        &self,
+        f: Box<dyn Fn(NonZeroU16, bool, Duration)>,
+        timed: bool,
+    ) {
         let mut flags = self.flags.get();
         flags.insert(Flags::ON_PUBLISH_ACK);
+        flags.set(Flags::ACK_TIMED, timed);
         self.flags.set(flags);
         self.on_publish_ack.set(Some(f));
     }
 
+    pub(super) fn set_subscriptions_count(&self, count: SubscriptionsCount) {
+        self.subscriptions_count.set(Some(count));
+    }
+
+    pub(super) fn take_subscriptions_count(&self) -> Option<SubscriptionsCount> {
+        self.subscriptions_count.take()
+    }
+
     pub(super) fn encode_packet(&self, pkt: codec::Packet) -> Result<(), EncodeError> {
-        self.io.encode(pkt, &self.codec)
+        self.flush_queued();
+        self.io.encode(pkt, self)
+    }
+
+    /// Encode packet into queue buffer, queued packets are written on flush
+    pub(super) fn queue_packet(&self, pkt: codec::Packet) -> Result<(), EncodeError> {
+        self.flags.set(self.flags.get() | Flags::ACTIVITY);
+        self.codec.encode(pkt, &mut self.queued.borrow_mut())
+    }
+
+    /// Move queued packets to io write buffer
+    ///
+    /// Queued packets stay buffered while streamed publish payload is being written.
+    pub(super) fn flush_queued(&self) {
+        if !self.flags.get().contains(Flags::STREAMING) && !self.queued.borrow().is_empty() {
+            let buf = self.queued.take();
+            if let Err(err) = self.io.with_write_buf(|wbuf| wbuf.extend_from_slice(&buf)) {
+                log::error!("Cannot write queued packets: {:?}", err);
+            }
+        }
+    }
+
+    /// Write queued packets and wait until io write buffer is flushed
+    pub(super) async fn flush(&self) -> Result<(), SendPacketError> {
+        self.flush_queued();
+        loop {
+            if self.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+            match self.io.with_write_buf(|buf| buf.is_empty()) {
+                Ok(true) => return Ok(()),
+                Ok(false) => sleep(FLUSH_POLL_INTERVAL).await,
+                Err(_) => return Err(SendPacketError::Disconnected),
+            }
+        }
+    }
+
+    /// Write publish packet with streamed payload
+    ///
+    /// Fixed header is encoded with total packet size, then payload chunks are
+    /// written to io write buffer as it drains. Other packets are buffered until
+    /// payload is written. Connection is closed if stream yields more or less
+    /// than `size` bytes, because packet could not be recalled.
+    pub(super) async fn write_stream<S>(
+        &self,
+        pkt: codec::Publish,
+        size: u32,
+        mut stream: S,
+        ack: Option<AckType>,
+    ) -> Result<Option<pool::Receiver<Ack>>, SendPacketError>
+    where
+        S: Stream<Item = Bytes> + Unpin,
+    {
+        // only one streamed payload could be written at a time
+        while self.flags.get().contains(Flags::STREAMING) {
+            if self.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+            let (tx, rx) = self.pool.waiters.channel();
+            self.queues.borrow_mut().stream_waiters.push(tx);
+            if rx.await.is_err() {
+                return Err(SendPacketError::Disconnected);
+            }
+        }
+        if self.is_closed() {
+            return Err(SendPacketError::Disconnected);
+        }
+        let packet_id = pkt.packet_id;
+
+        match self.encode_stream_header(pkt, size) {
+            Ok(hdr) => {
+                let rx = match (packet_id, ack) {
+                    (Some(id), Some(ack)) => Some(self.wait_response(id, ack)?),
+                    _ => None,
+                };
+                self.flush_queued();
+                self.flags.set(self.flags.get() | Flags::STREAMING | Flags::ACTIVITY);
+
+                // partially written packet could not be recovered
+                let guard = OnDropFn::new(|| {
+                    self.flags.set(self.flags.get() - Flags::STREAMING);
+                    self.force_close();
+                });
+                let result = self.write_payload(hdr, size, &mut stream).await;
+                guard.cancel();
+                self.release_stream();
+
+                match result {
+                    Ok(_) => {
+                        self.flush_queued();
+                        Ok(rx)
+                    }
+                    Err(e) => {
+                        log::trace!("Cannot write streamed publish payload: {:?}", e);
+                        self.force_close();
+                        Err(e)
+                    }
+                }
+            }
+            Err(e) => {
+                if let Some(id) = packet_id {
+                    if let Some(ref mut ids) = self.queues.borrow_mut().ids {
+                        ids.remove(id);
+                    }
+                }
+                Err(SendPacketError::Encode(e))
+            }
+        }
+    }
+
+    /// Encode publish packet header, remaining length includes payload size
+    fn encode_stream_header(
+        &self,
+        pkt: codec::Publish,
+        size: u32,
+    ) -> Result<BytesMut, EncodeError> {
+        let mut buf = BytesMut::new();
+        self.codec.encode(codec::Packet::Publish(pkt), &mut buf)?;
+
+        let (len, consumed) = match decode_variable_length(&buf[1..]) {
+            Ok(Some(val)) => val,
+            _ => return Err(EncodeError::MalformedPacket),
+        };
+        let max_size = MAX_PACKET_SIZE;
+        let total = len as u64 + size as u64;
+        if total > max_size as u64 {
+            return Err(EncodeError::OverMaxPacketSize);
+        }
+
+        let mut hdr = BytesMut::with_capacity(buf.len() + 4);
+        hdr.extend_from_slice(&buf[..1]);
+        write_variable_length(total as u32, &mut hdr);
+        hdr.extend_from_slice(&buf[1 + consumed..]);
+        Ok(hdr)
+    }
+
+    /// Finish streamed payload, wake up waiting writers
+    fn release_stream(&self) {
+        self.flags.set(self.flags.get() - Flags::STREAMING);
+        for tx in self.queues.borrow_mut().stream_waiters.drain(..) {
+            let _ = tx.send(());
+        }
+    }
+
+    async fn write_payload<S>(
+        &self,
+        hdr: BytesMut,
+        size: u32,
+        stream: &mut S,
+    ) -> Result<(), SendPacketError>
+    where
+        S: Stream<Item = Bytes> + Unpin,
+    {
+        self.io
+            .with_write_buf(|buf| buf.extend_from_slice(&hdr))
+            .map_err(|_| SendPacketError::Disconnected)?;
+
+        let mut remaining = size as usize;
+        while let Some(chunk) = stream_recv(stream).await {
+            if chunk.len() > remaining {
+                return Err(SendPacketError::Encode(EncodeError::InvalidLength));
+            }
+            remaining -= chunk.len();
+            self.io
+                .with_write_buf(|buf| buf.extend_from_slice(&chunk))
+                .map_err(|_| SendPacketError::Disconnected)?;
+
+            // wait until dispatcher reports that io write buffer is drained
+            while self.io.is_wr_backpressure() {
+                let (tx, rx) = self.pool.waiters.channel();
+                self.queues.borrow_mut().wrb_waiters.push(tx);
+                if rx.await.is_err() {
+                    return Err(SendPacketError::Disconnected);
+                }
+            }
+            if self.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+        }
+
+        if remaining == 0 {
+            Ok(())
+        } else {
+            Err(SendPacketError::Encode(EncodeError::InvalidLength))
+        }
+    }
+
+    pub(super) fn set_queue_limit(&self, qos: codec::QoS, len: usize, policy: QueueFullPolicy) {
+        if qos == codec::QoS::AtMostOnce {
+            self.limit_qos0.set((len, policy));
+        } else {
+            self.limit_qos1.set((len, policy));
+        }
+    }
+
+    /// Encode QoS0 publish packet, queue packet if write back-pressure is enabled
+    pub(super) fn encode_publish_qos0(
+        &self,
+        pkt: codec::Publish,
+        expire: Millis,
+    ) -> Result<(), SendPacketError> {
+        let (max, policy) = self.limit_qos0.get();
+        if max == 0 || !self.flags.get().contains(Flags::WRB_ENABLED) {
+            return self
+                .encode_packet(codec::Packet::Publish(pkt))
+                .map_err(SendPacketError::Encode);
+        }
+
+        let mut queues = self.queues.borrow_mut();
+        if queues.pending.len() >= max {
+            match policy {
+                QueueFullPolicy::Block => return Err(SendPacketError::QueueFull),
+                QueueFullPolicy::DropOldest => {
+                    log::trace!("Outbound queue is full, drop oldest packet");
+                    queues.pending.pop_front();
+                }
+                QueueFullPolicy::DropNewest => {
+                    log::trace!("Outbound queue is full, drop packet to {:?}", pkt.topic);
+                    return Ok(());
+                }
+                QueueFullPolicy::Disconnect => {
+                    log::trace!("Outbound queue is full, disconnecting");
+                    drop(queues);
+                    self.close();
+                    return Err(SendPacketError::Disconnected);
+                }
+            }
+        }
+        let deadline =
+            if expire.is_zero() { None } else { Some(now() + Duration::from(expire)) };
+        queues.pending.push_back((pkt, deadline));
+        Ok(())
     }
 
     fn clear_queues(&self) {
         let mut queues = self.queues.borrow_mut();
         queues.waiters.clear();
+        queues.pending.clear();
+        queues.drain_waiters.clear();
+        queues.stream_waiters.clear();
+        queues.wrb_waiters.clear();
+        if let Some(ref mut ids) = queues.ids {
+            ids.clear();
+        }
+        queues.expired.clear();
+        let cancelled = std::mem::take(&mut queues.cancelled);
+
+        let mut sent = std::mem::take(&mut queues.sent);
 
         if let Some(cb) = self.on_publish_ack.take() {
             for (idx, tx, _) in queues.inflight.drain(..) {
-                if tx.is_none() {
-                    (*cb)(idx, true);
+                if tx.is_none() && !cancelled.contains(&idx) {
+                    let rtt = sent.remove(&idx).map(|t| t.elapsed()).unwrap_or_default();
+                    (*cb)(idx, true, rtt);
                 }
             }
         } else {
@@ -175,8 +532,15 @@ impl MqttShared {
         flags.remove(Flags::WRB_ENABLED);
         self.flags.set(flags);
 
-        // check if there are waiters
+        self.flush_pending();
+
         let mut queues = self.queues.borrow_mut();
+        // wake up streamed payload writer
+        for tx in queues.wrb_waiters.drain(..) {
+            let _ = tx.send(());
+        }
+
+        // check if there are waiters
         if queues.inflight.len() < self.cap.get() {
             let mut num = self.cap.get() - queues.inflight.len();
             while num > 0 {
@@ -191,6 +555,180 @@ impl MqttShared {
         }
     }
 
+    /// Write queued QoS0 packets
+    pub(super) fn flush_pending(&self) {
+        let mut queues = self.queues.borrow_mut();
+        while let Some((pkt, deadline)) = queues.pending.pop_front() {
+            if deadline.map(|d| d <= now()).unwrap_or(false) {
+                log::trace!("Queued publish packet to {:?} is expired", pkt.topic);
+                continue;
+            }
+            if let Err(err) = self.encode_packet(codec::Packet::Publish(pkt)) {
+                log::error!("Cannot encode queued publish packet: {:?}", err);
+            }
+        }
+    }
+
+    /// Get notification when all in-flight packets get acknowledged
+    pub(super) fn wait_inflight(&self) -> Option<pool::Receiver<()>> {
+        let mut queues = self.queues.borrow_mut();
+        if queues.inflight.is_empty() {
+            None
+        } else {
+            let (tx, rx) = self.pool.waiters.channel();
+            queues.drain_waiters.push(tx);
+            Some(rx)
+        }
+    }
+
+    /// Ids of in-flight packets
+    pub(super) fn inflight_ids(&self) -> Vec<NonZeroU16> {
+        let queues = self.queues.borrow();
+        queues
+            .inflight
+            .iter()
+            .map(|item| item.0)
+            .filter(|id| !queues.cancelled.contains(id))
+            .collect()
+    }
+
+    /// Cancel in-flight QoS 1 publish
+    ///
+    /// Publish future resolves with `SendPacketError::Cancelled`, packet id
+    /// stays reserved until peer acknowledges the packet.
+    pub(super) fn cancel(&self, id: NonZeroU16) -> bool {
+        let mut queues = self.queues.borrow_mut();
+        let tx = queues.inflight.iter_mut().find_map(|(idx, tx, tp)| {
+            if *idx == id && matches!(tp, AckType::Publish) {
+                tx.take()
+            } else {
+                None
+            }
+        });
+        if let Some(tx) = tx {
+            log::trace!("Cancel in-flight publish: {:?}", id);
+            queues.remove_unacked(id);
+            queues.cancelled.insert(id);
+            let _ = tx.send(Ack::Cancelled(id));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Effective client id of the connection
+    pub(super) fn client_id(&self) -> &ByteString {
+        self.client_id.get_or_init(ByteString::new)
+    }
+
+    pub(super) fn set_client_id(&self, id: ByteString) {
+        let _ = self.client_id.set(id);
+    }
+
+    /// Keep-alive timeout of the connection dispatcher
+    pub(super) fn keepalive(&self) -> &KeepAlive {
+        &self.keepalive
+    }
+
+    pub(super) fn set_will(&self, will: Option<codec::LastWill>) {
+        self.will.set(will);
+    }
+
+    pub(super) fn take_will(&self) -> Option<codec::LastWill> {
+        self.will.take()
+    }
+
+    /// Default timeout for subscribe and unsubscribe acks
+    pub(super) fn ack_timeout(&self) -> Millis {
+        self.ack_timeout.get()
+    }
+
+    pub(super) fn set_ack_timeout(&self, timeout: Millis) {
+        self.ack_timeout.set(timeout);
+    }
+
+    pub(super) fn retransmit_interval(&self) -> Seconds {
+        self.retransmit_interval.get()
+    }
+
+    pub(super) fn set_retransmit_interval(&self, interval: Seconds) {
+        self.retransmit_interval.set(interval);
+    }
+
+    /// Copy of QoS 1 publish for re-sending on reconnect, client only
+    fn unacked_copy(&self, pkt: &codec::Packet) -> Option<codec::Publish> {
+        match pkt {
+            codec::Packet::Publish(pkt) if self.flags.get().contains(Flags::CLIENT) => {
+                Some(codec::Publish { dup: true, ..pkt.clone() })
+            }
+            _ => None,
+        }
+    }
+
+    /// Take unacknowledged QoS 1 publishes in send order
+    pub(super) fn take_unacked(&self) -> Vec<codec::Publish> {
+        self.queues.borrow_mut().unacked.drain(..).collect()
+    }
+
+    /// Re-send unacknowledged publishes of previous connection
+    ///
+    /// Publishes are written in original order with the same packet ids,
+    /// before any new packet could be sent.
+    pub(super) fn resend_unacked(&self, pkts: Vec<codec::Publish>) {
+        let mut queues = self.queues.borrow_mut();
+        for pkt in pkts {
+            let id = if let Some(id) = pkt.packet_id {
+                id
+            } else {
+                continue;
+            };
+            if queues.inflight_ids.contains(&id) {
+                continue;
+            }
+            log::trace!("Re-send unacknowledged publish {:?}", id);
+            if let Err(err) = self.encode_packet(codec::Packet::Publish(pkt.clone())) {
+                log::error!("Cannot encode unacknowledged publish packet: {:?}", err);
+                continue;
+            }
+            queues.inflight.push_back((id, None, AckType::Publish));
+            queues.inflight_ids.insert(id);
+            if let Some(ref mut ids) = queues.ids {
+                ids.insert(id);
+            }
+            queues.unacked.push_back(pkt);
+        }
+    }
+
+    /// Stop waiting for ack of in-flight packet
+    ///
+    /// Packet id is released, late ack from the peer is ignored.
+    pub(super) fn expire(&self, id: NonZeroU16) {
+        let mut queues = self.queues.borrow_mut();
+        if let Some(pos) = queues.inflight.iter().position(|item| item.0 == id) {
+            log::trace!("Packet ack is expired: {:?}", id);
+            queues.inflight.remove(pos);
+            queues.inflight_ids.remove(&id);
+            queues.sent.remove(&id);
+            queues.remove_unacked(id);
+            if let Some(ref mut ids) = queues.ids {
+                ids.remove(id);
+            }
+            queues.expired.insert(id);
+
+            // wake up queued request (receive max limit)
+            while let Some(tx) = queues.waiters.pop_front() {
+                if tx.send(()).is_ok() {
+                    break;
+                }
+            }
+            if queues.inflight.is_empty() {
+                for tx in queues.drain_waiters.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+        }
+    }
+
     pub(super) fn pkt_ack(&self, ack: Ack) -> Result<(), ProtocolError> {
         self.pkt_ack_inner(ack).map_err(|e| {
             self.close();
@@ -201,6 +739,20 @@ impl MqttShared {
     fn pkt_ack_inner(&self, pkt: Ack) -> Result<(), ProtocolError> {
         let mut queues = self.queues.borrow_mut();
 
+        // late ack for expired packet
+        if queues.expired.remove(&pkt.packet_id())
+            && queues.inflight.front().map(|item| item.0) != Some(pkt.packet_id())
+        {
+            log::trace!("Ack for expired packet: {}", pkt.packet_id());
+            return Ok(());
+        }
+
+        // late or spurious ack, packet id is not in-flight
+        if !queues.inflight_ids.contains(&pkt.packet_id()) {
+            log::trace!("Drop ack for unknown packet id: {}", pkt.packet_id());
+            return Ok(());
+        }
+
         // check ack order
         if let Some((idx, tx, tp)) = queues.inflight.pop_front() {
             if idx != pkt.packet_id() {
@@ -214,14 +766,24 @@ impl MqttShared {
                 // get publish ack channel
                 log::trace!("Ack packet with id: {}", pkt.packet_id());
                 queues.inflight_ids.remove(&pkt.packet_id());
+                let sent = queues.sent.remove(&pkt.packet_id());
+                if let Some(ref mut ids) = queues.ids {
+                    ids.remove(pkt.packet_id());
+                }
 
                 if pkt.is_match(tp) {
+                    if matches!(tp, AckType::Publish) {
+                        queues.remove_unacked(pkt.packet_id());
+                    }
                     if let Some(tx) = tx {
                         let _ = tx.send(pkt);
-                    } else {
-                        let cb = self.on_publish_ack.take().unwrap();
-                        (*cb)(pkt.packet_id(), false);
-                        self.on_publish_ack.set(Some(cb));
+                    } else if !queues.cancelled.remove(&pkt.packet_id()) {
+                        // re-sent publishes of previous connection have no callback
+                        if let Some(cb) = self.on_publish_ack.take() {
+                            let rtt = sent.map(|t| t.elapsed()).unwrap_or_default();
+                            (*cb)(pkt.packet_id(), false, rtt);
+                            self.on_publish_ack.set(Some(cb));
+                        }
                     }
 
                     // wake up queued request (receive max limit)
@@ -230,6 +792,13 @@ impl MqttShared {
                             break;
                         }
                     }
+
+                    // notify graceful close
+                    if queues.inflight.is_empty() {
+                        for tx in queues.drain_waiters.drain(..) {
+                            let _ = tx.send(());
+                        }
+                    }
                     Ok(())
                 } else {
                     log::trace!("MQTT protocol error, unexpected packet");
@@ -257,6 +826,9 @@ impl MqttShared {
             let (tx, rx) = self.pool.queue.channel();
             queues.inflight.push_back((id, Some(tx), ack));
             queues.inflight_ids.insert(id);
+            if let Some(ref mut ids) = queues.ids {
+                ids.insert(id);
+            }
             Ok(rx)
         }
     }
@@ -272,14 +844,24 @@ impl MqttShared {
         if queues.inflight_ids.contains(&id) {
             Err(SendPacketError::PacketIdInUse(id))
         } else {
-            match self.io.encode(pkt, &self.codec) {
+            let unacked = self.unacked_copy(&pkt);
+            match self.encode_packet(pkt) {
                 Ok(_) => {
+                    queues.unacked.extend(unacked);
                     let (tx, rx) = self.pool.queue.channel();
                     queues.inflight.push_back((id, Some(tx), ack));
                     queues.inflight_ids.insert(id);
+                    if let Some(ref mut ids) = queues.ids {
+                        ids.insert(id);
+                    }
                     Ok(rx)
                 }
-                Err(e) => Err(SendPacketError::Encode(e)),
+                Err(e) => {
+                    if let Some(ref mut ids) = queues.ids {
+                        ids.remove(id);
+                    }
+                    Err(SendPacketError::Encode(e))
+                }
             }
         }
     }
@@ -295,16 +877,29 @@ impl MqttShared {
         if queues.inflight_ids.contains(&id) {
             Err(SendPacketError::PacketIdInUse(id))
         } else {
-            match self.io.encode(pkt, &self.codec) {
+            let unacked = self.unacked_copy(&pkt);
+            match self.encode_packet(pkt) {
                 Ok(_) => {
+                    queues.unacked.extend(unacked);
                     queues.inflight.push_back((id, None, ack));
                     queues.inflight_ids.insert(id);
+                    if self.flags.get().contains(Flags::ACK_TIMED) {
+                        queues.sent.insert(id, Instant::now());
+                    }
+                    if let Some(ref mut ids) = queues.ids {
+                        ids.insert(id);
+                    }
                     if !self.flags.get().contains(Flags::ON_PUBLISH_ACK) {
                         panic!("Publish ack callback is not set");
                     }
                     Ok(())
                 }
-                Err(e) => Err(SendPacketError::Encode(e)),
+                Err(e) => {
+                    if let Some(ref mut ids) = queues.ids {
+                        ids.remove(id);
+                    }
+                    Err(SendPacketError::Encode(e))
+                }
             }
         }
     }
@@ -322,6 +917,36 @@ impl MqttShared {
             None
         }
     }
+
+    /// Register QoS1 publish readiness waiter, apply outbound queue limit
+    pub(super) fn wait_publish_readiness(
+        &self,
+    ) -> Result<Option<pool::Receiver<()>>, SendPacketError> {
+        let mut queues = self.queues.borrow_mut();
+
+        if queues.inflight.len() >= self.cap.get()
+            || self.flags.get().contains(Flags::WRB_ENABLED)
+        {
+            let (max, policy) = self.limit_qos1.get();
+            if max != 0 && queues.waiters.len() >= max {
+                if policy == QueueFullPolicy::Disconnect {
+                    log::trace!("Outbound queue is full, disconnecting");
+                    drop(queues);
+                    self.close();
+                    return Err(SendPacketError::Disconnected);
+                } else {
+                    log::trace!("Outbound queue is full, reject publish");
+                    return Err(SendPacketError::QueueFull);
+                }
+            }
+
+            let (tx, rx) = self.pool.waiters.channel();
+            queues.waiters.push_back(tx);
+            Ok(Some(rx))
+        } else {
+            Ok(None)
+        }
+    }
 }
 
 impl Encoder for MqttShared {
@@ -330,7 +955,13 @@ impl Encoder for MqttShared {
 
     #[inline]
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.codec.encode(item, dst)
+        self.flags.set(self.flags.get() | Flags::ACTIVITY);
+        if self.flags.get().contains(Flags::STREAMING) {
+            // packets could not be interleaved with streamed payload
+            self.codec.encode(item, &mut self.queued.borrow_mut())
+        } else {
+            self.codec.encode(item, dst)
+        }
     }
 }
 
@@ -350,6 +981,7 @@ impl Ack {
             Ack::Publish(_) => packet_type::PUBACK,
             Ack::Subscribe { .. } => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
+            Ack::Cancelled(_) => packet_type::PUBACK,
         }
     }
 
@@ -358,6 +990,7 @@ impl Ack {
             Ack::Publish(id) => *id,
             Ack::Subscribe { packet_id, .. } => *packet_id,
             Ack::Unsubscribe(id) => *id,
+            Ack::Cancelled(id) => *id,
         }
     }
 
@@ -1,12 +1,28 @@
-use std::{fmt, future::ready, future::Future, num::NonZeroU16, rc::Rc};
+use std::{fmt, future::ready, future::Future, num::NonZeroU16, rc::Rc, time::Duration};
 
 use ntex_bytes::{ByteString, Bytes};
-use ntex_util::future::{Either, Ready};
+use ntex_util::time::{sleep, timeout_checked, Millis, Seconds};
+use ntex_util::{future::select, future::Either, future::Ready, Stream};
+use serde::Serialize;
 
-use super::{codec, error::SendPacketError, shared::AckType, shared::MqttShared};
+use super::{codec, error::SendPacketError, shared::Ack, shared::AckType, shared::MqttShared};
+use crate::types::PacketIdStrategy;
 
 pub struct MqttSink(Rc<MqttShared>);
 
+/// Outbound queue overflow policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Reject new packet with `SendPacketError::QueueFull` error
+    Block,
+    /// Drop oldest queued packet, QoS0 only
+    DropOldest,
+    /// Drop new packet, QoS0 only
+    DropNewest,
+    /// Close connection, packets already written to io are flushed
+    Disconnect,
+}
+
 impl Clone for MqttSink {
     fn clone(&self) -> Self {
         MqttSink(self.0.clone())
@@ -58,12 +74,101 @@ impl MqttSink {
         }
     }
 
+    /// Set max outbound queue length for specified QoS
+    ///
+    /// QoS0 packets get queued while write back-pressure is enabled,
+    /// QoS1 publishers wait for sink readiness (receive maximum or write
+    /// back-pressure). If queue length exceeds `len`, `policy` is applied.
+    ///
+    /// If `len` is set to `0`, queue is unlimited. By default queue is unlimited.
+    ///
+    /// QoS1 publishes could not be dropped, `DropOldest` and `DropNewest`
+    /// policies act as `Block` for QoS1 and QoS2.
+    pub fn max_queue_len(&self, qos: codec::QoS, len: usize, policy: QueueFullPolicy) {
+        debug_assert!(
+            qos == codec::QoS::AtMostOnce
+                || !matches!(policy, QueueFullPolicy::DropOldest | QueueFullPolicy::DropNewest),
+            "{:?} policy is not allowed for {:?}",
+            policy,
+            qos
+        );
+        self.0.set_queue_limit(qos, len, policy);
+    }
+
+    /// Set packet id allocation strategy
+    ///
+    /// With `PacketIdStrategy::LowestFree` sink reuses lowest packet id that
+    /// is not in-flight, instead of incrementing packet id for every packet.
+    ///
+    /// By default `PacketIdStrategy::Monotonic` is used.
+    pub fn packet_id_strategy(&self, strategy: PacketIdStrategy) {
+        self.0.set_packet_id_strategy(strategy);
+    }
+
+    /// Set packet id for next allocated packet id
+    ///
+    /// Sink continues to allocate ids from `id`, ids of in-flight packets
+    /// are skipped. Setting is ignored by `PacketIdStrategy::LowestFree` strategy.
+    ///
+    /// By default sink starts from packet id 1.
+    pub fn initial_packet_id(&self, id: NonZeroU16) {
+        self.0.set_initial_packet_id(id);
+    }
+
+    /// Ids of in-flight packets, packets that are sent to the peer but
+    /// are not acknowledged yet
+    pub fn inflight_ids(&self) -> Vec<NonZeroU16> {
+        self.0.inflight_ids()
+    }
+
+    /// Cancel in-flight QoS 1 publish
+    ///
+    /// Publish future resolves with `SendPacketError::Cancelled` error.
+    /// If PUBLISH packet is already written to the socket, it could not be recalled,
+    /// cancellation only stops tracking of the acknowledgement. Packet id stays
+    /// reserved until peer acknowledges the packet.
+    ///
+    /// Returns `false` if there is no in-flight publish with such id.
+    pub fn cancel(&self, packet_id: NonZeroU16) -> bool {
+        self.0.cancel(packet_id)
+    }
+
     #[inline]
     /// Close mqtt connection
     pub fn close(&self) {
         self.0.close();
     }
 
+    /// Gracefully close mqtt connection
+    ///
+    /// Waits until all in-flight packets get acknowledged by the peer, then
+    /// closes connection (client sends `DISCONNECT` packet). Write buffer is flushed before closing.
+    /// If acknowledgements are not received within `timeout`, connection get
+    /// force closed and ids of unacknowledged packets are returned.
+    /// Empty list is returned if connection get closed while waiting.
+    ///
+    /// Zero `timeout` means wait indefinitely.
+    pub async fn close_graceful<T: Into<Millis>>(
+        &self,
+        timeout: T,
+    ) -> Result<(), Vec<NonZeroU16>> {
+        self.0.flush_pending();
+        if let Some(rx) = self.0.wait_inflight() {
+            match timeout_checked(timeout, rx).await {
+                Ok(Ok(())) => (),
+                Ok(Err(_)) => return Err(Vec::new()),
+                Err(_) => {
+                    let ids = self.0.inflight_ids();
+                    log::trace!("Graceful close timeout, unacknowledged packets: {:?}", ids);
+                    self.force_close();
+                    return Err(ids);
+                }
+            }
+        }
+        self.close();
+        Ok(())
+    }
+
     #[inline]
     /// Force close mqtt connection. mqtt dispatcher does not wait for uncompleted
     /// responses, but it flushes buffers.
@@ -71,6 +176,13 @@ impl MqttSink {
         self.0.force_close();
     }
 
+    /// Write queued packets to the connection
+    ///
+    /// Returned future resolves when io write buffer is written to the socket.
+    pub async fn flush(&self) -> Result<(), SendPacketError> {
+        self.0.flush().await
+    }
+
     #[inline]
     /// Send ping
     pub(super) fn ping(&self) -> bool {
@@ -93,10 +205,79 @@ impl MqttSink {
         })
     }
 
+    /// Create publish message builder with `application/json` encoded payload
+    pub fn publish_json<U, T>(
+        &self,
+        topic: U,
+        value: &T,
+    ) -> Result<PublishBuilder, serde_json::Error>
+    where
+        ByteString: From<U>,
+        T: Serialize + ?Sized,
+    {
+        let payload = serde_json::to_vec(value)?;
+        Ok(self.publish(topic, Bytes::from(payload)))
+    }
+
+    /// Publish message with streamed payload
+    ///
+    /// Fixed header is encoded with total payload `size`, then payload chunks are
+    /// written to the connection as io write buffer drains. Packets sent while
+    /// payload is being written are buffered. Connection is closed if stream
+    /// yields more or less than `size` bytes, or if future is dropped before
+    /// payload is written. Returned future resolves when payload
+    /// is written for QoS 0, and when publish is acknowledged otherwise.
+    /// QoS 2 publish is sent with QoS 1.
+    pub async fn publish_stream<U, S>(
+        &self,
+        topic: U,
+        qos: codec::QoS,
+        size: u32,
+        stream: S,
+    ) -> Result<(), SendPacketError>
+    where
+        ByteString: From<U>,
+        S: Stream<Item = Bytes> + Unpin,
+    {
+        let shared = &self.0;
+        let mut packet = codec::Publish {
+            dup: false,
+            retain: false,
+            topic: topic.into(),
+            qos: codec::QoS::AtMostOnce,
+            packet_id: None,
+            payload: Bytes::new(),
+        };
+
+        if qos == codec::QoS::AtMostOnce {
+            log::trace!("Publish stream (QoS-0) to {:?}", packet.topic);
+            return shared.write_stream(packet, size, stream, None).await.map(|_| ());
+        }
+
+        // handle client receive maximum
+        if let Some(rx) = shared.wait_publish_readiness()? {
+            if rx.await.is_err() {
+                return Err(SendPacketError::Disconnected);
+            }
+        }
+        packet.qos = codec::QoS::AtLeastOnce;
+        packet.packet_id = Some(shared.next_id());
+        log::trace!("Publish stream (QoS1) to {:?}", packet.topic);
+
+        match shared.write_stream(packet, size, stream, Some(AckType::Publish)).await? {
+            Some(rx) => match rx.await {
+                Ok(Ack::Cancelled(_)) => Err(SendPacketError::Cancelled),
+                Ok(_) => Ok(()),
+                Err(_) => Err(SendPacketError::Disconnected),
+            },
+            None => Ok(()),
+        }
+    }
+
     #[inline]
     /// Create publish builder with publish packet
     pub fn publish_pkt(&self, packet: codec::Publish) -> PublishBuilder {
-        PublishBuilder { packet, shared: self.0.clone() }
+        PublishBuilder { packet, shared: self.0.clone(), expire: Millis::ZERO }
     }
 
     /// Set publish ack callback
@@ -107,7 +288,20 @@ impl MqttSink {
     where
         F: Fn(NonZeroU16, bool) + 'static,
     {
-        self.0.set_publish_ack(Box::new(f));
+        self.0
+            .set_publish_ack(Box::new(move |ack, disconnected, _| f(ack, disconnected)), false);
+    }
+
+    /// Set publish ack callback with round-trip latency
+    ///
+    /// Same as `publish_ack_cb()`, third argument is time elapsed between
+    /// sending publish packet and receiving its ack. For packets that are not
+    /// acked due to disconnect, it is time elapsed since packet was sent.
+    pub fn publish_ack_cb_timed<F>(&self, f: F)
+    where
+        F: Fn(NonZeroU16, bool, Duration) + 'static,
+    {
+        self.0.set_publish_ack(Box::new(f), true);
     }
 
     #[inline]
@@ -115,13 +309,23 @@ impl MqttSink {
     ///
     /// panics if id is 0
     pub fn subscribe(&self) -> SubscribeBuilder {
-        SubscribeBuilder { id: None, topic_filters: Vec::new(), shared: self.0.clone() }
+        SubscribeBuilder {
+            id: None,
+            timeout: None,
+            topic_filters: Vec::new(),
+            shared: self.0.clone(),
+        }
     }
 
     #[inline]
     /// Create unsubscribe packet builder
     pub fn unsubscribe(&self) -> UnsubscribeBuilder {
-        UnsubscribeBuilder { id: None, topic_filters: Vec::new(), shared: self.0.clone() }
+        UnsubscribeBuilder {
+            id: None,
+            timeout: None,
+            topic_filters: Vec::new(),
+            shared: self.0.clone(),
+        }
     }
 }
 
@@ -131,9 +335,74 @@ impl fmt::Debug for MqttSink {
     }
 }
 
+impl<St> crate::Session<MqttSink, St> {
+    #[inline]
+    /// Effective client id of the connection
+    ///
+    /// Client id is provided by the client or assigned by the server.
+    pub fn client_id(&self) -> &ByteString {
+        self.sink().0.client_id()
+    }
+
+    /// Disconnect the client.
+    ///
+    /// Mqtt v3.1.1 has no server DISCONNECT packet, so write buffer is flushed
+    /// and connection is closed. Could be called from any service, for example
+    /// from publish service.
+    pub fn disconnect(&self) {
+        self.sink().close();
+    }
+
+    /// Override keep-alive timeout of the connection.
+    ///
+    /// New timeout applies next time keep-alive timer starts, negotiated
+    /// keep-alive value is not changed. Value is not limited by server's
+    /// dead peer timeout. Timeout shorter than client's keep-alive interval
+    /// may cause premature disconnects. To disable keep-alive set value to 0.
+    pub fn set_keep_alive(&self, timeout: Seconds) {
+        self.sink().0.keepalive().set(timeout);
+    }
+
+    /// Schedule application level heartbeat.
+    ///
+    /// QoS 0 publish with `payload` is sent to `topic` if nothing was sent
+    /// to the peer during last `interval`. Any outbound packet resets heartbeat
+    /// timer. Heartbeat stops when connection is closed.
+    ///
+    /// Heartbeat is not related to protocol level PINGREQ/PINGRESP packets.
+    pub fn schedule_heartbeat<U>(&self, topic: U, interval: Seconds, payload: Bytes)
+    where
+        ByteString: From<U>,
+    {
+        if interval.is_zero() {
+            return;
+        }
+        let sink = self.sink().clone();
+        let topic = ByteString::from(topic);
+        sink.0.take_activity();
+
+        ntex_util::spawn(async move {
+            loop {
+                if let Either::Right(_) = select(sleep(interval), sink.0.on_disconnect()).await
+                {
+                    break;
+                }
+                if !sink.0.take_activity() {
+                    log::trace!("Sending heartbeat to {:?}", topic);
+                    let _ = sink
+                        .publish::<ByteString>(topic.clone(), payload.clone())
+                        .send_at_most_once();
+                    sink.0.take_activity();
+                }
+            }
+        });
+    }
+}
+
 pub struct PublishBuilder {
     packet: codec::Publish,
     shared: Rc<MqttShared>,
+    expire: Millis,
 }
 
 impl PublishBuilder {
@@ -141,8 +410,13 @@ impl PublishBuilder {
     /// Set packet id.
     ///
     /// Note: if packet id is not set, it gets generated automatically.
+    /// User supplied id bypasses sink's id allocator, this is useful for proxies
+    /// that preserve packet ids between two connections. Sending fails with
+    /// `SendPacketError::PacketIdInUse` if the same id is already in-flight.
+    ///
     /// Packet id management should not be mixed, it should be auto-generated
-    /// or set by user. Otherwise collisions could occure.
+    /// or set by user. Allocator is not aware of user supplied ids, so auto-generated
+    /// id could collide with in-flight user id and publish would fail.
     ///
     /// panics if id is 0
     pub fn packet_id(mut self, id: u16) -> Self {
@@ -160,11 +434,26 @@ impl PublishBuilder {
 
     #[inline]
     /// Set retain flag
+    ///
+    /// Sink sends retain flag as is. Retained messages delivered on subscription
+    /// must have retain flag set, live messages are delivered without it.
     pub fn retain(mut self) -> Self {
         self.packet.retain = true;
         self
     }
 
+    #[inline]
+    /// Set send queue ttl
+    ///
+    /// Publish is dropped if it is not written to the connection within `ttl`.
+    /// Waiting QoS 1 publish resolves with `SendPacketError::Expired`, queued
+    /// QoS 0 publish is dropped silently. This is local send queue ttl,
+    /// it is not related to message expiry. Zero ttl disables expiration.
+    pub fn expire_after(mut self, ttl: Duration) -> Self {
+        self.expire = ttl.into();
+        self
+    }
+
     #[inline]
     /// Get size of the publish packet
     pub fn size(&self) -> u32 {
@@ -173,14 +462,31 @@ impl PublishBuilder {
 
     #[inline]
     /// Send publish packet with QoS 0
+    ///
+    /// Previously queued packets are written first, so this is equivalent
+    /// to `queue()` followed by [`MqttSink::flush`].
     pub fn send_at_most_once(mut self) -> Result<(), SendPacketError> {
         if !self.shared.is_closed() {
             log::trace!("Publish (QoS-0) to {:?}", self.packet.topic);
             self.packet.qos = codec::QoS::AtMostOnce;
+            self.shared.encode_publish_qos0(self.packet, self.expire)
+        } else {
+            log::error!("Mqtt sink is disconnected");
+            Err(SendPacketError::Disconnected)
+        }
+    }
+
+    /// Queue publish packet with QoS 0
+    ///
+    /// Packet is encoded and buffered, but it is not written to the connection
+    /// until [`MqttSink::flush`] is called or next packet is sent.
+    pub fn queue(mut self) -> Result<(), SendPacketError> {
+        if !self.shared.is_closed() {
+            log::trace!("Queue publish (QoS-0) to {:?}", self.packet.topic);
+            self.packet.qos = codec::QoS::AtMostOnce;
             self.shared
-                .encode_packet(codec::Packet::Publish(self.packet))
+                .queue_packet(codec::Packet::Publish(self.packet))
                 .map_err(SendPacketError::Encode)
-                .map(|_| ())
         } else {
             log::error!("Mqtt sink is disconnected");
             Err(SendPacketError::Disconnected)
@@ -191,14 +497,24 @@ impl PublishBuilder {
     pub fn send_at_least_once(self) -> impl Future<Output = Result<(), SendPacketError>> {
         if !self.shared.is_closed() {
             let shared = self.shared;
+            let expire = self.expire;
             let mut packet = self.packet;
             packet.qos = codec::QoS::AtLeastOnce;
 
             // handle client receive maximum
-            if let Some(rx) = shared.wait_readiness() {
+            let rx = match shared.wait_publish_readiness() {
+                Ok(rx) => rx,
+                Err(err) => return Either::Right(Ready::Err(err)),
+            };
+            if let Some(rx) = rx {
                 Either::Left(Either::Left(async move {
-                    if rx.await.is_err() {
-                        return Err(SendPacketError::Disconnected);
+                    match timeout_checked(expire, rx).await {
+                        Ok(Ok(_)) => (),
+                        Ok(Err(_)) => return Err(SendPacketError::Disconnected),
+                        Err(_) => {
+                            log::trace!("Publish to {:?} is expired", packet.topic);
+                            return Err(SendPacketError::Expired);
+                        }
                     }
                     Self::send_at_least_once_inner(packet, shared).await
                 }))
@@ -258,15 +574,51 @@ impl PublishBuilder {
         };
         log::trace!("Publish (QoS1) to {:#?}", packet);
 
+        // keep copy of the packet for retransmission
+        let interval = shared.retransmit_interval();
+        let retransmit = if interval.is_zero() {
+            None
+        } else {
+            Some(codec::Publish { dup: true, ..packet.clone() })
+        };
+
         let rx =
             shared.wait_packet_response(idx, AckType::Publish, codec::Packet::Publish(packet));
-        async move { rx?.await.map(|_| ()).map_err(|_| SendPacketError::Disconnected) }
+        async move {
+            let mut rx = rx?;
+            let res = match retransmit {
+                Some(pkt) => loop {
+                    match select(&mut rx, sleep(interval)).await {
+                        Either::Left(res) => break res,
+                        Either::Right(_) => {
+                            log::trace!("Retransmit publish packet {:?}", idx);
+                            if let Err(err) =
+                                shared.encode_packet(codec::Packet::Publish(pkt.clone()))
+                            {
+                                log::error!(
+                                    "Cannot retransmit publish packet {:?}: {:?}",
+                                    idx,
+                                    err
+                                );
+                            }
+                        }
+                    }
+                },
+                None => rx.await,
+            };
+            match res {
+                Ok(Ack::Cancelled(_)) => Err(SendPacketError::Cancelled),
+                Ok(_) => Ok(()),
+                Err(_) => Err(SendPacketError::Disconnected),
+            }
+        }
     }
 }
 
 /// Subscribe packet builder
 pub struct SubscribeBuilder {
     id: Option<NonZeroU16>,
+    timeout: Option<Millis>,
     shared: Rc<MqttShared>,
     topic_filters: Vec<(ByteString, codec::QoS)>,
 }
@@ -292,6 +644,17 @@ impl SubscribeBuilder {
         self
     }
 
+    #[inline]
+    /// Set ack timeout
+    ///
+    /// If ack is not received within timeout, `SendPacketError::Timeout` is returned
+    /// and packet id is released. By default client handshake timeout is used,
+    /// zero timeout disables timer.
+    pub fn timeout<T: Into<Millis>>(mut self, timeout: T) -> Self {
+        self.timeout = Some(timeout.into());
+        self
+    }
+
     #[inline]
     /// Get size of the subscribe packet
     pub fn size(&self) -> u32 {
@@ -322,9 +685,16 @@ impl SubscribeBuilder {
             }) {
                 Ok(_) => {
                     // wait ack from peer
-                    rx.await
-                        .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.subscribe())
+                    let timeout = self.timeout.unwrap_or_else(|| shared.ack_timeout());
+                    match timeout_checked(timeout, rx).await {
+                        Ok(res) => res
+                            .map_err(|_| SendPacketError::Disconnected)
+                            .map(|pkt| pkt.subscribe()),
+                        Err(_) => {
+                            shared.expire(idx);
+                            Err(SendPacketError::Timeout)
+                        }
+                    }
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -337,6 +707,7 @@ impl SubscribeBuilder {
 /// Unsubscribe packet builder
 pub struct UnsubscribeBuilder {
     id: Option<NonZeroU16>,
+    timeout: Option<Millis>,
     shared: Rc<MqttShared>,
     topic_filters: Vec<ByteString>,
 }
@@ -362,6 +733,17 @@ impl UnsubscribeBuilder {
         self
     }
 
+    #[inline]
+    /// Set ack timeout
+    ///
+    /// If ack is not received within timeout, `SendPacketError::Timeout` is returned
+    /// and packet id is released. By default client handshake timeout is used,
+    /// zero timeout disables timer.
+    pub fn timeout<T: Into<Millis>>(mut self, timeout: T) -> Self {
+        self.timeout = Some(timeout.into());
+        self
+    }
+
     #[inline]
     /// Get size of the unsubscribe packet
     pub fn size(&self) -> u32 {
@@ -393,7 +775,14 @@ impl UnsubscribeBuilder {
             }) {
                 Ok(_) => {
                     // wait ack from peer
-                    rx.await.map_err(|_| SendPacketError::Disconnected).map(|_| ())
+                    let timeout = self.timeout.unwrap_or_else(|| shared.ack_timeout());
+                    match timeout_checked(timeout, rx).await {
+                        Ok(res) => res.map_err(|_| SendPacketError::Disconnected).map(|_| ()),
+                        Err(_) => {
+                            shared.expire(idx);
+                            Err(SendPacketError::Timeout)
+                        }
+                    }
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -8,6 +8,7 @@ use ntex_service::{boxed, fn_service, IntoService, Pipeline, Service};
 use ntex_util::time::{sleep, Millis, Seconds};
 use ntex_util::{future::Either, future::Ready, HashMap};
 
+use crate::types::{QoS, MQTT_LEVEL_5};
 use crate::v5::publish::{Publish, PublishAck};
 use crate::v5::{codec, shared::MqttShared, sink::MqttSink, ControlAck};
 use crate::{error::MqttError, io::Dispatcher};
@@ -20,15 +21,43 @@ pub struct Client {
     shared: Rc<MqttShared>,
     keepalive: Seconds,
     max_receive: usize,
+    session_expiry: u32,
     config: DispatcherConfig,
     pkt: Box<codec::ConnectAck>,
 }
 
+/// Session parameters negotiated during connect
+///
+/// Values are taken from server's `ConnectAck` packet, absent properties
+/// are replaced with client requested values or protocol defaults.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Mqtt protocol version
+    pub protocol_version: u8,
+    /// Keep-alive interval, server keep-alive overrides client value
+    pub keepalive: Seconds,
+    /// Maximum QoS supported by server
+    pub max_qos: QoS,
+    /// Server supports retained messages
+    pub retain_available: bool,
+    /// Maximum packet size server is willing to accept
+    pub max_packet_size: Option<u32>,
+    /// Highest topic alias value server accepts
+    pub topic_alias_max: u16,
+    /// Maximum number of in-flight QoS 1 and QoS 2 publishes
+    pub receive_max: NonZeroU16,
+    /// Server has stored session state
+    pub session_present: bool,
+    /// Session expiry interval in seconds
+    pub session_expiry: u32,
+}
+
 impl fmt::Debug for Client {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("v5::Client")
             .field("keepalive", &self.keepalive)
             .field("max_receive", &self.max_receive)
+            .field("session_expiry", &self.session_expiry)
             .field("connect", &self.pkt)
             .field("config", &self.config)
             .finish()
@@ -43,9 +72,18 @@ impl Client {
         pkt: Box<codec::ConnectAck>,
         max_receive: u16,
         keepalive: Seconds,
+        session_expiry: u32,
         config: DispatcherConfig,
     ) -> Self {
-        Client { io, pkt, shared, keepalive, config, max_receive: max_receive as usize }
+        Client {
+            io,
+            pkt,
+            shared,
+            keepalive,
+            session_expiry,
+            config,
+            max_receive: max_receive as usize,
+        }
     }
 }
 
@@ -62,6 +100,46 @@ impl Client {
         self.pkt.session_present
     }
 
+    #[inline]
+    /// Negotiated session expiry interval in seconds
+    ///
+    /// Returns value provided by server in `ConnectAck` packet, otherwise
+    /// value requested by client. `u32::MAX` means session does not expire.
+    pub fn session_expiry(&self) -> u32 {
+        self.session_expiry
+    }
+
+    /// Session parameters negotiated with server
+    pub fn negotiated(&self) -> Negotiated {
+        Negotiated {
+            protocol_version: MQTT_LEVEL_5,
+            keepalive: self.keepalive,
+            max_qos: self.pkt.max_qos,
+            retain_available: self.pkt.retain_available,
+            max_packet_size: self.pkt.max_packet_size,
+            topic_alias_max: self.pkt.topic_alias_max,
+            receive_max: self.pkt.receive_max,
+            session_present: self.pkt.session_present,
+            session_expiry: self.session_expiry,
+        }
+    }
+
+    #[inline]
+    /// Client identifier assigned by server
+    ///
+    /// Server assigns identifier if client connects with empty client id.
+    pub fn assigned_client_id(&self) -> Option<&ByteString> {
+        self.pkt.assigned_client_id.as_ref()
+    }
+
+    #[inline]
+    /// Client identifier used for this connection
+    ///
+    /// Returns identifier assigned by server or requested by client.
+    pub fn client_id(&self) -> &ByteString {
+        self.shared.client_id()
+    }
+
     #[inline]
     /// Get reference to `ConnectAck` packet
     pub fn packet(&self) -> &codec::ConnectAck {
@@ -1,4 +1,4 @@
-use std::{num::NonZeroU16, num::NonZeroU32, rc::Rc};
+use std::{cell::RefCell, num::NonZeroU16, num::NonZeroU32, rc::Rc};
 
 use ntex_bytes::{ByteString, Bytes, PoolId};
 use ntex_io::{DispatcherConfig, IoBoxed};
@@ -7,18 +7,95 @@ use ntex_service::{IntoService, Pipeline, Service};
 use ntex_util::time::{timeout_checked, Seconds};
 
 use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
+use crate::socks::{self, Socks5};
+use crate::types::QoS;
 use crate::v5::shared::{MqttShared, MqttSinkPool};
 
+/// Smallest accepted max packet size, enough for control packets without properties
+const MIN_MAX_PACKET_SIZE: u32 = 16;
+
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
     address: A,
     connector: Pipeline<T>,
     pkt: codec::Connect,
     handshake_timeout: Seconds,
+    initial_packet_id: Option<NonZeroU16>,
+    reuse_assigned_id: bool,
+    assigned_id: RefCell<Option<ByteString>>,
+    proxy: Option<Socks5<A>>,
     config: DispatcherConfig,
     pool: Rc<MqttSinkPool>,
 }
 
+/// Mqtt client connection options
+///
+/// Plain representation of connector configuration, can be deserialized
+/// from configuration file and applied with `MqttConnector::with_options()`.
+/// Tls is configured with custom connector, see `MqttConnector::connector()`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ConnectOptions {
+    /// Client identifier
+    pub client_id: String,
+    /// Keep-alive interval in seconds
+    pub keep_alive: u16,
+    /// Start new session
+    pub clean_start: bool,
+    /// Session expiry interval in seconds
+    pub session_expiry_interval: u32,
+    /// Username for authentication
+    pub username: Option<String>,
+    /// Password for authentication
+    pub password: Option<String>,
+    /// Will message
+    pub last_will: Option<LastWillOptions>,
+    /// Max incoming packet size, `0` disables limit
+    pub max_packet_size: u32,
+    /// Number of in-flight incoming publish packets, `0` disables limit
+    pub max_receive: u16,
+    /// Handshake timeout in seconds, `0` disables timeout
+    pub handshake_timeout: u16,
+    /// Disconnect timeout in seconds, `0` disables timeout
+    pub disconnect_timeout: u16,
+}
+
+/// Will message options
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LastWillOptions {
+    /// Will topic
+    pub topic: String,
+    /// Will message payload
+    #[serde(default)]
+    pub message: String,
+    /// QoS level of will message
+    pub qos: QoS,
+    /// Retain will message
+    #[serde(default)]
+    pub retain: bool,
+    /// Will delay interval in seconds
+    #[serde(default)]
+    pub delay_interval: Option<u32>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            keep_alive: 0,
+            clean_start: false,
+            session_expiry_interval: 0,
+            username: None,
+            password: None,
+            last_will: None,
+            max_packet_size: 0,
+            max_receive: 0,
+            handshake_timeout: 0,
+            disconnect_timeout: 3,
+        }
+    }
+}
+
 impl<A> MqttConnector<A, ()>
 where
     A: Address + Clone,
@@ -34,6 +111,10 @@ where
             pkt: codec::Connect::default(),
             connector: Pipeline::new(Connector::default()),
             handshake_timeout: Seconds::ZERO,
+            initial_packet_id: None,
+            reuse_assigned_id: false,
+            assigned_id: RefCell::new(None),
+            proxy: None,
             pool: Rc::new(MqttSinkPool::default()),
         }
     }
@@ -55,8 +136,23 @@ where
 
     #[inline]
     /// The handling of the Session state.
-    pub fn clean_start(mut self) -> Self {
-        self.pkt.clean_start = true;
+    ///
+    /// If set to `true` server discards any existing session and starts a new one.
+    /// By default clean start is disabled.
+    pub fn clean_start(mut self, val: bool) -> Self {
+        self.pkt.clean_start = val;
+        self
+    }
+
+    #[inline]
+    /// Session expiry interval in seconds.
+    ///
+    /// If set to `0` session ends when network connection is closed. If set to
+    /// `u32::MAX` (0xFFFFFFFF) session does not expire.
+    ///
+    /// By default session expiry interval is set to `0`.
+    pub fn session_expiry_interval(mut self, val: u32) -> Self {
+        self.pkt.session_expiry_interval_secs = val;
         self
     }
 
@@ -103,8 +199,19 @@ where
     #[inline]
     /// Max incoming packet size.
     ///
+    /// Value is advertised to the server with `Maximum Packet Size` property of
+    /// connect packet and client's decoder rejects bigger packets.
     /// To disable max size limit set value to 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if value is not 0 and is less than 16 bytes.
     pub fn max_packet_size(mut self, val: u32) -> Self {
+        assert!(
+            val == 0 || val >= MIN_MAX_PACKET_SIZE,
+            "Max packet size must be at least {} bytes",
+            MIN_MAX_PACKET_SIZE
+        );
         if let Some(val) = NonZeroU32::new(val) {
             self.pkt.max_packet_size = Some(val);
         } else {
@@ -143,6 +250,38 @@ where
         self
     }
 
+    /// Apply connection options
+    ///
+    /// Options override values previously set with builder methods.
+    ///
+    /// # Panics
+    ///
+    /// Panics if max packet size is not 0 and is less than 16 bytes.
+    pub fn with_options(mut self, opts: ConnectOptions) -> Self {
+        self.pkt.client_id = opts.client_id.into();
+        self.pkt.keep_alive = opts.keep_alive;
+        self.pkt.clean_start = opts.clean_start;
+        self.pkt.session_expiry_interval_secs = opts.session_expiry_interval;
+        self.pkt.username = opts.username.map(ByteString::from);
+        self.pkt.password = opts.password.map(Bytes::from);
+        self.pkt.last_will = opts.last_will.map(|will| codec::LastWill {
+            qos: will.qos,
+            retain: will.retain,
+            topic: will.topic.into(),
+            message: will.message.into(),
+            will_delay_interval_sec: will.delay_interval,
+            correlation_data: None,
+            message_expiry_interval: None,
+            content_type: None,
+            user_properties: Vec::new(),
+            is_utf8_payload: None,
+            response_topic: None,
+        });
+        self.handshake_timeout = Seconds(opts.handshake_timeout);
+        self.config.set_disconnect_timeout(Seconds(opts.disconnect_timeout));
+        self.max_packet_size(opts.max_packet_size).max_receive(opts.max_receive)
+    }
+
     #[inline]
     /// Update connect packet
     pub fn packet<F>(mut self, f: F) -> Self
@@ -162,6 +301,30 @@ where
         self
     }
 
+    /// Set initial packet id.
+    ///
+    /// Sink allocates packet ids starting from `id`. Allocated ids wrap
+    /// around and skip ids of in-flight packets.
+    ///
+    /// By default packet ids start from 1.
+    pub fn initial_packet_id(mut self, id: NonZeroU16) -> Self {
+        self.initial_packet_id = Some(id);
+        self
+    }
+
+    /// Reuse client identifier assigned by server.
+    ///
+    /// If client id is empty, server assigns client identifier and returns
+    /// it in `ConnectAck` packet. With this option enabled, subsequent calls
+    /// to `connect()` use assigned identifier instead of requesting new one.
+    /// Empty client identifier requires clean start to be set.
+    ///
+    /// By default new identifier is requested on each connect.
+    pub fn reuse_assigned_client_id(mut self, val: bool) -> Self {
+        self.reuse_assigned_id = val;
+        self
+    }
+
     /// Set client connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -184,7 +347,24 @@ where
         self
     }
 
+    /// Connect through SOCKS5 proxy
+    ///
+    /// Connector establishes connection to the proxy and requests a tunnel
+    /// to the server address, server host name is resolved by the proxy.
+    /// `auth` is an optional username and password pair. Proxy handshake runs
+    /// on top of io object returned by connector, connections that require TLS
+    /// to the server are not supported.
+    pub fn socks5_proxy(mut self, addr: A, auth: Option<(ByteString, ByteString)>) -> Self {
+        self.proxy = Some(Socks5 { addr, auth });
+        self
+    }
+
     /// Use custom connector
+    ///
+    /// Socket level options (`TCP_NODELAY`, `SO_KEEPALIVE`, etc) are not managed by
+    /// the connector, ntex runtime enables `TCP_NODELAY` for all tcp connections.
+    /// To use different socket options, provide custom connector that configures
+    /// the socket before converting it to `Io` object.
     pub fn connector<U, F>(self, connector: F) -> MqttConnector<A, U>
     where
         F: IntoService<U, Connect<A>>,
@@ -197,6 +377,10 @@ where
             address: self.address,
             config: self.config,
             handshake_timeout: self.handshake_timeout,
+            initial_packet_id: self.initial_packet_id,
+            reuse_assigned_id: self.reuse_assigned_id,
+            assigned_id: self.assigned_id,
+            proxy: self.proxy,
             pool: self.pool,
         }
     }
@@ -217,11 +401,26 @@ where
     }
 
     async fn _connect(&self) -> Result<Client, ClientError<Box<codec::ConnectAck>>> {
-        let io: IoBoxed = self.connector.call(Connect::new(self.address.clone())).await?.into();
-        let pkt = self.pkt.clone();
+        let io: IoBoxed = if let Some(ref proxy) = self.proxy {
+            let io: IoBoxed =
+                self.connector.call(Connect::new(proxy.addr.clone())).await?.into();
+            let (host, port) = socks::host_port(&self.address);
+            socks::handshake(&io, host, port, proxy.auth.as_ref()).await?;
+            io
+        } else {
+            self.connector.call(Connect::new(self.address.clone())).await?.into()
+        };
+        let mut pkt = self.pkt.clone();
+        if pkt.client_id.is_empty() && self.reuse_assigned_id {
+            if let Some(ref id) = *self.assigned_id.borrow() {
+                pkt.client_id = id.clone();
+            }
+        }
+        let client_id = pkt.client_id.clone();
         let keep_alive = pkt.keep_alive;
         let max_packet_size = pkt.max_packet_size.map(|v| v.get()).unwrap_or(0);
         let max_receive = pkt.receive_max.map(|v| v.get()).unwrap_or(65535);
+        let session_expiry = pkt.session_expiry_interval_secs;
         let codec = codec::Codec::new();
         codec.set_max_inbound_size(max_packet_size);
         let pool = self.pool.clone();
@@ -235,6 +434,10 @@ where
         })?;
 
         let shared = Rc::new(MqttShared::new(io.get_ref(), codec, pool));
+        shared.set_ack_timeout(self.handshake_timeout.into());
+        if let Some(id) = self.initial_packet_id {
+            shared.set_initial_packet_id(id);
+        }
         match packet {
             (codec::Packet::ConnectAck(pkt), _) => {
                 log::trace!("Connect ack response from server: {:#?}", pkt);
@@ -248,7 +451,27 @@ where
 
                     shared.set_cap(pkt.receive_max.get() as usize);
 
-                    Ok(Client::new(io, shared, pkt, max_receive, Seconds(keep_alive), config))
+                    // server may override requested session expiry interval
+                    let session_expiry =
+                        pkt.session_expiry_interval_secs.unwrap_or(session_expiry);
+
+                    // server may assign client identifier
+                    if let Some(ref id) = pkt.assigned_client_id {
+                        *self.assigned_id.borrow_mut() = Some(id.clone());
+                        shared.set_client_id(id.clone());
+                    } else {
+                        shared.set_client_id(client_id);
+                    }
+
+                    Ok(Client::new(
+                        io,
+                        shared,
+                        pkt,
+                        max_receive,
+                        Seconds(keep_alive),
+                        session_expiry,
+                        config,
+                    ))
                 } else {
                     Err(ClientError::Ack(pkt))
                 }
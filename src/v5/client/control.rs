@@ -95,12 +95,15 @@ impl Publish {
     pub fn ack(self, reason_code: codec::PublishAckReason) -> ControlAck {
         ControlAck {
             packet: self.0.packet_id.map(|packet_id| {
-                codec::Packet::PublishAck(codec::PublishAck {
-                    packet_id,
-                    reason_code,
-                    properties: codec::UserProperties::new(),
-                    reason_string: None,
-                })
+                publish_ack(
+                    self.0.qos,
+                    codec::PublishAck {
+                        packet_id,
+                        reason_code,
+                        properties: codec::UserProperties::new(),
+                        reason_string: None,
+                    },
+                )
             }),
             disconnect: false,
         }
@@ -114,12 +117,10 @@ impl Publish {
     ) -> ControlAck {
         ControlAck {
             packet: self.0.packet_id.map(|packet_id| {
-                codec::Packet::PublishAck(codec::PublishAck {
-                    packet_id,
-                    reason_code,
-                    properties,
-                    reason_string,
-                })
+                publish_ack(
+                    self.0.qos,
+                    codec::PublishAck { packet_id, reason_code, properties, reason_string },
+                )
             }),
             disconnect: false,
         }
@@ -132,12 +133,15 @@ impl Publish {
         (
             ControlAck {
                 packet: self.0.packet_id.map(|packet_id| {
-                    codec::Packet::PublishAck(codec::PublishAck {
-                        packet_id,
-                        reason_code,
-                        properties: codec::UserProperties::new(),
-                        reason_string: None,
-                    })
+                    publish_ack(
+                        self.0.qos,
+                        codec::PublishAck {
+                            packet_id,
+                            reason_code,
+                            properties: codec::UserProperties::new(),
+                            reason_string: None,
+                        },
+                    )
                 }),
                 disconnect: false,
             },
@@ -146,6 +150,15 @@ impl Publish {
     }
 }
 
+/// PUBREC is sent for QoS2 publishes, PUBACK otherwise
+fn publish_ack(qos: codec::QoS, ack: codec::PublishAck) -> codec::Packet {
+    if qos == codec::QoS::ExactlyOnce {
+        codec::Packet::PublishReceived(ack)
+    } else {
+        codec::Packet::PublishAck(ack)
+    }
+}
+
 #[derive(Debug)]
 pub struct PeerGone(Option<io::Error>);
 
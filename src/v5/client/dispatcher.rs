@@ -52,9 +52,20 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<NonZeroU16>,
+    // QoS2 publishes waiting for PUBREL
+    received: HashSet<NonZeroU16>,
     aliases: HashMap<NonZeroU16, ByteString>,
 }
 
+impl<C> Inner<C> {
+    /// Keep packet id of accepted QoS2 publish until PUBREL is received
+    fn publish_received(&self, ack: &codec::PublishAck) {
+        if u8::from(ack.reason_code) < 0x80 {
+            self.info.borrow_mut().received.insert(ack.packet_id);
+        }
+    }
+}
+
 impl<T, C, E> Dispatcher<T, C, E>
 where
     T: Service<Publish, Response = Either<Publish, PublishAck>, Error = E>,
@@ -77,6 +88,7 @@ where
                 info: RefCell::new(PublishInfo {
                     aliases: HashMap::default(),
                     inflight: HashSet::default(),
+                    received: HashSet::default(),
                 }),
             }),
             _t: PhantomData,
@@ -119,17 +131,27 @@ where
             DispatchItem::Item((codec::Packet::Publish(mut publish), size)) => {
                 let info = self.inner.as_ref();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
 
                 {
                     let mut inner = info.info.borrow_mut();
 
                     if let Some(pid) = packet_id {
+                        // re-delivery of QoS2 publish, PUBREC is already sent
+                        if qos == codec::QoS::ExactlyOnce && inner.received.contains(&pid) {
+                            log::trace!("Duplicated QoS2 publish packet: {:?}", pid);
+                            return Ok(Some(codec::Packet::PublishReceived(
+                                codec::PublishAck { packet_id: pid, ..Default::default() },
+                            )));
+                        }
+
                         // check for receive maximum
-                        if self.max_receive != 0 && inner.inflight.len() >= self.max_receive {
+                        let inflight = inner.inflight.len() + inner.received.len();
+                        if self.max_receive != 0 && inflight >= self.max_receive {
                             log::trace!(
                                 "Receive maximum exceeded: max: {} inflight: {}",
                                 self.max_receive,
-                                inner.inflight.len()
+                                inflight
                             );
                             drop(inner);
                             return control(
@@ -147,13 +169,18 @@ where
 
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
-                            let _ = self.inner.sink.encode_packet(codec::Packet::PublishAck(
-                                codec::PublishAck {
-                                    packet_id: pid,
-                                    reason_code: codec::PublishAckReason::PacketIdentifierInUse,
-                                    ..Default::default()
+                            let ack = codec::PublishAck {
+                                packet_id: pid,
+                                reason_code: codec::PublishAckReason::PacketIdentifierInUse,
+                                ..Default::default()
+                            };
+                            let _ = self.inner.sink.encode_packet(
+                                if qos == codec::QoS::ExactlyOnce {
+                                    codec::Packet::PublishReceived(ack)
+                                } else {
+                                    codec::Packet::PublishAck(ack)
                                 },
-                            ));
+                            );
                             return Ok(None);
                         }
                     }
@@ -212,16 +239,36 @@ where
                     }
                 }
 
+                // responses of `MqttSink::rpc()` requests are delivered with QoS 0
+                if packet_id.is_none() && self.inner.sink.rpc_response(&publish) {
+                    return Ok(None);
+                }
+
                 publish_fn(
                     &self.publish,
                     Publish::new(publish, size),
                     packet_id.map(|v| v.get()).unwrap_or(0),
+                    qos,
                     size,
                     info,
                     ctx,
                 )
                 .await
             }
+            DispatchItem::Item((codec::Packet::PublishRelease(pkt), _)) => {
+                let reason_code =
+                    if self.inner.info.borrow_mut().received.remove(&pkt.packet_id) {
+                        codec::PublishAck2Reason::Success
+                    } else {
+                        codec::PublishAck2Reason::PacketIdNotFound
+                    };
+                Ok(Some(codec::Packet::PublishComplete(codec::PublishAck2 {
+                    packet_id: pkt.packet_id,
+                    reason_code,
+                    properties: codec::UserProperties::new(),
+                    reason_string: None,
+                })))
+            }
             DispatchItem::Item((codec::Packet::PublishAck(packet), _)) => {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish(packet)) {
                     control(Control::proto_error(err), &self.inner, ctx, 0).await
@@ -229,6 +276,20 @@ where
                     Ok(None)
                 }
             }
+            DispatchItem::Item((codec::Packet::PublishReceived(packet), _)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Receive(packet)) {
+                    control(Control::proto_error(err), &self.inner, ctx, 0).await
+                } else {
+                    Ok(None)
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishComplete(packet), _)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Complete(packet)) {
+                    control(Control::proto_error(err), &self.inner, ctx, 0).await
+                } else {
+                    Ok(None)
+                }
+            }
             DispatchItem::Item((codec::Packet::SubscribeAck(packet), _)) => {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Subscribe(packet)) {
                     control(Control::proto_error(err), &self.inner, ctx, 0).await
@@ -314,6 +375,7 @@ async fn publish_fn<'f, T, C, E>(
     svc: &'f T,
     pkt: Publish,
     packet_id: u16,
+    qos: codec::QoS,
     packet_size: u32,
     inner: &'f Inner<C>,
     ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
@@ -326,13 +388,17 @@ where
         Ok(res) => match res {
             Either::Right(ack) => ack,
             Either::Left(pkt) => {
-                return control(
+                let res = control(
                     Control::publish(pkt.into_inner(), packet_size),
                     inner,
                     ctx,
                     packet_id,
                 )
-                .await
+                .await;
+                if let Ok(Some(codec::Packet::PublishReceived(ref ack))) = res {
+                    inner.publish_received(ack);
+                }
+                return res;
             }
         },
         Err(e) => return control(Control::error(e), inner, ctx, 0).await,
@@ -347,7 +413,12 @@ where
             reason_string: ack.reason_string,
             properties: ack.properties,
         };
-        Ok(Some(codec::Packet::PublishAck(ack)))
+        if qos == codec::QoS::ExactlyOnce {
+            inner.publish_received(&ack);
+            Ok(Some(codec::Packet::PublishReceived(ack)))
+        } else {
+            Ok(Some(codec::Packet::PublishAck(ack)))
+        }
     } else {
         Ok(None)
     }
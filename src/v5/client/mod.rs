@@ -5,8 +5,8 @@ mod connector;
 pub mod control;
 mod dispatcher;
 
-pub use self::connection::{Client, ClientRouter};
-pub use self::connector::MqttConnector;
+pub use self::connection::{Client, ClientRouter, Negotiated};
+pub use self::connector::{ConnectOptions, LastWillOptions, MqttConnector};
 pub use self::control::{Control, ControlAck};
 
 pub use crate::topic::{TopicFilter, TopicFilterError};
@@ -1,12 +1,13 @@
-use std::cell::Cell;
+use std::{cell::Cell, cell::RefCell, fmt, rc::Rc};
 
-use ntex_bytes::{Buf, BytesMut};
+use ntex_bytes::{Buf, Bytes, BytesMut};
 use ntex_codec::{Decoder, Encoder};
 
-use super::{decode::decode_packet, encode::EncodeLtd, Packet};
-use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, MAX_PACKET_SIZE};
+use super::{decode, encode::EncodeLtd, Packet};
+use crate::error::{DecodeError, EncodeError, ProtocolError};
+use crate::types::{FilterDecision, FixedHeader, MAX_PACKET_SIZE};
 use crate::utils::decode_variable_length;
+use crate::ServerHandle;
 
 #[derive(Debug, Clone)]
 pub struct Codec {
@@ -14,6 +15,17 @@ pub struct Codec {
     max_in_size: Cell<u32>,
     max_out_size: Cell<u32>,
     flags: Cell<CodecFlags>,
+    metrics: RefCell<Option<ServerHandle>>,
+    egress: RefCell<Option<EgressFilter>>,
+}
+
+#[derive(Clone)]
+struct EgressFilter(Rc<dyn Fn(&mut Packet) -> FilterDecision<Packet>>);
+
+impl fmt::Debug for EgressFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EgressFilter")
+    }
 }
 
 bitflags::bitflags! {
@@ -22,6 +34,7 @@ bitflags::bitflags! {
         const NO_PROBLEM_INFO = 0b0000_0001;
         const NO_RETAIN       = 0b0000_0010;
         const NO_SUB_IDS      = 0b0000_1000;
+        const PASSTHROUGH     = 0b0001_0000;
     }
 }
 
@@ -39,6 +52,8 @@ impl Codec {
             max_in_size: Cell::new(0),
             max_out_size: Cell::new(0),
             flags: Cell::new(CodecFlags::empty()),
+            metrics: RefCell::new(None),
+            egress: RefCell::new(None),
         }
     }
 
@@ -61,6 +76,9 @@ impl Codec {
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
+    /// Limit is checked against frame's fixed header, so it is safe to change max size
+    /// on established connection, i.e. after negotiating maximum packet size,
+    /// new limit applies starting from next frame.
     /// By default max size is set to `0`
     pub fn set_max_inbound_size(&self, size: u32) {
         self.max_in_size.set(size);
@@ -78,6 +96,19 @@ impl Codec {
         self.max_out_size.set(size);
     }
 
+    /// Pass through packets with unrecognized fixed header.
+    ///
+    /// Unrecognized packets are decoded as `Packet::Unknown` instead of
+    /// failing with `DecodeError::UnsupportedPacketType`, so they could be
+    /// relayed verbatim, i.e. by a proxy. By default passthrough is disabled.
+    /// Known packet types with invalid fixed header flags are rejected
+    /// with `DecodeError::MalformedPacket`.
+    pub fn set_passthrough_unknown(&self, val: bool) {
+        let mut flags = self.flags.get();
+        flags.set(CodecFlags::PASSTHROUGH, val);
+        self.flags.set(flags);
+    }
+
     pub(crate) fn retain_available(&self) -> bool {
         !self.flags.get().contains(CodecFlags::NO_RETAIN)
     }
@@ -97,6 +128,19 @@ impl Codec {
         flags.set(CodecFlags::NO_SUB_IDS, !val);
         self.flags.set(flags);
     }
+
+    /// Count decode errors in server handle
+    pub(crate) fn set_metrics(&self, handle: Option<ServerHandle>) {
+        *self.metrics.borrow_mut() = handle;
+    }
+
+    /// Set filter for outbound packets, filter is called before packet encoding
+    pub(crate) fn set_egress_filter(
+        &self,
+        filter: Option<Rc<dyn Fn(&mut Packet) -> FilterDecision<Packet>>>,
+    ) {
+        *self.egress.borrow_mut() = filter.map(EgressFilter);
+    }
 }
 
 impl Default for Codec {
@@ -105,11 +149,8 @@ impl Default for Codec {
     }
 }
 
-impl Decoder for Codec {
-    type Item = (Packet, u32);
-    type Error = DecodeError;
-
-    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+impl Codec {
+    fn decode_frame(&self, src: &mut BytesMut) -> Result<Option<(Packet, u32)>, DecodeError> {
         loop {
             match self.state.get() {
                 DecodeState::FrameHeader => {
@@ -153,7 +194,19 @@ impl Decoder for Codec {
                         return Ok(None);
                     }
                     let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
-                    let packet = decode_packet(packet_buf, fixed.first_byte)?;
+                    let packet =
+                        match decode::decode_packet(packet_buf.clone(), fixed.first_byte) {
+                            Err(DecodeError::UnsupportedPacketType)
+                                if self.flags.get().contains(CodecFlags::PASSTHROUGH) =>
+                            {
+                                Packet::Unknown {
+                                    packet_type: fixed.first_byte >> 4,
+                                    flags: fixed.first_byte & 0b0000_1111,
+                                    payload: packet_buf,
+                                }
+                            }
+                            res => res?,
+                        };
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(5); // enough to fix 1 fixed header byte + 4 bytes max variable packet length
 
@@ -169,12 +222,35 @@ impl Decoder for Codec {
     }
 }
 
+impl Decoder for Codec {
+    type Item = (Packet, u32);
+    type Error = DecodeError;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        let result = self.decode_frame(src);
+        if let Err(err) = result {
+            if let Some(ref handle) = *self.metrics.borrow() {
+                handle.record_decode_error(err);
+            }
+        }
+        result
+    }
+}
+
 impl Encoder for Codec {
     type Item = Packet;
     type Error = EncodeError;
 
     fn encode(&self, mut item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
-        // handle [MQTT 3.1.2.11.7]
+        if let Some(ref filter) = *self.egress.borrow() {
+            match (filter.0)(&mut item) {
+                FilterDecision::Pass => (),
+                FilterDecision::Drop => return Ok(()),
+                FilterDecision::Replace(pkt) => item = pkt,
+            }
+        }
+        // handle [MQTT 3.1.2.11.7], reason string and user properties are allowed
+        // only for PUBLISH, CONNACK and DISCONNECT packets
         if self.flags.get().contains(CodecFlags::NO_PROBLEM_INFO) {
             match item {
                 Packet::PublishAck(ref mut pkt) | Packet::PublishReceived(ref mut pkt) => {
@@ -219,9 +295,35 @@ impl Encoder for Codec {
     }
 }
 
+/// Decode single packet from bytes slice.
+///
+/// Returns `Ok(None)` if slice does not contain complete packet, otherwise returns
+/// decoded packet and number of consumed bytes. Function does not panic on
+/// arbitrary input, so it could be used as a fuzz target.
+pub fn decode_packet_slice(src: &[u8]) -> Result<Option<(Packet, usize)>, ProtocolError> {
+    if src.len() < 2 {
+        return Ok(None);
+    }
+    let first_byte = src[0];
+    if let Some((remaining_length, consumed)) = decode_variable_length(&src[1..])? {
+        let start = consumed + 1;
+        let end = start + remaining_length as usize;
+        if src.len() < end {
+            return Ok(None);
+        }
+        let packet =
+            decode::decode_packet(Bytes::copy_from_slice(&src[start..end]), first_byte)?;
+        Ok(Some((packet, end)))
+    } else {
+        Ok(None)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::v5::codec::{PublishAck, PublishAckReason};
+    use std::num::NonZeroU16;
 
     #[test]
     fn test_max_size() {
@@ -231,4 +333,78 @@ mod tests {
         buf.extend_from_slice(b"\0\x09");
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
+
+    #[test]
+    fn test_max_size_change() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\x09\x00\x01t\x00");
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+
+        // partially received frame is not affected
+        codec.set_max_inbound_size(4);
+        buf.extend_from_slice(b"hello");
+        assert!(matches!(codec.decode(&mut buf), Ok(Some((Packet::Publish(_), 9)))));
+
+        buf.extend_from_slice(b"\x30\x09\x00\x01t\x00hello");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+    }
+
+    #[test]
+    fn test_passthrough_unknown() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x03\x02ab");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::UnsupportedPacketType));
+
+        let codec = Codec::new();
+        codec.set_passthrough_unknown(true);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x03\x02ab\xc0\0");
+        let pkt =
+            Packet::Unknown { packet_type: 0, flags: 3, payload: Bytes::from_static(b"ab") };
+        assert_eq!(codec.decode(&mut buf), Ok(Some((pkt.clone(), 2))));
+        assert_eq!(codec.decode(&mut buf), Ok(Some((Packet::PingRequest, 0))));
+
+        // relayed verbatim
+        codec.encode(pkt, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"\x03\x02ab");
+    }
+
+    #[test]
+    fn test_decode_packet_slice() {
+        let pkt = Packet::PublishAck(PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: PublishAckReason::NoMatchingSubscribers,
+            ..Default::default()
+        });
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+        let len = buf.len();
+        buf.extend_from_slice(b"\xc0\0");
+
+        assert_eq!(decode_packet_slice(&buf[..len]).unwrap(), Some((pkt.clone(), len)));
+        assert_eq!(decode_packet_slice(&buf).unwrap(), Some((pkt.clone(), len)));
+        assert_eq!(decode_packet_slice(&buf[len..]).unwrap(), Some((Packet::PingRequest, 2)));
+        for idx in 0..len {
+            assert_eq!(decode_packet_slice(&buf[..idx]).unwrap(), None);
+        }
+        assert!(decode_packet_slice(b"\x00\x00").is_err());
+        assert!(decode_packet_slice(b"\x10\xff\xff\xff\xff\x7f").is_err());
+
+        // arbitrary input must not panic
+        let mut seed = 0x2545_f491_u32;
+        for _ in 0..10_000 {
+            let mut data = [0u8; 32];
+            for b in data.iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                *b = seed as u8;
+            }
+            data[1] &= 0x1f;
+            let _ = decode_packet_slice(&data);
+        }
+    }
 }
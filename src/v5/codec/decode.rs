@@ -24,6 +24,8 @@ pub(super) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, De
         packet_type::PUBREC => Ok(Packet::PublishReceived(PublishAck::decode(&mut src)?)),
         packet_type::PUBREL => Ok(Packet::PublishRelease(PublishAck2::decode(&mut src)?)),
         packet_type::PUBCOMP => Ok(Packet::PublishComplete(PublishAck2::decode(&mut src)?)),
+        // known packet type with invalid fixed header flags [MQTT-2.2.2-2]
+        _ if (1..=15).contains(&(first_byte >> 4)) => Err(DecodeError::MalformedPacket),
         _ => Err(DecodeError::UnsupportedPacketType),
     }
 }
@@ -40,6 +42,7 @@ impl Decode for UserProperty {
 mod tests {
     use ntex_bytes::BytesMut;
     use std::num::{NonZeroU16, NonZeroU32};
+    use test_case::test_case;
 
     use super::*;
     use crate::utils::decode_variable_length;
@@ -143,6 +146,12 @@ mod tests {
             )),
             Err(DecodeError::ConnectReservedFlagSet)
         );
+        assert_eq!(
+            Connect::decode(&mut Bytes::from_static(
+                b"\x00\x04MQTT\x05\xC0\x00\x3C\x00\x00\x0512345\x00\x04user\x00\x04pass\x01\x02\x03"
+            )),
+            Err(DecodeError::MalformedPacket)
+        );
 
         assert_eq!(
             ConnectAck::decode(&mut Bytes::from_static(b"\x01\x86\x00")),
@@ -214,6 +223,12 @@ mod tests {
             }),
         );
 
+        // zero topic alias is not allowed
+        assert_eq!(
+            decode_packet(Bytes::from_static(b"\x00\x00\x03\x23\x00\x00"), 0x30),
+            Err(DecodeError::MalformedPacket)
+        );
+
         assert_decode_packet(
             b"\x40\x02\x43\x21",
             Packet::PublishAck(PublishAck {
@@ -362,4 +377,42 @@ mod tests {
         assert_decode_packet(b"\xc0\x00", Packet::PingRequest);
         assert_decode_packet(b"\xd0\x00", Packet::PingResponse);
     }
+
+    #[test_case(b"\x11\x00"; "connect")]
+    #[test_case(b"\x21\x00"; "connack")]
+    #[test_case(b"\x41\x00"; "puback")]
+    #[test_case(b"\x51\x00"; "pubrec")]
+    #[test_case(b"\x60\x00"; "pubrel")]
+    #[test_case(b"\x71\x00"; "pubcomp")]
+    #[test_case(b"\x80\x00"; "subscribe")]
+    #[test_case(b"\x91\x00"; "suback")]
+    #[test_case(b"\xa0\x00"; "unsubscribe")]
+    #[test_case(b"\xb1\x00"; "unsuback")]
+    #[test_case(b"\xc1\x00"; "pingreq")]
+    #[test_case(b"\xd1\x00"; "pingresp")]
+    #[test_case(b"\xe1\x00"; "disconnect")]
+    #[test_case(b"\xf1\x00"; "auth")]
+    fn test_decode_invalid_flags(bytes: &[u8]) {
+        assert_eq!(
+            decode_packet(Bytes::copy_from_slice(&bytes[2..]), bytes[0]),
+            Err(DecodeError::MalformedPacket)
+        );
+    }
+
+    #[test]
+    fn test_packet_flags() {
+        assert_eq!(Packet::PingRequest.flags(), 0);
+        let ack = PublishAck2 { packet_id: packet_id(1), ..Default::default() };
+        assert_eq!(Packet::PublishRelease(ack).flags(), 0b0010);
+        let publish = Publish {
+            dup: false,
+            retain: true,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("a"),
+            packet_id: Some(packet_id(1)),
+            payload: Bytes::new(),
+            properties: PublishProperties::default(),
+        };
+        assert_eq!(Packet::Publish(publish).flags(), 0b0011);
+    }
 }
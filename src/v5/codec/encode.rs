@@ -30,6 +30,7 @@ impl EncodeLtd for Packet {
             Packet::PingRequest | Packet::PingResponse => 0,
             Packet::Disconnect(disconnect) => disconnect.encoded_size(limit),
             Packet::Auth(auth) => auth.encoded_size(limit),
+            Packet::Unknown { payload, .. } => payload.len(),
         }
     }
 
@@ -113,6 +114,12 @@ impl EncodeLtd for Packet {
                 write_variable_length(check_size, buf);
                 auth.encode(buf, check_size)
             }
+            Packet::Unknown { packet_type, flags, payload } => {
+                buf.put_u8((packet_type << 4) | (flags & 0b0000_1111));
+                write_variable_length(check_size, buf);
+                buf.put_slice(payload);
+                Ok(())
+            }
         }
     }
 }
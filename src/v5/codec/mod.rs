@@ -8,9 +8,10 @@ mod decode;
 mod encode;
 mod packet;
 
-pub use self::codec::Codec;
+pub use self::codec::{decode_packet_slice, Codec};
 pub(crate) use self::encode::EncodeLtd;
 pub use self::packet::*;
+pub use crate::topic::{is_valid_topic_filter, is_valid_topic_name};
 
 pub type UserProperty = (ByteString, ByteString);
 pub type UserProperties = Vec<UserProperty>;
@@ -164,6 +164,8 @@ impl Connect {
         } else {
             None
         };
+        // remaining length must match decoded fields
+        ensure!(!src.has_remaining(), DecodeError::MalformedPacket);
 
         Ok(Connect {
             clean_start: flags.contains(ConnectFlags::CLEAN_START),
@@ -56,6 +56,17 @@ pub enum Packet {
     Disconnect(Disconnect),
     /// Auth exchange
     Auth(Auth),
+    /// Packet with unrecognized fixed header
+    ///
+    /// Produced only if codec is configured to pass through unknown packets.
+    Unknown {
+        /// Packet type, high nibble of fixed header
+        packet_type: u8,
+        /// Fixed header flags, low nibble of fixed header
+        flags: u8,
+        /// Packet payload after fixed header
+        payload: Bytes,
+    },
 }
 
 impl Packet {
@@ -76,6 +87,20 @@ impl Packet {
             Packet::PingResponse => packet_type::PINGRESP,
             Packet::Disconnect(_) => packet_type::DISCONNECT,
             Packet::Auth(_) => packet_type::AUTH,
+            Packet::Unknown { packet_type, flags, .. } => (packet_type << 4) | flags,
+        }
+    }
+
+    /// Fixed header flags, low nibble of fixed header
+    ///
+    /// Flags are fixed for all packets except `PUBLISH`, decoder rejects
+    /// packets with invalid flags [MQTT-2.2.2-2].
+    pub fn flags(&self) -> u8 {
+        match self {
+            Packet::Publish(p) => {
+                (u8::from(p.dup) << 3) | (u8::from(p.qos) << 1) | u8::from(p.retain)
+            }
+            _ => self.packet_type() & 0b0000_1111,
         }
     }
 }
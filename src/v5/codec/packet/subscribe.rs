@@ -39,6 +39,28 @@ impl Default for SubscriptionOptions {
     }
 }
 
+impl SubscriptionOptions {
+    /// Check if retained messages must be sent when subscription is established
+    ///
+    /// `is_new` indicates that subscription did not exist before.
+    pub fn send_retained(&self, is_new: bool) -> bool {
+        match self.retain_handling {
+            RetainHandling::AtSubscribe => true,
+            RetainHandling::AtSubscribeNew => is_new,
+            RetainHandling::NoAtSubscribe => false,
+        }
+    }
+
+    /// Retain flag for live application message forwarded with this subscription
+    ///
+    /// Retain flag is kept only if `Retain As Published` option is set [MQTT-3.3.1-12],
+    /// [MQTT-3.3.1-13]. Retained messages sent on subscription always have
+    /// retain flag set [MQTT-3.3.1-9].
+    pub fn forward_retain(&self, retain: bool) -> bool {
+        self.retain_as_published && retain
+    }
+}
+
 prim_enum! {
     pub enum RetainHandling {
         AtSubscribe = 0,
@@ -225,6 +247,8 @@ impl Decode for SubscriptionOptions {
     fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
         ensure!(src.has_remaining(), DecodeError::InvalidLength);
         let val = src.get_u8();
+        // reserved bits must be set to 0 [MQTT-3.8.3-5]
+        ensure!(val & 0b1100_0000 == 0, DecodeError::MalformedPacket);
         let qos = (val & 0b0000_0011).try_into()?;
         let retain_handling = ((val & 0b0011_0000) >> 4).try_into()?;
         Ok(SubscriptionOptions {
@@ -359,6 +383,46 @@ mod tests {
         assert_eq!(pkt, Unsubscribe::decode(&mut buf.freeze()).unwrap());
     }
 
+    #[test]
+    fn test_sub_options() {
+        let opts = SubscriptionOptions::decode(&mut Bytes::from_static(b"\x2d")).unwrap();
+        assert_eq!(
+            opts,
+            SubscriptionOptions {
+                qos: QoS::AtLeastOnce,
+                no_local: true,
+                retain_as_published: true,
+                retain_handling: RetainHandling::NoAtSubscribe,
+            }
+        );
+
+        let mut buf = BytesMut::new();
+        opts.encode(&mut buf).unwrap();
+        assert_eq!(buf.as_ref(), b"\x2d");
+
+        // retain handling 3 is not allowed, reserved bits must be zero
+        assert!(SubscriptionOptions::decode(&mut Bytes::from_static(b"\x30")).is_err());
+        assert!(SubscriptionOptions::decode(&mut Bytes::from_static(b"\x41")).is_err());
+    }
+
+    #[test]
+    fn test_sub_options_retain() {
+        let mut opts = SubscriptionOptions::default();
+        assert!(opts.send_retained(true));
+        assert!(opts.send_retained(false));
+        assert!(!opts.forward_retain(true));
+
+        opts.retain_handling = RetainHandling::AtSubscribeNew;
+        opts.retain_as_published = true;
+        assert!(opts.send_retained(true));
+        assert!(!opts.send_retained(false));
+        assert!(opts.forward_retain(true));
+        assert!(!opts.forward_retain(false));
+
+        opts.retain_handling = RetainHandling::NoAtSubscribe;
+        assert!(!opts.send_retained(true));
+    }
+
     #[test]
     fn test_sub_pkt() {
         let pkt = Packet::Subscribe(Subscribe {
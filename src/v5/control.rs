@@ -1,6 +1,7 @@
 use std::{io, marker::PhantomData};
 
 use ntex_bytes::ByteString;
+use ntex_util::time::Seconds;
 
 use super::codec::{self, DisconnectReasonCode, QoS, UserProperties};
 use crate::error;
@@ -18,6 +19,8 @@ pub enum Control<E> {
     Subscribe(Subscribe),
     /// Unsubscribe packet from a client
     Unsubscribe(Unsubscribe),
+    /// PubRel packet from a client
+    PubRel(PubRel),
     /// Write back-pressure is enabled/disabled
     WrBackpressure(WrBackpressure),
     /// Underlying transport connection closed
@@ -28,6 +31,10 @@ pub enum Control<E> {
     ProtocolError(ProtocolError),
     /// Peer is gone
     PeerGone(PeerGone),
+    /// Periodic connection statistics
+    Stats(Stats),
+    /// Client did not publish or subscribe within first action timeout
+    ActionTimeout(ActionTimeout),
 }
 
 /// Control message handling result
@@ -56,6 +63,10 @@ impl<E> Control<E> {
         Control::Unsubscribe(Unsubscribe::new(pkt, size))
     }
 
+    pub(super) fn pubrel(pkt: codec::PublishAck2, size: u32, found: bool) -> Self {
+        Control::PubRel(PubRel::new(pkt, size, found))
+    }
+
     /// Create a new PING `Control`.
     #[doc(hidden)]
     pub fn ping() -> Self {
@@ -80,14 +91,22 @@ impl<E> Control<E> {
         Control::Error(Error::new(err))
     }
 
-    pub(super) fn peer_gone(err: Option<io::Error>) -> Self {
-        Control::PeerGone(PeerGone(err))
+    pub(super) fn peer_gone(err: Option<io::Error>, clean: bool) -> Self {
+        Control::PeerGone(PeerGone(err, clean))
     }
 
     pub(super) fn proto_error(err: error::ProtocolError) -> Self {
         Control::ProtocolError(ProtocolError::new(err))
     }
 
+    pub(super) fn stats(stats: Stats) -> Self {
+        Control::Stats(stats)
+    }
+
+    pub(super) fn action_timeout(timeout: Seconds) -> Self {
+        Control::ActionTimeout(ActionTimeout(timeout))
+    }
+
     /// Disconnects the client by sending DISCONNECT packet
     /// with `NormalDisconnection` reason code.
     pub fn disconnect(&self) -> ControlAck {
@@ -115,11 +134,14 @@ impl<E> Control<E> {
             Control::Disconnect(msg) => msg.ack(),
             Control::Subscribe(msg) => msg.ack(),
             Control::Unsubscribe(msg) => msg.ack(),
+            Control::PubRel(msg) => msg.ack(),
             Control::WrBackpressure(msg) => msg.ack(),
             Control::Closed(msg) => msg.ack(),
             Control::Error(_) => super::disconnect("Error control message is not supported"),
             Control::ProtocolError(msg) => msg.ack(),
             Control::PeerGone(msg) => msg.ack(),
+            Control::Stats(msg) => msg.ack(),
+            Control::ActionTimeout(msg) => msg.ack(),
         }
     }
 }
@@ -175,6 +197,93 @@ impl Disconnect {
     }
 }
 
+/// PubRel message
+///
+/// Release of QoS 2 publish, PUBCOMP is sent after control service acknowledges
+/// the message. PUBCOMP reason code is `PacketIdNotFound` if server did not send
+/// PUBREC for the packet id, otherwise `Success`.
+#[derive(Debug)]
+pub struct PubRel {
+    packet: codec::PublishAck2,
+    result: codec::PublishAck2,
+    size: u32,
+}
+
+impl PubRel {
+    pub(super) fn new(packet: codec::PublishAck2, size: u32, found: bool) -> Self {
+        let result = codec::PublishAck2 {
+            packet_id: packet.packet_id,
+            reason_code: if found {
+                codec::PublishAck2Reason::Success
+            } else {
+                codec::PublishAck2Reason::PacketIdNotFound
+            },
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        };
+        Self { packet, result, size }
+    }
+
+    /// Returns reference to pubrel packet
+    pub fn packet(&self) -> &codec::PublishAck2 {
+        &self.packet
+    }
+
+    /// Returns size of the packet
+    pub fn packet_size(&self) -> u32 {
+        self.size
+    }
+
+    #[inline]
+    /// Packet id of released publish
+    pub fn packet_id(&self) -> std::num::NonZeroU16 {
+        self.packet.packet_id
+    }
+
+    #[inline]
+    /// Reason code of pubrel packet
+    pub fn reason_code(&self) -> codec::PublishAck2Reason {
+        self.packet.reason_code
+    }
+
+    #[inline]
+    /// Set reason code for ack packet
+    pub fn ack_reason_code(mut self, reason: codec::PublishAck2Reason) -> Self {
+        self.result.reason_code = reason;
+        self
+    }
+
+    #[inline]
+    /// Reason string for ack packet
+    ///
+    /// Not sent if client set `Request Problem Information` to `0` [MQTT-3.1.2-29]
+    pub fn ack_reason(mut self, reason: ByteString) -> Self {
+        self.result.reason_string = Some(reason);
+        self
+    }
+
+    #[inline]
+    /// Properties for ack packet
+    ///
+    /// Not sent if client set `Request Problem Information` to `0` [MQTT-3.1.2-29]
+    pub fn ack_properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::UserProperties),
+    {
+        f(&mut self.result.properties);
+        self
+    }
+
+    #[inline]
+    /// Ack PubRel packet
+    pub fn ack(self) -> ControlAck {
+        ControlAck {
+            packet: Some(codec::Packet::PublishComplete(self.result)),
+            disconnect: false,
+        }
+    }
+}
+
 /// Subscribe message
 #[derive(Debug)]
 pub struct Subscribe {
@@ -209,6 +318,8 @@ impl Subscribe {
 
     #[inline]
     /// Reason string for ack packet
+    ///
+    /// Not sent if client set `Request Problem Information` to `0` [MQTT-3.1.2-29]
     pub fn ack_reason(mut self, reason: ByteString) -> Self {
         self.result.reason_string = Some(reason);
         self
@@ -216,6 +327,8 @@ impl Subscribe {
 
     #[inline]
     /// Properties for ack packet
+    ///
+    /// Not sent if client set `Request Problem Information` to `0` [MQTT-3.1.2-29]
     pub fn ack_properties<F>(mut self, f: F) -> Self
     where
         F: FnOnce(&mut codec::UserProperties),
@@ -305,6 +418,45 @@ impl<'a> Subscription<'a> {
         self.options
     }
 
+    #[inline]
+    /// requested maximum qos for the subscription
+    pub fn qos(&self) -> QoS {
+        self.options.qos
+    }
+
+    #[inline]
+    /// application messages must not be forwarded to a connection with
+    /// a client id equal to the client id of the publishing connection [MQTT-3.8.3-3]
+    pub fn no_local(&self) -> bool {
+        self.options.no_local
+    }
+
+    #[inline]
+    /// application messages forwarded using this subscription keep the retain
+    /// flag they were published with
+    pub fn retain_as_published(&self) -> bool {
+        self.options.retain_as_published
+    }
+
+    #[inline]
+    /// whether retained messages are sent when the subscription is established
+    pub fn retain_handling(&self) -> codec::RetainHandling {
+        self.options.retain_handling
+    }
+
+    #[inline]
+    /// check if retained messages must be sent for this subscription,
+    /// `is_new` indicates that subscription did not exist before
+    pub fn send_retained(&self, is_new: bool) -> bool {
+        self.options.send_retained(is_new)
+    }
+
+    #[inline]
+    /// retain flag for live application message forwarded using this subscription
+    pub fn forward_retain(&self, retain: bool) -> bool {
+        self.options.forward_retain(retain)
+    }
+
     #[inline]
     /// fail to subscribe to the topic
     pub fn fail(&mut self, status: codec::SubscribeAckReason) {
@@ -330,6 +482,11 @@ impl<'a> Subscription<'a> {
 }
 
 /// Unsubscribe message
+///
+/// UNSUBACK is sent only after control service acknowledges the message, so
+/// subscription store must be updated before returning the ack. Deliveries that
+/// are already in flight for the removed filters complete normally, new
+/// deliveries must not be started once ack is returned [MQTT-3.10.4-2].
 #[derive(Debug)]
 pub struct Unsubscribe {
     packet: codec::Unsubscribe,
@@ -373,6 +530,8 @@ impl Unsubscribe {
 
     #[inline]
     /// Reason string for ack packet
+    ///
+    /// Not sent if client set `Request Problem Information` to `0` [MQTT-3.1.2-29]
     pub fn ack_reason(mut self, reason: ByteString) -> Self {
         self.result.reason_string = Some(reason);
         self
@@ -380,6 +539,8 @@ impl Unsubscribe {
 
     #[inline]
     /// Properties for ack packet
+    ///
+    /// Not sent if client set `Request Problem Information` to `0` [MQTT-3.1.2-29]
     pub fn ack_properties<F>(mut self, f: F) -> Self
     where
         F: FnOnce(&mut codec::UserProperties),
@@ -666,7 +827,7 @@ impl ProtocolError {
 }
 
 #[derive(Debug)]
-pub struct PeerGone(Option<io::Error>);
+pub struct PeerGone(Option<io::Error>, bool);
 
 impl PeerGone {
     /// Returns error reference
@@ -679,8 +840,105 @@ impl PeerGone {
         self.0.take()
     }
 
+    /// Connection is closed after DISCONNECT packet
+    ///
+    /// `false` means peer is gone without sending DISCONNECT packet
+    /// (read EOF or io error), in that case will message must be published.
+    pub fn is_clean(&self) -> bool {
+        self.1
+    }
+
     /// Ack PeerGone message
     pub fn ack(self) -> ControlAck {
         ControlAck { packet: None, disconnect: true }
     }
 }
+
+/// Connection statistics message
+///
+/// Delivered every `stats_interval`, counters cover the period
+/// since previous message.
+#[derive(Debug)]
+pub struct Stats {
+    pub(super) interval: Seconds,
+    pub(super) publishes: u64,
+    pub(super) bytes: u64,
+    pub(super) inflight: usize,
+}
+
+impl Stats {
+    #[inline]
+    /// Statistics period
+    pub fn interval(&self) -> Seconds {
+        self.interval
+    }
+
+    #[inline]
+    /// Number of received publish packets
+    pub fn publishes(&self) -> u64 {
+        self.publishes
+    }
+
+    #[inline]
+    /// Number of received bytes
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Received publish packets per second
+    pub fn publishes_per_sec(&self) -> f64 {
+        self.publishes as f64 / self.interval.seconds().max(1) as f64
+    }
+
+    /// Received bytes per second
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.interval.seconds().max(1) as f64
+    }
+
+    #[inline]
+    /// Number of in-flight inbound publishes
+    pub fn inflight(&self) -> usize {
+        self.inflight
+    }
+
+    #[inline]
+    /// Ack stats message, connection stays open
+    pub fn ack(self) -> ControlAck {
+        ControlAck { packet: None, disconnect: false }
+    }
+
+    #[inline]
+    /// Disconnect the client with `MessageRateTooHigh` reason code
+    pub fn disconnect(self) -> ControlAck {
+        let pkt = codec::Disconnect::new(DisconnectReasonCode::MessageRateTooHigh);
+        ControlAck { packet: Some(codec::Packet::Disconnect(pkt)), disconnect: true }
+    }
+}
+
+/// First action timeout message
+///
+/// Client did not send PUBLISH or SUBSCRIBE packet within configured
+/// timeout after connection got established.
+#[derive(Debug)]
+pub struct ActionTimeout(Seconds);
+
+impl ActionTimeout {
+    #[inline]
+    /// Configured first action timeout
+    pub fn timeout(&self) -> Seconds {
+        self.0
+    }
+
+    #[inline]
+    /// Disconnect the client with `AdministrativeAction` reason code
+    pub fn ack(self) -> ControlAck {
+        let pkt = codec::Disconnect::new(DisconnectReasonCode::AdministrativeAction);
+        ControlAck { packet: Some(codec::Packet::Disconnect(pkt)), disconnect: true }
+    }
+
+    #[inline]
+    /// Keep connection open
+    pub fn keep(self) -> ControlAck {
+        ControlAck { packet: None, disconnect: false }
+    }
+}
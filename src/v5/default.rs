@@ -74,6 +74,7 @@ impl<S, E: fmt::Debug> Service<Control<E>> for DefaultControlService<S, E> {
         match pkt {
             Control::Ping(pkt) => Ok(pkt.ack()),
             Control::Disconnect(pkt) => Ok(pkt.ack()),
+            Control::PubRel(pkt) => Ok(pkt.ack()),
             _ => {
                 log::warn!("MQTT5 Control service is not configured, pkt: {:?}", pkt);
                 Ok(pkt.disconnect_with(super::codec::Disconnect::new(
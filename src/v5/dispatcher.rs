@@ -1,26 +1,77 @@
-use std::{cell::RefCell, marker, num, rc::Rc};
+use std::{cell::Cell, cell::RefCell, marker, num, rc::Rc};
 
 use ntex_bytes::ByteString;
 use ntex_io::DispatchItem;
 use ntex_service::{self as service, Pipeline, Service, ServiceCtx, ServiceFactory};
+use ntex_util::future::{join, select, Either};
 use ntex_util::services::inflight::InFlightService;
 use ntex_util::services::{buffer::BufferService, buffer::BufferServiceError};
-use ntex_util::{future::join, HashMap, HashSet};
+use ntex_util::{time::sleep, time::Seconds, HashMap, HashSet};
 
 use crate::error::{HandshakeError, MqttError, ProtocolError};
-use crate::types::QoS;
+use crate::handle::SubscriptionsCount;
+use crate::topic::is_valid_topic_filter;
+use crate::types::{packet_type, ControlErrorPolicy, QoS, StatsCounter, SubscribePolicy};
+use crate::utils::InProgress;
 
-use super::control::{Control, ControlAck};
+use super::control::{Control, ControlAck, PubRel, Stats};
 use super::publish::{Publish, PublishAck};
-use super::shared::{Ack, MqttShared};
-use super::{codec, codec::DisconnectReasonCode, Session};
+use super::shared::{transform_payload, Ack, MqttShared, PayloadTransform};
+use super::{codec, codec::DisconnectReasonCode, Session, WillInfo};
+
+/// mqtt5 dispatcher settings
+#[derive(Clone)]
+pub(super) struct DispatcherConfig {
+    /// Max size of in-flight publishes
+    pub(super) max_inflight_size: usize,
+    /// Inbound queue depth
+    pub(super) inbound_depth: usize,
+    pub(super) handle_qos_after_disconnect: Option<QoS>,
+    pub(super) subscribe_policy: SubscribePolicy,
+    pub(super) immediate_ack: bool,
+    pub(super) control_pubrel: bool,
+    pub(super) strict_packet_ids: bool,
+    pub(super) deny_dollar_publish: bool,
+    pub(super) ordered_subscribe: bool,
+    pub(super) validate_payload_format: bool,
+    pub(super) stats_interval: Seconds,
+    pub(super) first_action_timeout: Seconds,
+    pub(super) control_error_policy: ControlErrorPolicy,
+    pub(super) payload_transform: Option<Rc<PayloadTransform>>,
+    pub(super) publish_filter: Option<Rc<dyn Fn(&Publish) -> bool>>,
+    pub(super) topic_size_limits: Option<Rc<dyn Fn(&str) -> Option<u32>>>,
+}
+
+impl Default for DispatcherConfig {
+    fn default() -> Self {
+        Self {
+            max_inflight_size: 65535,
+            inbound_depth: 0,
+            handle_qos_after_disconnect: None,
+            subscribe_policy: SubscribePolicy::default(),
+            immediate_ack: false,
+            control_pubrel: false,
+            strict_packet_ids: false,
+            deny_dollar_publish: false,
+            ordered_subscribe: false,
+            validate_payload_format: false,
+            stats_interval: Seconds::ZERO,
+            first_action_timeout: Seconds::ZERO,
+            control_error_policy: ControlErrorPolicy::Escalate,
+            payload_transform: None,
+            publish_filter: None,
+            topic_size_limits: None,
+        }
+    }
+}
 
 /// MQTT 5 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
-    max_inflight_size: usize,
-    handle_qos_after_disconnect: Option<QoS>,
+    config: DispatcherConfig,
+    on_connected: Option<Rc<dyn Fn(Session<St>)>>,
+    on_will: Option<Rc<dyn Fn(WillInfo<'_>, Session<St>)>>,
 ) -> impl ServiceFactory<
     DispatchItem<Rc<MqttShared>>,
     Session<St>,
@@ -36,19 +87,34 @@ where
     PublishAck: TryFrom<T::Error, Error = E>,
 {
     let factories = Rc::new((publish, control));
+    let inbound_depth = u16::try_from(config.inbound_depth).unwrap_or(u16::MAX);
 
     service::fn_factory_with_config(move |ses: Session<St>| {
         let factories = factories.clone();
+        let on_connected = on_connected.clone();
+        let on_will = on_will.clone();
+        let config = config.clone();
 
         async move {
             // create services
             let sink = ses.sink().shared();
             let (publish, control) =
-                join(factories.0.create(ses.clone()), factories.1.create(ses)).await;
+                join(factories.0.create(ses.clone()), factories.1.create(ses.clone())).await;
 
             let publish = publish.map_err(|e| MqttError::Service(e.into()))?;
             let control = control.map_err(|e| MqttError::Service(e.into()))?;
 
+            let on_will = on_will.map(|f| {
+                let ses = ses.clone();
+                Box::new(move |will: WillInfo<'_>| (*f)(will, ses.clone()))
+                    as Box<dyn Fn(WillInfo<'_>)>
+            });
+
+            // connection is established
+            if let Some(f) = on_connected {
+                (*f)(ses);
+            }
+
             let control = BufferService::new(
                 16,
                 // limit number of in-flight messages
@@ -61,10 +127,17 @@ where
                 }
             });
 
+            let (max_inflight_size, stats_interval, first_action_timeout) =
+                (config.max_inflight_size, config.stats_interval, config.first_action_timeout);
+            let dispatcher =
+                Dispatcher::<_, _, E>::new(sink, publish, control, config, on_will);
+            dispatcher.start_stats(stats_interval);
+            dispatcher.start_action_timeout(first_action_timeout);
+
             Ok(crate::inflight::InFlightService::new(
-                0,
+                inbound_depth,
                 max_inflight_size,
-                Dispatcher::<_, _, E>::new(sink, publish, control, handle_qos_after_disconnect),
+                dispatcher,
             ))
         }
     })
@@ -84,6 +157,17 @@ impl crate::inflight::SizedRequest for DispatchItem<Rc<MqttShared>> {
 pub(crate) struct Dispatcher<T, C: Service<Control<E>>, E> {
     publish: T,
     handle_qos_after_disconnect: Option<QoS>,
+    subscribe_policy: SubscribePolicy,
+    immediate_ack: bool,
+    control_pubrel: bool,
+    strict_packet_ids: bool,
+    deny_dollar_publish: bool,
+    ordered_subscribe: bool,
+    validate_payload_format: bool,
+    payload_transform: Option<Rc<PayloadTransform>>,
+    on_will: Option<Box<dyn Fn(WillInfo<'_>)>>,
+    publish_filter: Option<Rc<dyn Fn(&Publish) -> bool>>,
+    topic_size_limits: Option<Rc<dyn Fn(&str) -> Option<u32>>>,
     inner: Rc<Inner<C>>,
     _t: marker::PhantomData<E>,
 }
@@ -92,10 +176,32 @@ struct Inner<C> {
     control: C,
     sink: Rc<MqttShared>,
     info: RefCell<PublishInfo>,
+    subscriptions: RefCell<HashSet<ByteString>>,
+    subscriptions_count: Option<SubscriptionsCount>,
+    subscribing: InProgress,
+    stats: StatsCounter,
+    disconnect_received: Cell<bool>,
+    first_action: Cell<bool>,
+    control_error_policy: ControlErrorPolicy,
+}
+
+impl<C> Inner<C> {
+    /// Subscriptions are tracked for limit check and for server handle
+    fn track_subscriptions(&self, policy: &SubscribePolicy) -> bool {
+        policy.max_subscriptions != 0 || self.subscriptions_count.is_some()
+    }
+
+    fn update_subscriptions_count(&self) {
+        if let Some(ref count) = self.subscriptions_count {
+            count.set(self.subscriptions.borrow().len());
+        }
+    }
 }
 
 struct PublishInfo {
     inflight: HashSet<num::NonZeroU16>,
+    /// QoS 2 publishes that are acked with PUBREC and wait for PUBREL
+    received: HashSet<num::NonZeroU16>,
     aliases: HashMap<num::NonZeroU16, ByteString>,
 }
 
@@ -110,24 +216,122 @@ where
         sink: Rc<MqttShared>,
         publish: T,
         control: C,
-        handle_qos_after_disconnect: Option<QoS>,
+        config: DispatcherConfig,
+        on_will: Option<Box<dyn Fn(WillInfo<'_>)>>,
     ) -> Self {
+        let subscriptions_count = sink.take_subscriptions_count();
         Self {
             publish,
-            handle_qos_after_disconnect,
+            handle_qos_after_disconnect: config.handle_qos_after_disconnect,
+            subscribe_policy: config.subscribe_policy,
+            immediate_ack: config.immediate_ack,
+            control_pubrel: config.control_pubrel,
+            strict_packet_ids: config.strict_packet_ids,
+            deny_dollar_publish: config.deny_dollar_publish,
+            ordered_subscribe: config.ordered_subscribe,
+            validate_payload_format: config.validate_payload_format,
+            payload_transform: config.payload_transform,
+            on_will,
+            publish_filter: config.publish_filter,
+            topic_size_limits: config.topic_size_limits,
             inner: Rc::new(Inner {
                 sink,
                 control,
                 info: RefCell::new(PublishInfo {
                     aliases: HashMap::default(),
                     inflight: HashSet::default(),
+                    received: HashSet::default(),
                 }),
+                subscriptions: RefCell::new(HashSet::default()),
+                subscriptions_count,
+                subscribing: InProgress::default(),
+                stats: StatsCounter::default(),
+                disconnect_received: Cell::new(false),
+                first_action: Cell::new(false),
+                control_error_policy: config.control_error_policy,
             }),
             _t: marker::PhantomData,
         }
     }
 }
 
+impl<T, C, E> Dispatcher<T, C, E>
+where
+    C: Service<Control<E>, Response = ControlAck, Error = MqttError<E>> + 'static,
+    E: 'static,
+{
+    /// Deliver connection stats to control service every `interval`
+    fn start_stats(&self, interval: Seconds) {
+        if interval.is_zero() {
+            return;
+        }
+        let inner = self.inner.clone();
+
+        ntex_util::spawn(async move {
+            loop {
+                let on_disconnect = inner.sink.on_disconnect();
+                if let Either::Right(_) = select(sleep(interval), on_disconnect).await {
+                    break;
+                }
+                if inner.sink.is_closed() {
+                    break;
+                }
+
+                let (publishes, bytes) = inner.stats.take();
+                let stats = Stats {
+                    interval,
+                    publishes,
+                    bytes,
+                    inflight: inner.info.borrow().inflight.len(),
+                };
+                match Pipeline::new(&inner.control).call(Control::stats(stats)).await {
+                    Ok(ack) => {
+                        if let Some(pkt) = ack.packet {
+                            let _ = inner.sink.encode_packet(pkt);
+                        }
+                        if ack.disconnect {
+                            inner.sink.drop_sink();
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+
+    /// Notify control service if client does not publish or subscribe
+    /// within `timeout` after connection is established
+    fn start_action_timeout(&self, timeout: Seconds) {
+        if timeout.is_zero() {
+            return;
+        }
+        let inner = self.inner.clone();
+
+        ntex_util::spawn(async move {
+            let on_disconnect = inner.sink.on_disconnect();
+            if let Either::Right(_) = select(sleep(timeout), on_disconnect).await {
+                return;
+            }
+            if inner.sink.is_closed() || inner.first_action.get() {
+                return;
+            }
+
+            log::trace!("Client did not publish or subscribe within {:?}", timeout);
+            if let Ok(ack) =
+                Pipeline::new(&inner.control).call(Control::action_timeout(timeout)).await
+            {
+                if let Some(pkt) = ack.packet {
+                    let _ = inner.sink.encode_packet(pkt);
+                }
+                if ack.disconnect {
+                    inner.sink.drop_sink();
+                }
+            }
+        });
+    }
+}
+
 impl<T, C, E> Service<DispatchItem<Rc<MqttShared>>> for Dispatcher<T, C, E>
 where
     E: From<T::Error>,
@@ -147,25 +351,77 @@ where
 
     async fn shutdown(&self) {
         self.inner.sink.drop_sink();
+        // connection is closed without DISCONNECT, publish will message
+        if let Some(ref f) = self.on_will {
+            if let Some(will) = self.inner.sink.take_will() {
+                f(WillInfo(&will));
+            }
+        }
         let _ = Pipeline::new(&self.inner.control).call(Control::closed()).await;
 
         self.publish.shutdown().await;
         self.inner.control.shutdown().await;
     }
 
-    #[allow(clippy::await_holding_refcell_ref)]
     async fn call(
         &self,
         request: DispatchItem<Rc<MqttShared>>,
         ctx: ServiceCtx<'_, Self>,
     ) -> Result<Self::Response, Self::Error> {
+        let result = self.dispatch(request, ctx).await;
+
+        // DISCONNECT must be the last packet sent by the server [MQTT-3.14.4-1]
+        match result {
+            Ok(Some(pkt)) if self.inner.sink.is_closed() => {
+                log::trace!("Connection is closed, drop response {:?}", pkt.packet_type());
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+}
+
+impl<T, C, E> Dispatcher<T, C, E>
+where
+    E: From<T::Error>,
+    T: Service<Publish, Response = PublishAck>,
+    PublishAck: TryFrom<T::Error, Error = E>,
+    C: Service<Control<E>, Response = ControlAck, Error = MqttError<E>> + 'static,
+{
+    #[allow(clippy::await_holding_refcell_ref)]
+    async fn dispatch(
+        &self,
+        request: DispatchItem<Rc<MqttShared>>,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Option<codec::Packet>, MqttError<E>> {
         log::trace!("Dispatch v5 packet: {:#?}", request);
 
+        if let DispatchItem::Item((_, size)) = &request {
+            self.inner.stats.packet(*size);
+        }
+
         match request {
             DispatchItem::Item((codec::Packet::Publish(mut publish), size)) => {
+                self.inner.stats.publish();
+                self.inner.first_action.set(true);
+
                 let info = self.inner.as_ref();
                 let packet_id = publish.packet_id;
 
+                // topic must be resolved with topic alias
+                if publish.topic.is_empty() && publish.properties.topic_alias.is_none() {
+                    return control(
+                        Control::proto_error(ProtocolError::violation(
+                            DisconnectReasonCode::TopicNameInvalid,
+                            "PUBLISH packet's topic name is empty and topic alias is not set",
+                        )),
+                        &self.inner,
+                        ctx,
+                        0,
+                    )
+                    .await;
+                }
+
                 if publish.topic.contains(['#', '+']) {
                     return control(
                         Control::proto_error(
@@ -179,6 +435,25 @@ where
                     ).await;
                 }
 
+                // clients are not allowed to publish to reserved topics
+                if self.deny_dollar_publish && publish.topic.starts_with('$') {
+                    log::trace!("Reject publish to reserved topic: {:?}", publish.topic);
+                    if let Some(pid) = packet_id {
+                        let ack = codec::PublishAck {
+                            packet_id: pid,
+                            reason_code: codec::PublishAckReason::TopicNameInvalid,
+                            ..Default::default()
+                        };
+                        let _ =
+                            self.inner.sink.encode_packet(if publish.qos == QoS::ExactlyOnce {
+                                codec::Packet::PublishReceived(ack)
+                            } else {
+                                codec::Packet::PublishAck(ack)
+                            });
+                    }
+                    return Ok(None);
+                }
+
                 {
                     let mut inner = info.info.borrow_mut();
                     let state = &self.inner.sink;
@@ -240,8 +515,34 @@ where
                             .await;
                         }
 
+                        // QoS 2 publish is already received, PUBREL is expected [MQTT-4.3.3-10]
+                        if publish.qos == QoS::ExactlyOnce && inner.received.contains(&pid) {
+                            log::trace!("Drop publish that is waiting for release: {:?}", pid);
+                            return Ok(Some(codec::Packet::PublishReceived(
+                                codec::PublishAck { packet_id: pid, ..Default::default() },
+                            )));
+                        }
+
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
+                            // re-delivery of in-flight publish, original packet gets acked
+                            if publish.dup {
+                                log::trace!("Drop re-delivered publish packet: {:?}", pid);
+                                return Ok(None);
+                            }
+                            if self.strict_packet_ids {
+                                drop(inner);
+                                return control(
+                                    Control::proto_error(ProtocolError::violation(
+                                        DisconnectReasonCode::ProtocolError,
+                                        "PUBLISH received with packet id that is already in use [MQTT-2.2.1-3]",
+                                    )),
+                                    &self.inner,
+                                    ctx,
+                                    0,
+                                )
+                                .await;
+                            }
                             let _ = self.inner.sink.encode_packet(codec::Packet::PublishAck(
                                 codec::PublishAck {
                                     packet_id: pid,
@@ -255,6 +556,21 @@ where
 
                     // handle topic aliases
                     if let Some(alias) = publish.properties.topic_alias {
+                        // alias table is bounded by advertised topic alias maximum
+                        if alias.get() > state.topic_alias_max() {
+                            drop(inner);
+                            return control(
+                                Control::proto_error(ProtocolError::violation(
+                                    DisconnectReasonCode::TopicAliasInvalid,
+                                    "Topic alias is greater than max allowed [MQTT-3.3.2-9]",
+                                )),
+                                &self.inner,
+                                ctx,
+                                0,
+                            )
+                            .await;
+                        }
+
                         if publish.topic.is_empty() {
                             // lookup topic by provided alias
                             match inner.aliases.get(&alias) {
@@ -284,19 +600,6 @@ where
                                     }
                                 }
                                 std::collections::hash_map::Entry::Vacant(entry) => {
-                                    if alias.get() > state.topic_alias_max() {
-                                        drop(inner);
-                                        return control(
-                                                Control::proto_error(
-                                                    ProtocolError::generic_violation(
-                                                        "Topic alias is greater than max allowed [MQTT-3.2.2-17]",
-                                                    )
-                                                ),
-                                                &self.inner,
-                                            ctx,
-                                            0,
-                                            ).await;
-                                    }
                                     let mut topic = publish.topic.clone();
                                     topic.trimdown();
                                     entry.insert(topic);
@@ -315,22 +618,127 @@ where
                     }
                 }
 
-                publish_fn(
+                // check per-topic payload size limit
+                if let Some(ref f) = self.topic_size_limits {
+                    if let Some(limit) = (*f)(&publish.topic) {
+                        if publish.payload.len() > limit as usize {
+                            log::trace!(
+                                "Publish payload size limit exceeded for {:?}, limit {}, size {}",
+                                publish.topic,
+                                limit,
+                                publish.payload.len()
+                            );
+                            return control(
+                                Control::proto_error(ProtocolError::violation(
+                                    DisconnectReasonCode::PacketTooLarge,
+                                    "PUBLISH payload exceeds topic size limit",
+                                )),
+                                &self.inner,
+                                ctx,
+                                0,
+                            )
+                            .await;
+                        }
+                    }
+                }
+
+                // check utf-8 payload
+                if self.validate_payload_format
+                    && publish.properties.is_utf8_payload
+                    && std::str::from_utf8(&publish.payload).is_err()
+                {
+                    log::trace!("Publish payload is not valid utf-8: {:?}", publish.topic);
+                    return control(
+                        Control::proto_error(ProtocolError::violation(
+                            DisconnectReasonCode::PayloadFormatInvalid,
+                            "PUBLISH payload is not valid UTF-8 but Payload Format Indicator is set",
+                        )),
+                        &self.inner,
+                        ctx,
+                        0,
+                    )
+                    .await;
+                }
+
+                if let Some(ref f) = self.payload_transform {
+                    transform_payload(&**f, &mut publish);
+                }
+
+                #[cfg(feature = "tracing")]
+                let span = tracing::debug_span!(
+                    "mqtt.publish",
+                    packet_id = packet_id.map(|id| id.get()),
+                    topic = %publish.topic,
+                );
+
+                // wait for in-progress subscribe and unsubscribe handling
+                if self.ordered_subscribe {
+                    self.inner.subscribing.wait().await;
+                }
+
+                let publish = Publish::new(publish, size);
+                if let Some(ref f) = self.publish_filter {
+                    if !(*f)(&publish) {
+                        log::trace!("Publish to {:?} is skipped", publish.publish_topic());
+                        return Ok(packet_id.map(|packet_id| {
+                            let mut info = info.info.borrow_mut();
+                            info.inflight.remove(&packet_id);
+                            let ack = codec::PublishAck { packet_id, ..Default::default() };
+                            if publish.qos() == QoS::ExactlyOnce {
+                                info.received.insert(packet_id);
+                                codec::Packet::PublishReceived(ack)
+                            } else {
+                                codec::Packet::PublishAck(ack)
+                            }
+                        }));
+                    }
+                }
+
+                let qos = publish.qos();
+                let fut = publish_fn(
                     &self.publish,
-                    Publish::new(publish, size),
+                    publish,
                     packet_id.map(|v| v.get()).unwrap_or(0),
+                    qos,
+                    self.immediate_ack,
                     info,
                     ctx,
-                )
-                .await
+                );
+                #[cfg(feature = "tracing")]
+                let fut = tracing::Instrument::instrument(fut, span);
+                fut.await
             }
             DispatchItem::Item((codec::Packet::PublishAck(packet), _)) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(packet_id = packet.packet_id.get(), "PUBACK received");
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish(packet)) {
                     control(Control::proto_error(err), &self.inner, ctx, 0).await
                 } else {
                     Ok(None)
                 }
             }
+            DispatchItem::Item((codec::Packet::PublishReceived(packet), _)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Receive(packet)) {
+                    control(Control::proto_error(err), &self.inner, ctx, 0).await
+                } else {
+                    Ok(None)
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishComplete(packet), _)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Complete(packet)) {
+                    control(Control::proto_error(err), &self.inner, ctx, 0).await
+                } else {
+                    Ok(None)
+                }
+            }
+            DispatchItem::Item((codec::Packet::PublishRelease(pkt), size)) => {
+                let found = self.inner.info.borrow_mut().received.remove(&pkt.packet_id);
+                if self.control_pubrel {
+                    control(Control::pubrel(pkt, size, found), &self.inner, ctx, 0).await
+                } else {
+                    Ok(PubRel::new(pkt, size, found).ack().packet)
+                }
+            }
             DispatchItem::Item((codec::Packet::Auth(pkt), size)) => {
                 if self.inner.sink.is_closed() {
                     return Ok(None);
@@ -342,14 +750,57 @@ where
                 control(Control::ping(), &self.inner, ctx, 0).await
             }
             DispatchItem::Item((codec::Packet::Disconnect(pkt), size)) => {
+                // client may update session expiry interval
+                if let Some(val) = pkt.session_expiry_interval_secs {
+                    if self.inner.sink.session_expiry() == 0 && val != 0 {
+                        return control(
+                            Control::proto_error(ProtocolError::violation(
+                                DisconnectReasonCode::ProtocolError,
+                                "DISCONNECT sets Session Expiry Interval while it was zero in CONNECT [MQTT-3.14.2-2]",
+                            )),
+                            &self.inner,
+                            ctx,
+                            0,
+                        )
+                        .await;
+                    }
+                    self.inner.sink.set_session_expiry(val);
+                }
+                self.inner.disconnect_received.set(true);
+                // will message is removed only on normal disconnect
+                if pkt.reason_code == DisconnectReasonCode::NormalDisconnection {
+                    self.inner.sink.take_will();
+                }
                 control(Control::remote_disconnect(pkt, size), &self.inner, ctx, 0).await
             }
-            DispatchItem::Item((codec::Packet::Subscribe(pkt), size)) => {
+            DispatchItem::Item((codec::Packet::Subscribe(mut pkt), size)) => {
+                // publishes wait for subscription side effects
+                let _guard = self.inner.subscribing.enter();
+                self.inner.first_action.set(true);
                 if self.inner.sink.is_closed() {
                     return Ok(None);
                 }
 
-                if pkt.topic_filters.iter().any(|(tf, _)| !crate::topic::is_valid(tf)) {
+                let max_filters = self.subscribe_policy.max_filters;
+                if max_filters != 0 && pkt.topic_filters.len() > max_filters {
+                    log::trace!(
+                        "Max topic filters per subscribe is exceeded: max {} provided {}",
+                        max_filters,
+                        pkt.topic_filters.len()
+                    );
+                    return control(
+                        Control::proto_error(ProtocolError::violation(
+                            DisconnectReasonCode::QuotaExceeded,
+                            "Number of topic filters in SUBSCRIBE exceeds set maximum",
+                        )),
+                        &self.inner,
+                        ctx,
+                        0,
+                    )
+                    .await;
+                }
+
+                if pkt.topic_filters.iter().any(|(tf, _)| !is_valid_topic_filter(tf)) {
                     return control(
                         Control::proto_error(ProtocolError::generic_violation(
                             "Topic filter is malformed [MQTT-4.7.1-*]",
@@ -393,14 +844,118 @@ where
                     return Ok(None);
                 }
                 let id = pkt.packet_id;
-                control(Control::subscribe(pkt, size), &self.inner, ctx, id.get()).await
+                let count = pkt.topic_filters.len();
+                let policy = self.subscribe_policy;
+                let mut result = if policy.max_subscriptions == 0
+                    && !policy.deny_wildcards
+                    && self.inner.subscriptions_count.is_none()
+                {
+                    control(Control::subscribe(pkt, size), &self.inner, ctx, id.get()).await?
+                } else {
+                    // check wildcards and subscriptions limit,
+                    // already existing subscriptions do not count
+                    let mut rejected = Vec::new();
+                    {
+                        let subs = self.inner.subscriptions.borrow();
+                        let mut count = subs.len();
+                        let mut idx = 0;
+                        pkt.topic_filters.retain(|(tf, _)| {
+                            idx += 1;
+                            if policy.deny_wildcards && crate::topic::has_wildcards(tf) {
+                                log::trace!("Wildcard subscriptions are denied, reject {:?}", tf);
+                                rejected.push((
+                                    idx - 1,
+                                    codec::SubscribeAckReason::WildcardSubscriptionsNotSupported,
+                                ));
+                                false
+                            } else if policy.max_subscriptions == 0 || subs.contains(tf) {
+                                true
+                            } else if count < policy.max_subscriptions {
+                                count += 1;
+                                true
+                            } else {
+                                log::trace!("Subscriptions limit is reached, reject {:?}", tf);
+                                rejected.push((idx - 1, codec::SubscribeAckReason::QuotaExceeded));
+                                false
+                            }
+                        });
+                    }
+
+                    if pkt.topic_filters.is_empty() {
+                        self.inner.info.borrow_mut().inflight.remove(&id);
+                        return Ok(Some(codec::Packet::SubscribeAck(codec::SubscribeAck {
+                            packet_id: id,
+                            status: rejected.into_iter().map(|(_, code)| code).collect(),
+                            properties: codec::UserProperties::new(),
+                            reason_string: None,
+                        })));
+                    }
+
+                    let filters: Vec<_> =
+                        pkt.topic_filters.iter().map(|(tf, _)| tf.clone()).collect();
+                    let allowed_count = filters.len();
+                    let mut result =
+                        control(Control::subscribe(pkt, size), &self.inner, ctx, id.get())
+                            .await?;
+
+                    if let Some(codec::Packet::SubscribeAck(ref mut ack)) = result {
+                        ack.status
+                            .resize(allowed_count, codec::SubscribeAckReason::UnspecifiedError);
+                        if self.inner.track_subscriptions(&policy) {
+                            let mut subs = self.inner.subscriptions.borrow_mut();
+                            for (tf, code) in filters.into_iter().zip(ack.status.iter()) {
+                                if matches!(
+                                    code,
+                                    codec::SubscribeAckReason::GrantedQos0
+                                        | codec::SubscribeAckReason::GrantedQos1
+                                        | codec::SubscribeAckReason::GrantedQos2
+                                ) {
+                                    subs.insert(tf);
+                                }
+                            }
+                        }
+                        self.inner.update_subscriptions_count();
+                        for (idx, code) in rejected {
+                            ack.status.insert(idx, code);
+                        }
+                    }
+                    result
+                };
+
+                // cap granted qos by server max qos and subscribe policy,
+                // applied after control service
+                if let Some(codec::Packet::SubscribeAck(ref mut ack)) = result {
+                    // SUBACK must contain reason code for each topic filter [MQTT-3.8.4-6]
+                    if ack.status.len() != count {
+                        log::error!(
+                            "SUBACK contains {} reason codes for {} topic filters",
+                            ack.status.len(),
+                            count
+                        );
+                        ack.status.resize(count, codec::SubscribeAckReason::UnspecifiedError);
+                    }
+
+                    let max = match policy.max_granted_qos.min(self.inner.sink.max_qos()) {
+                        QoS::AtMostOnce => codec::SubscribeAckReason::GrantedQos0,
+                        QoS::AtLeastOnce => codec::SubscribeAckReason::GrantedQos1,
+                        QoS::ExactlyOnce => codec::SubscribeAckReason::GrantedQos2,
+                    };
+                    for code in ack.status.iter_mut() {
+                        if u8::from(*code) <= 2 && u8::from(*code) > u8::from(max) {
+                            *code = max;
+                        }
+                    }
+                }
+                Ok(result)
             }
             DispatchItem::Item((codec::Packet::Unsubscribe(pkt), size)) => {
+                // publishes wait for subscription side effects
+                let _guard = self.inner.subscribing.enter();
                 if self.inner.sink.is_closed() {
                     return Ok(None);
                 }
 
-                if pkt.topic_filters.iter().any(|tf| !crate::topic::is_valid(tf)) {
+                if pkt.topic_filters.iter().any(|tf| !is_valid_topic_filter(tf)) {
                     return control(
                         Control::proto_error(ProtocolError::generic_violation(
                             "Topic filter is malformed [MQTT-4.7.1-*]",
@@ -429,9 +984,31 @@ where
                     ));
                     return Ok(None);
                 }
+                if self.inner.track_subscriptions(&self.subscribe_policy) {
+                    let mut subs = self.inner.subscriptions.borrow_mut();
+                    pkt.topic_filters.iter().for_each(|tf| {
+                        subs.remove(tf);
+                    });
+                    drop(subs);
+                    self.inner.update_subscriptions_count();
+                }
+
                 let id = pkt.packet_id;
                 control(Control::unsubscribe(pkt, size), &self.inner, ctx, id.get()).await
             }
+            DispatchItem::Item((codec::Packet::Connect(_), _)) => {
+                log::trace!("Unexpected CONNECT packet on established connection");
+                control(
+                    Control::proto_error(ProtocolError::unexpected_packet(
+                        packet_type::CONNECT,
+                        "CONNECT packet can be sent only once [MQTT-3.1.0-2]",
+                    )),
+                    &self.inner,
+                    ctx,
+                    0,
+                )
+                .await
+            }
             DispatchItem::Item((_, _)) => Ok(None),
             DispatchItem::EncoderError(err) => {
                 control(Control::proto_error(ProtocolError::Encode(err)), &self.inner, ctx, 0)
@@ -455,7 +1032,8 @@ where
                     .await
             }
             DispatchItem::Disconnect(err) => {
-                control(Control::peer_gone(err), &self.inner, ctx, 0).await
+                let clean = self.inner.disconnect_received.get();
+                control(Control::peer_gone(err, clean), &self.inner, ctx, 0).await
             }
             DispatchItem::WBackPressureEnabled => {
                 self.inner.sink.enable_wr_backpressure();
@@ -474,6 +1052,8 @@ async fn publish_fn<'f, T, C, E>(
     publish: &T,
     pkt: Publish,
     packet_id: u16,
+    qos: QoS,
+    immediate_ack: bool,
     inner: &'f Inner<C>,
     ctx: ServiceCtx<'f, Dispatcher<T, C, E>>,
 ) -> Result<Option<codec::Packet>, MqttError<E>>
@@ -497,14 +1077,34 @@ where
         }
     };
     if let Some(id) = num::NonZeroU16::new(packet_id) {
-        inner.info.borrow_mut().inflight.remove(&id);
+        let mut info = inner.info.borrow_mut();
+        info.inflight.remove(&id);
         let ack = codec::PublishAck {
             packet_id: id,
             reason_code: ack.reason_code,
             reason_string: ack.reason_string,
             properties: ack.properties,
         };
-        Ok(Some(codec::Packet::PublishAck(ack)))
+        let pkt = if qos == QoS::ExactlyOnce {
+            // failed publish does not continue with PUBREL
+            if u8::from(ack.reason_code) < 0x80 {
+                info.received.insert(id);
+            }
+            codec::Packet::PublishReceived(ack)
+        } else {
+            codec::Packet::PublishAck(ack)
+        };
+        drop(info);
+
+        if immediate_ack {
+            // do not wait for responses of previous packets
+            if let Err(err) = inner.sink.encode_packet(pkt) {
+                log::error!("Cannot encode publish ack {:?}: {:?}", packet_id, err);
+            }
+            Ok(None)
+        } else {
+            Ok(Some(pkt))
+        }
     } else {
         Ok(None)
     }
@@ -536,10 +1136,23 @@ where
             } else {
                 // handle error from control service
                 match err {
-                    MqttError::Service(err) => {
-                        error = true;
-                        ctx.call(&inner.control, Control::error(err)).await?
-                    }
+                    MqttError::Service(err) => match inner.control_error_policy {
+                        ControlErrorPolicy::Escalate => {
+                            error = true;
+                            ctx.call(&inner.control, Control::error(err)).await?
+                        }
+                        ControlErrorPolicy::Close => {
+                            inner.sink.drop_sink();
+                            return Err(MqttError::Service(err));
+                        }
+                        ControlErrorPolicy::Ignore => {
+                            log::error!("Control service error is ignored");
+                            if let Some(id) = num::NonZeroU16::new(packet_id) {
+                                inner.info.borrow_mut().inflight.remove(&id);
+                            }
+                            return Ok(None);
+                        }
+                    },
                     _ => return Err(err),
                 }
             }
@@ -596,6 +1209,7 @@ mod tests {
                     disconnect: false,
                 })
             }),
+            DispatcherConfig::default(),
             None,
         ));
 
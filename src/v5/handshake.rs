@@ -1,11 +1,14 @@
+use ntex_bytes::{ByteString, Bytes};
 use ntex_io::IoBoxed;
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use ntex_service::{Service, ServiceCtx, ServiceFactory};
+use std::{cell::Cell, fmt, future::Future, num::NonZeroU16, num::NonZeroU32, rc::Rc};
 
 use super::{codec, shared::MqttShared, sink::MqttSink};
+use crate::{service::HandshakeIo, version::ProtocolVersion};
 
 /// Handshake message
 pub struct Handshake {
-    io: IoBoxed,
+    io: HandshakeIo,
     pkt: Box<codec::Connect>,
     size: u32,
     pub(super) shared: Rc<MqttShared>,
@@ -18,7 +21,7 @@ impl Handshake {
         io: IoBoxed,
         shared: Rc<MqttShared>,
     ) -> Self {
-        Self { io, pkt, size, shared }
+        Self { io: HandshakeIo::new(io), pkt, size, shared }
     }
 
     #[inline]
@@ -31,14 +34,32 @@ impl Handshake {
         &mut self.pkt
     }
 
+    #[inline]
+    /// Protocol version of the connection
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        ProtocolVersion::MQTT5
+    }
+
     #[inline]
     pub fn packet_size(&self) -> u32 {
         self.size
     }
 
+    #[inline]
+    /// Returns connect packet properties
+    pub fn properties(&self) -> ConnectProperties<'_> {
+        ConnectProperties(&self.pkt)
+    }
+
+    #[inline]
+    /// Returns last will message, if will flag is set in connect packet
+    pub fn will(&self) -> Option<WillInfo<'_>> {
+        self.pkt.last_will.as_ref().map(WillInfo)
+    }
+
     #[inline]
     pub fn io(&self) -> &IoBoxed {
-        &self.io
+        self.io.get()
     }
 
     #[inline]
@@ -47,6 +68,22 @@ impl Handshake {
         MqttSink::new(self.shared.clone())
     }
 
+    /// Upgrade handshake io object, for example to negotiate TLS after CONNECT.
+    ///
+    /// Upgrade must happen before handshake is acknowledged. Returned io must be
+    /// derived from the provided one (i.e. with `Io::add_filter()`), so mqtt sink
+    /// keeps writing into the same connection. Bytes that are already read from
+    /// the peer stay in io read buffer and are processed by the new filter.
+    pub async fn upgrade_io<F, Fut, E>(mut self, f: F) -> Result<Self, E>
+    where
+        F: FnOnce(IoBoxed) -> Fut,
+        Fut: Future<Output = Result<IoBoxed, E>>,
+    {
+        let io = self.io.take_io();
+        self.io.set_io(f(io).await?);
+        Ok(self)
+    }
+
     #[inline]
     /// Ack handshake message and set state
     pub fn ack<St>(self, st: St) -> HandshakeAck<St> {
@@ -61,7 +98,10 @@ impl Handshake {
             ..codec::ConnectAck::default()
         };
 
-        let Handshake { io, shared, pkt, .. } = self;
+        let Handshake { io, shared, mut pkt, .. } = self;
+        shared.set_will(pkt.last_will.take());
+        shared.set_session_expiry(pkt.session_expiry_interval_secs);
+        let io = io.take();
         // [MQTT-3.1.2-22]
         let keepalive = if pkt.keep_alive != 0 {
             (pkt.keep_alive >> 1).checked_add(pkt.keep_alive).unwrap_or(u16::MAX)
@@ -75,7 +115,7 @@ impl Handshake {
     /// Create handshake ack object with error
     pub fn failed<St>(self, reason_code: codec::ConnectAckReason) -> HandshakeAck<St> {
         HandshakeAck {
-            io: self.io,
+            io: self.io.take(),
             shared: self.shared,
             session: None,
             keepalive: 30,
@@ -83,11 +123,35 @@ impl Handshake {
         }
     }
 
+    /// Create handshake ack object that redirects client to another server
+    ///
+    /// `reason_code` must be `UseAnotherServer` or `ServerMoved`, `server_reference`
+    /// is sent as `Server Reference` property of `ConnectAck` packet.
+    pub fn redirect<St>(
+        self,
+        reason_code: codec::ConnectAckReason,
+        server_reference: ByteString,
+    ) -> HandshakeAck<St> {
+        assert!(
+            matches!(
+                reason_code,
+                codec::ConnectAckReason::UseAnotherServer
+                    | codec::ConnectAckReason::ServerMoved
+            ),
+            "Redirect reason must be UseAnotherServer or ServerMoved"
+        );
+        self.fail_with(codec::ConnectAck {
+            reason_code,
+            server_reference: Some(server_reference),
+            ..codec::ConnectAck::default()
+        })
+    }
+
     #[inline]
     /// Create handshake ack object with provided ConnectAck packet
     pub fn fail_with<St>(self, ack: codec::ConnectAck) -> HandshakeAck<St> {
         HandshakeAck {
-            io: self.io,
+            io: self.io.take(),
             shared: self.shared,
             session: None,
             packet: ack,
@@ -102,6 +166,258 @@ impl fmt::Debug for Handshake {
     }
 }
 
+/// Handshake service factory that maps handshake errors to CONNACK packet.
+///
+/// Created by `MqttServer::map_handshake_error()`.
+pub struct MapHandshakeError<H, F> {
+    factory: H,
+    f: Rc<F>,
+}
+
+impl<H, F> MapHandshakeError<H, F> {
+    pub(super) fn new(factory: H, f: F) -> Self {
+        Self { factory, f: Rc::new(f) }
+    }
+}
+
+impl<H, F> ServiceFactory<Handshake> for MapHandshakeError<H, F>
+where
+    H: ServiceFactory<Handshake>,
+    F: Fn(&H::Error) -> (codec::ConnectAckReason, Option<ByteString>),
+{
+    type Response = H::Response;
+    type Error = H::Error;
+    type Service = MapHandshakeErrorService<H::Service, F>;
+    type InitError = H::InitError;
+
+    async fn create(&self, cfg: ()) -> Result<Self::Service, Self::InitError> {
+        let service = self.factory.create(cfg).await?;
+        Ok(MapHandshakeErrorService { service, f: self.f.clone() })
+    }
+}
+
+/// Handshake service that maps handshake errors to CONNACK packet
+pub struct MapHandshakeErrorService<S, F> {
+    service: S,
+    f: Rc<F>,
+}
+
+impl<S, F> Service<Handshake> for MapHandshakeErrorService<S, F>
+where
+    S: Service<Handshake>,
+    F: Fn(&S::Error) -> (codec::ConnectAckReason, Option<ByteString>),
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    ntex_service::forward_ready!(service);
+    ntex_service::forward_shutdown!(service);
+
+    async fn call(
+        &self,
+        mut req: Handshake,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        // keep io object if handshake service drops handshake message
+        let slot = Rc::new(Cell::new(None));
+        let shared = req.shared.clone();
+        req.io.set_slot(slot.clone());
+
+        match ctx.call(&self.service, req).await {
+            Ok(ack) => Ok(ack),
+            Err(err) => {
+                if let Some(io) = slot.take() {
+                    let (reason_code, reason_string) = (self.f)(&err);
+                    let pkt = codec::ConnectAck {
+                        reason_code,
+                        reason_string,
+                        ..codec::ConnectAck::default()
+                    };
+                    log::trace!("Sending failed handshake ack: {:#?}", pkt);
+                    if io
+                        .encode(codec::Packet::ConnectAck(Box::new(pkt)), &shared.codec)
+                        .is_ok()
+                    {
+                        let _ = io.shutdown().await;
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Connect packet properties
+#[derive(Copy, Clone)]
+pub struct ConnectProperties<'a>(&'a codec::Connect);
+
+impl<'a> ConnectProperties<'a> {
+    #[inline]
+    /// Session expiry interval in seconds
+    pub fn session_expiry_interval(&self) -> u32 {
+        self.0.session_expiry_interval_secs
+    }
+
+    #[inline]
+    /// Max number of QoS 1 and QoS 2 publications client is willing to process concurrently
+    pub fn receive_max(&self) -> Option<NonZeroU16> {
+        self.0.receive_max
+    }
+
+    #[inline]
+    /// Max packet size client is willing to accept
+    pub fn max_packet_size(&self) -> Option<NonZeroU32> {
+        self.0.max_packet_size
+    }
+
+    #[inline]
+    /// Highest value client accepts as a topic alias sent by the server
+    pub fn topic_alias_max(&self) -> u16 {
+        self.0.topic_alias_max
+    }
+
+    #[inline]
+    /// Client requests response information in `ConnectAck` packet
+    pub fn request_response_info(&self) -> bool {
+        self.0.request_response_info
+    }
+
+    #[inline]
+    /// Client requests reason string and user properties in case of failures
+    pub fn request_problem_info(&self) -> bool {
+        self.0.request_problem_info
+    }
+
+    #[inline]
+    /// Authentication method
+    pub fn auth_method(&self) -> Option<&'a ByteString> {
+        self.0.auth_method.as_ref()
+    }
+
+    #[inline]
+    /// Authentication data
+    pub fn auth_data(&self) -> Option<&'a Bytes> {
+        self.0.auth_data.as_ref()
+    }
+
+    #[inline]
+    /// Connect packet user properties
+    pub fn user_properties(&self) -> &'a codec::UserProperties {
+        &self.0.user_properties
+    }
+}
+
+impl fmt::Debug for ConnectProperties<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectProperties")
+            .field("session_expiry_interval", &self.0.session_expiry_interval_secs)
+            .field("receive_max", &self.0.receive_max)
+            .field("max_packet_size", &self.0.max_packet_size)
+            .field("topic_alias_max", &self.0.topic_alias_max)
+            .field("request_response_info", &self.0.request_response_info)
+            .field("request_problem_info", &self.0.request_problem_info)
+            .field("auth_method", &self.0.auth_method)
+            .field("user_properties", &self.0.user_properties)
+            .finish()
+    }
+}
+
+/// Last will message of connect packet
+///
+/// All will properties are optional, absent properties are returned
+/// as `None` or as protocol defaults.
+#[derive(Copy, Clone)]
+pub struct WillInfo<'a>(pub(super) &'a codec::LastWill);
+
+impl<'a> WillInfo<'a> {
+    #[inline]
+    /// Will topic
+    pub fn topic(&self) -> &'a ByteString {
+        &self.0.topic
+    }
+
+    #[inline]
+    /// Will message payload
+    pub fn payload(&self) -> &'a Bytes {
+        &self.0.message
+    }
+
+    #[inline]
+    /// QoS level to be used when publishing will message
+    pub fn qos(&self) -> codec::QoS {
+        self.0.qos
+    }
+
+    #[inline]
+    /// Will message is to be retained when it is published
+    pub fn retain(&self) -> bool {
+        self.0.retain
+    }
+
+    #[inline]
+    /// Will delay interval in seconds, defaults to 0
+    pub fn delay_interval(&self) -> u32 {
+        self.0.will_delay_interval_sec.unwrap_or(0)
+    }
+
+    #[inline]
+    /// Message expiry interval in seconds
+    pub fn message_expiry_interval(&self) -> Option<NonZeroU32> {
+        self.0.message_expiry_interval
+    }
+
+    #[inline]
+    /// Payload format indicator
+    pub fn is_utf8_payload(&self) -> Option<bool> {
+        self.0.is_utf8_payload
+    }
+
+    #[inline]
+    /// Content type of will message
+    pub fn content_type(&self) -> Option<&'a ByteString> {
+        self.0.content_type.as_ref()
+    }
+
+    #[inline]
+    /// Response topic of will message
+    pub fn response_topic(&self) -> Option<&'a ByteString> {
+        self.0.response_topic.as_ref()
+    }
+
+    #[inline]
+    /// Correlation data of will message
+    pub fn correlation_data(&self) -> Option<&'a Bytes> {
+        self.0.correlation_data.as_ref()
+    }
+
+    #[inline]
+    /// Will message user properties
+    pub fn user_properties(&self) -> &'a codec::UserProperties {
+        &self.0.user_properties
+    }
+
+    #[inline]
+    /// Get reference to last will packet
+    pub fn packet(&self) -> &'a codec::LastWill {
+        self.0
+    }
+}
+
+impl fmt::Debug for WillInfo<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WillInfo")
+            .field("topic", &self.0.topic)
+            .field("qos", &self.0.qos)
+            .field("retain", &self.0.retain)
+            .field("delay_interval", &self.0.will_delay_interval_sec)
+            .field("message_expiry_interval", &self.0.message_expiry_interval)
+            .field("content_type", &self.0.content_type)
+            .field("response_topic", &self.0.response_topic)
+            .field("user_properties", &self.0.user_properties)
+            .finish()
+    }
+}
+
 /// Handshake ack message
 pub struct HandshakeAck<St> {
     pub(crate) io: IoBoxed,
@@ -117,7 +433,9 @@ impl<St> HandshakeAck<St> {
     /// This method sets `server_keepalive_sec` property for `ConnectAck`
     /// response packet.
     ///
-    /// By default idle keep-alive is set to 30 seconds. Panics if timeout is `0`.
+    /// Keep-alive is set per connection, so it could be computed from the client's
+    /// connect packet. By default idle keep-alive is set to 30 seconds. Panics if
+    /// timeout is `0`.
     pub fn keep_alive(mut self, timeout: u16) -> Self {
         if timeout == 0 {
             panic!("Timeout must be greater than 0")
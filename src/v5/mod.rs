@@ -17,15 +17,22 @@ pub type Session<St> = crate::Session<MqttSink, St>;
 use std::num::NonZeroU16;
 
 pub use self::control::{Control, ControlAck};
-pub use self::handshake::{Handshake, HandshakeAck};
-pub use self::publish::{Publish, PublishAck};
+pub use self::handshake::{
+    ConnectProperties, Handshake, HandshakeAck, MapHandshakeError, MapHandshakeErrorService,
+    WillInfo,
+};
+pub use self::publish::{
+    PayloadFormat, Publish, PublishAck, PublishErrorHandler, PublishErrorHandlerService,
+};
 pub use self::router::Router;
 pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
+pub use self::sink::{
+    MqttSink, PublishAcked, PublishBuilder, RpcBuilder, SubscribeBuilder, UnsubscribeBuilder,
+};
 
 pub use crate::error;
 pub use crate::topic::{TopicFilter, TopicFilterError};
-pub use crate::types::QoS;
+pub use crate::types::{FilterDecision, PacketIdStrategy, QoS};
 
 const RECEIVE_MAX_DEFAULT: NonZeroU16 = unsafe { NonZeroU16::new_unchecked(65_535) };
 
@@ -1,12 +1,57 @@
-use std::{mem, num::NonZeroU16};
+use std::{cell::Cell, mem, num::NonZeroU16, rc::Rc};
 
-use ntex_bytes::{ByteString, Bytes};
+use ntex_bytes::{BufMut, ByteString, Bytes, BytesMut};
 use ntex_router::Path;
+use ntex_service::{Service, ServiceCtx, ServiceFactory};
+use ntex_util::time::{sleep, Millis};
 use serde::de::DeserializeOwned;
 use serde_json::Error as JsonError;
 
+use crate::{error::PayloadError, ErrorAction};
+
 use super::codec;
 
+/// User property that marks batched publish payload
+pub(super) const BATCH_PROPERTY: (&str, &str) = ("batch", "1");
+
+/// Encode messages to batch payload
+///
+/// Batch wire format: sequence of messages, each message is prefixed with
+/// its length encoded as 4 bytes big-endian unsigned integer. Publish with
+/// batched payload carries `batch=1` user property.
+pub(super) fn encode_batch(messages: Vec<Bytes>) -> Bytes {
+    let size = messages.iter().map(|m| m.len() + 4).sum();
+    let mut buf = BytesMut::with_capacity(size);
+    for msg in messages {
+        buf.put_u32(msg.len() as u32);
+        buf.extend_from_slice(&msg);
+    }
+    buf.freeze()
+}
+
+/// Check that payload is valid batch
+fn is_valid_batch(mut payload: &[u8]) -> bool {
+    while !payload.is_empty() {
+        if payload.len() < 4 {
+            return false;
+        }
+        let len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+        if payload.len() - 4 < len {
+            return false;
+        }
+        payload = &payload[4 + len..];
+    }
+    true
+}
+
+/// Payload format of publish message
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PayloadFormat {
+    /// UTF-8 encoded character data
+    Utf8,
+}
+
 /// Publish message
 pub struct Publish {
     pkt: codec::Publish,
@@ -33,6 +78,16 @@ impl Publish {
         self.pkt.retain
     }
 
+    #[inline]
+    /// Check if publish removes retained message of the topic.
+    ///
+    /// Retained publish with empty payload removes retained message and must
+    /// not be stored [MQTT-3.3.1-11]. It is still passed to publish service and
+    /// must be delivered to matching subscribers as a normal message [MQTT-3.3.1-10].
+    pub fn clears_retained(&self) -> bool {
+        self.pkt.retain && self.pkt.payload.is_empty()
+    }
+
     #[inline]
     /// the level of assurance for delivery of an Application Message.
     pub fn qos(&self) -> codec::QoS {
@@ -61,6 +116,30 @@ impl Publish {
         &mut self.topic
     }
 
+    #[inline]
+    /// the topic name for a response message (request/response interaction).
+    pub fn response_topic(&self) -> Option<&ByteString> {
+        self.pkt.properties.response_topic.as_ref()
+    }
+
+    #[inline]
+    /// correlation data of the request/response interaction.
+    pub fn correlation_data(&self) -> Option<&Bytes> {
+        self.pkt.properties.correlation_data.as_ref()
+    }
+
+    #[inline]
+    /// payload format indicator.
+    ///
+    /// Returns `None` if payload is unspecified bytes.
+    pub fn payload_format(&self) -> Option<PayloadFormat> {
+        if self.pkt.properties.is_utf8_payload {
+            Some(PayloadFormat::Utf8)
+        } else {
+            None
+        }
+    }
+
     #[inline]
     pub fn packet(&self) -> &codec::Publish {
         &self.pkt
@@ -83,6 +162,34 @@ impl Publish {
         &self.pkt.payload
     }
 
+    /// Iterate messages of batched payload
+    ///
+    /// Returns `None` if publish does not carry `batch=1` user property
+    /// or payload is not valid batch. See `MqttSink::publish_batch()`.
+    pub fn iter_batch(&self) -> Option<impl Iterator<Item = Bytes>> {
+        let batch = self
+            .pkt
+            .properties
+            .user_properties
+            .iter()
+            .any(|(k, v)| k.as_str() == BATCH_PROPERTY.0 && v.as_str() == BATCH_PROPERTY.1);
+
+        if batch && is_valid_batch(&self.pkt.payload) {
+            let mut payload = self.pkt.payload.clone();
+            Some(std::iter::from_fn(move || {
+                if payload.is_empty() {
+                    None
+                } else {
+                    let len = payload.split_to(4);
+                    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+                    Some(payload.split_to(len))
+                }
+            }))
+        } else {
+            None
+        }
+    }
+
     /// Replace packet'a payload with empty bytes, returns existing payload.
     pub fn take_payload(&mut self) -> Bytes {
         mem::take(&mut self.pkt.payload)
@@ -93,6 +200,25 @@ impl Publish {
         serde_json::from_slice(&self.pkt.payload)
     }
 
+    /// Loads and parse `application/json` encoded body, error contains packet topic.
+    pub fn decode_json<T: DeserializeOwned>(&mut self) -> Result<T, PayloadError> {
+        self.json().map_err(|err| PayloadError::Json { topic: self.pkt.topic.clone(), err })
+    }
+
+    #[cfg(feature = "cbor")]
+    /// Loads and parse `application/cbor` encoded body.
+    pub fn cbor<T: DeserializeOwned>(&mut self) -> Result<T, PayloadError> {
+        ciborium::de::from_reader(&self.pkt.payload[..])
+            .map_err(|err| PayloadError::Cbor { topic: self.pkt.topic.clone(), err })
+    }
+
+    #[cfg(feature = "msgpack")]
+    /// Loads and parse `application/msgpack` encoded body.
+    pub fn msgpack<T: DeserializeOwned>(&mut self) -> Result<T, PayloadError> {
+        rmp_serde::from_slice(&self.pkt.payload)
+            .map_err(|err| PayloadError::MsgPack { topic: self.pkt.topic.clone(), err })
+    }
+
     /// Create acknowledgement for this packet
     pub fn ack(self) -> PublishAck {
         PublishAck {
@@ -139,6 +265,8 @@ impl PublishAck {
     }
 
     /// Update user properties
+    ///
+    /// Not sent if client set `Request Problem Information` to `0` [MQTT-3.1.2-29]
     #[inline]
     pub fn properties<F>(mut self, f: F) -> Self
     where
@@ -149,9 +277,92 @@ impl PublishAck {
     }
 
     /// Set ack reason string
+    ///
+    /// Not sent if client set `Request Problem Information` to `0` [MQTT-3.1.2-29]
     #[inline]
     pub fn reason(mut self, reason: ByteString) -> Self {
         self.reason_string = Some(reason);
         self
     }
 }
+
+/// Publish service factory with error handler
+///
+/// Handler decides how publish service errors are processed,
+/// see [`ErrorAction`](crate::ErrorAction).
+pub struct PublishErrorHandler<S, F> {
+    factory: S,
+    f: Rc<F>,
+}
+
+impl<S, F> PublishErrorHandler<S, F> {
+    pub(crate) fn new(factory: S, f: F) -> Self {
+        Self { factory, f: Rc::new(f) }
+    }
+}
+
+impl<S, F, Cfg> ServiceFactory<Publish, Cfg> for PublishErrorHandler<S, F>
+where
+    S: ServiceFactory<Publish, Cfg>,
+    S::Response: Into<PublishAck>,
+    F: Fn(&S::Error) -> ErrorAction,
+{
+    type Response = PublishAck;
+    type Error = S::Error;
+    type Service = PublishErrorHandlerService<S::Service, F>;
+    type InitError = S::InitError;
+
+    async fn create(&self, cfg: Cfg) -> Result<Self::Service, Self::InitError> {
+        let service = self.factory.create(cfg).await?;
+        Ok(PublishErrorHandlerService { service, f: self.f.clone(), pause: Cell::new(None) })
+    }
+}
+
+/// Publish service with error handler
+pub struct PublishErrorHandlerService<S, F> {
+    service: S,
+    f: Rc<F>,
+    pause: Cell<Option<Millis>>,
+}
+
+impl<S, F> Service<Publish> for PublishErrorHandlerService<S, F>
+where
+    S: Service<Publish>,
+    S::Response: Into<PublishAck>,
+    F: Fn(&S::Error) -> ErrorAction,
+{
+    type Response = PublishAck;
+    type Error = S::Error;
+
+    ntex_service::forward_shutdown!(service);
+
+    async fn ready(&self, ctx: ServiceCtx<'_, Self>) -> Result<(), Self::Error> {
+        // service is not ready while paused, dispatcher stops reading
+        if let Some(dur) = self.pause.take() {
+            log::trace!("Pause reading inbound packets for {:?}", dur);
+            sleep(dur).await;
+        }
+        ctx.ready(&self.service).await
+    }
+
+    async fn call(
+        &self,
+        req: Publish,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        match ctx.call(&self.service, req).await {
+            Ok(res) => Ok(res.into()),
+            Err(err) => match (self.f)(&err) {
+                ErrorAction::Disconnect => Err(err),
+                ErrorAction::Ignore => {
+                    log::trace!("Publish service error is ignored");
+                    Ok(PublishAck::new(codec::PublishAckReason::UnspecifiedError))
+                }
+                ErrorAction::PauseReads(dur) => {
+                    self.pause.set(Some(dur));
+                    Ok(PublishAck::new(codec::PublishAckReason::UnspecifiedError))
+                }
+            },
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, num::NonZeroU16, rc::Rc};
+use std::{cell::RefCell, marker::PhantomData, num::NonZeroU16, rc::Rc};
 
 use ntex_bytes::ByteString;
 use ntex_router::{IntoPattern, Path, RouterBuilder};
@@ -6,7 +6,7 @@ use ntex_service::boxed::{self, BoxService, BoxServiceFactory};
 use ntex_service::{IntoServiceFactory, Service, ServiceCtx, ServiceFactory};
 use ntex_util::HashMap;
 
-use super::{publish::Publish, publish::PublishAck, Session};
+use super::{codec::QoS, publish::Publish, publish::PublishAck, Session};
 
 type Handler<S, E> = BoxServiceFactory<Session<S>, Publish, PublishAck, E, E>;
 type HandlerService<E> = BoxService<Publish, PublishAck, E>;
@@ -160,3 +160,67 @@ impl<Err: 'static> Service<Publish> for RouterService<Err> {
         ctx.call(&self.default, req).await
     }
 }
+
+/// Publish service factory that routes publish packets by QoS
+pub struct QosPublish<A, B, Err> {
+    qos0: A,
+    reliable: B,
+    _t: PhantomData<Err>,
+}
+
+impl<A, B, Err> QosPublish<A, B, Err> {
+    pub(super) fn new(qos0: A, reliable: B) -> Self {
+        Self { qos0, reliable, _t: PhantomData }
+    }
+}
+
+impl<S, A, B, Err> ServiceFactory<Publish, Session<S>> for QosPublish<A, B, Err>
+where
+    S: 'static,
+    A: ServiceFactory<Publish, Session<S>, Response = PublishAck>,
+    B: ServiceFactory<Publish, Session<S>, Response = PublishAck, Error = A::Error>,
+    Err: From<A::InitError> + From<B::InitError>,
+{
+    type Response = PublishAck;
+    type Error = A::Error;
+    type InitError = Err;
+    type Service = QosPublishService<A::Service, B::Service>;
+
+    async fn create(&self, session: Session<S>) -> Result<Self::Service, Self::InitError> {
+        let qos0 = self.qos0.create(session.clone()).await?;
+        let reliable = self.reliable.create(session).await?;
+        Ok(QosPublishService { qos0, reliable })
+    }
+}
+
+pub struct QosPublishService<A, B> {
+    qos0: A,
+    reliable: B,
+}
+
+impl<A, B> Service<Publish> for QosPublishService<A, B>
+where
+    A: Service<Publish, Response = PublishAck>,
+    B: Service<Publish, Response = PublishAck, Error = A::Error>,
+{
+    type Response = PublishAck;
+    type Error = A::Error;
+
+    #[inline]
+    async fn ready(&self, ctx: ServiceCtx<'_, Self>) -> Result<(), Self::Error> {
+        ctx.ready(&self.qos0).await?;
+        ctx.ready(&self.reliable).await
+    }
+
+    async fn call(
+        &self,
+        req: Publish,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        if req.qos() == QoS::AtMostOnce {
+            ctx.call(&self.qos0, req).await
+        } else {
+            ctx.call(&self.reliable, req).await
+        }
+    }
+}
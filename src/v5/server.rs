@@ -1,17 +1,23 @@
-use std::{fmt, marker::PhantomData, rc::Rc};
+use std::{cell::OnceCell, fmt, marker::PhantomData, rc::Rc, time::Duration};
 
+use ntex_bytes::{ByteString, Bytes};
 use ntex_io::{DispatchItem, DispatcherConfig, IoBoxed};
 use ntex_service::{IntoServiceFactory, Service, ServiceCtx, ServiceFactory};
-use ntex_util::time::{timeout_checked, Millis, Seconds};
+use ntex_util::time::{Millis, Seconds};
 
+use crate::clock::{self, Clock, SystemClock};
 use crate::error::{HandshakeError, MqttError, ProtocolError};
-use crate::{service, types::QoS};
+use crate::inflight::InFlightService;
+use crate::io::KeepAlive;
+use crate::types::{ControlErrorPolicy, FilterDecision, QoS, SubscribePolicy};
+use crate::{service, ErrorAction, ServerHandle};
 
 use super::control::{Control, ControlAck};
 use super::default::{DefaultControlService, DefaultPublishService};
-use super::handshake::{Handshake, HandshakeAck};
-use super::publish::{Publish, PublishAck};
-use super::shared::{MqttShared, MqttSinkPool};
+use super::handshake::{Handshake, HandshakeAck, MapHandshakeError, WillInfo};
+use super::publish::{Publish, PublishAck, PublishErrorHandler};
+use super::router::QosPublish;
+use super::shared::{MqttShared, MqttSinkPool, PayloadTransform};
 use super::{codec as mqtt, dispatcher::factory, MqttSink, Session};
 
 /// Mqtt Server
@@ -23,9 +29,33 @@ pub struct MqttServer<St, C, Cn, P> {
     max_size: u32,
     max_receive: u16,
     max_receive_size: usize,
+    inbound_queue_depth: usize,
     max_topic_alias: u16,
     handle_qos_after_disconnect: Option<QoS>,
+    subscribe_policy: SubscribePolicy,
+    immediate_ack: bool,
+    control_pubrel: bool,
+    strict_packet_ids: bool,
+    deny_dollar_publish: bool,
+    ordered_subscribe: bool,
+    validate_payload_format: bool,
+    stats_interval: Seconds,
+    first_action_timeout: Seconds,
+    control_error_policy: ControlErrorPolicy,
+    max_handshakes: u16,
+    read_params: Option<(u32, u32)>,
+    on_connected: Option<Rc<dyn Fn(Session<St>)>>,
+    on_will: Option<Rc<dyn Fn(WillInfo<'_>, Session<St>)>>,
+    publish_filter: Option<Rc<dyn Fn(&Publish) -> bool>>,
+    topic_size_limits: Option<Rc<dyn Fn(&str) -> Option<u32>>>,
     connect_timeout: Seconds,
+    auth_failure_delay: Millis,
+    overload_check: Option<Rc<dyn Fn() -> bool>>,
+    egress_filter: Option<Rc<dyn Fn(&mut mqtt::Packet) -> FilterDecision<mqtt::Packet>>>,
+    clock: Rc<dyn Clock>,
+    dead_peer_timeout: Seconds,
+    payload_transform: Option<Rc<PayloadTransform>>,
+    handle: OnceCell<ServerHandle>,
     config: DispatcherConfig,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
@@ -54,9 +84,33 @@ where
             max_size: 0,
             max_receive: 15,
             max_receive_size: 65535,
+            inbound_queue_depth: 0,
             max_topic_alias: 32,
             handle_qos_after_disconnect: None,
+            subscribe_policy: SubscribePolicy::default(),
+            immediate_ack: false,
+            control_pubrel: false,
+            strict_packet_ids: false,
+            deny_dollar_publish: false,
+            ordered_subscribe: false,
+            validate_payload_format: false,
+            stats_interval: Seconds::ZERO,
+            first_action_timeout: Seconds::ZERO,
+            control_error_policy: ControlErrorPolicy::Escalate,
+            max_handshakes: 0,
+            read_params: None,
+            on_connected: None,
+            on_will: None,
+            publish_filter: None,
+            topic_size_limits: None,
             connect_timeout: Seconds::ZERO,
+            auth_failure_delay: Millis::ZERO,
+            overload_check: None,
+            egress_filter: None,
+            clock: Rc::new(SystemClock),
+            dead_peer_timeout: Seconds::ZERO,
+            payload_transform: None,
+            handle: OnceCell::new(),
             pool: Rc::new(MqttSinkPool::default()),
             _t: PhantomData,
         }
@@ -71,6 +125,31 @@ where
     Cn: ServiceFactory<Control<C::Error>, Session<St>, Response = ControlAck> + 'static,
     P: ServiceFactory<Publish, Session<St>, Response = PublishAck> + 'static,
 {
+    /// Set dead peer timeout.
+    ///
+    /// Connection is closed if no packets arrive within this time, regardless of
+    /// keep-alive negotiated with the client. Control service receives
+    /// `ProtocolError::KeepAliveTimeout` error.
+    ///
+    /// By default dead peer timeout is disabled.
+    pub fn dead_peer_timeout(mut self, timeout: Seconds) -> Self {
+        self.dead_peer_timeout = timeout;
+        self
+    }
+
+    /// Set clock for handshake timers.
+    ///
+    /// Clock drives `Connect` frame timeout and authentication failure delay,
+    /// custom clock could be used for deterministic timeout testing.
+    /// Keep-alive, read rate and dispatcher timers use ntex timer and
+    /// ignore the clock.
+    ///
+    /// By default `SystemClock` is used.
+    pub fn clock<T: Clock>(mut self, clock: T) -> Self {
+        self.clock = Rc::new(clock);
+        self
+    }
+
     /// Set client timeout for first `Connect` frame.
     ///
     /// Defines a timeout for reading `Connect` frame. If a client does not transmit
@@ -83,6 +162,50 @@ where
         self
     }
 
+    /// Set delay before sending rejected handshake response.
+    ///
+    /// Slows down credential guessing, server waits for specified time
+    /// before sending failed `ConnectAck` packet. Delay applies only to
+    /// the connection being rejected.
+    ///
+    /// By default delay is disabled.
+    pub fn auth_failure_delay(mut self, delay: Duration) -> Self {
+        self.auth_failure_delay = delay.into();
+        self
+    }
+
+    /// Set server overload check.
+    ///
+    /// Function is called for every decoded `Connect` packet before handshake
+    /// service. If it returns `true`, connection is rejected with
+    /// `ServerUnavailable` reason and closed, so clients could back off.
+    ///
+    /// By default overload check is not set.
+    pub fn overload_check<F>(mut self, check: F) -> Self
+    where
+        F: Fn() -> bool + 'static,
+    {
+        self.overload_check = Some(Rc::new(check));
+        self
+    }
+
+    /// Set filter for outbound packets.
+    ///
+    /// Filter is called for every packet sent to the client, including `ConnectAck`,
+    /// just before packet encoding. Filter could modify packet in place and return
+    /// `FilterDecision::Pass`, replace packet or drop it. Dropped packets are not
+    /// tracked by the server, i.e. dropped qos1 `Publish` stays in-flight
+    /// until connection is closed.
+    ///
+    /// By default egress filter is not set.
+    pub fn egress_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&mut mqtt::Packet) -> FilterDecision<mqtt::Packet> + 'static,
+    {
+        self.egress_filter = Some(Rc::new(filter));
+        self
+    }
+
     /// Set server connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -151,7 +274,9 @@ where
 
     /// Set server max qos setting.
     ///
-    /// By default max qos is not set.
+    /// Value is advertised as `Maximum QoS` in CONNACK. Publish with higher
+    /// qos is rejected with `QoS not supported` disconnect, granted qos in
+    /// SUBACK is downgraded to this value. By default max qos is `AtLeastOnce`.
     pub fn max_qos(mut self, qos: QoS) -> Self {
         self.max_qos = qos;
         self
@@ -164,6 +289,19 @@ where
         self
     }
 
+    /// Max number of inbound packets queued for processing.
+    ///
+    /// Dispatcher does not read next packet from the socket until queue has
+    /// free capacity, so slow publish service applies tcp backpressure
+    /// to the peer instead of buffering inbound packets in memory.
+    ///
+    /// By default queue depth is not limited, only total size of in-flight
+    /// messages is limited by `max_receive_size`.
+    pub fn inbound_queue_depth(mut self, depth: usize) -> Self {
+        self.inbound_queue_depth = depth;
+        self
+    }
+
     /// Handle max received QoS messages after client disconnect.
     ///
     /// By default, messages received before dispatched to the publish service will be dropped if
@@ -187,6 +325,300 @@ where
         self
     }
 
+    /// Set max number of subscriptions per connection.
+    ///
+    /// Topic filters that exceed the limit get `QuotaExceeded` reason code
+    /// in SUBACK, connection stays open. Wildcard filter counts as one subscription,
+    /// UNSUBSCRIBE releases subscription slots.
+    ///
+    /// If max subscriptions is set to `0`, number of subscriptions is unlimited.
+    /// By default max subscriptions is set to `0`
+    pub fn max_subscriptions(mut self, val: usize) -> Self {
+        self.subscribe_policy.max_subscriptions = val;
+        self
+    }
+
+    /// Set max granted QoS for subscriptions.
+    ///
+    /// QoS granted in SUBACK is capped to this value, policy is applied
+    /// after control service handled subscribe message.
+    ///
+    /// By default max granted QoS is set to `ExactlyOnce`
+    pub fn max_granted_qos(mut self, qos: QoS) -> Self {
+        self.subscribe_policy.max_granted_qos = qos;
+        self
+    }
+
+    /// Deny subscriptions with wildcard topic filters.
+    ///
+    /// Topic filters that contain `#` or `+` get `WildcardSubscriptionsNotSupported` reason code in SUBACK
+    /// and are not passed to control service.
+    ///
+    /// By default wildcard subscriptions are allowed.
+    pub fn deny_wildcard_subscriptions(mut self, val: bool) -> Self {
+        self.subscribe_policy.deny_wildcards = val;
+        self
+    }
+
+    /// Set max number of topic filters per SUBSCRIBE packet.
+    ///
+    /// SUBSCRIBE packet with more topic filters is treated as protocol
+    /// violation and connection gets closed, control service does not
+    /// receive subscribe message.
+    ///
+    /// If max filters is set to `0`, number of filters is unlimited.
+    /// By default max filters is set to `1024`
+    pub fn max_filters_per_subscribe(mut self, val: usize) -> Self {
+        self.subscribe_policy.max_filters = val;
+        self
+    }
+
+    /// Send publish acks immediately.
+    ///
+    /// If immediate ack is enabled, PUBACK is written to the connection
+    /// directly as soon as publish service completes, instead of being returned
+    /// to io dispatcher as service response. Acks could be sent out of order
+    /// which relaxes message ordering requirement [MQTT-4.6.0-2].
+    /// Outbound QoS 0 publishes are not affected by this option.
+    ///
+    /// Latency difference between both modes is not measured.
+    ///
+    /// By default immediate ack is disabled.
+    pub fn immediate_ack(mut self, val: bool) -> Self {
+        self.immediate_ack = val;
+        self
+    }
+
+    /// Pass PUBREL packets to control service.
+    ///
+    /// If enabled, PUBREL is passed to control service as `Control::PubRel`
+    /// and PUBCOMP is sent after control service acknowledges it. Otherwise
+    /// server replies with PUBCOMP immediately, with `Success` reason code or
+    /// `PacketIdNotFound` if packet id is unknown.
+    ///
+    /// By default PUBREL is not passed to control service.
+    pub fn control_pubrel(mut self, val: bool) -> Self {
+        self.control_pubrel = val;
+        self
+    }
+
+    /// Set strict inbound packet id check
+    ///
+    /// If enabled, PUBLISH packet with packet id that is already in use
+    /// is treated as protocol violation and connection gets closed.
+    /// Otherwise packet is rejected with `PacketIdentifierInUse` reason code.
+    /// Re-delivered packet with DUP flag set is always dropped, original
+    /// packet gets acknowledged.
+    ///
+    /// By default strict check is disabled.
+    pub fn strict_packet_ids(mut self, val: bool) -> Self {
+        self.strict_packet_ids = val;
+        self
+    }
+
+    /// Reject PUBLISH packets to reserved `$` topics
+    ///
+    /// If enabled, PUBLISH packet with topic name that starts with `$` character,
+    /// i.e. `$SYS/`, is acknowledged with `TopicNameInvalid` reason code, QoS 0
+    /// packet is dropped. Publish service is not called for such packets.
+    /// Subscriptions to `$` topics are not affected.
+    ///
+    /// By default `$` topics are allowed.
+    pub fn deny_dollar_publish(mut self, val: bool) -> Self {
+        self.deny_dollar_publish = val;
+        self
+    }
+
+    /// Process publishes after in-progress subscribe handling
+    ///
+    /// Control service handles SUBSCRIBE and UNSUBSCRIBE packets concurrently
+    /// with publish service. If enabled, PUBLISH packets received after
+    /// SUBSCRIBE or UNSUBSCRIBE packet are passed to publish service only after
+    /// control service completes subscription handling, so subscription side
+    /// effects are visible to subsequent publishes of the same client.
+    ///
+    /// By default ordering is disabled.
+    pub fn ordered_subscribe(mut self, val: bool) -> Self {
+        self.ordered_subscribe = val;
+        self
+    }
+
+    /// Validate payload of PUBLISH packets with `Payload Format Indicator` set
+    ///
+    /// If enabled, PUBLISH packet that indicates UTF-8 payload but carries
+    /// invalid UTF-8 data is treated as protocol violation and connection
+    /// gets closed with `PayloadFormatInvalid` reason code.
+    ///
+    /// By default validation is disabled.
+    pub fn validate_payload_format(mut self, val: bool) -> Self {
+        self.validate_payload_format = val;
+        self
+    }
+
+    /// Set connection stats interval
+    ///
+    /// Control service receives `Control::Stats` message every `interval`
+    /// with received publishes and bytes since previous message and number of
+    /// in-flight inbound packets. Returning `disconnect()` ack closes connection.
+    ///
+    /// By default stats are disabled.
+    pub fn stats_interval(mut self, interval: Seconds) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// Set first action timeout
+    ///
+    /// If client does not send PUBLISH or SUBSCRIBE packet within `timeout`
+    /// after connection is established, control service receives
+    /// `Control::ActionTimeout` message. Default ack closes connection,
+    /// `keep()` leaves connection open. PINGREQ packets are not counted.
+    ///
+    /// By default timeout is disabled.
+    pub fn first_action_timeout(mut self, timeout: Seconds) -> Self {
+        self.first_action_timeout = timeout;
+        self
+    }
+
+    /// Set control service error handling policy
+    ///
+    /// Policy is applied to errors returned by control service for
+    /// control messages. Errors returned for `Control::Error` and
+    /// `Control::ProtocolError` messages always close connection.
+    ///
+    /// By default error is passed to control service as `Control::Error` message.
+    pub fn control_error_policy(mut self, policy: ControlErrorPolicy) -> Self {
+        self.control_error_policy = policy;
+        self
+    }
+
+    /// Set max number of connections in handshake phase
+    ///
+    /// Limit is applied per worker thread. Once limit is reached, server
+    /// stops accepting new connections until one of in-progress handshakes
+    /// completes. Established connections do not count towards the limit.
+    ///
+    /// By default handshakes are not limited, zero value disables limit.
+    pub fn max_concurrent_handshakes(mut self, val: u16) -> Self {
+        self.max_handshakes = val;
+        self
+    }
+
+    /// Set read buffer parameters
+    ///
+    /// `hi` is the size of read buffer, `lo` is minimal free space in read
+    /// buffer before buffer gets extended, same order as ntex's
+    /// `MemoryPool::set_read_params()`. Large buffer reduces number of read
+    /// syscalls for high-throughput connections but increases memory usage
+    /// of idle connections. Parameters are applied to memory pool of
+    /// connection io, all connections that use same pool share parameters.
+    ///
+    /// By default memory pool parameters are not changed.
+    pub fn read_buffer_params(mut self, hi: u32, lo: u32) -> Self {
+        self.read_params = Some((hi, lo));
+        self
+    }
+
+    /// Set callback for established connections
+    ///
+    /// Callback is called once per connection, after CONNACK packet is written
+    /// to connection and before first inbound packet is dispatched. Packets sent
+    /// from callback are delivered to client after CONNACK.
+    ///
+    /// By default callback is not set.
+    pub fn on_connected<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Session<St>) + 'static,
+    {
+        self.on_connected = Some(Rc::new(f));
+        self
+    }
+
+    /// Set callback for will messages
+    ///
+    /// Callback is called once per connection with stored will message if
+    /// connection gets closed without DISCONNECT packet with `NormalDisconnection` reason code, for example
+    /// if peer is gone or connection is closed because of protocol error.
+    /// Callback is responsible for publishing will message.
+    ///
+    /// By default callback is not set and will messages are dropped.
+    pub fn on_will<F>(mut self, f: F) -> Self
+    where
+        F: Fn(WillInfo<'_>, Session<St>) + 'static,
+    {
+        self.on_will = Some(Rc::new(f));
+        self
+    }
+
+    /// Set publish filter
+    ///
+    /// Filter is called for every inbound publish before publish service.
+    /// If filter returns `false`, publish service is not called and packet is
+    /// acknowledged according to its QoS level.
+    ///
+    /// By default all publishes are passed to publish service.
+    pub fn publish_filter<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Publish) -> bool + 'static,
+    {
+        self.publish_filter = Some(Rc::new(f));
+        self
+    }
+
+    /// Set per-topic payload size limits
+    ///
+    /// Function is called for every inbound publish with publish topic and returns
+    /// max allowed payload size for the topic, `None` means no limit. Publish with
+    /// larger payload is rejected with protocol error before publish service is called,
+    /// connection gets closed with `PacketTooLarge` reason code.
+    /// Limit is checked against decoded payload length, it complements global `max_size`.
+    ///
+    /// By default per-topic limits are not set.
+    pub fn topic_size_limits<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> Option<u32> + 'static,
+    {
+        self.topic_size_limits = Some(Rc::new(f));
+        self
+    }
+
+    /// Set payload transform function for incoming publish packets.
+    ///
+    /// Transform function is applied before publish packet is passed to
+    /// the publish service, it receives publish topic and payload and returns
+    /// new payload and optional properties. Returned properties replace content
+    /// type and payload format indicator of the packet, user properties are appended.
+    ///
+    /// By default payload transform is not set.
+    pub fn payload_transform<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ByteString, Bytes) -> (Bytes, Option<mqtt::PublishProperties>) + 'static,
+    {
+        self.payload_transform = Some(Rc::new(f));
+        self
+    }
+
+    /// Get server handle
+    ///
+    /// All established connections get registered in the handle, active
+    /// connections could be listed and disconnected via handle. Codec decode
+    /// errors are counted in the handle by error type.
+    ///
+    /// Server builder is created per worker, each builder creates its own handle.
+    /// Use `with_handle()` to share one handle between workers.
+    /// Connections are registered only if handle is requested or set.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.get_or_init(ServerHandle::new).clone()
+    }
+
+    /// Set server handle
+    ///
+    /// Connections of all builders with the same handle get registered in it.
+    pub fn with_handle(mut self, handle: ServerHandle) -> Self {
+        self.handle = OnceCell::from(handle);
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max number of buffered
@@ -207,8 +639,32 @@ where
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             max_receive_size: self.max_receive_size,
+            inbound_queue_depth: self.inbound_queue_depth,
             handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+            subscribe_policy: self.subscribe_policy,
+            immediate_ack: self.immediate_ack,
+            control_pubrel: self.control_pubrel,
+            strict_packet_ids: self.strict_packet_ids,
+            deny_dollar_publish: self.deny_dollar_publish,
+            ordered_subscribe: self.ordered_subscribe,
+            validate_payload_format: self.validate_payload_format,
+            stats_interval: self.stats_interval,
+            first_action_timeout: self.first_action_timeout,
+            control_error_policy: self.control_error_policy,
+            max_handshakes: self.max_handshakes,
+            read_params: self.read_params,
+            on_connected: self.on_connected,
+            on_will: self.on_will,
+            publish_filter: self.publish_filter,
+            topic_size_limits: self.topic_size_limits,
             connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check,
+            egress_filter: self.egress_filter,
+            clock: self.clock,
+            dead_peer_timeout: self.dead_peer_timeout,
+            payload_transform: self.payload_transform,
+            handle: self.handle,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -222,19 +678,192 @@ where
         Srv: ServiceFactory<Publish, Session<St>, Response = PublishAck> + 'static,
         Srv::Error: fmt::Debug,
         PublishAck: TryFrom<Srv::Error, Error = C::Error>,
+    {
+        self.map_publish(|_| publish.into_factory())
+    }
+
+    /// Set service to handle QoS 0 publish packets
+    ///
+    /// Publish packets with QoS 1 and QoS 2 are handled by the service set with
+    /// `publish()` or `publish_reliable()`. If only `publish()` is set, all publish
+    /// packets are handled by that service. Both services must use the same error type.
+    pub fn publish_qos0<F, Srv>(
+        self,
+        publish: F,
+    ) -> MqttServer<St, C, Cn, QosPublish<Srv, P, C::Error>>
+    where
+        F: IntoServiceFactory<Srv, Publish, Session<St>>,
+        Srv: ServiceFactory<Publish, Session<St>, Response = PublishAck, Error = P::Error>
+            + 'static,
+        C::Error: From<Srv::InitError> + From<P::InitError>,
+    {
+        let publish = publish.into_factory();
+        self.map_publish(|p| QosPublish::new(publish, p))
+    }
+
+    /// Set service to handle QoS 1 and QoS 2 publish packets
+    ///
+    /// Publish packets with QoS 0 are handled by the service set with `publish()`
+    /// or `publish_qos0()`. Both services must use the same error type.
+    pub fn publish_reliable<F, Srv>(
+        self,
+        publish: F,
+    ) -> MqttServer<St, C, Cn, QosPublish<P, Srv, C::Error>>
+    where
+        F: IntoServiceFactory<Srv, Publish, Session<St>>,
+        Srv: ServiceFactory<Publish, Session<St>, Response = PublishAck, Error = P::Error>
+            + 'static,
+        C::Error: From<Srv::InitError> + From<P::InitError>,
+    {
+        let publish = publish.into_factory();
+        self.map_publish(|p| QosPublish::new(p, publish))
+    }
+
+    fn map_publish<Srv, F>(self, f: F) -> MqttServer<St, C, Cn, Srv>
+    where
+        F: FnOnce(P) -> Srv,
+    {
+        MqttServer {
+            config: self.config,
+            handshake: self.handshake,
+            srv_publish: f(self.srv_publish),
+            srv_control: self.srv_control,
+            max_size: self.max_size,
+            max_receive: self.max_receive,
+            max_topic_alias: self.max_topic_alias,
+            max_qos: self.max_qos,
+            max_receive_size: self.max_receive_size,
+            inbound_queue_depth: self.inbound_queue_depth,
+            handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+            subscribe_policy: self.subscribe_policy,
+            immediate_ack: self.immediate_ack,
+            control_pubrel: self.control_pubrel,
+            strict_packet_ids: self.strict_packet_ids,
+            deny_dollar_publish: self.deny_dollar_publish,
+            ordered_subscribe: self.ordered_subscribe,
+            validate_payload_format: self.validate_payload_format,
+            stats_interval: self.stats_interval,
+            first_action_timeout: self.first_action_timeout,
+            control_error_policy: self.control_error_policy,
+            max_handshakes: self.max_handshakes,
+            read_params: self.read_params,
+            on_connected: self.on_connected,
+            on_will: self.on_will,
+            publish_filter: self.publish_filter,
+            topic_size_limits: self.topic_size_limits,
+            connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check,
+            egress_filter: self.egress_filter,
+            clock: self.clock,
+            dead_peer_timeout: self.dead_peer_timeout,
+            payload_transform: self.payload_transform,
+            handle: self.handle,
+            pool: self.pool,
+            _t: PhantomData,
+        }
+    }
+
+    /// Map handshake service errors to CONNACK reason code and reason string.
+    ///
+    /// By default connection is dropped if handshake service returns error.
+    /// With error mapping, CONNACK with mapped reason is sent to the client
+    /// before connection gets closed.
+    pub fn map_handshake_error<F>(self, f: F) -> MqttServer<St, MapHandshakeError<C, F>, Cn, P>
+    where
+        F: Fn(&C::Error) -> (mqtt::ConnectAckReason, Option<ByteString>) + 'static,
+    {
+        MqttServer {
+            config: self.config,
+            handshake: MapHandshakeError::new(self.handshake, f),
+            srv_publish: self.srv_publish,
+            srv_control: self.srv_control,
+            max_size: self.max_size,
+            max_receive: self.max_receive,
+            max_topic_alias: self.max_topic_alias,
+            max_qos: self.max_qos,
+            max_receive_size: self.max_receive_size,
+            inbound_queue_depth: self.inbound_queue_depth,
+            handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+            subscribe_policy: self.subscribe_policy,
+            immediate_ack: self.immediate_ack,
+            control_pubrel: self.control_pubrel,
+            strict_packet_ids: self.strict_packet_ids,
+            deny_dollar_publish: self.deny_dollar_publish,
+            ordered_subscribe: self.ordered_subscribe,
+            validate_payload_format: self.validate_payload_format,
+            stats_interval: self.stats_interval,
+            first_action_timeout: self.first_action_timeout,
+            control_error_policy: self.control_error_policy,
+            max_handshakes: self.max_handshakes,
+            read_params: self.read_params,
+            on_connected: self.on_connected,
+            on_will: self.on_will,
+            publish_filter: self.publish_filter,
+            topic_size_limits: self.topic_size_limits,
+            connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check,
+            egress_filter: self.egress_filter,
+            clock: self.clock,
+            dead_peer_timeout: self.dead_peer_timeout,
+            payload_transform: self.payload_transform,
+            handle: self.handle,
+            pool: self.pool,
+            _t: PhantomData,
+        }
+    }
+
+    /// Set publish service error handler
+    ///
+    /// Handler is called for every publish service error and decides whether
+    /// error is passed to control service, ignored, or reading of inbound packets
+    /// gets paused for a cooldown period. Ignored publish is acknowledged with
+    /// `UnspecifiedError` reason code. Handler applies to publish service that
+    /// is set at the time of the call.
+    ///
+    /// By default all errors are passed to control service.
+    pub fn on_handler_error<F>(self, f: F) -> MqttServer<St, C, Cn, PublishErrorHandler<P, F>>
+    where
+        P: ServiceFactory<Publish, Session<St>>,
+        F: Fn(&P::Error) -> ErrorAction + 'static,
     {
         MqttServer {
             config: self.config,
             handshake: self.handshake,
-            srv_publish: publish.into_factory(),
+            srv_publish: PublishErrorHandler::new(self.srv_publish, f),
             srv_control: self.srv_control,
             max_size: self.max_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             max_receive_size: self.max_receive_size,
+            inbound_queue_depth: self.inbound_queue_depth,
             handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+            subscribe_policy: self.subscribe_policy,
+            immediate_ack: self.immediate_ack,
+            control_pubrel: self.control_pubrel,
+            strict_packet_ids: self.strict_packet_ids,
+            deny_dollar_publish: self.deny_dollar_publish,
+            ordered_subscribe: self.ordered_subscribe,
+            validate_payload_format: self.validate_payload_format,
+            stats_interval: self.stats_interval,
+            first_action_timeout: self.first_action_timeout,
+            control_error_policy: self.control_error_policy,
+            max_handshakes: self.max_handshakes,
+            read_params: self.read_params,
+            on_connected: self.on_connected,
+            on_will: self.on_will,
+            publish_filter: self.publish_filter,
+            topic_size_limits: self.topic_size_limits,
             connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check,
+            egress_filter: self.egress_filter,
+            clock: self.clock,
+            dead_peer_timeout: self.dead_peer_timeout,
+            payload_transform: self.payload_transform,
+            handle: self.handle,
             pool: self.pool,
             _t: PhantomData,
         }
@@ -262,7 +891,7 @@ where
         Session<St>,
         impl ServiceFactory<
             IoBoxed,
-            Response = (IoBoxed, Rc<MqttShared>, Session<St>, Seconds),
+            Response = (IoBoxed, Rc<MqttShared>, Session<St>, KeepAlive),
             Error = MqttError<C::Error>,
             InitError = C::InitError,
         >,
@@ -278,32 +907,65 @@ where
         service::MqttServer::new(
             HandshakeFactory {
                 factory: self.handshake,
+                max_handshakes: self.max_handshakes,
+                read_params: self.read_params,
                 max_size: self.max_size,
                 max_receive: self.max_receive,
                 max_topic_alias: self.max_topic_alias,
                 max_qos: self.max_qos,
                 connect_timeout: self.connect_timeout.into(),
+                auth_failure_delay: self.auth_failure_delay,
+                overload_check: self.overload_check,
+                egress_filter: self.egress_filter,
+                clock: self.clock,
+                handle: self.handle.into_inner(),
                 pool: self.pool,
                 _t: PhantomData,
             },
             factory(
                 self.srv_publish,
                 self.srv_control,
-                self.max_receive_size,
-                self.handle_qos_after_disconnect,
+                super::dispatcher::DispatcherConfig {
+                    max_inflight_size: self.max_receive_size,
+                    inbound_depth: self.inbound_queue_depth,
+                    handle_qos_after_disconnect: self.handle_qos_after_disconnect,
+                    subscribe_policy: self.subscribe_policy,
+                    immediate_ack: self.immediate_ack,
+                    control_pubrel: self.control_pubrel,
+                    strict_packet_ids: self.strict_packet_ids,
+                    deny_dollar_publish: self.deny_dollar_publish,
+                    ordered_subscribe: self.ordered_subscribe,
+                    validate_payload_format: self.validate_payload_format,
+                    stats_interval: self.stats_interval,
+                    first_action_timeout: self.first_action_timeout,
+                    control_error_policy: self.control_error_policy,
+                    payload_transform: self.payload_transform,
+                    publish_filter: self.publish_filter,
+                    topic_size_limits: self.topic_size_limits,
+                },
+                self.on_connected,
+                self.on_will,
             ),
             self.config,
         )
+        .dead_peer_timeout(self.dead_peer_timeout)
     }
 }
 
 struct HandshakeFactory<St, H> {
     factory: H,
+    max_handshakes: u16,
+    read_params: Option<(u32, u32)>,
     max_size: u32,
     max_receive: u16,
     max_topic_alias: u16,
     max_qos: QoS,
     connect_timeout: Millis,
+    auth_failure_delay: Millis,
+    overload_check: Option<Rc<dyn Fn() -> bool>>,
+    egress_filter: Option<Rc<dyn Fn(&mut mqtt::Packet) -> FilterDecision<mqtt::Packet>>>,
+    clock: Rc<dyn Clock>,
+    handle: Option<ServerHandle>,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -313,14 +975,15 @@ where
     H: ServiceFactory<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
 {
-    type Response = (IoBoxed, Rc<MqttShared>, Session<St>, Seconds);
+    type Response = (IoBoxed, Rc<MqttShared>, Session<St>, KeepAlive);
     type Error = MqttError<H::Error>;
 
-    type Service = HandshakeService<St, H::Service>;
+    type Service = InFlightService<HandshakeService<St, H::Service>>;
     type InitError = H::InitError;
 
     async fn create(&self, _: ()) -> Result<Self::Service, Self::InitError> {
-        Ok(HandshakeService {
+        let service = HandshakeService {
+            read_params: self.read_params,
             service: self.factory.create(()).await?,
             max_size: self.max_size,
             max_receive: self.max_receive,
@@ -328,18 +991,30 @@ where
             max_qos: self.max_qos,
             pool: self.pool.clone(),
             connect_timeout: self.connect_timeout,
+            auth_failure_delay: self.auth_failure_delay,
+            overload_check: self.overload_check.clone(),
+            egress_filter: self.egress_filter.clone(),
+            clock: self.clock.clone(),
+            handle: self.handle.clone(),
             _t: PhantomData,
-        })
+        };
+        Ok(InFlightService::new(self.max_handshakes, 0, service))
     }
 }
 
 struct HandshakeService<St, H> {
     service: H,
+    read_params: Option<(u32, u32)>,
     max_size: u32,
     max_receive: u16,
     max_topic_alias: u16,
     max_qos: QoS,
     connect_timeout: Millis,
+    auth_failure_delay: Millis,
+    overload_check: Option<Rc<dyn Fn() -> bool>>,
+    egress_filter: Option<Rc<dyn Fn(&mut mqtt::Packet) -> FilterDecision<mqtt::Packet>>>,
+    clock: Rc<dyn Clock>,
+    handle: Option<ServerHandle>,
     pool: Rc<MqttSinkPool>,
     _t: PhantomData<St>,
 }
@@ -349,7 +1024,7 @@ where
     H: Service<Handshake, Response = HandshakeAck<St>> + 'static,
     H::Error: fmt::Debug,
 {
-    type Response = (IoBoxed, Rc<MqttShared>, Session<St>, Seconds);
+    type Response = (IoBoxed, Rc<MqttShared>, Session<St>, KeepAlive);
     type Error = MqttError<H::Error>;
 
     ntex_service::forward_ready!(service, MqttError::Service);
@@ -362,15 +1037,21 @@ where
     ) -> Result<Self::Response, Self::Error> {
         log::trace!("Starting mqtt v5 handshake");
 
+        if let Some((h, l)) = self.read_params {
+            io.memory_pool().set_read_params(h, l);
+        }
+
         let codec = mqtt::Codec::default();
         codec.set_max_inbound_size(self.max_size);
+        codec.set_metrics(self.handle.clone());
+        codec.set_egress_filter(self.egress_filter.clone());
         let shared = Rc::new(MqttShared::new(io.get_ref(), codec, self.pool.clone()));
         shared.set_max_qos(self.max_qos);
         shared.set_receive_max(self.max_receive);
         shared.set_topic_alias_max(self.max_topic_alias);
 
         // read first packet
-        let packet = timeout_checked(self.connect_timeout, io.recv(&shared.codec))
+        let packet = clock::timeout(&*self.clock, self.connect_timeout, io.recv(&shared.codec))
             .await
             .map_err(|_| MqttError::Handshake(HandshakeError::Timeout))?
             .map_err(|err| {
@@ -384,6 +1065,19 @@ where
 
         match packet {
             (mqtt::Packet::Connect(connect), size) => {
+                if let Some(ref check) = self.overload_check {
+                    if check() {
+                        log::trace!("Server is overloaded, reject connection");
+                        let pkt = mqtt::Packet::ConnectAck(Box::new(mqtt::ConnectAck {
+                            reason_code: mqtt::ConnectAckReason::ServerUnavailable,
+                            ..mqtt::ConnectAck::default()
+                        }));
+                        io.encode(pkt, &shared.codec)?;
+                        let _ = io.shutdown().await;
+                        return Err(MqttError::Handshake(HandshakeError::Disconnected(None)));
+                    }
+                }
+
                 // set max outbound (encoder) packet size
                 if let Some(size) = connect.max_packet_size {
                     shared.codec.set_max_outbound_size(size.get());
@@ -391,12 +1085,22 @@ where
                 let keep_alive = connect.keep_alive;
                 let peer_receive_max =
                     connect.receive_max.map(|v| v.get()).unwrap_or(16) as usize;
+                let client_id = connect.client_id.clone();
+                #[cfg(feature = "tracing")]
+                tracing::Span::current()
+                    .record("client_id", tracing::field::display(&client_id));
 
-                // authenticate mqtt connection
-                let mut ack = ctx
-                    .call(&self.service, Handshake::new(connect, size, io, shared))
-                    .await
-                    .map_err(|e| MqttError::Handshake(HandshakeError::Service(e)))?;
+                // authenticate mqtt connection, readiness is checked by InFlightService,
+                // waiting for it here would block on pending handshakes limit
+                let fut =
+                    ctx.call_nowait(&self.service, Handshake::new(connect, size, io, shared));
+                #[cfg(feature = "tracing")]
+                let fut = tracing::Instrument::instrument(
+                    fut,
+                    tracing::info_span!("mqtt.handshake", client_id = %client_id),
+                );
+                let mut ack =
+                    fut.await.map_err(|e| MqttError::Handshake(HandshakeError::Service(e)))?;
 
                 match ack.session {
                     Some(session) => {
@@ -419,22 +1123,38 @@ where
                             ack.packet.server_keepalive_sec = Some(ack.keepalive);
                         }
                         shared.set_cap(peer_receive_max);
+                        let client_id =
+                            ack.packet.assigned_client_id.clone().unwrap_or(client_id);
+                        shared.set_client_id(client_id.clone());
+                        if let Some(val) = ack.packet.session_expiry_interval_secs {
+                            shared.set_session_expiry(val);
+                        }
 
                         ack.io.encode(
                             mqtt::Packet::ConnectAck(Box::new(ack.packet)),
                             &shared.codec,
                         )?;
 
-                        Ok((
-                            ack.io,
-                            shared.clone(),
-                            Session::new(session, MqttSink::new(shared)),
-                            Seconds(ack.keepalive),
-                        ))
+                        let sink = MqttSink::new(shared.clone());
+                        if let Some(ref handle) = self.handle {
+                            let sink = sink.clone();
+                            let count =
+                                handle.register(client_id, ack.io.get_ref(), move || {
+                                    sink.force_close()
+                                });
+                            shared.set_subscriptions_count(count);
+                        }
+
+                        shared.keepalive().set(Seconds(ack.keepalive));
+                        let keepalive = shared.keepalive().clone();
+                        Ok((ack.io, shared, Session::new(session, sink), keepalive))
                     }
                     None => {
                         log::trace!("Failed to complete handshake: {:#?}", ack.packet);
 
+                        if !self.auth_failure_delay.is_zero() {
+                            self.clock.sleep(self.auth_failure_delay).await;
+                        }
                         ack.io.encode(
                             mqtt::Packet::ConnectAck(Box::new(ack.packet)),
                             &ack.shared.codec,
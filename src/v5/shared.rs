@@ -1,17 +1,50 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc::Rc};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::time::{Duration, Instant};
+use std::{collections::VecDeque, mem, num::NonZeroU16, rc::Rc};
 
-use ntex_bytes::{BytesMut, PoolId, PoolRef};
+use ntex_bytes::{ByteString, Bytes, BytesMut, PoolId, PoolRef};
 use ntex_codec::{Decoder, Encoder};
-use ntex_io::IoRef;
-use ntex_util::{channel::pool, HashSet};
+use ntex_io::{IoRef, OnDisconnect};
+use ntex_util::future::{stream_recv, OnDropFn};
+use ntex_util::Stream;
+use ntex_util::{channel::oneshot, channel::pool, time::sleep, time::Millis, HashMap, HashSet};
 
-use crate::{error, error::SendPacketError, types::packet_type, v5::codec, QoS};
+use crate::handle::SubscriptionsCount;
+use crate::io::KeepAlive;
+use crate::types::{packet_type, PacketIdSet, PacketIdStrategy, MAX_PACKET_SIZE};
+use crate::utils::{decode_variable_length, write_variable_length};
+use crate::{error, error::SendPacketError, v5::codec, QoS};
+
+/// Interval for checking io write buffer in `flush()`
+const FLUSH_POLL_INTERVAL: Millis = Millis(1);
 
 bitflags::bitflags! {
     #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
     struct Flags: u8 {
         const WRB_ENABLED    = 0b0100_0000; // write-backpressure
         const ON_PUBLISH_ACK = 0b0010_0000; // on-publish-ack callback
+        const ACTIVITY       = 0b0001_0000; // outbound activity
+        const STREAMING      = 0b0000_1000; // streamed publish payload is being written
+        const ACK_TIMED      = 0b0000_0100; // measure publish ack latency
+        const CLOSED         = 0b0000_0010; // DISCONNECT is sent
+    }
+}
+
+/// Publish payload transform function
+pub(super) type PayloadTransform =
+    dyn Fn(&ByteString, Bytes) -> (Bytes, Option<codec::PublishProperties>);
+
+/// Apply payload transform to publish packet
+///
+/// Returned properties replace content type and payload format indicator
+/// of the packet, user properties are appended.
+pub(super) fn transform_payload(f: &PayloadTransform, pkt: &mut codec::Publish) {
+    let (payload, props) = f(&pkt.topic, mem::take(&mut pkt.payload));
+    pkt.payload = payload;
+    if let Some(props) = props {
+        pkt.properties.content_type = props.content_type;
+        pkt.properties.is_utf8_payload = props.is_utf8_payload;
+        pkt.properties.user_properties.extend(props.user_properties);
     }
 }
 
@@ -25,14 +58,32 @@ pub struct MqttShared {
     queues: RefCell<MqttSharedQueues>,
     flags: Cell<Flags>,
     pool: Rc<MqttSinkPool>,
-    on_publish_ack: Cell<Option<Box<dyn Fn(codec::PublishAck, bool)>>>,
+    on_publish_ack: Cell<Option<Box<dyn Fn(codec::PublishAck, bool, Duration)>>>,
+    payload_transform: Cell<Option<Rc<PayloadTransform>>>,
+    queued: RefCell<BytesMut>,
+    subscriptions_count: Cell<Option<SubscriptionsCount>>,
+    ack_timeout: Cell<Millis>,
+    client_id: OnceCell<ByteString>,
+    keepalive: KeepAlive,
+    session_expiry: Cell<u32>,
+    will: Cell<Option<codec::LastWill>>,
+    rpc: RefCell<HashMap<ByteString, (Bytes, Option<oneshot::Sender<Bytes>>)>>,
     pub(super) codec: codec::Codec,
 }
 
 pub(super) struct MqttSharedQueues {
     inflight: VecDeque<(NonZeroU16, Option<pool::Sender<Ack>>, AckType)>,
     inflight_ids: HashSet<NonZeroU16>,
+    ids: Option<PacketIdSet>,
+    cancelled: HashSet<NonZeroU16>,
+    expired: HashSet<NonZeroU16>,
+    sent: HashMap<NonZeroU16, Instant>,
     waiters: VecDeque<pool::Sender<()>>,
+    drain_waiters: Vec<pool::Sender<()>>,
+    // streamed publish writers waiting for current stream to finish
+    stream_waiters: Vec<pool::Sender<()>>,
+    // streamed publish writer waiting for write back-pressure to be disabled
+    wrb_waiters: Vec<pool::Sender<()>>,
 }
 
 pub(super) struct MqttSinkPool {
@@ -59,9 +110,16 @@ impl MqttShared {
             codec,
             cap: Cell::new(0),
             queues: RefCell::new(MqttSharedQueues {
-                inflight: VecDeque::with_capacity(8),
+                inflight: VecDeque::new(),
                 inflight_ids: HashSet::default(),
+                ids: None,
+                cancelled: HashSet::default(),
+                expired: HashSet::default(),
+                sent: HashMap::default(),
                 waiters: VecDeque::new(),
+                drain_waiters: Vec::new(),
+                stream_waiters: Vec::new(),
+                wrb_waiters: Vec::new(),
             }),
             receive_max: Cell::new(0),
             topic_alias_max: Cell::new(0),
@@ -69,6 +127,15 @@ impl MqttShared {
             inflight_idx: Cell::new(0),
             flags: Cell::new(Flags::empty()),
             on_publish_ack: Cell::new(None),
+            queued: RefCell::new(BytesMut::new()),
+            ack_timeout: Cell::new(Millis::ZERO),
+            client_id: OnceCell::new(),
+            keepalive: KeepAlive::default(),
+            session_expiry: Cell::new(0),
+            will: Cell::new(None),
+            rpc: RefCell::new(HashMap::default()),
+            payload_transform: Cell::new(None),
+            subscriptions_count: Cell::new(None),
         }
     }
 
@@ -98,7 +165,9 @@ impl MqttShared {
 
     pub(super) fn close(&self, pkt: codec::Disconnect) {
         if !self.is_closed() {
-            let _ = self.io.encode(codec::Packet::Disconnect(pkt), &self.codec);
+            self.flush_queued();
+            let _ = self.io.encode(codec::Packet::Disconnect(pkt), self);
+            self.flags.set(self.flags.get() | Flags::CLOSED);
             self.io.close();
         }
         self.clear_queues();
@@ -110,7 +179,18 @@ impl MqttShared {
     }
 
     pub(super) fn is_closed(&self) -> bool {
-        self.io.is_closed()
+        self.flags.get().contains(Flags::CLOSED) || self.io.is_closed()
+    }
+
+    pub(super) fn on_disconnect(&self) -> OnDisconnect {
+        self.io.on_disconnect()
+    }
+
+    /// Check and reset outbound activity flag
+    pub(super) fn take_activity(&self) -> bool {
+        let flags = self.flags.get();
+        self.flags.set(flags - Flags::ACTIVITY);
+        flags.contains(Flags::ACTIVITY)
     }
 
     pub(super) fn credit(&self) -> usize {
@@ -122,16 +202,40 @@ impl MqttShared {
     }
 
     pub(super) fn next_id(&self) -> NonZeroU16 {
-        let idx = self.inflight_idx.get() + 1;
-        self.inflight_idx.set(idx);
-        let idx = if idx == u16::MAX {
-            self.inflight_idx.set(0);
-            u16::MAX
-        } else {
-            self.inflight_idx.set(idx);
-            idx
+        let mut queues = self.queues.borrow_mut();
+        if let Some(ref mut ids) = queues.ids {
+            if let Some(id) = ids.insert_lowest() {
+                return id;
+            }
+        }
+
+        // skip ids of in-flight packets
+        let start = self.inflight_idx.get();
+        let mut idx = start;
+        loop {
+            idx = idx.wrapping_add(1).max(1);
+            let id = NonZeroU16::new(idx).unwrap();
+            if !queues.inflight_ids.contains(&id) || idx == start {
+                self.inflight_idx.set(idx);
+                return id;
+            }
+        }
+    }
+
+    pub(super) fn set_initial_packet_id(&self, id: NonZeroU16) {
+        self.inflight_idx.set(id.get() - 1);
+    }
+
+    pub(super) fn set_packet_id_strategy(&self, strategy: PacketIdStrategy) {
+        let mut queues = self.queues.borrow_mut();
+        queues.ids = match strategy {
+            PacketIdStrategy::Monotonic => None,
+            PacketIdStrategy::LowestFree => {
+                let mut ids = PacketIdSet::new();
+                queues.inflight_ids.iter().for_each(|id| ids.insert(*id));
+                Some(ids)
+            }
         };
-        NonZeroU16::new(idx).unwrap()
     }
 
     pub(super) fn set_cap(&self, cap: usize) {
@@ -149,15 +253,235 @@ impl MqttShared {
         self.cap.set(cap);
     }
 
-    pub(super) fn set_publish_ack(&self, f: Box<dyn Fn(codec::PublishAck, bool)>) {
+    pub(super) fn set_publish_ack(
+        &self,
+        f: Box<dyn Fn(codec::PublishAck, bool, Duration)>,
+        timed: bool,
+    ) {
         let mut flags = self.flags.get();
         flags.insert(Flags::ON_PUBLISH_ACK);
+        flags.set(Flags::ACK_TIMED, timed);
         self.flags.set(flags);
         self.on_publish_ack.set(Some(f));
     }
 
+    pub(super) fn set_subscriptions_count(&self, count: SubscriptionsCount) {
+        self.subscriptions_count.set(Some(count));
+    }
+
+    pub(super) fn take_subscriptions_count(&self) -> Option<SubscriptionsCount> {
+        self.subscriptions_count.take()
+    }
+
+    pub(super) fn set_payload_transform(&self, f: Rc<PayloadTransform>) {
+        self.payload_transform.set(Some(f));
+    }
+
+    /// Check if payload transform is set
+    pub(super) fn has_payload_transform(&self) -> bool {
+        let f = self.payload_transform.take();
+        let result = f.is_some();
+        self.payload_transform.set(f);
+        result
+    }
+
+    /// Apply payload transform to outgoing publish packet
+    pub(super) fn transform_publish(&self, pkt: &mut codec::Publish) {
+        if let Some(f) = self.payload_transform.take() {
+            transform_payload(&*f, pkt);
+            self.payload_transform.set(Some(f));
+        }
+    }
+
     pub(super) fn encode_packet(&self, pkt: codec::Packet) -> Result<(), error::EncodeError> {
-        self.io.encode(pkt, &self.codec)
+        self.flush_queued();
+        self.io.encode(pkt, self)
+    }
+
+    /// Encode packet into queue buffer, queued packets are written on flush
+    pub(super) fn queue_packet(&self, pkt: codec::Packet) -> Result<(), error::EncodeError> {
+        self.flags.set(self.flags.get() | Flags::ACTIVITY);
+        self.codec.encode(pkt, &mut self.queued.borrow_mut())
+    }
+
+    /// Move queued packets to io write buffer
+    ///
+    /// Queued packets stay buffered while streamed publish payload is being written.
+    pub(super) fn flush_queued(&self) {
+        if !self.flags.get().contains(Flags::STREAMING) && !self.queued.borrow().is_empty() {
+            let buf = self.queued.take();
+            if let Err(err) = self.io.with_write_buf(|wbuf| wbuf.extend_from_slice(&buf)) {
+                log::error!("Cannot write queued packets: {:?}", err);
+            }
+        }
+    }
+
+    /// Write queued packets and wait until io write buffer is flushed
+    pub(super) async fn flush(&self) -> Result<(), SendPacketError> {
+        self.flush_queued();
+        loop {
+            if self.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+            match self.io.with_write_buf(|buf| buf.is_empty()) {
+                Ok(true) => return Ok(()),
+                Ok(false) => sleep(FLUSH_POLL_INTERVAL).await,
+                Err(_) => return Err(SendPacketError::Disconnected),
+            }
+        }
+    }
+
+    /// Write publish packet with streamed payload
+    ///
+    /// Fixed header is encoded with total packet size, then payload chunks are
+    /// written to io write buffer as it drains. Other packets are buffered until
+    /// payload is written. Connection is closed if stream yields more or less
+    /// than `size` bytes, because packet could not be recalled.
+    pub(super) async fn write_stream<S>(
+        &self,
+        pkt: codec::Publish,
+        size: u32,
+        mut stream: S,
+        ack: Option<AckType>,
+    ) -> Result<Option<pool::Receiver<Ack>>, SendPacketError>
+    where
+        S: Stream<Item = Bytes> + Unpin,
+    {
+        // only one streamed payload could be written at a time
+        while self.flags.get().contains(Flags::STREAMING) {
+            if self.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+            let (tx, rx) = self.pool.waiters.channel();
+            self.queues.borrow_mut().stream_waiters.push(tx);
+            if rx.await.is_err() {
+                return Err(SendPacketError::Disconnected);
+            }
+        }
+        if self.is_closed() {
+            return Err(SendPacketError::Disconnected);
+        }
+        let packet_id = pkt.packet_id;
+
+        match self.encode_stream_header(pkt, size) {
+            Ok(hdr) => {
+                let rx = match (packet_id, ack) {
+                    (Some(id), Some(ack)) => Some(self.wait_response(id, ack)?),
+                    _ => None,
+                };
+                self.flush_queued();
+                self.flags.set(self.flags.get() | Flags::STREAMING | Flags::ACTIVITY);
+
+                // partially written packet could not be recovered
+                let guard = OnDropFn::new(|| {
+                    self.flags.set(self.flags.get() - Flags::STREAMING);
+                    self.force_close();
+                });
+                let result = self.write_payload(hdr, size, &mut stream).await;
+                guard.cancel();
+                self.release_stream();
+
+                match result {
+                    Ok(_) => {
+                        self.flush_queued();
+                        Ok(rx)
+                    }
+                    Err(e) => {
+                        log::trace!("Cannot write streamed publish payload: {:?}", e);
+                        self.force_close();
+                        Err(e)
+                    }
+                }
+            }
+            Err(e) => {
+                if let Some(id) = packet_id {
+                    if let Some(ref mut ids) = self.queues.borrow_mut().ids {
+                        ids.remove(id);
+                    }
+                }
+                Err(SendPacketError::Encode(e))
+            }
+        }
+    }
+
+    /// Encode publish packet header, remaining length includes payload size
+    fn encode_stream_header(
+        &self,
+        pkt: codec::Publish,
+        size: u32,
+    ) -> Result<BytesMut, error::EncodeError> {
+        let mut buf = BytesMut::new();
+        self.codec.encode(codec::Packet::Publish(pkt), &mut buf)?;
+
+        let (len, consumed) = match decode_variable_length(&buf[1..]) {
+            Ok(Some(val)) => val,
+            _ => return Err(error::EncodeError::MalformedPacket),
+        };
+        let max_size = match self.codec.max_outbound_size() {
+            0 => MAX_PACKET_SIZE,
+            size => size,
+        };
+        let total = len as u64 + size as u64;
+        if total > max_size as u64 {
+            return Err(error::EncodeError::OverMaxPacketSize);
+        }
+
+        let mut hdr = BytesMut::with_capacity(buf.len() + 4);
+        hdr.extend_from_slice(&buf[..1]);
+        write_variable_length(total as u32, &mut hdr);
+        hdr.extend_from_slice(&buf[1 + consumed..]);
+        Ok(hdr)
+    }
+
+    /// Finish streamed payload, wake up waiting writers
+    fn release_stream(&self) {
+        self.flags.set(self.flags.get() - Flags::STREAMING);
+        for tx in self.queues.borrow_mut().stream_waiters.drain(..) {
+            let _ = tx.send(());
+        }
+    }
+
+    async fn write_payload<S>(
+        &self,
+        hdr: BytesMut,
+        size: u32,
+        stream: &mut S,
+    ) -> Result<(), SendPacketError>
+    where
+        S: Stream<Item = Bytes> + Unpin,
+    {
+        self.io
+            .with_write_buf(|buf| buf.extend_from_slice(&hdr))
+            .map_err(|_| SendPacketError::Disconnected)?;
+
+        let mut remaining = size as usize;
+        while let Some(chunk) = stream_recv(stream).await {
+            if chunk.len() > remaining {
+                return Err(SendPacketError::Encode(error::EncodeError::InvalidLength));
+            }
+            remaining -= chunk.len();
+            self.io
+                .with_write_buf(|buf| buf.extend_from_slice(&chunk))
+                .map_err(|_| SendPacketError::Disconnected)?;
+
+            // wait until dispatcher reports that io write buffer is drained
+            while self.io.is_wr_backpressure() {
+                let (tx, rx) = self.pool.waiters.channel();
+                self.queues.borrow_mut().wrb_waiters.push(tx);
+                if rx.await.is_err() {
+                    return Err(SendPacketError::Disconnected);
+                }
+            }
+            if self.is_closed() {
+                return Err(SendPacketError::Disconnected);
+            }
+        }
+
+        if remaining == 0 {
+            Ok(())
+        } else {
+            Err(SendPacketError::Encode(error::EncodeError::InvalidLength))
+        }
     }
 
     /// Close mqtt connection, dont send disconnect message
@@ -169,11 +493,24 @@ impl MqttShared {
     fn clear_queues(&self) {
         let mut queues = self.queues.borrow_mut();
         queues.waiters.clear();
+        queues.drain_waiters.clear();
+        queues.stream_waiters.clear();
+        queues.wrb_waiters.clear();
+        if let Some(ref mut ids) = queues.ids {
+            ids.clear();
+        }
+        queues.expired.clear();
+        self.rpc.borrow_mut().clear();
+        let cancelled = mem::take(&mut queues.cancelled);
+
+        let mut sent = mem::take(&mut queues.sent);
 
         if let Some(cb) = self.on_publish_ack.take() {
             for (idx, tx, _) in queues.inflight.drain(..) {
-                if tx.is_none() {
-                    (*cb)(codec::PublishAck { packet_id: idx, ..Default::default() }, true);
+                if tx.is_none() && !cancelled.contains(&idx) {
+                    let rtt = sent.remove(&idx).map(|t| t.elapsed()).unwrap_or_default();
+                    let pkt = codec::PublishAck { packet_id: idx, ..Default::default() };
+                    (*cb)(pkt, true, rtt);
                 }
             }
         } else {
@@ -181,6 +518,88 @@ impl MqttShared {
         }
     }
 
+    /// Register response waiter for request/response exchange
+    pub(super) fn rpc_register(
+        &self,
+        topic: ByteString,
+        correlation: Bytes,
+    ) -> oneshot::Receiver<Bytes> {
+        let (tx, rx) = oneshot::channel();
+        self.rpc.borrow_mut().insert(topic, (correlation, Some(tx)));
+        rx
+    }
+
+    pub(super) fn rpc_remove(&self, topic: &ByteString) {
+        self.rpc.borrow_mut().remove(topic);
+    }
+
+    /// Pass response publish to request waiter
+    ///
+    /// Returns `true` if publish is received on response topic of
+    /// active request. First publish with matching correlation data completes
+    /// request, other publishes on response topic are dropped.
+    pub(super) fn rpc_response(&self, pkt: &codec::Publish) -> bool {
+        let mut rpc = self.rpc.borrow_mut();
+        if let Some((correlation, tx)) = rpc.get_mut(&pkt.topic) {
+            if pkt.properties.correlation_data.as_ref() == Some(correlation) {
+                if let Some(tx) = tx.take() {
+                    let _ = tx.send(pkt.payload.clone());
+                    return true;
+                }
+            }
+            log::trace!("Drop unexpected response publish for {:?}", pkt.topic);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get notification when all in-flight packets get acknowledged
+    pub(super) fn wait_inflight(&self) -> Option<pool::Receiver<()>> {
+        let mut queues = self.queues.borrow_mut();
+        if queues.inflight.is_empty() {
+            None
+        } else {
+            let (tx, rx) = self.pool.waiters.channel();
+            queues.drain_waiters.push(tx);
+            Some(rx)
+        }
+    }
+
+    /// Ids of in-flight packets
+    pub(super) fn inflight_ids(&self) -> Vec<NonZeroU16> {
+        let queues = self.queues.borrow();
+        queues
+            .inflight
+            .iter()
+            .map(|item| item.0)
+            .filter(|id| !queues.cancelled.contains(id))
+            .collect()
+    }
+
+    /// Cancel in-flight QoS 1 publish
+    ///
+    /// Publish future resolves with `SendPacketError::Cancelled`, packet id
+    /// stays reserved until peer acknowledges the packet.
+    pub(super) fn cancel(&self, id: NonZeroU16) -> bool {
+        let mut queues = self.queues.borrow_mut();
+        let tx = queues.inflight.iter_mut().find_map(|(idx, tx, tp)| {
+            if *idx == id && matches!(tp, AckType::Publish) {
+                tx.take()
+            } else {
+                None
+            }
+        });
+        if let Some(tx) = tx {
+            log::trace!("Cancel in-flight publish: {:?}", id);
+            queues.cancelled.insert(id);
+            let _ = tx.send(Ack::Cancelled(id));
+            true
+        } else {
+            false
+        }
+    }
+
     pub(super) fn enable_wr_backpressure(&self) {
         let mut flags = self.flags.get();
         flags.insert(Flags::WRB_ENABLED);
@@ -192,8 +611,13 @@ impl MqttShared {
         flags.remove(Flags::WRB_ENABLED);
         self.flags.set(flags);
 
-        // check if there are waiters
         let mut queues = self.queues.borrow_mut();
+        // wake up streamed payload writer
+        for tx in queues.wrb_waiters.drain(..) {
+            let _ = tx.send(());
+        }
+
+        // check if there are waiters
         if queues.inflight.len() < self.cap.get() {
             let mut num = self.cap.get() - queues.inflight.len();
             while num > 0 {
@@ -208,6 +632,75 @@ impl MqttShared {
         }
     }
 
+    /// Effective client id of the connection
+    pub(super) fn client_id(&self) -> &ByteString {
+        self.client_id.get_or_init(ByteString::new)
+    }
+
+    pub(super) fn set_client_id(&self, id: ByteString) {
+        let _ = self.client_id.set(id);
+    }
+
+    /// Keep-alive timeout of the connection dispatcher
+    pub(super) fn keepalive(&self) -> &KeepAlive {
+        &self.keepalive
+    }
+
+    pub(super) fn set_will(&self, will: Option<codec::LastWill>) {
+        self.will.set(will);
+    }
+
+    pub(super) fn take_will(&self) -> Option<codec::LastWill> {
+        self.will.take()
+    }
+
+    /// Session expiry interval in seconds
+    pub(super) fn session_expiry(&self) -> u32 {
+        self.session_expiry.get()
+    }
+
+    pub(super) fn set_session_expiry(&self, val: u32) {
+        self.session_expiry.set(val);
+    }
+
+    /// Default timeout for subscribe and unsubscribe acks
+    pub(super) fn ack_timeout(&self) -> Millis {
+        self.ack_timeout.get()
+    }
+
+    pub(super) fn set_ack_timeout(&self, timeout: Millis) {
+        self.ack_timeout.set(timeout);
+    }
+
+    /// Stop waiting for ack of in-flight packet
+    ///
+    /// Packet id is released, late ack from the peer is ignored.
+    pub(super) fn expire(&self, id: NonZeroU16) {
+        let mut queues = self.queues.borrow_mut();
+        if let Some(pos) = queues.inflight.iter().position(|item| item.0 == id) {
+            log::trace!("Packet ack is expired: {:?}", id);
+            queues.inflight.remove(pos);
+            queues.inflight_ids.remove(&id);
+            queues.sent.remove(&id);
+            if let Some(ref mut ids) = queues.ids {
+                ids.remove(id);
+            }
+            queues.expired.insert(id);
+
+            // wake up queued request (receive max limit)
+            while let Some(tx) = queues.waiters.pop_front() {
+                if tx.send(()).is_ok() {
+                    break;
+                }
+            }
+            if queues.inflight.is_empty() {
+                for tx in queues.drain_waiters.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+        }
+    }
+
     pub(super) fn pkt_ack(&self, ack: Ack) -> Result<(), error::ProtocolError> {
         self.pkt_ack_inner(ack).map_err(|e| {
             self.close(codec::Disconnect {
@@ -221,6 +714,20 @@ impl MqttShared {
     fn pkt_ack_inner(&self, pkt: Ack) -> Result<(), error::ProtocolError> {
         let mut queues = self.queues.borrow_mut();
 
+        // late ack for expired packet
+        if queues.expired.remove(&pkt.packet_id())
+            && queues.inflight.front().map(|item| item.0) != Some(pkt.packet_id())
+        {
+            log::trace!("Ack for expired packet: {}", pkt.packet_id());
+            return Ok(());
+        }
+
+        // late or spurious ack, packet id is not in-flight
+        if !queues.inflight_ids.contains(&pkt.packet_id()) {
+            log::trace!("Drop ack for unknown packet id: {}", pkt.packet_id());
+            return Ok(());
+        }
+
         // check ack order
         if let Some((idx, tx, tp)) = queues.inflight.pop_front() {
             if idx != pkt.packet_id() {
@@ -234,15 +741,46 @@ impl MqttShared {
                 // get publish ack channel
                 log::trace!("Ack packet with id: {}", pkt.packet_id());
 
+                // successful PUBREC, packet id stays reserved until PUBCOMP [MQTT-4.3.3]
+                if let (Ack::Receive(ref ack), AckType::Receive) = (&pkt, tp) {
+                    if u8::from(ack.reason_code) < 0x80 {
+                        let rel = codec::Packet::PublishRelease(codec::PublishAck2 {
+                            packet_id: idx,
+                            ..Default::default()
+                        });
+                        match self.encode_packet(rel) {
+                            Ok(_) => {
+                                queues.inflight.push_back((idx, tx, AckType::Complete));
+                                return Ok(());
+                            }
+                            Err(err) => {
+                                // dropped ack channel resolves publish future with error
+                                log::error!("Cannot encode PUBREL packet: {:?}", err);
+                                queues.inflight_ids.remove(&idx);
+                                queues.sent.remove(&idx);
+                                if let Some(ref mut ids) = queues.ids {
+                                    ids.remove(idx);
+                                }
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+
                 // cleanup ack queue
                 queues.inflight_ids.remove(&pkt.packet_id());
+                let sent = queues.sent.remove(&pkt.packet_id());
+                if let Some(ref mut ids) = queues.ids {
+                    ids.remove(pkt.packet_id());
+                }
 
                 if pkt.is_match(tp) {
                     if let Some(tx) = tx {
                         let _ = tx.send(pkt);
-                    } else {
+                    } else if !queues.cancelled.remove(&pkt.packet_id()) {
                         let cb = self.on_publish_ack.take().unwrap();
-                        (*cb)(pkt.publish(), false);
+                        let rtt = sent.map(|t| t.elapsed()).unwrap_or_default();
+                        (*cb)(pkt.publish(), false, rtt);
                         self.on_publish_ack.set(Some(cb));
                     }
 
@@ -252,6 +790,13 @@ impl MqttShared {
                             break;
                         }
                     }
+
+                    // notify graceful close
+                    if queues.inflight.is_empty() {
+                        for tx in queues.drain_waiters.drain(..) {
+                            let _ = tx.send(());
+                        }
+                    }
                     Ok(())
                 } else {
                     log::trace!("MQTT protocol error, unexpeted packet");
@@ -282,6 +827,9 @@ impl MqttShared {
             let (tx, rx) = self.pool.queue.channel();
             queues.inflight.push_back((id, Some(tx), ack));
             queues.inflight_ids.insert(id);
+            if let Some(ref mut ids) = queues.ids {
+                ids.insert(id);
+            }
             Ok(rx)
         }
     }
@@ -297,14 +845,22 @@ impl MqttShared {
         if queues.inflight_ids.contains(&id) {
             Err(SendPacketError::PacketIdInUse(id))
         } else {
-            match self.io.encode(pkt, &self.codec) {
+            match self.encode_packet(pkt) {
                 Ok(_) => {
                     let (tx, rx) = self.pool.queue.channel();
                     queues.inflight.push_back((id, Some(tx), ack));
                     queues.inflight_ids.insert(id);
+                    if let Some(ref mut ids) = queues.ids {
+                        ids.insert(id);
+                    }
                     Ok(rx)
                 }
-                Err(e) => Err(SendPacketError::Encode(e)),
+                Err(e) => {
+                    if let Some(ref mut ids) = queues.ids {
+                        ids.remove(id);
+                    }
+                    Err(SendPacketError::Encode(e))
+                }
             }
         }
     }
@@ -319,13 +875,24 @@ impl MqttShared {
         if queues.inflight_ids.contains(&id) {
             Err(SendPacketError::PacketIdInUse(id))
         } else {
-            match self.io.encode(pkt, &self.codec) {
+            match self.encode_packet(pkt) {
                 Ok(_) => {
                     queues.inflight.push_back((id, None, ack));
                     queues.inflight_ids.insert(id);
+                    if self.flags.get().contains(Flags::ACK_TIMED) {
+                        queues.sent.insert(id, Instant::now());
+                    }
+                    if let Some(ref mut ids) = queues.ids {
+                        ids.insert(id);
+                    }
                     Ok(())
                 }
-                Err(e) => Err(SendPacketError::Encode(e)),
+                Err(e) => {
+                    if let Some(ref mut ids) = queues.ids {
+                        ids.remove(id);
+                    }
+                    Err(SendPacketError::Encode(e))
+                }
             }
         }
     }
@@ -351,7 +918,13 @@ impl Encoder for MqttShared {
 
     #[inline]
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.codec.encode(item, dst)
+        self.flags.set(self.flags.get() | Flags::ACTIVITY);
+        if self.flags.get().contains(Flags::STREAMING) {
+            // packets could not be interleaved with streamed payload
+            self.codec.encode(item, &mut self.queued.borrow_mut())
+        } else {
+            self.codec.encode(item, dst)
+        }
     }
 }
 
@@ -368,30 +941,41 @@ impl Decoder for MqttShared {
 #[derive(Copy, Clone)]
 pub(super) enum AckType {
     Publish,
+    Receive,
+    Complete,
     Subscribe,
     Unsubscribe,
 }
 
 pub(super) enum Ack {
     Publish(codec::PublishAck),
+    Receive(codec::PublishAck),
+    Complete(codec::PublishAck2),
     Subscribe(codec::SubscribeAck),
     Unsubscribe(codec::UnsubscribeAck),
+    Cancelled(NonZeroU16),
 }
 
 impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) => packet_type::PUBCOMP,
             Ack::Subscribe(_) => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
+            Ack::Cancelled(_) => packet_type::PUBACK,
         }
     }
 
     pub(super) fn packet_id(&self) -> NonZeroU16 {
         match self {
             Ack::Publish(ref pkt) => pkt.packet_id,
+            Ack::Receive(ref pkt) => pkt.packet_id,
+            Ack::Complete(ref pkt) => pkt.packet_id,
             Ack::Subscribe(ref pkt) => pkt.packet_id,
             Ack::Unsubscribe(ref pkt) => pkt.packet_id,
+            Ack::Cancelled(id) => *id,
         }
     }
 
@@ -422,6 +1006,8 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
+            (Ack::Complete(_), AckType::Complete) => true,
             (Ack::Subscribe(_), AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -433,6 +1019,8 @@ impl AckType {
     pub(super) fn expected_str(&self) -> &'static str {
         match self {
             AckType::Publish => "Expected PUBACK packet",
+            AckType::Receive => "Expected PUBREC packet",
+            AckType::Complete => "Expected PUBCOMP packet",
             AckType::Subscribe => "Expected SUBACK packet",
             AckType::Unsubscribe => "Expected UNSUBACK packet",
         }
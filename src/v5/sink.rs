@@ -1,12 +1,20 @@
-use std::{fmt, future::ready, future::Future, num::NonZeroU16, num::NonZeroU32, rc::Rc};
+use std::hash::{BuildHasher, Hasher};
+use std::{cell::Cell, collections::hash_map::RandomState, fmt, future::ready, future::Future};
+use std::{num::NonZeroU16, num::NonZeroU32, rc::Rc, time::Duration};
 
 use ntex_bytes::{ByteString, Bytes};
-use ntex_util::future::{Either, Ready};
+use ntex_util::time::{sleep, timeout_checked, Millis, Seconds};
+use ntex_util::{future::select, future::Either, future::Ready, Stream};
+use serde::Serialize;
 
 use super::{
-    codec, codec::EncodeLtd, error::SendPacketError, shared::AckType, shared::MqttShared,
+    codec, codec::EncodeLtd, error::EncodeError, error::RpcError, error::SendPacketError,
+    publish, shared::Ack, shared::AckType, shared::MqttShared,
 };
-use crate::types::QoS;
+use crate::types::{PacketIdStrategy, QoS};
+
+/// Default response timeout for `MqttSink::rpc()`
+const DEFAULT_RPC_TIMEOUT: Millis = Millis(30_000);
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -47,6 +55,44 @@ impl MqttSink {
         self.0.credit()
     }
 
+    /// Set packet id allocation strategy
+    ///
+    /// With `PacketIdStrategy::LowestFree` sink reuses lowest packet id that
+    /// is not in-flight, instead of incrementing packet id for every packet.
+    ///
+    /// By default `PacketIdStrategy::Monotonic` is used.
+    pub fn packet_id_strategy(&self, strategy: PacketIdStrategy) {
+        self.0.set_packet_id_strategy(strategy);
+    }
+
+    /// Set packet id for next allocated packet id
+    ///
+    /// Sink continues to allocate ids from `id`, ids of in-flight packets
+    /// are skipped. Setting is ignored by `PacketIdStrategy::LowestFree` strategy.
+    ///
+    /// By default sink starts from packet id 1.
+    pub fn initial_packet_id(&self, id: NonZeroU16) {
+        self.0.set_initial_packet_id(id);
+    }
+
+    /// Ids of in-flight packets, packets that are sent to the peer but
+    /// are not acknowledged yet
+    pub fn inflight_ids(&self) -> Vec<NonZeroU16> {
+        self.0.inflight_ids()
+    }
+
+    /// Cancel in-flight QoS 1 publish
+    ///
+    /// Publish future resolves with `SendPacketError::Cancelled` error.
+    /// If PUBLISH packet is already written to the socket, it could not be recalled,
+    /// cancellation only stops tracking of the acknowledgement. Packet id stays
+    /// reserved until peer acknowledges the packet.
+    ///
+    /// Returns `false` if there is no in-flight publish with such id.
+    pub fn cancel(&self, packet_id: NonZeroU16) -> bool {
+        self.0.cancel(packet_id)
+    }
+
     /// Get notification when packet could be send to the peer.
     ///
     /// Result indicates if connection is alive
@@ -61,6 +107,35 @@ impl MqttSink {
         }
     }
 
+    /// Gracefully close mqtt connection
+    ///
+    /// Waits until all in-flight packets get acknowledged by the peer, then
+    /// closes connection with `DISCONNECT` packet. Write buffer is flushed before closing.
+    /// If acknowledgements are not received within `timeout`, connection get
+    /// force closed and ids of unacknowledged packets are returned.
+    /// Empty list is returned if connection get closed while waiting.
+    ///
+    /// Zero `timeout` means wait indefinitely.
+    pub async fn close_graceful<T: Into<Millis>>(
+        &self,
+        timeout: T,
+    ) -> Result<(), Vec<NonZeroU16>> {
+        if let Some(rx) = self.0.wait_inflight() {
+            match timeout_checked(timeout, rx).await {
+                Ok(Ok(())) => (),
+                Ok(Err(_)) => return Err(Vec::new()),
+                Err(_) => {
+                    let ids = self.0.inflight_ids();
+                    log::trace!("Graceful close timeout, unacknowledged packets: {:?}", ids);
+                    self.force_close();
+                    return Err(ids);
+                }
+            }
+        }
+        self.close();
+        Ok(())
+    }
+
     #[inline]
     /// Force close MQTT connection. Dispatcher does not wait for uncompleted
     /// responses (ending them with error), but it flushes buffers.
@@ -80,6 +155,13 @@ impl MqttSink {
         self.0.close(pkt);
     }
 
+    /// Write queued packets to the connection
+    ///
+    /// Returned future resolves when io write buffer is written to the socket.
+    pub async fn flush(&self) -> Result<(), SendPacketError> {
+        self.0.flush().await
+    }
+
     /// Send ping
     pub(super) fn ping(&self) -> bool {
         self.0.encode_packet(codec::Packet::PingRequest).is_ok()
@@ -102,6 +184,158 @@ impl MqttSink {
         })
     }
 
+    /// Create publish message builder with `application/json` encoded payload
+    ///
+    /// Sets `Content Type` property of the publish packet.
+    pub fn publish_json<U, T>(
+        &self,
+        topic: U,
+        value: &T,
+    ) -> Result<PublishBuilder, serde_json::Error>
+    where
+        ByteString: From<U>,
+        T: Serialize + ?Sized,
+    {
+        let payload = serde_json::to_vec(value)?;
+        Ok(self.publish(topic, Bytes::from(payload)).properties(|props| {
+            props.content_type = Some(ByteString::from_static("application/json"));
+        }))
+    }
+
+    /// Create publish builder with batched payload
+    ///
+    /// Messages are encoded as sequence of length prefixed messages, each
+    /// prefix is 4 bytes big-endian length. Sets `batch=1` user property,
+    /// receiver decodes messages with `Publish::iter_batch()`.
+    pub fn publish_batch<U>(&self, topic: U, messages: Vec<Bytes>) -> PublishBuilder
+    where
+        ByteString: From<U>,
+    {
+        self.publish(topic, publish::encode_batch(messages)).properties(|props| {
+            props.user_properties.push((
+                ByteString::from_static(publish::BATCH_PROPERTY.0),
+                ByteString::from_static(publish::BATCH_PROPERTY.1),
+            ));
+        })
+    }
+
+    /// Publish message with streamed payload
+    ///
+    /// Fixed header is encoded with total payload `size`, then payload chunks are
+    /// written to the connection as io write buffer drains. Packets sent while
+    /// payload is being written are buffered. Connection is closed if stream
+    /// yields more or less than `size` bytes, or if future is dropped before
+    /// payload is written. Returned future resolves when payload
+    /// is written for QoS 0, and when QoS 1 or QoS 2 flow is completed otherwise.
+    /// Fails with `SendPacketError::TransformUnsupported` if payload transform is set.
+    pub async fn publish_stream<U, S>(
+        &self,
+        topic: U,
+        qos: QoS,
+        size: u32,
+        stream: S,
+    ) -> Result<(), SendPacketError>
+    where
+        ByteString: From<U>,
+        S: Stream<Item = Bytes> + Unpin,
+    {
+        let shared = &self.0;
+        if shared.has_payload_transform() {
+            return Err(SendPacketError::TransformUnsupported);
+        }
+        let mut packet = codec::Publish {
+            qos,
+            dup: false,
+            retain: false,
+            topic: topic.into(),
+            packet_id: None,
+            payload: Bytes::new(),
+            properties: codec::PublishProperties::default(),
+        };
+
+        if qos == QoS::AtMostOnce {
+            log::trace!("Publish stream (QoS-0) to {:?}", packet.topic);
+            return shared.write_stream(packet, size, stream, None).await.map(|_| ());
+        }
+
+        // handle client receive maximum
+        if let Some(rx) = shared.wait_readiness() {
+            if rx.await.is_err() {
+                return Err(SendPacketError::Disconnected);
+            }
+        }
+        let idx = shared.next_id();
+        packet.packet_id = Some(idx);
+        log::trace!("Publish stream ({:?}) to {:?}", qos, packet.topic);
+
+        let ack_type =
+            if qos == QoS::AtLeastOnce { AckType::Publish } else { AckType::Receive };
+        let ack = match shared.write_stream(packet, size, stream, Some(ack_type)).await? {
+            Some(rx) => rx.await,
+            None => return Ok(()),
+        };
+        // PUBREL is sent by the ack handler, qos 2 flow resolves on PUBCOMP
+        match ack {
+            Ok(Ack::Cancelled(_)) => Err(SendPacketError::Cancelled),
+            Ok(Ack::Complete(_)) => Ok(()),
+            Ok(Ack::Publish(ack)) | Ok(Ack::Receive(ack)) => {
+                // failure reason code terminates publish flow
+                if u8::from(ack.reason_code) >= 0x80 {
+                    Err(SendPacketError::PublishRejected(ack.reason_code))
+                } else {
+                    Ok(())
+                }
+            }
+            Ok(_) | Err(_) => Err(SendPacketError::Disconnected),
+        }
+    }
+
+    #[inline]
+    /// Create empty publish packet builder
+    ///
+    /// Topic, payload and QoS are set with builder's methods.
+    pub fn publish_builder(&self) -> PublishBuilder {
+        self.publish(ByteString::new(), Bytes::new())
+    }
+
+    #[inline]
+    /// Create publish packet builder for request/response interaction
+    ///
+    /// Sets `Response Topic` and `Correlation Data` properties of the publish packet.
+    pub fn request<U, R>(
+        &self,
+        topic: U,
+        payload: Bytes,
+        response_topic: R,
+        correlation_data: Bytes,
+    ) -> PublishBuilder
+    where
+        ByteString: From<U> + From<R>,
+    {
+        self.publish(topic, payload).properties(|props| {
+            props.response_topic = Some(response_topic.into());
+            props.correlation_data = Some(correlation_data);
+        })
+    }
+
+    #[inline]
+    /// Create request/response exchange builder
+    ///
+    /// Request is published with unique `Response Topic` and `Correlation Data`
+    /// properties. Sink subscribes to response topic, waits for the first
+    /// response with matching correlation data and unsubscribes. Available
+    /// for client connections only.
+    pub fn rpc<U>(&self, topic: U, payload: Bytes) -> RpcBuilder
+    where
+        ByteString: From<U>,
+    {
+        RpcBuilder {
+            publish: self.publish(topic, payload),
+            prefix: ByteString::from_static("rpc/response"),
+            timeout: DEFAULT_RPC_TIMEOUT,
+        }
+    }
+
     #[inline]
     /// Create publish builder with publish packet
     pub fn publish_pkt(&self, packet: codec::Publish) -> PublishBuilder {
@@ -116,7 +350,35 @@ impl MqttSink {
     where
         F: Fn(codec::PublishAck, bool) + 'static,
     {
-        self.0.set_publish_ack(Box::new(f));
+        self.0
+            .set_publish_ack(Box::new(move |ack, disconnected, _| f(ack, disconnected)), false);
+    }
+
+    /// Set publish ack callback with round-trip latency
+    ///
+    /// Same as `publish_ack_cb()`, third argument is time elapsed between
+    /// sending publish packet and receiving its ack. For packets that are not
+    /// acked due to disconnect, it is time elapsed since packet was sent.
+    pub fn publish_ack_cb_timed<F>(&self, f: F)
+    where
+        F: Fn(codec::PublishAck, bool, Duration) + 'static,
+    {
+        self.0.set_publish_ack(Box::new(f), true);
+    }
+
+    /// Set payload transform function
+    ///
+    /// Transform function is applied to every outgoing publish packet before it
+    /// gets encoded, it receives publish topic and payload and returns new payload
+    /// and optional properties. Returned properties replace content type and
+    /// payload format indicator of the packet, user properties are appended.
+    /// Streamed payloads could not be transformed, `publish_stream()` fails
+    /// if transform is set. By default payload is sent as is.
+    pub fn with_payload_transform<F>(&self, f: F)
+    where
+        F: Fn(&ByteString, Bytes) -> (Bytes, Option<codec::PublishProperties>) + 'static,
+    {
+        self.0.set_payload_transform(Rc::new(f));
     }
 
     #[inline]
@@ -124,6 +386,7 @@ impl MqttSink {
     pub fn subscribe(&self, id: Option<NonZeroU32>) -> SubscribeBuilder {
         SubscribeBuilder {
             id: None,
+            timeout: None,
             packet: codec::Subscribe {
                 id,
                 packet_id: NonZeroU16::new(1).unwrap(),
@@ -139,6 +402,7 @@ impl MqttSink {
     pub fn unsubscribe(&self) -> UnsubscribeBuilder {
         UnsubscribeBuilder {
             id: None,
+            timeout: None,
             packet: codec::Unsubscribe {
                 packet_id: NonZeroU16::new(1).unwrap(),
                 user_properties: Vec::new(),
@@ -155,6 +419,86 @@ impl fmt::Debug for MqttSink {
     }
 }
 
+impl<St> crate::Session<MqttSink, St> {
+    #[inline]
+    /// Effective client id of the connection
+    ///
+    /// Client id is provided by the client or assigned by the server.
+    pub fn client_id(&self) -> &ByteString {
+        self.sink().0.client_id()
+    }
+
+    /// Disconnect the client with reason code and reason string.
+    ///
+    /// DISCONNECT packet is queued after already queued packets, then write
+    /// buffer is flushed and connection is closed. Could be called from any
+    /// service, for example from publish service.
+    pub fn disconnect_with(
+        &self,
+        code: codec::DisconnectReasonCode,
+        reason: Option<ByteString>,
+    ) {
+        let pkt = codec::Disconnect { reason_string: reason, ..codec::Disconnect::new(code) };
+        self.sink().close_with_reason(pkt);
+    }
+
+    /// Override keep-alive timeout of the connection.
+    ///
+    /// New timeout applies next time keep-alive timer starts, negotiated
+    /// keep-alive value is not changed. Value is not limited by server's
+    /// dead peer timeout. Timeout shorter than client's keep-alive interval
+    /// may cause premature disconnects. To disable keep-alive set value to 0.
+    pub fn set_keep_alive(&self, timeout: Seconds) {
+        self.sink().0.keepalive().set(timeout);
+    }
+
+    #[inline]
+    /// Session expiry interval in seconds
+    ///
+    /// Value is requested by the client in CONNECT packet, could be overridden
+    /// by the server in CONNACK packet and updated by the client with DISCONNECT
+    /// packet. DISCONNECT update is applied before `Control::Disconnect`
+    /// message is passed to control service.
+    pub fn session_expiry_interval(&self) -> u32 {
+        self.sink().0.session_expiry()
+    }
+
+    /// Schedule application level heartbeat.
+    ///
+    /// QoS 0 publish with `payload` is sent to `topic` if nothing was sent
+    /// to the peer during last `interval`. Any outbound packet resets heartbeat
+    /// timer. Heartbeat stops when connection is closed.
+    ///
+    /// Heartbeat is not related to protocol level PINGREQ/PINGRESP packets.
+    pub fn schedule_heartbeat<U>(&self, topic: U, interval: Seconds, payload: Bytes)
+    where
+        ByteString: From<U>,
+    {
+        if interval.is_zero() {
+            return;
+        }
+        let sink = self.sink().clone();
+        let topic = ByteString::from(topic);
+        sink.0.take_activity();
+
+        ntex_util::spawn(async move {
+            loop {
+                if let Either::Right(_) = select(sleep(interval), sink.0.on_disconnect()).await
+                {
+                    break;
+                }
+                if !sink.0.take_activity() {
+                    log::trace!("Sending heartbeat to {:?}", topic);
+                    let _ = sink
+                        .publish::<ByteString>(topic.clone(), payload.clone())
+                        .send_at_most_once();
+                    sink.0.take_activity();
+                }
+            }
+        });
+    }
+}
+
 pub struct PublishBuilder {
     shared: Rc<MqttShared>,
     packet: codec::Publish,
@@ -165,8 +509,13 @@ impl PublishBuilder {
     /// Set packet id.
     ///
     /// Note: if packet id is not set, it gets generated automatically.
+    /// User supplied id bypasses sink's id allocator, this is useful for proxies
+    /// that preserve packet ids between two connections. Sending fails with
+    /// `SendPacketError::PacketIdInUse` if the same id is already in-flight.
+    ///
     /// Packet id management should not be mixed, it should be auto-generated
-    /// or set by user. Otherwise collisions could occure.
+    /// or set by user. Allocator is not aware of user supplied ids, so auto-generated
+    /// id could collide with in-flight user id and publish would fail.
     ///
     /// panics if id is 0
     pub fn packet_id(mut self, id: u16) -> Self {
@@ -175,6 +524,30 @@ impl PublishBuilder {
         self
     }
 
+    #[inline]
+    /// Set publish topic
+    pub fn topic<U>(mut self, topic: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.packet.topic = topic.into();
+        self
+    }
+
+    #[inline]
+    /// Set publish payload
+    pub fn payload(mut self, payload: Bytes) -> Self {
+        self.packet.payload = payload;
+        self
+    }
+
+    #[inline]
+    /// Set publish QoS, used by `send()` method
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.packet.qos = qos;
+        self
+    }
+
     #[inline]
     /// This might be re-delivery of an earlier attempt to send the Packet.
     pub fn dup(mut self, val: bool) -> Self {
@@ -184,6 +557,10 @@ impl PublishBuilder {
 
     #[inline]
     /// Set retain flag
+    ///
+    /// Sink sends retain flag as is. Retained messages delivered on subscription
+    /// must have retain flag set, see `SubscriptionOptions::forward_retain()`
+    /// for live messages.
     pub fn retain(mut self, val: bool) -> Self {
         self.packet.retain = val;
         self
@@ -216,10 +593,14 @@ impl PublishBuilder {
 
     #[inline]
     /// Send publish packet with QoS 0
+    ///
+    /// Previously queued packets are written first, so this is equivalent
+    /// to `queue()` followed by [`MqttSink::flush`].
     pub fn send_at_most_once(mut self) -> Result<(), SendPacketError> {
         if !self.shared.is_closed() {
             log::trace!("Publish (QoS-0) to {:?}", self.packet.topic);
             self.packet.qos = QoS::AtMostOnce;
+            self.shared.transform_publish(&mut self.packet);
             self.shared
                 .encode_packet(codec::Packet::Publish(self.packet))
                 .map_err(SendPacketError::Encode)
@@ -230,6 +611,40 @@ impl PublishBuilder {
         }
     }
 
+    /// Queue publish packet with QoS 0
+    ///
+    /// Packet is encoded and buffered, but it is not written to the connection
+    /// until [`MqttSink::flush`] is called or next packet is sent.
+    pub fn queue(mut self) -> Result<(), SendPacketError> {
+        if !self.shared.is_closed() {
+            log::trace!("Queue publish (QoS-0) to {:?}", self.packet.topic);
+            self.packet.qos = QoS::AtMostOnce;
+            self.shared.transform_publish(&mut self.packet);
+            self.shared
+                .queue_packet(codec::Packet::Publish(self.packet))
+                .map_err(SendPacketError::Encode)
+        } else {
+            log::error!("Mqtt sink is disconnected");
+            Err(SendPacketError::Disconnected)
+        }
+    }
+
+    /// Send publish packet with configured QoS
+    ///
+    /// Packet id is allocated for QoS 1 and QoS 2 publishes. QoS 2 publish
+    /// completes after PUBCOMP is received, see `send_exactly_once()`.
+    pub async fn send(self) -> Result<PublishAcked, SendPacketError> {
+        if self.packet.topic.is_empty() && self.packet.properties.topic_alias.is_none() {
+            return Err(SendPacketError::Encode(EncodeError::MalformedPacket));
+        }
+
+        match self.packet.qos {
+            QoS::AtMostOnce => self.send_at_most_once().map(|_| PublishAcked::AtMostOnce),
+            QoS::AtLeastOnce => self.send_at_least_once().await.map(PublishAcked::AtLeastOnce),
+            QoS::ExactlyOnce => self.send_exactly_once().await.map(PublishAcked::ExactlyOnce),
+        }
+    }
+
     /// Send publish packet with QoS 1
     pub fn send_at_least_once(
         self,
@@ -268,6 +683,7 @@ impl PublishBuilder {
             }
             let mut packet = self.packet;
             packet.qos = codec::QoS::AtLeastOnce;
+            shared.transform_publish(&mut packet);
 
             // packet id
             let idx = if let Some(idx) = packet.packet_id {
@@ -293,6 +709,8 @@ impl PublishBuilder {
         mut packet: codec::Publish,
         shared: Rc<MqttShared>,
     ) -> impl Future<Output = Result<codec::PublishAck, SendPacketError>> {
+        shared.transform_publish(&mut packet);
+
         // packet id
         let idx = if let Some(idx) = packet.packet_id {
             idx
@@ -307,13 +725,75 @@ impl PublishBuilder {
 
         let rx =
             shared.wait_packet_response(idx, AckType::Publish, codec::Packet::Publish(packet));
-        async move { rx?.await.map(|pkt| pkt.publish()).map_err(|_| SendPacketError::Disconnected) }
+        async move {
+            match rx?.await {
+                Ok(Ack::Cancelled(_)) => Err(SendPacketError::Cancelled),
+                Ok(pkt) => Ok(pkt.publish()),
+                Err(_) => Err(SendPacketError::Disconnected),
+            }
+        }
+    }
+
+    /// Send publish packet with QoS 2
+    ///
+    /// Sink waits for PUBREC, sends PUBREL and waits for PUBCOMP. If peer
+    /// responds with PUBREC with failure reason code, QoS 2 flow is terminated
+    /// and `SendPacketError::PublishRejected` error is returned.
+    pub async fn send_exactly_once(self) -> Result<codec::PublishAck2, SendPacketError> {
+        if self.shared.is_closed() {
+            return Err(SendPacketError::Disconnected);
+        }
+        let shared = self.shared;
+        let mut packet = self.packet;
+        packet.qos = QoS::ExactlyOnce;
+
+        // handle client receive maximum
+        if let Some(rx) = shared.wait_readiness() {
+            if rx.await.is_err() {
+                return Err(SendPacketError::Disconnected);
+            }
+        }
+        shared.transform_publish(&mut packet);
+
+        // packet id
+        let idx = if let Some(idx) = packet.packet_id {
+            idx
+        } else {
+            let idx = shared.next_id();
+            packet.packet_id = Some(idx);
+            idx
+        };
+        log::trace!("Publish (QoS2) to {:#?}", packet);
+
+        // shared state sends PUBREL on successful PUBREC and keeps packet id
+        // reserved until PUBCOMP, channel resolves with PUBCOMP or failed PUBREC
+        let rx =
+            shared.wait_packet_response(idx, AckType::Receive, codec::Packet::Publish(packet));
+        match rx?.await {
+            Ok(Ack::Complete(pkt)) => Ok(pkt),
+            // failure reason code terminates QoS 2 flow [MQTT-4.3.3]
+            Ok(Ack::Receive(pkt)) => Err(SendPacketError::PublishRejected(pkt.reason_code)),
+            Ok(Ack::Cancelled(_)) => Err(SendPacketError::Cancelled),
+            Ok(_) | Err(_) => Err(SendPacketError::Disconnected),
+        }
     }
 }
 
+#[derive(Debug, Clone)]
+/// Result of publish sent with `PublishBuilder::send()`
+pub enum PublishAcked {
+    /// QoS 0 publish is written to the peer, there is no ack
+    AtMostOnce,
+    /// PUBACK of QoS 1 publish
+    AtLeastOnce(codec::PublishAck),
+    /// PUBCOMP of QoS 2 publish
+    ExactlyOnce(codec::PublishAck2),
+}
+
 /// Subscribe packet builder
 pub struct SubscribeBuilder {
     id: Option<NonZeroU16>,
+    timeout: Option<Millis>,
     packet: codec::Subscribe,
     shared: Rc<MqttShared>,
 }
@@ -350,6 +830,17 @@ impl SubscribeBuilder {
         self
     }
 
+    #[inline]
+    /// Set ack timeout
+    ///
+    /// If ack is not received within timeout, `SendPacketError::Timeout` is returned
+    /// and packet id is released. By default client handshake timeout is used,
+    /// zero timeout disables timer.
+    pub fn timeout<T: Into<Millis>>(mut self, timeout: T) -> Self {
+        self.timeout = Some(timeout.into());
+        self
+    }
+
     #[inline]
     /// Get size of the subscribe packet
     pub fn size(&self) -> u32 {
@@ -375,13 +866,21 @@ impl SubscribeBuilder {
             // send subscribe to client
             log::trace!("Sending subscribe packet {:#?}", packet);
 
-            let rx = shared.wait_response(packet.packet_id, AckType::Subscribe)?;
+            let idx = packet.packet_id;
+            let rx = shared.wait_response(idx, AckType::Subscribe)?;
             match shared.encode_packet(codec::Packet::Subscribe(packet)) {
                 Ok(_) => {
                     // wait ack from peer
-                    rx.await
-                        .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.subscribe())
+                    let timeout = self.timeout.unwrap_or_else(|| shared.ack_timeout());
+                    match timeout_checked(timeout, rx).await {
+                        Ok(res) => res
+                            .map_err(|_| SendPacketError::Disconnected)
+                            .map(|pkt| pkt.subscribe()),
+                        Err(_) => {
+                            shared.expire(idx);
+                            Err(SendPacketError::Timeout)
+                        }
+                    }
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -394,6 +893,7 @@ impl SubscribeBuilder {
 /// Unsubscribe packet builder
 pub struct UnsubscribeBuilder {
     id: Option<NonZeroU16>,
+    timeout: Option<Millis>,
     packet: codec::Unsubscribe,
     shared: Rc<MqttShared>,
 }
@@ -426,6 +926,17 @@ impl UnsubscribeBuilder {
         self
     }
 
+    #[inline]
+    /// Set ack timeout
+    ///
+    /// If ack is not received within timeout, `SendPacketError::Timeout` is returned
+    /// and packet id is released. By default client handshake timeout is used,
+    /// zero timeout disables timer.
+    pub fn timeout<T: Into<Millis>>(mut self, timeout: T) -> Self {
+        self.timeout = Some(timeout.into());
+        self
+    }
+
     #[inline]
     /// Get size of the unsubscribe packet
     pub fn size(&self) -> u32 {
@@ -450,13 +961,21 @@ impl UnsubscribeBuilder {
             // send unsubscribe to client
             log::trace!("Sending unsubscribe packet {:#?}", packet);
 
-            let rx = shared.wait_response(packet.packet_id, AckType::Unsubscribe)?;
+            let idx = packet.packet_id;
+            let rx = shared.wait_response(idx, AckType::Unsubscribe)?;
             match shared.encode_packet(codec::Packet::Unsubscribe(packet)) {
                 Ok(_) => {
                     // wait ack from peer
-                    rx.await
-                        .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.unsubscribe())
+                    let timeout = self.timeout.unwrap_or_else(|| shared.ack_timeout());
+                    match timeout_checked(timeout, rx).await {
+                        Ok(res) => res
+                            .map_err(|_| SendPacketError::Disconnected)
+                            .map(|pkt| pkt.unsubscribe()),
+                        Err(_) => {
+                            shared.expire(idx);
+                            Err(SendPacketError::Timeout)
+                        }
+                    }
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -465,3 +984,107 @@ impl UnsubscribeBuilder {
         }
     }
 }
+
+/// Request/response exchange builder
+pub struct RpcBuilder {
+    publish: PublishBuilder,
+    prefix: ByteString,
+    timeout: Millis,
+}
+
+impl RpcBuilder {
+    #[inline]
+    /// Set response topic prefix
+    ///
+    /// Unique suffix is appended to prefix for each request.
+    /// By default `rpc/response` is used.
+    pub fn response_topic_prefix<U>(mut self, prefix: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.prefix = prefix.into();
+        self
+    }
+
+    #[inline]
+    /// Set request publish QoS
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.publish = self.publish.qos(qos);
+        self
+    }
+
+    #[inline]
+    /// Update request publish properties
+    pub fn properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::PublishProperties),
+    {
+        self.publish = self.publish.properties(f);
+        self
+    }
+
+    #[inline]
+    /// Set response timeout
+    ///
+    /// Timeout covers subscription, request publish and response.
+    /// Default timeout is 30 seconds, zero timeout disables timer.
+    pub fn timeout<T: Into<Millis>>(mut self, timeout: T) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    /// Send request and wait for response payload
+    pub async fn send(self) -> Result<Bytes, RpcError> {
+        let shared = self.publish.shared.clone();
+        if shared.is_closed() {
+            return Err(RpcError::Disconnected);
+        }
+
+        let token = RandomState::new().build_hasher().finish();
+        let topic = ByteString::from(format!("{}/{:016x}", self.prefix, token));
+        let correlation = Bytes::copy_from_slice(&token.to_be_bytes());
+        let rx = shared.rpc_register(topic.clone(), correlation.clone());
+
+        let subscribed = Cell::new(false);
+        let exchange = async {
+            let ack = MqttSink(shared.clone())
+                .subscribe(None)
+                .topic_filter(topic.clone(), codec::SubscriptionOptions::default())
+                .send()
+                .await?;
+            match ack.status.first() {
+                Some(codec::SubscribeAckReason::GrantedQos0)
+                | Some(codec::SubscribeAckReason::GrantedQos1)
+                | Some(codec::SubscribeAckReason::GrantedQos2) => subscribed.set(true),
+                Some(reason) => return Err(RpcError::Subscribe(*reason)),
+                None => {
+                    return Err(RpcError::Subscribe(
+                        codec::SubscribeAckReason::UnspecifiedError,
+                    ))
+                }
+            }
+
+            let mut publish = self.publish;
+            publish.packet.properties.response_topic = Some(topic.clone());
+            publish.packet.properties.correlation_data = Some(correlation);
+            publish.send().await?;
+
+            rx.await.map_err(|_| RpcError::Disconnected)
+        };
+        let result = match timeout_checked(self.timeout, exchange).await {
+            Ok(res) => res,
+            Err(_) => Err(RpcError::Timeout),
+        };
+
+        shared.rpc_remove(&topic);
+        if subscribed.get() && !shared.is_closed() {
+            let sink = MqttSink(shared);
+            ntex_util::spawn(async move {
+                if let Err(err) = sink.unsubscribe().topic_filter(topic).send().await {
+                    log::trace!("Cannot unsubscribe from response topic: {:?}", err);
+                }
+            });
+        }
+        result
+    }
+}
@@ -5,9 +5,12 @@ use crate::error::{DecodeError, EncodeError};
 use crate::types::{packet_type, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_5};
 use crate::utils;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub(super) enum ProtocolVersion {
+/// Mqtt protocol version
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolVersion {
+    /// Mqtt v3.1.1
     MQTT3,
+    /// Mqtt v5
     MQTT5,
 }
 
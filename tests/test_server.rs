@@ -1,15 +1,18 @@
 use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{cell::RefCell, future::Future, num::NonZeroU16, pin::Pin, rc::Rc, time::Duration};
+use std::{cell::RefCell, collections::HashSet, future::Future, num::NonZeroU16, pin::Pin};
+use std::{rc::Rc, time::Duration};
 
 use ntex::service::{fn_service, Pipeline, ServiceFactory};
-use ntex::time::{sleep, Millis, Seconds};
+use ntex::time::{sleep, timeout, Millis, Seconds};
 use ntex::util::{join_all, lazy, ByteString, Bytes, BytesMut, Ready};
 use ntex::{codec::Encoder, server, service::chain_factory};
 
+use ntex_mqtt::error::{DecodeError, ProtocolError, SendPacketError};
 use ntex_mqtt::v3::{
-    client, codec, Control, Handshake, HandshakeAck, MqttServer, Publish, Session,
+    client, codec, Control, Handshake, HandshakeAck, MqttServer, Publish, PublishResult,
+    QueueFullPolicy, Session,
 };
-use ntex_mqtt::{error::ProtocolError, QoS};
+use ntex_mqtt::{Clock, FilterDecision, QoS, ServerHandle};
 
 struct St;
 
@@ -46,6 +49,46 @@ async fn test_simple() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_publish_by_qos() -> std::io::Result<()> {
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let qos0 = received2.clone();
+        let reliable = received2.clone();
+        MqttServer::new(handshake)
+            .publish_qos0(move |p: Publish| {
+                qos0.lock().unwrap().push(("qos0", p.publish_topic().to_string()));
+                Ready::Ok::<_, ()>(())
+            })
+            .publish_reliable(move |p: Publish| {
+                reliable.lock().unwrap().push(("reliable", p.publish_topic().to_string()));
+                Ready::Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("t1"), Bytes::new()).send_at_most_once().unwrap();
+    sink.publish(ByteString::from_static("t2"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Millis(50)).await;
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![("qos0", "t1".to_string()), ("reliable", "t2".to_string())]
+    );
+    sink.close();
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_connect_fail() -> std::io::Result<()> {
     // bad user name or password
@@ -103,6 +146,103 @@ async fn test_connect_fail() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_auth_failure_delay() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|conn: Handshake| Ready::Ok::<_, ()>(conn.bad_username_or_pwd::<St>()))
+            .auth_failure_delay(Duration::from_millis(300))
+            .publish(|_t| Ready::Ok(()))
+            .finish()
+    });
+
+    let start = std::time::Instant::now();
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.err().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    if let client::ClientError::Ack(codec::ConnectAck { return_code, .. }) = err {
+        assert_eq!(return_code, codec::ConnectAckReason::BadUserNameOrPassword);
+    } else {
+        panic!("error");
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_overload_check() -> std::io::Result<()> {
+    let overloaded = Arc::new(AtomicBool::new(true));
+    let overloaded2 = overloaded.clone();
+    let called = Arc::new(AtomicBool::new(false));
+    let called2 = called.clone();
+
+    let srv = server::test_server(move || {
+        let overloaded = overloaded2.clone();
+        let called = called2.clone();
+        MqttServer::new(move |conn: Handshake| {
+            called.store(true, Relaxed);
+            Ready::Ok::<_, ()>(conn.ack(St, false))
+        })
+        .overload_check(move || overloaded.load(Relaxed))
+        .publish(|_t| Ready::Ok(()))
+        .finish()
+    });
+
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.err().unwrap();
+    if let client::ClientError::Ack(codec::ConnectAck { return_code, .. }) = err {
+        assert_eq!(return_code, codec::ConnectAckReason::ServiceUnavailable);
+    } else {
+        panic!("error");
+    }
+    assert!(!called.load(Relaxed));
+
+    overloaded.store(false, Relaxed);
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(called.load(Relaxed));
+    drop(client);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_egress_filter() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .egress_filter(|pkt: &mut codec::Packet| match pkt {
+                codec::Packet::ConnectAck(ack) => {
+                    FilterDecision::Replace(codec::Packet::ConnectAck(codec::ConnectAck {
+                        session_present: true,
+                        return_code: ack.return_code,
+                    }))
+                }
+                codec::Packet::PingResponse => FilterDecision::Drop,
+                _ => FilterDecision::Pass,
+            })
+            .publish(|_t| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            session_present: true,
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+        })
+    );
+
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    assert!(timeout(Millis(300), io.recv(&codec)).await.is_err());
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_ping() -> std::io::Result<()> {
     let ping = Arc::new(AtomicBool::new(false));
@@ -254,6 +394,48 @@ async fn test_ack_order_sink() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_pipelined_subscribes() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .control(move |msg| match msg {
+                Control::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        match sub.topic().as_ref() {
+                            "topic/0" => sub.confirm(codec::QoS::AtMostOnce),
+                            "topic/1" => sub.confirm(codec::QoS::AtLeastOnce),
+                            _ => sub.fail(),
+                        }
+                    }
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.ack()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let futs: Vec<_> = ["topic/0", "topic/1", "topic/2"]
+        .into_iter()
+        .map(|topic| {
+            sink.subscribe()
+                .topic_filter(ByteString::from_static(topic), codec::QoS::AtLeastOnce)
+                .send()
+        })
+        .collect();
+
+    let res = join_all(futs).await;
+    assert_eq!(res[0], Ok(vec![codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce)]));
+    assert_eq!(res[1], Ok(vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)]));
+    assert_eq!(res[2], Ok(vec![codec::SubscribeReturnCode::Failure]));
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_disconnect() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -285,6 +467,47 @@ async fn test_disconnect() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_session_disconnect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok(ntex::service::fn_service(move |_: Publish| {
+                    session.disconnect();
+                    Ready::Ok::<_, ()>(())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from("test"),
+            packet_id: None,
+            payload: Bytes::new(),
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // v3 has no server DISCONNECT packet, connection gets closed
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_client_disconnect() -> std::io::Result<()> {
     let disconnect = Arc::new(AtomicBool::new(false));
@@ -711,6 +934,45 @@ async fn test_sink_publish_noblock() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_sink_publish_ack_cb_timed() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| async {
+                sleep(Millis(100)).await;
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let results = Rc::new(RefCell::new(Vec::new()));
+    let results2 = results.clone();
+
+    sink.publish_ack_cb_timed(move |idx, disconnected, rtt| {
+        assert!(!disconnected);
+        results2.borrow_mut().push((idx, rtt));
+    });
+
+    let res = sink
+        .publish(ByteString::from_static("test1"), Bytes::new())
+        .send_at_least_once_no_block();
+    assert!(res.is_ok());
+    sleep(Millis(300)).await;
+
+    let results = results.borrow();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, NonZeroU16::new(1).unwrap());
+    assert!(results[0].1 >= Duration::from_millis(100));
+
+    sink.close();
+    Ok(())
+}
+
 // Slow frame rate
 #[ntex::test]
 async fn test_frame_read_rate() -> std::io::Result<()> {
@@ -776,3 +1038,2678 @@ async fn test_frame_read_rate() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_server_handle() -> std::io::Result<()> {
+    let handle = Arc::new(std::sync::Mutex::new(None));
+    let handle2 = handle.clone();
+
+    let srv = server::test_server(move || {
+        let server =
+            MqttServer::new(handshake).publish(|_| Ready::Ok(())).control(|msg| match msg {
+                Control::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| s.confirm(QoS::AtLeastOnce));
+                    Ready::<_, ()>::Ok(msg.ack())
+                }
+                Control::Unsubscribe(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            });
+        *handle2.lock().unwrap() = Some(server.handle());
+        server.finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sleep(Millis(50)).await;
+
+    // handle of server builder
+    let handle: ServerHandle = handle.lock().unwrap().clone().unwrap();
+
+    let conns = handle.connections();
+    assert_eq!(conns.len(), 1);
+    assert_eq!(conns[0].client_id(), "user");
+    assert!(conns[0].peer_addr().is_some());
+    assert_eq!(conns[0].subscriptions(), 0);
+
+    // subscriptions count
+    sink.subscribe()
+        .topic_filter(ByteString::from_static("a/b"), QoS::AtLeastOnce)
+        .topic_filter(ByteString::from_static("c/#"), QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(handle.connections()[0].subscriptions(), 2);
+    sink.unsubscribe().topic_filter(ByteString::from_static("a/b")).send().await.unwrap();
+    assert_eq!(handle.connections()[0].subscriptions(), 1);
+
+    assert!(!handle.disconnect("unknown"));
+    assert!(handle.disconnect("user"));
+    sleep(Millis(150)).await;
+    assert!(!sink.is_open());
+    assert!(handle.is_empty());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_server_handle_decode_errors() -> std::io::Result<()> {
+    let handle = ServerHandle::new();
+    let handle2 = handle.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .with_handle(handle2.clone())
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    assert!(handle.decode_errors().is_empty());
+
+    // PUBREL with invalid fixed header flags
+    io.write(&[0x60, 0x02, 0x00, 0x01]).unwrap();
+    sleep(Millis(150)).await;
+
+    assert_eq!(handle.decode_error_count(DecodeError::MalformedPacket), 1);
+    assert_eq!(handle.decode_errors().len(), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_packet_id() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| async {
+                sleep(Millis(150)).await;
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let fut = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .packet_id(10)
+        .send_at_least_once();
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .packet_id(10)
+        .send_at_least_once()
+        .await;
+    assert!(matches!(res, Err(SendPacketError::PacketIdInUse(id)) if id.get() == 10));
+    assert!(fut.await.is_ok());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .packet_id(10)
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_oversize_skip() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_size(1024)
+            .on_oversize(codec::OversizePolicy::SkipPacket)
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let p = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from("test"),
+        packet_id: Some(NonZeroU16::new(1).unwrap()),
+        payload: Bytes::from(vec![b'*'; 270 * 1024]),
+    };
+    io.send(p.clone().into(), &codec).await.unwrap();
+
+    let p = codec::Publish {
+        packet_id: Some(NonZeroU16::new(2).unwrap()),
+        payload: Bytes::from_static(b"data"),
+        ..p
+    };
+    io.send(p.into(), &codec).await.unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt.0, codec::Packet::PublishAck { packet_id: NonZeroU16::new(2).unwrap() });
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_queue_len_disconnect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(fn_service(|packet: Handshake| async move {
+            let sink = packet.sink();
+            sink.max_queue_len(QoS::AtLeastOnce, 1, QueueFullPolicy::Disconnect);
+
+            ntex::rt::spawn(async move {
+                sleep(Millis(50)).await;
+                let f1 = sink.publish("/test", Bytes::new()).send_at_least_once();
+                let f2 = sink.publish("/test", Bytes::new()).send_at_least_once();
+                let f3 = sink.publish("/test", Bytes::new()).send_at_least_once();
+                assert!(f3.await.is_err());
+                assert!(f2.await.is_err());
+                assert!(f1.await.is_err());
+                assert!(!sink.is_open());
+            });
+
+            Ok::<_, ()>(packet.ack(St, false).max_send(1))
+        }))
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    // connect to server
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // first publish is in-flight and never acked
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::Publish(_)));
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_queue_len_block() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(fn_service(|packet: Handshake| async move {
+            let sink = packet.sink();
+            sink.max_queue_len(QoS::AtLeastOnce, 1, QueueFullPolicy::Block);
+
+            ntex::rt::spawn(async move {
+                sleep(Millis(50)).await;
+                let f1 = sink.publish("/test", Bytes::new()).send_at_least_once();
+                let f2 = sink.publish("/test", Bytes::new()).send_at_least_once();
+                let f3 = sink.publish("/test", Bytes::new()).send_at_least_once();
+                assert_eq!(f3.await, Err(SendPacketError::QueueFull));
+                assert!(sink.is_open());
+                assert!(f1.await.is_ok());
+                assert!(f2.await.is_ok());
+            });
+
+            Ok::<_, ()>(packet.ack(St, false).max_send(1))
+        }))
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    // connect to server
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // third publish is rejected, queued publish is sent after ack
+    for id in 1..3 {
+        let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+        let packet_id = NonZeroU16::new(id).unwrap();
+        match pkt {
+            codec::Packet::Publish(pkt) => assert_eq!(pkt.packet_id, Some(packet_id)),
+            pkt => panic!("Unexpected packet {:?}", pkt),
+        }
+        io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_expire_after() -> std::io::Result<()> {
+    let expired = Arc::new(AtomicBool::new(false));
+    let expired2 = expired.clone();
+
+    let srv = server::test_server(move || {
+        let expired = expired2.clone();
+        MqttServer::new(fn_service(move |packet: Handshake| {
+            let expired = expired.clone();
+            async move {
+                let sink = packet.sink();
+
+                ntex::rt::spawn(async move {
+                    sleep(Millis(50)).await;
+                    let f1 = sink.publish("/test", Bytes::new()).send_at_least_once();
+                    let f2 = sink
+                        .publish("/test", Bytes::new())
+                        .expire_after(Duration::from_millis(100))
+                        .send_at_least_once();
+                    assert!(matches!(f2.await, Err(SendPacketError::Expired)));
+                    expired.store(true, Relaxed);
+                    assert!(f1.await.is_ok());
+                });
+
+                Ok::<_, ()>(packet.ack(St, false).max_send(1))
+            }
+        }))
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    // connect to server
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // first publish is in-flight, second one waits and expires
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        _ => panic!("Expected PUBLISH packet"),
+    };
+    sleep(Millis(300)).await;
+    assert!(expired.load(Relaxed));
+
+    // expired publish is not sent
+    io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+    assert!(timeout(Millis(300), io.recv(&codec)).await.is_err());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_close_graceful() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|pkt: Publish| async move {
+                if pkt.topic().path() == "slow" {
+                    sleep(Millis(1000)).await;
+                } else {
+                    sleep(Millis(50)).await;
+                }
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let fut = sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once();
+    assert_eq!(sink.close_graceful(Seconds(1)).await, Ok(()));
+    assert!(fut.await.is_ok());
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let fut = sink
+        .publish(ByteString::from_static("slow"), Bytes::new())
+        .packet_id(5)
+        .send_at_least_once();
+    let res = sink.close_graceful(Millis(100)).await;
+    assert_eq!(res, Err(vec![NonZeroU16::new(5).unwrap()]));
+    assert!(fut.await.is_err());
+    assert!(!sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_subscribe() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::<_, ()>::Ok(ntex::service::fn_service(move |msg| match msg {
+                    Control::Subscribe(mut msg) => {
+                        for mut sub in &mut msg {
+                            sub.confirm(codec::QoS::AtLeastOnce);
+                        }
+                        let sink = session.sink().clone();
+                        ntex::rt::spawn(async move {
+                            sleep(Millis(25)).await;
+                            sink.publish("topic/1", Bytes::from_static(b"data"))
+                                .send_at_least_once()
+                                .await
+                                .unwrap();
+                        });
+                        Ready::<_, ()>::Ok(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let received2 = received.clone();
+    let router = client.resource(
+        "topic/{id}",
+        fn_service(move |pkt: Publish| {
+            received2
+                .borrow_mut()
+                .push((pkt.publish_topic().to_string(), pkt.payload().clone()));
+            Ready::<_, ()>::Ok(())
+        }),
+    );
+    ntex::rt::spawn(router.start_default());
+
+    let codes = sink
+        .subscribe()
+        .topic_filter(ByteString::from_static("topic/+"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(codes, vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)]);
+
+    sleep(Millis(100)).await;
+    assert_eq!(&*received.borrow(), &[("topic/1".to_string(), Bytes::from_static(b"data"))]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retained_delete() -> std::io::Result<()> {
+    let store = Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
+    let delivered = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let store2 = store.clone();
+    let delivered2 = delivered.clone();
+
+    let srv = server::test_server(move || {
+        let store = store2.clone();
+        let store_sub = store2.clone();
+        let delivered = delivered2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let topic = p.publish_topic().to_string();
+                if p.clears_retained() {
+                    store.lock().unwrap().remove(&topic);
+                } else if p.retain() {
+                    store.lock().unwrap().insert(topic.clone(), p.payload().clone());
+                }
+                delivered.lock().unwrap().push((topic, p.payload().clone()));
+                Ready::Ok::<_, ()>(())
+            })
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let store = store_sub.clone();
+                Ready::<_, ()>::Ok(ntex::service::fn_service(move |msg| match msg {
+                    Control::Subscribe(mut msg) => {
+                        let mut retained = Vec::new();
+                        for mut sub in &mut msg {
+                            sub.confirm(codec::QoS::AtLeastOnce);
+                            let topic: &str = sub.topic();
+                            if let Some(payload) = store.lock().unwrap().get(topic) {
+                                retained.push((sub.topic().clone(), payload.clone()));
+                            }
+                        }
+                        let sink = session.sink().clone();
+                        ntex::rt::spawn(async move {
+                            sleep(Millis(25)).await;
+                            for (topic, payload) in retained {
+                                sink.publish(topic, payload)
+                                    .retain()
+                                    .send_at_least_once()
+                                    .await
+                                    .unwrap();
+                            }
+                        });
+                        Ready::<_, ()>::Ok(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let received2 = received.clone();
+    let router = client.resource(
+        "a/b",
+        fn_service(move |pkt: Publish| {
+            received2.borrow_mut().push((pkt.retain(), pkt.payload().clone()));
+            Ready::<_, ()>::Ok(())
+        }),
+    );
+    ntex::rt::spawn(router.start_default());
+
+    // store
+    sink.publish("a/b", Bytes::from_static(b"v1")).retain().send_at_least_once().await.unwrap();
+
+    // subscribe replays retained message
+    sink.subscribe()
+        .topic_filter(ByteString::from_static("a/b"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    sleep(Millis(100)).await;
+    assert_eq!(&*received.borrow(), &[(true, Bytes::from_static(b"v1"))]);
+
+    // delete, empty retained publish is still handled as normal message
+    sink.publish("a/b", Bytes::new()).retain().send_at_least_once().await.unwrap();
+    assert!(store.lock().unwrap().is_empty());
+    assert_eq!(
+        *delivered.lock().unwrap(),
+        vec![("a/b".to_string(), Bytes::from_static(b"v1")), ("a/b".to_string(), Bytes::new())]
+    );
+
+    // nothing to replay
+    sink.subscribe()
+        .topic_filter(ByteString::from_static("a/b"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    sleep(Millis(100)).await;
+    assert_eq!(received.borrow().len(), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_bridge() -> std::io::Result<()> {
+    let source = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::<_, ()>::Ok(ntex::service::fn_service(move |msg| match msg {
+                    Control::Subscribe(mut msg) => {
+                        for mut sub in &mut msg {
+                            sub.confirm(codec::QoS::AtLeastOnce);
+                        }
+                        let sink = session.sink().clone();
+                        ntex::rt::spawn(async move {
+                            sleep(Millis(25)).await;
+                            // repeated message is forwarded as well
+                            for _ in 0..2 {
+                                sink.publish("topic/1", Bytes::from_static(b"data"))
+                                    .retain()
+                                    .send_at_least_once()
+                                    .await
+                                    .unwrap();
+                            }
+                        });
+                        Ready::<_, ()>::Ok(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let dest = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let pkt = p.packet();
+                received.lock().unwrap().push((
+                    pkt.topic.to_string(),
+                    pkt.payload.clone(),
+                    pkt.qos,
+                    pkt.retain,
+                ));
+                Ready::Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let options =
+        client::bridge::BridgeOptions { max_qos: QoS::AtMostOnce, ..Default::default() };
+    ntex::rt::spawn(client::bridge::bridge(
+        client::MqttConnector::new(source.addr()).client_id("bridge-src"),
+        client::MqttConnector::new(dest.addr()).client_id("bridge-dst"),
+        vec![(ByteString::from_static("topic/+"), QoS::AtLeastOnce)],
+        options,
+    ));
+
+    sleep(Millis(300)).await;
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![("topic/1".to_string(), Bytes::from_static(b"data"), QoS::AtMostOnce, true); 2]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_bridge_pair() -> std::io::Result<()> {
+    // server fans out every publish back to the publisher
+    fn echo_server(
+        received: Arc<std::sync::Mutex<Vec<String>>>,
+        publish: Option<&'static str>,
+    ) -> server::TestServer {
+        server::test_server(move || {
+            let received = received.clone();
+            MqttServer::new(handshake)
+                .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                    let received = received.clone();
+                    Ready::Ok(ntex::service::fn_service(move |p: Publish| {
+                        received.lock().unwrap().push(p.topic().path().to_string());
+                        session
+                            .sink()
+                            .publish(p.topic().path(), p.payload().clone())
+                            .send_at_most_once()
+                            .unwrap();
+                        Ready::Ok::<_, ()>(())
+                    }))
+                }))
+                .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                    Ready::<_, ()>::Ok(ntex::service::fn_service(move |msg| match msg {
+                        Control::Subscribe(mut msg) => {
+                            for mut sub in &mut msg {
+                                sub.confirm(codec::QoS::AtLeastOnce);
+                            }
+                            if let Some(topic) = publish {
+                                let sink = session.sink().clone();
+                                ntex::rt::spawn(async move {
+                                    sleep(Millis(25)).await;
+                                    sink.publish(topic, Bytes::from_static(b"data"))
+                                        .send_at_least_once()
+                                        .await
+                                        .unwrap();
+                                });
+                            }
+                            Ready::<_, ()>::Ok(msg.ack())
+                        }
+                        _ => Ready::Ok(msg.disconnect()),
+                    }))
+                }))
+                .finish()
+        })
+    }
+
+    let received_a = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received_b = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let srv_a = echo_server(received_a.clone(), Some("topic/1"));
+    let srv_b = echo_server(received_b.clone(), None);
+
+    let filters = vec![(ByteString::from_static("topic/+"), QoS::AtLeastOnce)];
+    ntex::rt::spawn(client::bridge::bridge_pair(
+        client::MqttConnector::new(srv_a.addr()).client_id("bridge-a"),
+        client::MqttConnector::new(srv_b.addr()).client_id("bridge-b"),
+        filters.clone(),
+        filters,
+        Default::default(),
+    ));
+
+    // echo from server b is not forwarded back to server a
+    sleep(Millis(300)).await;
+    assert_eq!(*received_b.lock().unwrap(), vec!["topic/1".to_string()]);
+    assert!(received_a.lock().unwrap().is_empty());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ordered_subscribe() -> std::io::Result<()> {
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let events = events2.clone();
+        let events2 = events2.clone();
+        MqttServer::new(handshake)
+            .ordered_subscribe(true)
+            .publish(move |_: Publish| {
+                events.lock().unwrap().push("publish");
+                Ready::Ok::<_, ()>(())
+            })
+            .control(move |msg| {
+                let events = events2.clone();
+                async move {
+                    match msg {
+                        Control::Subscribe(mut msg) => {
+                            // slow subscription registration
+                            sleep(Millis(100)).await;
+                            events.lock().unwrap().push("subscribe");
+                            for mut sub in &mut msg {
+                                sub.confirm(codec::QoS::AtLeastOnce);
+                            }
+                            Ok::<_, ()>(msg.ack())
+                        }
+                        _ => Ok(msg.disconnect()),
+                    }
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from_static("topic"), codec::QoS::AtLeastOnce)],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.send(
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("topic"),
+            packet_id: None,
+            payload: Bytes::new(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::SubscribeAck { .. }));
+    sleep(Millis(50)).await;
+    assert_eq!(*events.lock().unwrap(), vec!["subscribe", "publish"]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unsubscribe_during_delivery() -> std::io::Result<()> {
+    let delivered = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let delivered2 = delivered.clone();
+
+    let srv = server::test_server(move || {
+        let delivered = delivered2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let delivered = delivered.clone();
+                let store = Rc::new(RefCell::new(HashSet::new()));
+                Ready::<_, ()>::Ok(ntex::service::fn_service(move |msg| match msg {
+                    Control::Subscribe(mut msg) => {
+                        for mut sub in &mut msg {
+                            store.borrow_mut().insert(sub.topic().clone());
+                            sub.confirm(codec::QoS::AtLeastOnce);
+                        }
+                        let sink = session.sink().clone();
+                        let store = store.clone();
+                        let delivered = delivered.clone();
+                        ntex::rt::spawn(async move {
+                            while store.borrow().contains("topic") {
+                                let res = sink
+                                    .publish("topic", Bytes::from_static(b"data"))
+                                    .send_at_least_once()
+                                    .await;
+                                delivered.lock().unwrap().push(res.is_ok());
+                            }
+                        });
+                        Ready::<_, ()>::Ok(msg.ack())
+                    }
+                    Control::Unsubscribe(msg) => {
+                        for topic in msg.iter() {
+                            store.borrow_mut().remove(topic);
+                        }
+                        Ready::Ok(msg.ack())
+                    }
+                    _ => Ready::Ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(ByteString::from("topic"), codec::QoS::AtLeastOnce)],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck { .. }));
+
+    // first delivery is in flight
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap().0 {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+
+    io.send(
+        codec::Packet::Unsubscribe {
+            packet_id: NonZeroU16::new(2).unwrap(),
+            topic_filters: vec![ByteString::from("topic")],
+        },
+        &codec,
+    )
+    .await
+    .unwrap();
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::UnsubscribeAck { packet_id: NonZeroU16::new(2).unwrap() });
+
+    // in-flight delivery completes normally
+    io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+
+    // no new deliveries after UNSUBACK
+    let res = timeout(Millis(100), io.recv(&codec)).await;
+    assert!(res.is_err());
+    assert_eq!(&*delivered.lock().unwrap(), &[true]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_upgrade_io() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|packet: Handshake| async move {
+            let packet = packet.upgrade_io(|io| async move { Ok::<_, ()>(io) }).await?;
+            Ok::<_, ()>(packet.ack(St, false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+            ..
+        })
+    ));
+
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_map_handshake_error() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|_: Handshake| Ready::<HandshakeAck<St>, _>::Err(()))
+            .map_handshake_error(|_| codec::ConnectAckReason::NotAuthorized)
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::NotAuthorized,
+        })
+    );
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_second_connect() -> std::io::Result<()> {
+    let violated = Arc::new(AtomicBool::new(false));
+    let violated2 = violated.clone();
+
+    let srv = server::test_server(move || {
+        let violated = violated2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::ProtocolError(err) => {
+                    if let ProtocolError::ProtocolViolation(_) = err.get_ref() {
+                        violated.store(true, Relaxed);
+                    }
+                    Ready::Ok(err.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert!(violated.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_subscriptions() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_subscriptions(1)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok(msg.ack())
+                }
+                Control::Unsubscribe(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let codes = sink
+        .subscribe()
+        .topic_filter(ByteString::from_static("topic/#"), codec::QoS::AtLeastOnce)
+        .topic_filter(ByteString::from_static("topic2"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        codes,
+        vec![
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            codec::SubscribeReturnCode::Failure
+        ]
+    );
+
+    // existing subscription does not count
+    let codes = sink
+        .subscribe()
+        .topic_filter(ByteString::from_static("topic2"), codec::QoS::AtLeastOnce)
+        .topic_filter(ByteString::from_static("topic/#"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        codes,
+        vec![
+            codec::SubscribeReturnCode::Failure,
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)
+        ]
+    );
+    assert!(sink.is_open());
+
+    sink.unsubscribe().topic_filter(ByteString::from_static("topic/#")).send().await.unwrap();
+    let codes = sink
+        .subscribe()
+        .topic_filter(ByteString::from_static("topic2"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(codes, vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_policy() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_granted_qos(codec::QoS::AtMostOnce)
+            .deny_wildcard_subscriptions(true)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        assert_eq!(sub.topic(), "topic");
+                        sub.confirm(codec::QoS::ExactlyOnce);
+                    }
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let codes = sink
+        .subscribe()
+        .topic_filter(ByteString::from_static("topic/#"), codec::QoS::AtLeastOnce)
+        .topic_filter(ByteString::from_static("topic"), codec::QoS::ExactlyOnce)
+        .topic_filter(ByteString::from_static("+/topic"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        codes,
+        vec![
+            codec::SubscribeReturnCode::Failure,
+            codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce),
+            codec::SubscribeReturnCode::Failure,
+        ]
+    );
+    assert!(sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_heartbeat() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                session.schedule_heartbeat(
+                    "heartbeat",
+                    Seconds(1),
+                    Bytes::from_static(b"alive"),
+                );
+                Ready::<_, ()>::Ok(ntex::service::fn_service(|msg: Control<()>| {
+                    Ready::Ok(msg.disconnect())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    match pkt {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.topic, "heartbeat");
+            assert_eq!(pkt.qos, codec::QoS::AtMostOnce);
+            assert_eq!(pkt.payload, Bytes::from_static(b"alive"));
+        }
+        _ => panic!("Unexpected packet: {:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_replay() {
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec
+        .encode(
+            codec::Packet::Connect(codec::Connect::default().client_id("user").into()),
+            &mut buf,
+        )
+        .unwrap();
+    codec.encode(codec::Packet::PingRequest, &mut buf).unwrap();
+
+    let packets: Vec<codec::Packet> = ntex_mqtt::test::replay(
+        &buf,
+        MqttServer::new(handshake).publish(|_| Ready::Ok(())).finish(),
+    )
+    .await;
+    assert_eq!(packets.len(), 2);
+    assert!(matches!(
+        packets[0],
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+            ..
+        })
+    ));
+    assert_eq!(packets[1], codec::Packet::PingResponse);
+}
+
+#[ntex::test]
+async fn test_immediate_ack() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .immediate_ack(true)
+            .publish(|pkt: Publish| async move {
+                if pkt.topic().path() == "slow" {
+                    sleep(Millis(200)).await;
+                }
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    for (topic, id) in [("slow", 1), ("fast", 2)] {
+        io.encode(
+            codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtLeastOnce,
+                topic: ByteString::from(topic),
+                packet_id: NonZeroU16::new(id),
+                payload: Bytes::new(),
+            }
+            .into(),
+            &codec,
+        )
+        .unwrap();
+    }
+    io.flush(true).await.unwrap();
+
+    // ack for second publish does not wait for the first one
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(2).unwrap() });
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_packet_id_lowest_free() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            let sink = packet.sink();
+            sink.packet_id_strategy(ntex_mqtt::PacketIdStrategy::LowestFree);
+            ntex::rt::spawn(async move {
+                // wait for handshake completion
+                assert!(sink.ready().await);
+                let f1 = sink.publish("t1", Bytes::new()).send_at_least_once();
+                let f2 = sink.publish("t2", Bytes::new()).send_at_least_once();
+                let f3 = sink.publish("t3", Bytes::new()).send_at_least_once();
+                ntex::rt::spawn(f2);
+                ntex::rt::spawn(f3);
+                f1.await.unwrap();
+                let _ = sink.publish("t4", Bytes::new()).send_at_least_once().await;
+            });
+            Ok::<_, ()>(packet.ack(St, false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let mut ids = Vec::new();
+    for _ in 0..3 {
+        match io.recv(&codec).await.unwrap().unwrap().0 {
+            codec::Packet::Publish(pkt) => ids.push(pkt.packet_id.unwrap().get()),
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        }
+    }
+    assert_eq!(ids, vec![1, 2, 3]);
+
+    // acked id is reused
+    io.send(codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }, &codec)
+        .await
+        .unwrap();
+    match io.recv(&codec).await.unwrap().unwrap().0 {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.topic, "t4");
+            assert_eq!(pkt.packet_id, NonZeroU16::new(1));
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_initial_packet_id() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            let sink = packet.sink();
+            sink.initial_packet_id(NonZeroU16::new(u16::MAX - 1).unwrap());
+            ntex::rt::spawn(async move {
+                // wait for handshake completion
+                assert!(sink.ready().await);
+                let f1 = sink.publish("t1", Bytes::new()).packet_id(1).send_at_least_once();
+                let f2 = sink.publish("t2", Bytes::new()).send_at_least_once();
+                let f3 = sink.publish("t3", Bytes::new()).send_at_least_once();
+                let f4 = sink.publish("t4", Bytes::new()).send_at_least_once();
+                let _ = join_all(vec![f1, f2, f3, f4]).await;
+            });
+            Ok::<_, ()>(packet.ack(St, false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let mut ids = Vec::new();
+    for _ in 0..4 {
+        match io.recv(&codec).await.unwrap().unwrap().0 {
+            codec::Packet::Publish(pkt) => ids.push(pkt.packet_id.unwrap().get()),
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        }
+    }
+    // id 1 is in-flight, allocator wraps around and skips it
+    assert_eq!(ids, vec![1, u16::MAX - 1, u16::MAX, 2]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handler_error_pause_reads() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|pkt: Publish| async move {
+                if pkt.topic().path() == "fail" {
+                    Err(())
+                } else {
+                    Ok(())
+                }
+            })
+            .on_handler_error(|_: &()| ntex_mqtt::ErrorAction::PauseReads(Millis(300)))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let publish = |topic, id| {
+        codec::Packet::from(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: NonZeroU16::new(id),
+            payload: Bytes::new(),
+        })
+    };
+
+    // failed publish is acknowledged, connection stays open
+    io.send(publish("fail", 1), &codec).await.unwrap();
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+
+    // next publish is handled after cooldown
+    let start = std::time::Instant::now();
+    io.send(publish("ok", 2), &codec).await.unwrap();
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(2).unwrap() });
+    assert!(start.elapsed() >= Duration::from_millis(250));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_queue_flush() -> std::io::Result<()> {
+    let topics = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .publish(move |pkt: Publish| {
+                topics.lock().unwrap().push(pkt.topic().path().to_string());
+                Ready::Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish("t1", Bytes::new()).queue().unwrap();
+    sink.publish("t2", Bytes::new()).queue().unwrap();
+    sink.publish("t3", Bytes::new()).queue().unwrap();
+    sleep(Millis(50)).await;
+    assert!(topics.lock().unwrap().is_empty());
+
+    sink.flush().await.unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(*topics.lock().unwrap(), vec!["t1", "t2", "t3"]);
+
+    // send flushes queued packets first
+    sink.publish("t4", Bytes::new()).queue().unwrap();
+    sink.publish("t5", Bytes::new()).send_at_most_once().unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(*topics.lock().unwrap(), vec!["t1", "t2", "t3", "t4", "t5"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_reply() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|pkt: Publish| async move {
+                let replies =
+                    [codec::QoS::AtMostOnce, codec::QoS::AtLeastOnce, codec::QoS::AtMostOnce]
+                        .into_iter()
+                        .enumerate()
+                        .map(|(idx, qos)| codec::Publish {
+                            dup: false,
+                            retain: false,
+                            qos,
+                            topic: ByteString::from(format!("reply/{}", idx)),
+                            packet_id: None,
+                            payload: pkt.payload().clone(),
+                        })
+                        .collect();
+                Ok::<_, ()>(PublishResult::Reply(replies))
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from("echo"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::from_static(b"data"),
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // ack is sent before replies
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+
+    // replies keep order
+    for idx in 0..3 {
+        let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+        match pkt {
+            codec::Packet::Publish(pkt) => {
+                assert_eq!(pkt.topic, format!("reply/{}", idx).as_str());
+                assert_eq!(pkt.payload, Bytes::from_static(b"data"));
+            }
+            _ => panic!("Unexpected packet: {:?}", pkt),
+        }
+    }
+
+    Ok(())
+}
+
+struct InstantClock;
+
+impl Clock for InstantClock {
+    fn sleep(&self, _: Millis) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(async {})
+    }
+}
+
+#[ntex::test]
+async fn test_connect_timeout_clock() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .connect_timeout(Seconds(60))
+            .clock(InstantClock)
+            .publish(|_| Ready::Ok(()))
+            .finish()
+    });
+
+    // connect timeout fires without waiting for wall-clock time
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_dead_peer_timeout() -> std::io::Result<()> {
+    let ka = Arc::new(AtomicBool::new(false));
+    let ka2 = ka.clone();
+
+    let srv = server::test_server(move || {
+        let ka = ka2.clone();
+        MqttServer::new(handshake)
+            .dead_peer_timeout(Seconds(1))
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::ProtocolError(err) => {
+                    if let ProtocolError::KeepAliveTimeout = err.get_ref() {
+                        ka.store(true, Relaxed);
+                    }
+                    Ready::Ok(err.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // handshake idle timeout (16 seconds) does not extend dead peer timeout
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    sleep(Millis(2500)).await;
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert!(ka.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_set_keep_alive() -> std::io::Result<()> {
+    let ka = Arc::new(AtomicBool::new(false));
+    let ka2 = ka.clone();
+
+    let srv = server::test_server(move || {
+        let ka = ka2.clone();
+        MqttServer::new(handshake)
+            .on_connected(|session: Session<St>| session.set_keep_alive(Seconds(1)))
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::ProtocolError(err) => {
+                    if let ProtocolError::KeepAliveTimeout = err.get_ref() {
+                        ka.store(true, Relaxed);
+                    }
+                    Ready::Ok(err.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // runtime override shortens handshake idle timeout (16 seconds)
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    sleep(Millis(2500)).await;
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert!(ka.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_idle_timeout_per_client() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            let timeout =
+                if packet.packet().client_id == "premium" { Seconds(3) } else { Seconds(1) };
+            Ok::<_, ()>(packet.ack(St, false).idle_timeout(timeout))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let basic = srv.connect().await.unwrap();
+    basic
+        .send(
+            codec::Packet::Connect(codec::Connect::default().client_id("basic").into()),
+            &codec,
+        )
+        .await
+        .unwrap();
+    basic.recv(&codec).await.unwrap().unwrap();
+
+    let premium = srv.connect().await.unwrap();
+    premium
+        .send(
+            codec::Packet::Connect(codec::Connect::default().client_id("premium").into()),
+            &codec,
+        )
+        .await
+        .unwrap();
+    premium.recv(&codec).await.unwrap().unwrap();
+
+    // basic client is disconnected, premium client is still alive
+    sleep(Millis(2000)).await;
+    assert!(basic.recv(&codec).await.unwrap().is_none());
+    premium.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    assert_eq!(premium.recv(&codec).await.unwrap().unwrap().0, codec::Packet::PingResponse);
+
+    sleep(Millis(4000)).await;
+    assert!(premium.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_cancel_publish() -> std::io::Result<()> {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let cancelled2 = cancelled.clone();
+
+    let srv = server::test_server(move || {
+        let cancelled = cancelled2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let cancelled = cancelled.clone();
+            async move {
+                let sink = packet.sink();
+                ntex::rt::spawn(async move {
+                    assert!(sink.ready().await);
+                    let fut = sink.publish("t1", Bytes::new()).send_at_least_once();
+                    let ids = sink.inflight_ids();
+                    assert_eq!(ids, vec![NonZeroU16::new(1).unwrap()]);
+                    assert!(sink.cancel(ids[0]));
+                    assert!(!sink.cancel(ids[0]));
+                    assert!(sink.inflight_ids().is_empty());
+                    assert_eq!(fut.await, Err(SendPacketError::Cancelled));
+                    cancelled.store(true, Relaxed);
+                });
+                Ok::<_, ()>(packet.ack(St, false))
+            }
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let packet_id = match io.recv(&codec).await.unwrap().unwrap().0 {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+    sleep(Millis(50)).await;
+    assert!(cancelled.load(Relaxed));
+
+    // late ack for cancelled publish is accepted
+    io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    assert_eq!(io.recv(&codec).await.unwrap().unwrap().0, codec::Packet::PingResponse);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_stats_disconnect() -> std::io::Result<()> {
+    let publishes = Arc::new(std::sync::Mutex::new(0));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .stats_interval(Seconds(1))
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::Stats(msg) => {
+                    *publishes.lock().unwrap() = msg.publishes();
+                    assert!(msg.bytes() > 0);
+                    assert_eq!(msg.publishes_per_sec(), msg.publishes() as f64);
+                    if msg.publishes() > 3 {
+                        Ready::Ok(msg.disconnect())
+                    } else {
+                        Ready::Ok(msg.ack())
+                    }
+                }
+                _ => Ready::Ok(msg.ack()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    for _ in 0..5 {
+        io.send(
+            codec::Packet::Publish(codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtMostOnce,
+                topic: ByteString::from("test"),
+                packet_id: None,
+                payload: Bytes::new(),
+            }),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+
+    // connection is closed on next stats tick
+    let res = timeout(Millis(2000), io.recv(&codec)).await.unwrap();
+    assert!(res.unwrap().is_none());
+    assert_eq!(*publishes.lock().unwrap(), 5);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_first_action_timeout() -> std::io::Result<()> {
+    let timeouts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let timeouts2 = timeouts.clone();
+
+    let srv = server::test_server(move || {
+        let timeouts = timeouts2.clone();
+        MqttServer::new(handshake)
+            .first_action_timeout(Seconds(1))
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::ActionTimeout(msg) => {
+                    assert_eq!(msg.timeout(), Seconds(1));
+                    timeouts.fetch_add(1, Relaxed);
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.ack()),
+            })
+            .finish()
+    });
+
+    // client does nothing after connect
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    let res = timeout(Millis(2500), io.recv(&codec)).await.unwrap();
+    assert!(res.unwrap().is_none());
+    assert_eq!(timeouts.load(Relaxed), 1);
+
+    // client publishes before timeout
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from("test"),
+            packet_id: None,
+            payload: Bytes::new(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    sleep(Millis(1500)).await;
+    assert!(!io.is_closed());
+    assert_eq!(timeouts.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_peer_gone_without_disconnect() -> std::io::Result<()> {
+    let clean = Arc::new(std::sync::Mutex::new(None));
+    let clean2 = clean.clone();
+
+    let srv = server::test_server(move || {
+        let clean = clean2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::PeerGone(msg) => {
+                    *clean.lock().unwrap() = Some(msg.is_clean());
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.ack()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // drop connection without DISCONNECT packet
+    drop(io);
+    sleep(Millis(100)).await;
+    assert_eq!(*clean.lock().unwrap(), Some(false));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_on_abnormal_disconnect() -> std::io::Result<()> {
+    let wills = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let wills2 = wills.clone();
+
+    let srv = server::test_server(move || {
+        let wills = wills2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .on_will(move |will, session| {
+                assert_eq!(session.client_id(), "user");
+                wills.lock().unwrap().push((
+                    will.topic().clone(),
+                    will.payload().clone(),
+                    will.qos(),
+                    will.retain(),
+                ));
+            })
+            .finish()
+    });
+
+    let connect = codec::Connect {
+        last_will: Some(codec::LastWill {
+            qos: codec::QoS::AtLeastOnce,
+            retain: true,
+            topic: ByteString::from_static("will"),
+            message: Bytes::from_static(b"gone"),
+        }),
+        ..codec::Connect::default().client_id("user")
+    };
+
+    // clean disconnect, will is dropped
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(connect.clone().into()), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Packet::Disconnect, &codec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    sleep(Millis(100)).await;
+    assert!(wills.lock().unwrap().is_empty());
+
+    // connection is closed without DISCONNECT packet
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Packet::Connect(connect.into()), &codec).await.unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+    drop(io);
+    sleep(Millis(100)).await;
+    assert_eq!(
+        &*wills.lock().unwrap(),
+        &[(
+            ByteString::from_static("will"),
+            Bytes::from_static(b"gone"),
+            codec::QoS::AtLeastOnce,
+            true
+        )]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_control_error_policy_ignore() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::Ping(_) => Ready::Err(()),
+                Control::Error(_) => panic!("Control error must not be escalated"),
+                _ => Ready::Ok(msg.ack()),
+            })
+            .control_error_policy(ntex_mqtt::ControlErrorPolicy::Ignore)
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Packet::Connect(codec::Connect::default().client_id("user").into()), &codec)
+        .await
+        .unwrap();
+    io.recv(&codec).await.unwrap().unwrap();
+
+    // ping is not acknowledged, connection stays open
+    io.send(codec::Packet::PingRequest, &codec).await.unwrap();
+    io.send(
+        codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from("test"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        io.recv(&codec).await.unwrap().unwrap().0,
+        codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() }
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_subscribe_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| async move {
+                match msg {
+                    Control::Subscribe(mut msg) => {
+                        sleep(Millis(300)).await;
+                        for mut sub in &mut msg {
+                            sub.confirm(codec::QoS::AtLeastOnce);
+                        }
+                        Ok::<_, ()>(msg.ack())
+                    }
+                    _ => Ok(msg.ack()),
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .subscribe()
+        .packet_id(1)
+        .topic_filter(ByteString::from_static("topic"), codec::QoS::AtLeastOnce)
+        .timeout(Millis(50))
+        .send()
+        .await;
+    assert_eq!(res, Err(SendPacketError::Timeout));
+    assert!(sink.inflight_ids().is_empty());
+
+    // late SUBACK is ignored, connection stays open
+    sleep(Millis(400)).await;
+    assert!(sink.is_open());
+    let res = sink
+        .subscribe()
+        .packet_id(1)
+        .topic_filter(ByteString::from_static("topic"), codec::QoS::AtLeastOnce)
+        .send()
+        .await;
+    assert_eq!(res, Ok(vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)]));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_subscriptions_codes_count() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_subscriptions(3)
+            .publish(|_| Ready::Ok(()))
+            .control(move |msg| match msg {
+                Control::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    Ready::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let mut builder = sink.subscribe();
+    for idx in 0..5 {
+        builder =
+            builder.topic_filter(format!("topic/{}", idx).into(), codec::QoS::AtLeastOnce);
+    }
+    let codes = builder.send().await.unwrap();
+    assert_eq!(
+        codes,
+        vec![
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            codec::SubscribeReturnCode::Failure,
+            codec::SubscribeReturnCode::Failure,
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_retransmit() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        fn_service(|io: ntex::io::Io| async move {
+            let codec = codec::Codec::default();
+            let pkt = io.recv(&codec).await.unwrap().unwrap();
+            assert!(matches!(pkt.0, codec::Packet::Connect(_)));
+            io.send(
+                codec::Packet::ConnectAck(codec::ConnectAck {
+                    session_present: false,
+                    return_code: codec::ConnectAckReason::ConnectionAccepted,
+                }),
+                &codec,
+            )
+            .await
+            .unwrap();
+
+            // first publish is not acked
+            let pkt = io.recv(&codec).await.unwrap().unwrap();
+            let packet_id = match pkt.0 {
+                codec::Packet::Publish(pkt) => {
+                    assert!(!pkt.dup);
+                    pkt.packet_id.unwrap()
+                }
+                _ => panic!("Expected PUBLISH packet"),
+            };
+
+            // retransmitted publish
+            let pkt = io.recv(&codec).await.unwrap().unwrap();
+            match pkt.0 {
+                codec::Packet::Publish(pkt) => {
+                    assert!(pkt.dup);
+                    assert_eq!(pkt.packet_id, Some(packet_id));
+                    assert_eq!(pkt.payload, Bytes::from_static(b"data"));
+                }
+                _ => panic!("Expected PUBLISH packet"),
+            }
+            io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+            sleep(Millis(100)).await;
+            Ok::<_, ()>(())
+        })
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .retransmit_interval(Some(Seconds(1)))
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = timeout(
+        Millis(3000),
+        sink.publish("test", Bytes::from_static(b"data")).send_at_least_once(),
+    )
+    .await;
+    assert!(res.unwrap().is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_socks5_proxy() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        fn_service(|io: ntex::io::Io| async move {
+            let raw = ntex::codec::BytesCodec;
+
+            // greeting with username/password method
+            let req = io.recv(&raw).await.unwrap().unwrap();
+            assert_eq!(&req[..], &[5, 2, 0, 2]);
+            io.send(Bytes::from_static(&[5, 2]), &raw).await.unwrap();
+            let req = io.recv(&raw).await.unwrap().unwrap();
+            assert_eq!(&req[..], b"\x01\x04user\x04pass");
+            io.send(Bytes::from_static(&[1, 0]), &raw).await.unwrap();
+
+            // connect request with domain address
+            let req = io.recv(&raw).await.unwrap().unwrap();
+            assert_eq!(&req[..], b"\x05\x01\x00\x03\x0cbroker.local\x07\x5b");
+            io.send(Bytes::from_static(&[5, 0, 0, 1, 127, 0, 0, 1, 0x07, 0x5b]), &raw)
+                .await
+                .unwrap();
+
+            // tunnel is established
+            let codec = codec::Codec::default();
+            let pkt = io.recv(&codec).await.unwrap().unwrap();
+            assert!(matches!(pkt.0, codec::Packet::Connect(_)));
+            io.send(
+                codec::Packet::ConnectAck(codec::ConnectAck {
+                    session_present: false,
+                    return_code: codec::ConnectAckReason::ConnectionAccepted,
+                }),
+                &codec,
+            )
+            .await
+            .unwrap();
+            sleep(Millis(100)).await;
+            Ok::<_, ()>(())
+        })
+    });
+
+    let client = client::MqttConnector::new("broker.local:1883".to_string())
+        .socks5_proxy(
+            srv.addr().to_string(),
+            Some((ByteString::from_static("user"), ByteString::from_static("pass"))),
+        )
+        .client_id("user")
+        .connect()
+        .await
+        .unwrap();
+    assert!(!client.session_present());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_concurrent_handshakes() -> std::io::Result<()> {
+    let active = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let max_active = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let active2 = active.clone();
+    let max_active2 = max_active.clone();
+
+    let srv = server::test_server(move || {
+        let active = active2.clone();
+        let max_active = max_active2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let active = active.clone();
+            let max_active = max_active.clone();
+            async move {
+                let num = active.fetch_add(1, Relaxed) + 1;
+                max_active.fetch_max(num, Relaxed);
+                sleep(Millis(100)).await;
+                active.fetch_sub(1, Relaxed);
+                Ok::<_, ()>(packet.ack(St, false))
+            }
+        })
+        .max_concurrent_handshakes(1)
+        .publish(|_t| Ready::Ok(()))
+        .finish()
+    });
+
+    let connectors: Vec<_> =
+        (0..3).map(|_| client::MqttConnector::new(srv.addr()).client_id("user")).collect();
+    let clients = join_all(connectors.iter().map(|c| c.connect())).await;
+    assert!(clients.iter().all(|c| c.is_ok()));
+    assert_eq!(max_active.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_dup_packet_id() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| async {
+                sleep(Millis(50)).await;
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from("test"),
+        packet_id: Some(NonZeroU16::new(1).unwrap()),
+        payload: Bytes::new(),
+    };
+
+    // re-delivery with DUP flag is not a violation
+    io.send(publish.clone().into(), &codec).await.unwrap();
+    io.send(codec::Publish { dup: true, ..publish.clone() }.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(pkt.0, codec::Packet::PublishAck { packet_id: NonZeroU16::new(1).unwrap() });
+
+    // packet id reuse without DUP flag closes connection
+    io.send(publish.clone().into(), &codec).await.unwrap();
+    io.send(publish.into(), &codec).await.unwrap();
+    assert!(!matches!(io.recv(&codec).await, Ok(Some(_))));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_read_buffer_params() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .read_buffer_params(2048, 256)
+            .publish(|_t| Ready::Ok(()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let payload = Bytes::from(vec![b'*'; 16 * 1024]);
+    let res = sink.publish(ByteString::from_static("test"), payload).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_on_connected() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .on_connected(|session: Session<St>| {
+                let _ = session
+                    .sink()
+                    .publish(ByteString::from_static("snapshot"), Bytes::from_static(b"state"))
+                    .send_at_most_once();
+            })
+            .publish(|_t| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::ConnectAck(_)));
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Publish(publish) => {
+            assert_eq!(publish.topic.as_str(), "snapshot");
+            assert_eq!(publish.payload, Bytes::from_static(b"state"));
+        }
+        pkt => panic!("Unexpected packet {:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_empty_client_id() -> std::io::Result<()> {
+    let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            ids.lock().unwrap().push(packet.packet().client_id.clone());
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .publish(|_t| Ready::Ok(()))
+        .finish()
+    });
+
+    // server assigns client id
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect { clean_session: true, ..Default::default() }.into(), &codec)
+        .await
+        .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+        })
+    );
+    assert!(ids.lock().unwrap()[0].starts_with("auto-"));
+
+    // empty client id requires clean session
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::ConnectAck(codec::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::IdentifierRejected,
+        })
+    );
+    assert_eq!(ids.lock().unwrap().len(), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_client_id() -> std::io::Result<()> {
+    let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(handshake)
+            .on_connected(move |session: Session<St>| {
+                ids.lock().unwrap().push(session.client_id().clone());
+            })
+            .publish(|_t| Ready::Ok(()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    client.sink().close();
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect { clean_session: true, ..Default::default() }.into(), &codec)
+        .await
+        .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    sleep(Millis(50)).await;
+
+    let ids = ids.lock().unwrap();
+    assert_eq!(ids[0], "user");
+    assert!(ids[1].starts_with("auto-"));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_deny_dollar_publish() -> std::io::Result<()> {
+    let called = Arc::new(AtomicBool::new(false));
+    let called2 = called.clone();
+
+    let srv = server::test_server(move || {
+        let called = called2.clone();
+        MqttServer::new(handshake)
+            .deny_dollar_publish(true)
+            .publish(move |_| {
+                called.store(true, Relaxed);
+                Ready::Ok(())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static("$SYS/foo"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    // connection is closed without invoking publish service
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    assert!(!called.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_filter() -> std::io::Result<()> {
+    let publishes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .publish_filter(|p: &Publish| !p.publish_topic().starts_with("noisy"))
+            .publish(move |p: Publish| {
+                publishes.lock().unwrap().push(p.publish_topic().to_string());
+                Ready::Ok(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // filtered publishes are still acked
+    sink.publish("noisy/1", Bytes::new()).send_at_most_once().unwrap();
+    sink.publish("noisy/2", Bytes::new()).send_at_least_once().await.unwrap();
+    sink.publish("test", Bytes::new()).send_at_least_once().await.unwrap();
+    assert_eq!(*publishes.lock().unwrap(), vec!["test".to_string()]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_topic_size_limits() -> std::io::Result<()> {
+    let publishes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .topic_size_limits(
+                |topic| if topic.starts_with("telemetry/") { Some(4) } else { None },
+            )
+            .publish(move |p: Publish| {
+                publishes.lock().unwrap().push(p.publish_topic().to_string());
+                Ready::Ok(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish("firmware", Bytes::from(vec![0; 1024])).send_at_least_once().await.unwrap();
+    sink.publish("telemetry/1", Bytes::from_static(b"1234"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert!(sink
+        .publish("telemetry/2", Bytes::from_static(b"12345"))
+        .send_at_least_once()
+        .await
+        .is_err());
+    sleep(Millis(50)).await;
+    assert!(!sink.is_open());
+    assert_eq!(
+        *publishes.lock().unwrap(),
+        vec!["firmware".to_string(), "telemetry/1".to_string()]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_inbound_queue_depth() -> std::io::Result<()> {
+    let called = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let called2 = called.clone();
+
+    let srv = server::test_server(move || {
+        let called = called2.clone();
+        MqttServer::new(handshake)
+            .inbound_queue_depth(2)
+            .publish(move |_: Publish| {
+                called.fetch_add(1, Relaxed);
+                async {
+                    // slow publish service
+                    sleep(Millis(10_000)).await;
+                    Ok::<_, ()>(())
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    for _ in 0..4 {
+        io.send(
+            codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtMostOnce,
+                topic: ByteString::from_static("test"),
+                packet_id: None,
+                payload: Bytes::new(),
+            }
+            .into(),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+    sleep(Millis(100)).await;
+
+    // dispatcher stops reading once queue is full
+    assert_eq!(called.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_connect_options() -> std::io::Result<()> {
+    let packets = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let packets2 = packets.clone();
+
+    let srv = server::test_server(move || {
+        let packets = packets2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            packets.lock().unwrap().push(packet.packet().clone());
+            Ready::Ok::<_, ()>(packet.ack(St, false))
+        })
+        .publish(|_| Ready::Ok(()))
+        .finish()
+    });
+
+    let opts: client::ConnectOptions = serde_json::from_str(
+        r#"{
+            "client_id": "user",
+            "keep_alive": 10,
+            "clean_session": true,
+            "username": "name",
+            "password": "secret",
+            "last_will": {"topic": "will", "message": "bye", "qos": "AtLeastOnce"}
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(opts.max_send, 16);
+
+    let client =
+        client::MqttConnector::new(srv.addr()).with_options(opts).connect().await.unwrap();
+    client.sink().close();
+
+    let pkt = packets.lock().unwrap()[0].clone();
+    assert_eq!(pkt.client_id, "user");
+    assert_eq!(pkt.keep_alive, 10);
+    assert!(pkt.clean_session);
+    assert_eq!(pkt.username, Some(ByteString::from_static("name")));
+    assert_eq!(pkt.password, Some(Bytes::from_static(b"secret")));
+    let will = pkt.last_will.unwrap();
+    assert_eq!(will.topic, "will");
+    assert_eq!(will.message, Bytes::from_static(b"bye"));
+    assert_eq!(will.qos, QoS::AtLeastOnce);
+    assert!(!will.retain);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unknown_packet_id_ack() -> std::io::Result<()> {
+    let acked = Arc::new(AtomicBool::new(false));
+    let acked2 = acked.clone();
+
+    let srv = server::test_server(move || {
+        let acked = acked2.clone();
+        MqttServer::new(handshake)
+            .on_connected(move |session: Session<St>| {
+                let acked = acked.clone();
+                ntex::rt::spawn(async move {
+                    sleep(Millis(50)).await;
+                    let res = session
+                        .sink()
+                        .publish(ByteString::from_static("test"), Bytes::new())
+                        .send_at_least_once()
+                        .await;
+                    acked.store(res.is_ok(), Relaxed);
+                });
+            })
+            .publish(|_t| Ready::Ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // spurious ack is dropped
+    io.send(codec::Packet::PublishAck { packet_id: NonZeroU16::new(10).unwrap() }, &codec)
+        .await
+        .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = match pkt.0 {
+        codec::Packet::Publish(publish) => publish.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet {:?}", pkt),
+    };
+    assert_ne!(packet_id.get(), 10);
+    io.send(codec::Packet::PublishAck { packet_id }, &codec).await.unwrap();
+    sleep(Millis(50)).await;
+    assert!(acked.load(Relaxed));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[ntex::test]
+async fn test_unix_socket() -> std::io::Result<()> {
+    use ntex::connect::{Connect, ConnectError};
+
+    let path = std::env::temp_dir().join(format!("ntex-mqtt-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let handle = ServerHandle::new();
+    let handle2 = handle.clone();
+    let srv = ntex::server::build()
+        .workers(1)
+        .disable_signals()
+        .bind_uds("mqtt", &path, move |_| {
+            MqttServer::new(handshake)
+                .publish(|_| Ready::Ok(()))
+                .with_handle(handle2.clone())
+                .finish()
+        })?
+        .run();
+
+    let path2 = path.clone();
+    let client = client::MqttConnector::new(path.to_string_lossy().to_string())
+        .connector(fn_service(move |_: Connect<String>| {
+            let path = path2.clone();
+            async move { ntex::rt::unix_connect(path).await.map_err(ConnectError::Io) }
+        }))
+        .client_id("user")
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    // unix socket peer has no network address
+    let conns = handle.connections();
+    assert_eq!(conns.len(), 1);
+    assert!(conns[0].peer_addr().is_none());
+
+    sink.close();
+    srv.stop(true).await;
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_ready() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| Ready::Ok::<_, ()>(()))
+            .control(|msg| match msg {
+                Control::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        if sub.topic().starts_with("denied/") {
+                            sub.fail();
+                        } else {
+                            let qos = sub.qos();
+                            sub.confirm(qos);
+                        }
+                    }
+                    Ready::<_, ()>::Ok(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // no initial subscriptions
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert_eq!(client.ready().await.unwrap(), vec![]);
+    client.sink().close();
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .subscribe(vec![
+            (ByteString::from_static("a/b"), codec::QoS::AtLeastOnce),
+            (ByteString::from_static("denied/c"), codec::QoS::AtMostOnce),
+        ])
+        .subscribe(vec![(ByteString::from_static("d/#"), codec::QoS::AtMostOnce)])
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    let ready = client.ready();
+    ntex::rt::spawn(client.start_default());
+
+    assert_eq!(
+        ready.await.unwrap(),
+        vec![
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+            codec::SubscribeReturnCode::Failure,
+            codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_resend_unacked_on_reconnect() -> std::io::Result<()> {
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                received.lock().unwrap().push((p.payload().clone(), p.dup()));
+                let hold = !p.dup() && matches!(p.payload().as_ref(), b"2" | b"3");
+                async move {
+                    // keep second and third publishes unacknowledged
+                    if hold {
+                        std::future::pending::<()>().await;
+                    }
+                    Ok::<_, ()>(())
+                }
+            })
+            .finish()
+    });
+
+    let connector = client::MqttConnector::new(srv.addr()).client_id("user");
+    let client = connector.connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = join_all(["1", "2", "3"].into_iter().map(|payload| {
+        let fut =
+            sink.publish("a/b", Bytes::from_static(payload.as_bytes())).send_at_least_once();
+        let sink = sink.clone();
+        async move {
+            let res = fut.await;
+            // drop connection after first publish is acked
+            if payload == "1" {
+                sleep(Millis(50)).await;
+                sink.close();
+            }
+            res
+        }
+    }))
+    .await;
+    assert!(res[0].is_ok());
+    assert_eq!(res[1], Err(SendPacketError::Disconnected));
+    assert_eq!(res[2], Err(SendPacketError::Disconnected));
+
+    let client = connector.reconnect(&sink).await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish("a/b", Bytes::from_static(b"4")).send_at_least_once().await.unwrap();
+    assert!(sink.inflight_ids().is_empty());
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![
+            (Bytes::from_static(b"1"), false),
+            (Bytes::from_static(b"2"), false),
+            (Bytes::from_static(b"3"), false),
+            (Bytes::from_static(b"2"), true),
+            (Bytes::from_static(b"3"), true),
+            (Bytes::from_static(b"4"), false),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
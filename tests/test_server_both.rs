@@ -1,7 +1,7 @@
 use ntex::server;
 use ntex::util::{ByteString, Bytes, Ready};
 
-use ntex_mqtt::{v3, v5, MqttServer};
+use ntex_mqtt::{error::ClientError, v3, v5, MqttServer, ProtocolVersion};
 
 struct St;
 
@@ -60,3 +60,46 @@ async fn test_simple() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_allowed_versions() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new()
+            .min_protocol_version(ProtocolVersion::MQTT5)
+            // order of settings does not matter
+            .allowed_versions(&[ProtocolVersion::MQTT3, ProtocolVersion::MQTT5])
+            .v3(v3::MqttServer::new(|con: v3::Handshake| {
+                Ready::Ok::<_, TestError>(con.ack(St, false))
+            })
+            .publish(|_| Ready::Ok::<_, TestError>(())))
+            .v5(v5::MqttServer::new(|con: v5::Handshake| {
+                assert_eq!(con.protocol_version(), ProtocolVersion::MQTT5);
+                Ready::Ok::<_, TestError>(con.ack(St))
+            })
+            .publish(|p: v5::Publish| Ready::Ok::<_, TestError>(p.ack())))
+    });
+
+    // v3 client is rejected
+    let err =
+        v3::client::MqttConnector::new(srv.addr()).client_id("user").connect().await.err();
+    match err {
+        Some(ClientError::Ack(ack)) => assert_eq!(
+            ack.return_code,
+            v3::codec::ConnectAckReason::UnacceptableProtocolVersion
+        ),
+        err => panic!("Unexpected result: {:?}", err),
+    }
+
+    // v5 client is accepted
+    let client =
+        v5::client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("topic"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    Ok(())
+}
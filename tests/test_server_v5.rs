@@ -1,5 +1,5 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{cell::RefCell, rc::Rc};
+use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc, Mutex};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 use std::{future::Future, num::NonZeroU16, pin::Pin, time::Duration};
 
 use ntex::time::{sleep, Millis, Seconds};
@@ -7,9 +7,10 @@ use ntex::util::{lazy, ByteString, Bytes, BytesMut, Ready};
 use ntex::{codec::Encoder, server, service::fn_service};
 
 use ntex_mqtt::v5::{
-    client, codec, error, Control, Handshake, HandshakeAck, MqttServer, Publish, PublishAck,
-    QoS, Session,
+    client, codec, error, Control, FilterDecision, Handshake, HandshakeAck, MqttServer,
+    PayloadFormat, Publish, PublishAck, PublishAcked, QoS, Session,
 };
+use ntex_mqtt::PacketIdStrategy;
 
 struct St;
 
@@ -97,6 +98,122 @@ async fn test_handshake_failed() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_auth_failure_delay() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(fn_service(|hnd: Handshake| async move {
+            Ok(hnd.failed::<St>(codec::ConnectAckReason::BadUserNameOrPassword))
+        }))
+        .auth_failure_delay(Duration::from_millis(300))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let start = std::time::Instant::now();
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap_err();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    match err {
+        error::ClientError::Ack(pkt) => {
+            assert_eq!(pkt.reason_code, codec::ConnectAckReason::BadUserNameOrPassword);
+        }
+        _ => panic!("error"),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_overload_check() -> std::io::Result<()> {
+    let overloaded = Arc::new(AtomicBool::new(true));
+    let overloaded2 = overloaded.clone();
+    let called = Arc::new(AtomicBool::new(false));
+    let called2 = called.clone();
+
+    let srv = server::test_server(move || {
+        let overloaded = overloaded2.clone();
+        let called = called2.clone();
+        MqttServer::new(fn_service(move |hnd: Handshake| {
+            called.store(true, Relaxed);
+            Ready::Ok::<_, TestError>(hnd.ack(St))
+        }))
+        .overload_check(move || overloaded.load(Relaxed))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap_err();
+    match err {
+        error::ClientError::Ack(pkt) => {
+            assert_eq!(pkt.reason_code, codec::ConnectAckReason::ServerUnavailable);
+        }
+        _ => panic!("error"),
+    }
+    assert!(!called.load(Relaxed));
+
+    overloaded.store(false, Relaxed);
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(called.load(Relaxed));
+    drop(client);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_egress_filter() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .egress_filter(|pkt: &mut codec::Packet| {
+                if let codec::Packet::ConnectAck(ref mut ack) = pkt {
+                    ack.user_properties.push((
+                        ByteString::from_static("region"),
+                        ByteString::from_static("eu"),
+                    ));
+                }
+                FilterDecision::Pass
+            })
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert_eq!(
+        client.packet().user_properties,
+        vec![(ByteString::from_static("region"), ByteString::from_static("eu"))]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_redirect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(fn_service(|hnd: Handshake| async move {
+            Ok(hnd.redirect::<St>(
+                codec::ConnectAckReason::UseAnotherServer,
+                ByteString::from_static("other.example.com:1883"),
+            ))
+        }))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap_err();
+    assert_eq!(err.server_reference().map(|v| v.as_str()), Some("other.example.com:1883"));
+    match err {
+        error::ClientError::Ack(pkt) => {
+            assert_eq!(pkt.reason_code, codec::ConnectAckReason::UseAnotherServer);
+        }
+        _ => panic!("error"),
+    }
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_disconnect() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -127,6 +244,41 @@ async fn test_disconnect() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_session_disconnect_with() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                Ready::Ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    session.disconnect_with(
+                        codec::DisconnectReasonCode::AdministrativeAction,
+                        Some(ByteString::from_static("bye")),
+                    );
+                    Ready::Ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_string: Some(ByteString::from_static("bye")),
+            ..codec::Disconnect::new(codec::DisconnectReasonCode::AdministrativeAction)
+        })
+    );
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_disconnect_with_reason() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -161,6 +313,69 @@ async fn test_disconnect_with_reason() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_disconnect_session_expiry() -> std::io::Result<()> {
+    let expiry = Arc::new(Mutex::new(Vec::new()));
+    let expiry2 = expiry.clone();
+    let violation = Arc::new(AtomicBool::new(false));
+    let violation2 = violation.clone();
+
+    let srv = server::test_server(move || {
+        let expiry = expiry2.clone();
+        let violation = violation2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let expiry = expiry.clone();
+                let violation = violation.clone();
+                Ready::Ok::<_, TestError>(ntex::service::fn_service(
+                    move |msg: Control<TestError>| match msg {
+                        Control::Disconnect(msg) => {
+                            expiry.lock().unwrap().push(session.session_expiry_interval());
+                            Ready::Ok::<_, TestError>(msg.ack())
+                        }
+                        Control::ProtocolError(msg) => {
+                            violation.store(true, Relaxed);
+                            Ready::Ok(msg.ack())
+                        }
+                        _ => Ready::Ok(msg.ack()),
+                    },
+                ))
+            }))
+            .finish()
+    });
+
+    // client clears persistent session on graceful exit
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    let connect = codec::Connect {
+        client_id: ByteString::from_static("user"),
+        session_expiry_interval_secs: 60,
+        ..Default::default()
+    };
+    io.send(connect.into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    let disconnect =
+        codec::Disconnect { session_expiry_interval_secs: Some(0), ..Default::default() };
+    io.send(disconnect.into(), &codec).await.unwrap();
+    sleep(Millis(50)).await;
+    assert_eq!(*expiry.lock().unwrap(), vec![0]);
+    assert!(!violation.load(Relaxed));
+
+    // non-zero expiry is not allowed if it was zero at connect [MQTT-3.14.2-2]
+    let io = srv.connect().await.unwrap();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    let disconnect =
+        codec::Disconnect { session_expiry_interval_secs: Some(10), ..Default::default() };
+    io.send(disconnect.into(), &codec).await.unwrap();
+    sleep(Millis(50)).await;
+    assert!(violation.load(Relaxed));
+    assert_eq!(*expiry.lock().unwrap(), vec![0]);
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_nested_errors_handling() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -188,6 +403,37 @@ async fn test_nested_errors_handling() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_first_action_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .first_action_timeout(Seconds(1))
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(|msg| match msg {
+                Control::ActionTimeout(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.ack()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt.0,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::AdministrativeAction,
+            ..
+        })
+    ));
+    assert!(io.recv(&codec).await.unwrap().is_none());
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_disconnect_on_error() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -380,6 +626,76 @@ async fn test_ack_order() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_ordered_subscribe() -> std::io::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let events = events2.clone();
+        let events2 = events2.clone();
+        MqttServer::new(handshake)
+            .ordered_subscribe(true)
+            .publish(move |p: Publish| {
+                events.lock().unwrap().push("publish");
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| {
+                let events = events2.clone();
+                async move {
+                    match msg {
+                        Control::Subscribe(mut msg) => {
+                            // slow subscription registration
+                            sleep(Millis(100)).await;
+                            events.lock().unwrap().push("subscribe");
+                            for mut sub in &mut msg {
+                                sub.subscribe(codec::QoS::AtLeastOnce);
+                            }
+                            Ok::<_, TestError>(msg.ack())
+                        }
+                        _ => Ok(msg.disconnect()),
+                    }
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Subscribe {
+            id: None,
+            packet_id: NonZeroU16::new(2).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: vec![(
+                ByteString::from("test"),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::SubscribeAck(_)));
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::PublishAck(_)));
+    assert_eq!(*events.lock().unwrap(), vec!["subscribe", "publish"]);
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_dups() {
     let srv = server::test_server(move || {
@@ -629,6 +945,38 @@ async fn test_keepalive2() {
     assert!(ka.load(Relaxed));
 }
 
+#[ntex::test]
+async fn test_session_set_keep_alive() {
+    let ka = Arc::new(AtomicBool::new(false));
+    let ka2 = ka.clone();
+
+    let srv = server::test_server(move || {
+        let ka = ka2.clone();
+
+        MqttServer::new(|con: Handshake| async move { Ok(con.ack(St).keep_alive(30)) })
+            .on_connected(|session: Session<St>| session.set_keep_alive(Seconds(1)))
+            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+            .control(move |msg| match msg {
+                Control::ProtocolError(msg) => {
+                    if let &error::ProtocolError::KeepAliveTimeout = msg.get_ref() {
+                        ka.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    sleep(Duration::from_millis(2500)).await;
+    assert!(ka.load(Relaxed));
+}
+
 #[ntex::test]
 async fn test_keepalive3() {
     let ka = Arc::new(AtomicBool::new(false));
@@ -1149,31 +1497,105 @@ async fn test_max_qos() -> std::io::Result<()> {
 }
 
 #[ntex::test]
-async fn test_sink_ready() -> std::io::Result<()> {
-    let srv = server::test_server(|| {
-        MqttServer::new(fn_service(|packet: Handshake| async move {
-            let sink = packet.sink();
-            let mut ready = Box::pin(sink.ready());
-            let res = lazy(|cx| Pin::new(&mut ready).poll(cx)).await;
-            assert!(res.is_pending());
-            assert!(!sink.is_ready());
-
-            ntex::rt::spawn(async move {
-                sink.ready().await;
-                assert!(sink.is_ready());
-                sink.publish("/test", Bytes::from_static(b"body")).send_at_most_once().unwrap();
-            });
-
-            Ok::<_, TestError>(packet.ack(St))
-        }))
-        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
-        .finish()
+async fn test_max_qos_exactly_once_rejected() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_qos(QoS::AtLeastOnce)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                Control::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| s.confirm(QoS::ExactlyOnce));
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                Control::ProtocolError(msg) => Ready::Ok(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
     });
 
-    // connect to server
     let io = srv.connect().await.unwrap();
     let codec = codec::Codec::default();
-    io.encode(
+    io.send(
+        codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::ConnectAck(ack) => assert_eq!(ack.max_qos, QoS::AtLeastOnce),
+        _ => panic!("Unexpected packet {:?}", pkt.0),
+    }
+
+    // granted qos is downgraded to server max qos
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(
+                "topic1".into(),
+                codec::SubscriptionOptions {
+                    qos: codec::QoS::ExactlyOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::AtSubscribe,
+                },
+            )],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::SubscribeAck(ack) => {
+            assert_eq!(ack.status, vec![codec::SubscribeAckReason::GrantedQos1])
+        }
+        _ => panic!("Unexpected packet {:?}", pkt.0),
+    }
+
+    let mut pkt = pkt_publish();
+    pkt.qos = QoS::ExactlyOnce;
+    io.send(pkt.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::QosNotSupported,
+            ..Default::default()
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_ready() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(fn_service(|packet: Handshake| async move {
+            let sink = packet.sink();
+            let mut ready = Box::pin(sink.ready());
+            let res = lazy(|cx| Pin::new(&mut ready).poll(cx)).await;
+            assert!(res.is_pending());
+            assert!(!sink.is_ready());
+
+            ntex::rt::spawn(async move {
+                sink.ready().await;
+                assert!(sink.is_ready());
+                sink.publish("/test", Bytes::from_static(b"body")).send_at_most_once().unwrap();
+            });
+
+            Ok::<_, TestError>(packet.ack(St))
+        }))
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // connect to server
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.encode(
         codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
         &codec,
     )
@@ -1239,6 +1661,45 @@ async fn test_sink_publish_noblock() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_sink_publish_ack_cb_timed() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                sleep(Millis(100)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let results = Rc::new(RefCell::new(Vec::new()));
+    let results2 = results.clone();
+
+    sink.publish_ack_cb_timed(move |pkt, disconnected, rtt| {
+        assert!(!disconnected);
+        results2.borrow_mut().push((pkt.packet_id, rtt));
+    });
+
+    let res = sink
+        .publish(ByteString::from_static("test1"), Bytes::new())
+        .send_at_least_once_no_block();
+    assert!(res.is_ok());
+    sleep(Millis(300)).await;
+
+    let results = results.borrow();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, NonZeroU16::new(1).unwrap());
+    assert!(results[0].1 >= Duration::from_millis(100));
+
+    sink.close();
+    Ok(())
+}
+
 // Slow frame rate
 #[ntex::test]
 async fn test_frame_read_rate() -> std::io::Result<()> {
@@ -1303,3 +1764,1890 @@ async fn test_frame_read_rate() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_request_response_properties() -> std::io::Result<()> {
+    let check = Arc::new(AtomicBool::new(false));
+    let check2 = check.clone();
+
+    let srv = server::test_server(move || {
+        let check = check2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                if p.response_topic().map(|t| t.as_str()) == Some("response")
+                    && p.correlation_data().map(|d| d.as_ref()) == Some(b"id-1".as_ref())
+                {
+                    check.store(true, Relaxed);
+                }
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .request(
+            ByteString::from_static("test"),
+            Bytes::new(),
+            ByteString::from_static("response"),
+            Bytes::from_static(b"id-1"),
+        )
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    assert!(check.load(Relaxed));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_payload_transform() -> std::io::Result<()> {
+    let check = Arc::new(AtomicBool::new(false));
+    let check2 = check.clone();
+
+    let srv = server::test_server(move || {
+        let check = check2.clone();
+        MqttServer::new(handshake)
+            .payload_transform(|_, payload| {
+                let props = codec::PublishProperties {
+                    content_type: Some(ByteString::from_static("text/plain")),
+                    ..Default::default()
+                };
+                (payload.iter().rev().copied().collect::<Vec<_>>().into(), Some(props))
+            })
+            .publish(move |p: Publish| {
+                let props = &p.packet().properties;
+                if p.payload().as_ref() == b"payload"
+                    && props.content_type.as_ref().map(|t| t.as_str()) == Some("text/plain")
+                    && props.user_properties
+                        == vec![(
+                            ByteString::from_static("codec"),
+                            ByteString::from_static("rev"),
+                        )]
+                {
+                    check.store(true, Relaxed);
+                }
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.with_payload_transform(|_, payload| {
+        let props = codec::PublishProperties {
+            content_type: Some(ByteString::from_static("application/octet-stream")),
+            user_properties: vec![(
+                ByteString::from_static("codec"),
+                ByteString::from_static("rev"),
+            )],
+            ..Default::default()
+        };
+        (payload.iter().rev().copied().collect::<Vec<_>>().into(), Some(props))
+    });
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from_static(b"payload"))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    assert!(check.load(Relaxed));
+
+    // streamed payload could not be transformed
+    let (_tx, rx) = ntex::channel::mpsc::channel::<Bytes>();
+    let res = sink.publish_stream("test", QoS::AtLeastOnce, 7, rx).await;
+    assert_eq!(res, Err(error::SendPacketError::TransformUnsupported));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_properties() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            let props = packet.properties();
+            assert_eq!(props.session_expiry_interval(), 60);
+            assert_eq!(props.receive_max(), NonZeroU16::new(8));
+            assert_eq!(props.max_packet_size().map(|v| v.get()), Some(1024));
+            assert_eq!(
+                props.user_properties(),
+                &vec![(ByteString::from_static("key"), ByteString::from_static("value"))]
+            );
+            Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .session_expiry_interval(60)
+        .max_receive(8)
+        .max_packet_size(1024)
+        .properties(|props| props.push(("key".into(), "value".into())))
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_will() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            let will = packet.will().unwrap();
+            assert_eq!(will.topic(), "will");
+            assert_eq!(will.payload(), &Bytes::from_static(b"gone"));
+            assert_eq!(will.qos(), QoS::AtLeastOnce);
+            assert!(will.retain());
+            assert_eq!(will.delay_interval(), 10);
+            assert_eq!(will.content_type().map(|v| v.as_str()), Some("text/plain"));
+            assert_eq!(will.correlation_data(), Some(&Bytes::from_static(b"corr")));
+            assert_eq!(
+                will.user_properties(),
+                &vec![(ByteString::from_static("key"), ByteString::from_static("value"))]
+            );
+            // optional properties
+            assert_eq!(will.message_expiry_interval(), None);
+            assert_eq!(will.response_topic(), None);
+            assert_eq!(will.is_utf8_payload(), None);
+            Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .last_will(codec::LastWill {
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            topic: ByteString::from_static("will"),
+            message: Bytes::from_static(b"gone"),
+            will_delay_interval_sec: Some(10),
+            correlation_data: Some(Bytes::from_static(b"corr")),
+            message_expiry_interval: None,
+            content_type: Some(ByteString::from_static("text/plain")),
+            user_properties: vec![(
+                ByteString::from_static("key"),
+                ByteString::from_static("value"),
+            )],
+            is_utf8_payload: None,
+            response_topic: None,
+        })
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_on_abnormal_disconnect() -> std::io::Result<()> {
+    let wills = Arc::new(Mutex::new(Vec::new()));
+    let wills2 = wills.clone();
+
+    let srv = server::test_server(move || {
+        let wills = wills2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(|msg| match msg {
+                Control::Disconnect(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.ack()),
+            })
+            .on_will(move |will, _| {
+                wills.lock().unwrap().push((will.topic().clone(), will.qos(), will.retain()));
+            })
+            .finish()
+    });
+
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.last_will = Some(codec::LastWill {
+        qos: QoS::ExactlyOnce,
+        retain: true,
+        topic: ByteString::from_static("will"),
+        message: Bytes::from_static(b"gone"),
+        will_delay_interval_sec: None,
+        correlation_data: None,
+        message_expiry_interval: None,
+        content_type: None,
+        user_properties: Vec::new(),
+        is_utf8_payload: None,
+        response_topic: None,
+    });
+    let codec = codec::Codec::default();
+
+    // normal disconnect, will is dropped
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    io.send(codec::Disconnect::default().into(), &codec).await.unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    sleep(Millis(100)).await;
+    assert!(wills.lock().unwrap().is_empty());
+
+    // disconnect with will message
+    let io = srv.connect().await.unwrap();
+    io.send(connect.clone().into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    io.send(
+        codec::Disconnect::new(codec::DisconnectReasonCode::DisconnectWithWillMessage).into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    assert!(io.recv(&codec).await.unwrap().is_none());
+    sleep(Millis(100)).await;
+    assert_eq!(wills.lock().unwrap().len(), 1);
+
+    // connection is closed without DISCONNECT packet
+    let io = srv.connect().await.unwrap();
+    io.send(connect.into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    drop(io);
+    sleep(Millis(100)).await;
+    assert_eq!(
+        wills.lock().unwrap()[1],
+        (ByteString::from_static("will"), QoS::ExactlyOnce, true)
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_no_will() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            assert!(packet.will().is_none());
+            Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_negotiated() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|packet: Handshake| async move {
+            Ok::<_, TestError>(packet.ack(St).with(|ack| {
+                ack.max_qos = QoS::AtLeastOnce;
+                ack.retain_available = false;
+                ack.max_packet_size = Some(2048);
+                ack.server_keepalive_sec = Some(15);
+            }))
+        })
+        .max_topic_alias(5)
+        .max_receive(4)
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(30))
+        .connect()
+        .await
+        .unwrap();
+
+    let neg = client.negotiated();
+    assert_eq!(neg.protocol_version, 5);
+    assert_eq!(neg.keepalive, Seconds(15));
+    assert_eq!(neg.max_qos, QoS::AtLeastOnce);
+    assert!(!neg.retain_available);
+    assert_eq!(neg.max_packet_size, Some(2048));
+    assert_eq!(neg.topic_alias_max, 5);
+    assert_eq!(neg.receive_max.get(), 4);
+    assert!(!neg.session_present);
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_inbound_topic_alias() -> std::io::Result<()> {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+    let violated = Arc::new(AtomicBool::new(false));
+    let violated2 = violated.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        let violated = violated2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                topics.lock().unwrap().push(p.topic().path().to_string());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                Control::ProtocolError(msg) => {
+                    if let error::ProtocolError::ProtocolViolation(_) = msg.get_ref() {
+                        violated.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let alias = NonZeroU16::new(1);
+    let mut pkt = pkt_publish();
+    pkt.topic = ByteString::from("topic/alias");
+    pkt.properties.topic_alias = alias;
+    io.send(pkt.into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let mut pkt = pkt_publish();
+    pkt.topic = ByteString::new();
+    pkt.packet_id = NonZeroU16::new(2);
+    pkt.properties.topic_alias = alias;
+    io.send(pkt.into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    assert_eq!(
+        &*topics.lock().unwrap(),
+        &["topic/alias".to_string(), "topic/alias".to_string()]
+    );
+
+    // unknown alias
+    let mut pkt = pkt_publish();
+    pkt.topic = ByteString::new();
+    pkt.packet_id = NonZeroU16::new(3);
+    pkt.properties.topic_alias = NonZeroU16::new(2);
+    io.send(pkt.into(), &codec).await.unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::Disconnect(_)));
+    assert!(violated.load(Relaxed));
+    assert_eq!(topics.lock().unwrap().len(), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_inbound_topic_alias_max() -> std::io::Result<()> {
+    let violated = Arc::new(AtomicBool::new(false));
+    let violated2 = violated.clone();
+
+    let srv = server::test_server(move || {
+        let violated = violated2.clone();
+        MqttServer::new(handshake)
+            .max_topic_alias(10)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                Control::ProtocolError(msg) => {
+                    if let error::ProtocolError::ProtocolViolation(_) = msg.get_ref() {
+                        violated.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let ack = io.recv(&codec).await.unwrap().unwrap();
+    match ack.0 {
+        codec::Packet::ConnectAck(ack) => assert_eq!(ack.topic_alias_max, 10),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+
+    let mut pkt = pkt_publish();
+    pkt.topic = ByteString::from("topic/alias");
+    pkt.properties.topic_alias = NonZeroU16::new(11);
+    io.send(pkt.into(), &codec).await.unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    match pkt.0 {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::TopicAliasInvalid)
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+    assert!(violated.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_second_connect() -> std::io::Result<()> {
+    let violated = Arc::new(AtomicBool::new(false));
+    let violated2 = violated.clone();
+
+    let srv = server::test_server(move || {
+        let violated = violated2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                Control::ProtocolError(msg) => {
+                    if let error::ProtocolError::ProtocolViolation(_) = msg.get_ref() {
+                        violated.store(true, Relaxed);
+                    }
+                    Ready::Ok::<_, TestError>(msg.ack())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::Disconnect(_)));
+    assert!(violated.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_builder() -> std::io::Result<()> {
+    let acked = Arc::new(AtomicBool::new(false));
+    let acked2 = acked.clone();
+
+    let srv = server::test_server(move || {
+        let acked = acked2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let sink = packet.sink();
+            let acked = acked.clone();
+            ntex::rt::spawn(async move {
+                sleep(Millis(25)).await;
+                let res = sink
+                    .publish_builder()
+                    .topic("topic")
+                    .payload(Bytes::from_static(b"data"))
+                    .qos(QoS::AtLeastOnce)
+                    .properties(|props| {
+                        props.user_properties.push(("key".into(), "value".into()))
+                    })
+                    .send()
+                    .await;
+                if let Ok(PublishAcked::AtLeastOnce(_)) = res {
+                    acked.store(true, Relaxed);
+                }
+                assert!(sink.publish_builder().send().await.is_err());
+            });
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let publish = if let codec::Packet::Publish(publish) = pkt.0 {
+        publish
+    } else {
+        panic!("Expected publish packet")
+    };
+    assert_eq!(publish.topic.as_str(), "topic");
+    assert_eq!(publish.payload, Bytes::from_static(b"data"));
+    assert_eq!(publish.qos, codec::QoS::AtLeastOnce);
+    assert_eq!(publish.properties.user_properties, vec![("key".into(), "value".into())]);
+
+    io.send(
+        codec::PublishAck { packet_id: publish.packet_id.unwrap(), ..Default::default() }
+            .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Millis(50)).await;
+    assert!(acked.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_suback_no_problem_info() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .control(move |msg| match msg {
+                Control::Subscribe(mut msg) => {
+                    msg.iter_mut()
+                        .for_each(|mut s| s.fail(codec::SubscribeAckReason::NotAuthorized));
+                    Ready::Ok::<_, TestError>(
+                        msg.ack_reason("some reason".into())
+                            .ack_properties(|props| props.push(("key".into(), "value".into())))
+                            .ack(),
+                    )
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::new();
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.request_problem_info = false;
+    io.send(codec::Packet::Connect(Box::new(connect)), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![("topic1".into(), codec::SubscriptionOptions::default())],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![codec::SubscribeAckReason::NotAuthorized],
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_publish_exactly_once() -> std::io::Result<()> {
+    let rejected = Arc::new(AtomicBool::new(false));
+    let rejected2 = rejected.clone();
+    let completed = Arc::new(AtomicBool::new(false));
+    let completed2 = completed.clone();
+
+    let srv = server::test_server(move || {
+        let rejected = rejected2.clone();
+        let completed = completed2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let sink = packet.sink();
+            let rejected = rejected.clone();
+            let completed = completed.clone();
+            ntex::rt::spawn(async move {
+                sleep(Millis(25)).await;
+                let res = sink
+                    .publish(ByteString::from_static("topic"), Bytes::new())
+                    .send_exactly_once()
+                    .await;
+                if res
+                    == Err(error::SendPacketError::PublishRejected(
+                        codec::PublishAckReason::NotAuthorized,
+                    ))
+                {
+                    rejected.store(true, Relaxed);
+                }
+
+                // builder with QoS 2 uses exactly once flow
+                let res =
+                    sink.publish_builder().topic("topic").qos(QoS::ExactlyOnce).send().await;
+                if let Ok(PublishAcked::ExactlyOnce(_)) = res {
+                    completed.store(true, Relaxed);
+                }
+            });
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // PUBREC with failure reason code terminates QoS 2 flow
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let publish = if let codec::Packet::Publish(publish) = pkt.0 {
+        publish
+    } else {
+        panic!("Expected publish packet")
+    };
+    assert_eq!(publish.qos, codec::QoS::ExactlyOnce);
+    io.send(
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: publish.packet_id.unwrap(),
+            reason_code: codec::PublishAckReason::NotAuthorized,
+            ..Default::default()
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    let publish = if let codec::Packet::Publish(publish) = pkt.0 {
+        publish
+    } else {
+        panic!("Expected publish packet")
+    };
+    assert!(rejected.load(Relaxed));
+
+    let packet_id = publish.packet_id.unwrap();
+    io.send(
+        codec::Packet::PublishReceived(codec::PublishAck { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(
+        matches!(pkt.0, codec::Packet::PublishRelease(ref ack) if ack.packet_id == packet_id)
+    );
+    io.send(
+        codec::Packet::PublishComplete(codec::PublishAck2 { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Millis(50)).await;
+    assert!(completed.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_exactly_once_packet_id_reserved() -> std::io::Result<()> {
+    let completed = Arc::new(AtomicBool::new(false));
+    let completed2 = completed.clone();
+
+    let srv = server::test_server(move || {
+        let completed = completed2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let sink = packet.sink();
+            sink.packet_id_strategy(PacketIdStrategy::LowestFree);
+            let completed = completed.clone();
+            ntex::rt::spawn(async move {
+                sleep(Millis(25)).await;
+                let sink2 = sink.clone();
+                ntex::rt::spawn(async move {
+                    let res = sink2
+                        .publish(ByteString::from_static("topic"), Bytes::new())
+                        .send_exactly_once()
+                        .await;
+                    completed.store(res.is_ok(), Relaxed);
+                });
+
+                // publish while QoS 2 exchange is between PUBREC and PUBCOMP
+                sleep(Millis(100)).await;
+                let _ = sink
+                    .publish(ByteString::from_static("topic2"), Bytes::new())
+                    .send_at_least_once()
+                    .await;
+            });
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    let packet_id = if let codec::Packet::Publish(publish) = pkt {
+        publish.packet_id.unwrap()
+    } else {
+        panic!("Expected publish packet")
+    };
+    io.send(
+        codec::Packet::PublishReceived(codec::PublishAck { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    assert!(
+        matches!(pkt, codec::Packet::PublishRelease(ref ack) if ack.packet_id == packet_id)
+    );
+
+    // packet id of QoS 2 publish is not reused before PUBCOMP
+    let (pkt, _) = io.recv(&codec).await.unwrap().unwrap();
+    let publish = if let codec::Packet::Publish(publish) = pkt {
+        publish
+    } else {
+        panic!("Expected publish packet")
+    };
+    assert_eq!(publish.topic.as_str(), "topic2");
+    assert_ne!(publish.packet_id, Some(packet_id));
+
+    io.send(
+        codec::Packet::PublishComplete(codec::PublishAck2 { packet_id, ..Default::default() }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    io.send(
+        codec::PublishAck { packet_id: publish.packet_id.unwrap(), ..Default::default() }
+            .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    sleep(Millis(50)).await;
+    assert!(completed.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_exactly_once_flow() -> std::io::Result<()> {
+    let publishes = Arc::new(Mutex::new(0));
+    let publishes2 = publishes.clone();
+    let released = Arc::new(Mutex::new(Vec::new()));
+    let released2 = released.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        let released = released2.clone();
+        MqttServer::new(|con: Handshake| async move { Ok::<_, TestError>(con.ack(St)) })
+            .max_qos(QoS::ExactlyOnce)
+            .control_pubrel(true)
+            .publish(move |p: Publish| {
+                *publishes.lock().unwrap() += 1;
+                Ready::Ok::<_, TestError>(
+                    p.ack().reason_code(codec::PublishAckReason::NoMatchingSubscribers),
+                )
+            })
+            .control(move |msg| match msg {
+                Control::PubRel(msg) => {
+                    released.lock().unwrap().push((msg.packet_id().get(), msg.reason_code()));
+                    match msg.packet_id().get() {
+                        1 => Ready::Ok::<_, TestError>(
+                            msg.ack_reason("released".into())
+                                .ack_properties(|props| {
+                                    props.push(("key".into(), "value".into()))
+                                })
+                                .ack(),
+                        ),
+                        2 => Ready::Ok(
+                            msg.ack_reason_code(codec::PublishAck2Reason::PacketIdNotFound)
+                                .ack(),
+                        ),
+                        _ => Ready::Ok(msg.ack()),
+                    }
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let id = NonZeroU16::new(1).unwrap();
+    let publish = codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() };
+    io.send(publish.clone().into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: id,
+            reason_code: codec::PublishAckReason::NoMatchingSubscribers,
+            ..Default::default()
+        })
+    );
+
+    // re-delivered publish is not handled until release
+    io.send(codec::Publish { dup: true, ..publish }.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt.0,
+        codec::Packet::PublishReceived(codec::PublishAck { packet_id, .. }) if packet_id == id
+    ));
+    assert_eq!(*publishes.lock().unwrap(), 1);
+
+    io.send(
+        codec::Packet::PublishRelease(codec::PublishAck2 {
+            packet_id: id,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: vec![("client".into(), "prop".into())],
+            reason_string: Some("done".into()),
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id: id,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: vec![("key".into(), "value".into())],
+            reason_string: Some("released".into()),
+        })
+    );
+
+    // control service overrides reason code
+    let id2 = NonZeroU16::new(2).unwrap();
+    io.send(
+        codec::Publish { qos: codec::QoS::ExactlyOnce, packet_id: Some(id2), ..pkt_publish() }
+            .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    io.send(
+        codec::Packet::PublishRelease(codec::PublishAck2 {
+            packet_id: id2,
+            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+            ..Default::default()
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id: id2,
+            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+            ..Default::default()
+        })
+    );
+
+    // unknown packet id
+    let id3 = NonZeroU16::new(3).unwrap();
+    io.send(
+        codec::Packet::PublishRelease(codec::PublishAck2 {
+            packet_id: id3,
+            ..Default::default()
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id: id3,
+            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+            ..Default::default()
+        })
+    );
+
+    assert_eq!(*publishes.lock().unwrap(), 2);
+    assert_eq!(
+        *released.lock().unwrap(),
+        vec![
+            (1, codec::PublishAck2Reason::Success),
+            (2, codec::PublishAck2Reason::PacketIdNotFound),
+            (3, codec::PublishAck2Reason::Success),
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_release_default() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake| async move { Ok::<_, TestError>(con.ack(St)) })
+            .max_qos(QoS::ExactlyOnce)
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .control(|msg: Control<TestError>| Ready::Ok::<_, TestError>(msg.disconnect()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let id = NonZeroU16::new(1).unwrap();
+    io.send(codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() }.into(), &codec)
+        .await
+        .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt.0,
+        codec::Packet::PublishReceived(codec::PublishAck { packet_id, .. }) if packet_id == id
+    ));
+
+    // PUBREL is acked without control service
+    io.send(
+        codec::Packet::PublishRelease(codec::PublishAck2 {
+            packet_id: id,
+            ..Default::default()
+        }),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id: id,
+            reason_code: codec::PublishAck2Reason::Success,
+            ..Default::default()
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_by_qos() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let qos0 = received2.clone();
+        let reliable = received2.clone();
+        MqttServer::new(|con: Handshake| async move { Ok::<_, TestError>(con.ack(St)) })
+            .publish_qos0(move |p: Publish| {
+                qos0.lock().unwrap().push(("qos0", p.publish_topic().to_string()));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .publish_reliable(move |p: Publish| {
+                reliable.lock().unwrap().push(("reliable", p.publish_topic().to_string()));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("t1"), Bytes::new()).send_at_most_once().unwrap();
+    sink.publish(ByteString::from_static("t2"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Millis(50)).await;
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![("qos0", "t1".to_string()), ("reliable", "t2".to_string())]
+    );
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_json() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |mut p: Publish| {
+                let content_type = p.packet().properties.content_type.clone();
+                let value = p.decode_json::<HashMap<String, u32>>().map_err(|e| e.to_string());
+                received.lock().unwrap().push((content_type, value));
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let value = HashMap::from([("value".to_string(), 1u32)]);
+    sink.publish_json("json", &value).unwrap().send_at_least_once().await.unwrap();
+    sink.publish("invalid", Bytes::from_static(b"{")).send_at_least_once().await.unwrap();
+
+    let received = received.lock().unwrap();
+    assert_eq!(received[0].0.as_deref(), Some("application/json"));
+    assert_eq!(received[0].1, Ok(value));
+    // error carries topic of the packet
+    assert!(received[1].1.as_ref().unwrap_err().contains("invalid"));
+
+    sink.close();
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "Max packet size must be at least")]
+fn test_client_max_packet_size_too_small() {
+    let _ = client::MqttConnector::new("127.0.0.1:1883").max_packet_size(8);
+}
+
+#[ntex::test]
+async fn test_map_handshake_error() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|_: Handshake| Ready::<HandshakeAck<St>, _>::Err(TestError))
+            .map_handshake_error(|_| {
+                (codec::ConnectAckReason::BadUserNameOrPassword, Some("invalid".into()))
+            })
+            .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap_err();
+    match err {
+        error::ClientError::Ack(pkt) => {
+            assert_eq!(pkt.reason_code, codec::ConnectAckReason::BadUserNameOrPassword);
+            assert_eq!(pkt.reason_string, Some(ByteString::from_static("invalid")));
+        }
+        _ => panic!("error"),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_rpc() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        fn_service(|io: ntex::io::Io| async move {
+            let codec = codec::Codec::new();
+            let mut subscriptions = 0;
+            while let Ok(Some((pkt, _))) = io.recv(&codec).await {
+                let reply = |topic: ByteString, correlation: Bytes, payload: Bytes| {
+                    codec::Packet::Publish(codec::Publish {
+                        dup: false,
+                        retain: false,
+                        qos: QoS::AtMostOnce,
+                        topic,
+                        packet_id: None,
+                        payload,
+                        properties: codec::PublishProperties {
+                            correlation_data: Some(correlation),
+                            ..Default::default()
+                        },
+                    })
+                };
+
+                match pkt {
+                    codec::Packet::Connect(_) => {
+                        io.send(codec::ConnectAck::default().into(), &codec).await.unwrap();
+                    }
+                    codec::Packet::Subscribe(pkt) => {
+                        subscriptions += 1;
+                        let status = if subscriptions == 1 {
+                            codec::SubscribeAckReason::GrantedQos0
+                        } else {
+                            codec::SubscribeAckReason::NotAuthorized
+                        };
+                        let ack = codec::SubscribeAck {
+                            packet_id: pkt.packet_id,
+                            status: vec![status],
+                            properties: codec::UserProperties::default(),
+                            reason_string: None,
+                        };
+                        io.send(codec::Packet::SubscribeAck(ack), &codec).await.unwrap();
+                    }
+                    codec::Packet::Publish(pkt) => {
+                        assert_eq!(pkt.payload, Bytes::from_static(b"request"));
+                        let topic = pkt.properties.response_topic.clone().unwrap();
+                        let correlation = pkt.properties.correlation_data.clone().unwrap();
+                        assert!(topic.starts_with("rpc/response/"));
+
+                        // response for other request
+                        let pkt = reply(
+                            topic.clone(),
+                            Bytes::from_static(b"other"),
+                            Bytes::from_static(b"other"),
+                        );
+                        io.send(pkt, &codec).await.unwrap();
+                        let pkt = reply(
+                            topic.clone(),
+                            correlation.clone(),
+                            Bytes::from_static(b"response"),
+                        );
+                        io.send(pkt, &codec).await.unwrap();
+                        // duplicated response
+                        let pkt = reply(topic, correlation, Bytes::from_static(b"duplicate"));
+                        io.send(pkt, &codec).await.unwrap();
+                    }
+                    codec::Packet::Unsubscribe(pkt) => {
+                        let ack = codec::UnsubscribeAck {
+                            packet_id: pkt.packet_id,
+                            properties: codec::UserProperties::default(),
+                            reason_string: None,
+                            status: vec![codec::UnsubscribeAckReason::Success],
+                        };
+                        io.send(codec::Packet::UnsubscribeAck(ack), &codec).await.unwrap();
+                    }
+                    _ => (),
+                }
+            }
+            Ok::<_, ()>(())
+        })
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.rpc("test", Bytes::from_static(b"request")).send().await;
+    assert_eq!(res, Ok(Bytes::from_static(b"response")));
+
+    let res = sink.rpc("test", Bytes::from_static(b"request")).send().await;
+    assert_eq!(res, Err(error::RpcError::Subscribe(codec::SubscribeAckReason::NotAuthorized)));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_receive_qos2() -> std::io::Result<()> {
+    let done = Arc::new(AtomicBool::new(false));
+    let done2 = done.clone();
+
+    let srv = server::test_server(move || {
+        let done = done2.clone();
+        fn_service(move |io: ntex::io::Io| {
+            let done = done.clone();
+            async move {
+                let codec = codec::Codec::default();
+                let pkt = io.recv(&codec).await.unwrap().unwrap();
+                assert!(matches!(pkt.0, codec::Packet::Connect(_)));
+                io.send(codec::ConnectAck::default().into(), &codec).await.unwrap();
+
+                let pkt = io.recv(&codec).await.unwrap().unwrap();
+                let packet_id = match pkt.0 {
+                    codec::Packet::Subscribe(pkt) => pkt.packet_id,
+                    _ => panic!("Expected SUBSCRIBE packet"),
+                };
+                let ack = codec::SubscribeAck {
+                    packet_id,
+                    status: vec![codec::SubscribeAckReason::GrantedQos2],
+                    properties: codec::UserProperties::default(),
+                    reason_string: None,
+                };
+                io.send(codec::Packet::SubscribeAck(ack), &codec).await.unwrap();
+
+                let id = NonZeroU16::new(1).unwrap();
+                let publish = codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() };
+                io.send(publish.clone().into(), &codec).await.unwrap();
+                let pkt = io.recv(&codec).await.unwrap().unwrap();
+                assert!(matches!(
+                    pkt.0,
+                    codec::Packet::PublishReceived(codec::PublishAck {
+                        packet_id,
+                        reason_code: codec::PublishAckReason::Success,
+                        ..
+                    }) if packet_id == id
+                ));
+
+                // duplicated publish is not delivered
+                io.send(codec::Publish { dup: true, ..publish }.into(), &codec).await.unwrap();
+                let pkt = io.recv(&codec).await.unwrap().unwrap();
+                assert!(matches!(
+                    pkt.0,
+                    codec::Packet::PublishReceived(codec::PublishAck { packet_id, .. })
+                        if packet_id == id
+                ));
+
+                io.send(
+                    codec::Packet::PublishRelease(codec::PublishAck2 {
+                        packet_id: id,
+                        reason_code: codec::PublishAck2Reason::Success,
+                        properties: codec::UserProperties::default(),
+                        reason_string: None,
+                    }),
+                    &codec,
+                )
+                .await
+                .unwrap();
+                let pkt = io.recv(&codec).await.unwrap().unwrap();
+                assert!(matches!(
+                    pkt.0,
+                    codec::Packet::PublishComplete(codec::PublishAck2 {
+                        packet_id,
+                        reason_code: codec::PublishAck2Reason::Success,
+                        ..
+                    }) if packet_id == id
+                ));
+                done.store(true, Relaxed);
+                sleep(Millis(100)).await;
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let publishes = Arc::new(Mutex::new(0));
+    let publishes2 = publishes.clone();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(fn_service(move |msg: client::Control<()>| {
+        if let client::Control::Publish(msg) = msg {
+            assert_eq!(msg.packet().qos, codec::QoS::ExactlyOnce);
+            *publishes2.lock().unwrap() += 1;
+            Ready::Ok(msg.ack(codec::PublishAckReason::Success))
+        } else {
+            Ready::Ok(msg.ack())
+        }
+    })));
+
+    sink.subscribe(None)
+        .topic_filter(
+            ByteString::from("test"),
+            codec::SubscriptionOptions {
+                qos: codec::QoS::ExactlyOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            },
+        )
+        .send()
+        .await
+        .unwrap();
+
+    sleep(Millis(300)).await;
+    assert!(done.load(Relaxed));
+    assert_eq!(*publishes.lock().unwrap(), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_batch() -> std::io::Result<()> {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let messages2 = messages.clone();
+
+    let srv = server::test_server(move || {
+        let messages = messages2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let mut messages = messages.lock().unwrap();
+                match p.iter_batch() {
+                    Some(batch) => messages.extend(batch),
+                    None => messages.push(p.payload().clone()),
+                }
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let batch = vec![Bytes::from_static(b"msg1"), Bytes::new(), Bytes::from_static(b"msg3")];
+    sink.publish_batch("test", batch).send_at_least_once().await.unwrap();
+    // length prefix without batch property
+    sink.publish("test", Bytes::from_static(b"\x00\x00\x00\x01a"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        *messages.lock().unwrap(),
+        vec![
+            Bytes::from_static(b"msg1"),
+            Bytes::new(),
+            Bytes::from_static(b"msg3"),
+            Bytes::from_static(b"\x00\x00\x00\x01a"),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_stream() -> std::io::Result<()> {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let messages2 = messages.clone();
+
+    let srv = server::test_server(move || {
+        let messages = messages2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                messages.lock().unwrap().push(p.payload().clone());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let (tx, rx) = ntex::channel::mpsc::channel();
+    ntex::rt::spawn(async move {
+        for chunk in [&b"chunk1"[..], b"chunk2", b"chunk3"] {
+            sleep(Millis(25)).await;
+            let _ = tx.send(Bytes::from_static(chunk));
+        }
+    });
+    let sink2 = sink.clone();
+    ntex::rt::spawn(async move {
+        sleep(Millis(10)).await;
+        // written after streamed payload
+        let _ = sink2.publish("test", Bytes::from_static(b"msg")).send_at_most_once();
+    });
+    sink.publish_stream("test", QoS::AtLeastOnce, 18, rx).await.unwrap();
+    sink.publish("test", Bytes::from_static(b"msg2")).send_at_least_once().await.unwrap();
+
+    assert_eq!(
+        *messages.lock().unwrap(),
+        vec![
+            Bytes::from_static(b"chunk1chunk2chunk3"),
+            Bytes::from_static(b"msg"),
+            Bytes::from_static(b"msg2"),
+        ]
+    );
+
+    // stream is shorter than declared size
+    let (tx, rx) = ntex::channel::mpsc::channel();
+    let _ = tx.send(Bytes::from_static(b"chunk"));
+    drop(tx);
+    let res = sink.publish_stream("test", QoS::AtLeastOnce, 10, rx).await;
+    assert_eq!(res, Err(error::SendPacketError::Encode(error::EncodeError::InvalidLength)));
+    assert!(!sink.is_open());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_client_id() -> std::io::Result<()> {
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(|packet: Handshake| {
+            let assigned = packet.packet().client_id.is_empty();
+            Ready::Ok::<_, TestError>(packet.ack(St).with(|ack| {
+                if assigned {
+                    ack.assigned_client_id = Some(ByteString::from_static("assigned"));
+                }
+            }))
+        })
+        .on_connected(move |session: Session<St>| {
+            ids.lock().unwrap().push(session.client_id().clone());
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    client.sink().close();
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect { clean_start: true, ..Default::default() }.into(), &codec)
+        .await
+        .unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+    sleep(Millis(50)).await;
+
+    assert_eq!(
+        *ids.lock().unwrap(),
+        vec![ByteString::from_static("user"), ByteString::from_static("assigned")]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_assigned_client_id() -> std::io::Result<()> {
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            let requested = packet.packet().client_id.clone();
+            ids.lock().unwrap().push(requested.clone());
+            Ready::Ok::<_, TestError>(packet.ack(St).with(|ack| {
+                if requested.is_empty() {
+                    ack.assigned_client_id = Some(ByteString::from_static("assigned"));
+                }
+            }))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // server rejects empty client id without clean start
+    let connector =
+        client::MqttConnector::new(srv.addr()).clean_start(true).reuse_assigned_client_id(true);
+    let client = connector.connect().await.unwrap();
+    assert_eq!(client.assigned_client_id(), Some(&ByteString::from_static("assigned")));
+    assert_eq!(client.client_id(), "assigned");
+    client.sink().close();
+
+    // reconnect uses assigned client id
+    let client = connector.connect().await.unwrap();
+    assert_eq!(client.assigned_client_id(), None);
+    assert_eq!(client.client_id(), "assigned");
+    client.sink().close();
+
+    // without reuse, server assigns new id on each connect
+    let connector = client::MqttConnector::new(srv.addr()).clean_start(true);
+    let client = connector.connect().await.unwrap();
+    client.sink().close();
+    let client = connector.connect().await.unwrap();
+    assert_eq!(client.client_id(), "assigned");
+    client.sink().close();
+
+    assert_eq!(
+        *ids.lock().unwrap(),
+        vec![
+            ByteString::new(),
+            ByteString::from_static("assigned"),
+            ByteString::new(),
+            ByteString::new()
+        ]
+    );
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_deny_dollar_publish() -> std::io::Result<()> {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .deny_dollar_publish(true)
+            .publish(move |p: Publish| {
+                publishes.lock().unwrap().push(p.publish_topic().to_string());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let ack = sink.publish("$SYS/foo", Bytes::new()).send_at_least_once().await.unwrap();
+    assert_eq!(ack.reason_code, codec::PublishAckReason::TopicNameInvalid);
+    let ack = sink.publish("test", Bytes::new()).send_at_least_once().await.unwrap();
+    assert_eq!(ack.reason_code, codec::PublishAckReason::Success);
+    assert_eq!(*publishes.lock().unwrap(), vec!["test".to_string()]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_filter() -> std::io::Result<()> {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .publish_filter(|p: &Publish| !p.publish_topic().starts_with("noisy"))
+            .publish(move |p: Publish| {
+                publishes.lock().unwrap().push(p.publish_topic().to_string());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // filtered publishes are still acked
+    sink.publish("noisy/1", Bytes::new()).send_at_most_once().unwrap();
+    let ack = sink.publish("noisy/2", Bytes::new()).send_at_least_once().await.unwrap();
+    assert_eq!(ack.reason_code, codec::PublishAckReason::Success);
+    let ack = sink.publish("test", Bytes::new()).send_at_least_once().await.unwrap();
+    assert_eq!(ack.reason_code, codec::PublishAckReason::Success);
+    assert_eq!(*publishes.lock().unwrap(), vec!["test".to_string()]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_topic_size_limits() -> std::io::Result<()> {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .topic_size_limits(
+                |topic| if topic.starts_with("telemetry/") { Some(4) } else { None },
+            )
+            .publish(move |p: Publish| {
+                publishes.lock().unwrap().push(p.publish_topic().to_string());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                Control::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    io.send(
+        codec::Publish {
+            topic: ByteString::from_static("telemetry/1"),
+            payload: Bytes::from_static(b"1234"),
+            ..pkt_publish()
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::PublishAck(_)));
+
+    io.send(
+        codec::Publish {
+            packet_id: Some(NonZeroU16::new(2).unwrap()),
+            topic: ByteString::from_static("telemetry/2"),
+            payload: Bytes::from_static(b"12345"),
+            ..pkt_publish()
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt.0,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::PacketTooLarge,
+            ..
+        })
+    ));
+    assert_eq!(*publishes.lock().unwrap(), vec!["telemetry/1".to_string()]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_inbound_queue_depth() -> std::io::Result<()> {
+    let called = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let called2 = called.clone();
+
+    let srv = server::test_server(move || {
+        let called = called2.clone();
+        MqttServer::new(handshake)
+            .inbound_queue_depth(2)
+            .publish(move |p: Publish| {
+                called.fetch_add(1, Relaxed);
+                async move {
+                    // slow publish service
+                    sleep(Millis(10_000)).await;
+                    Ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    for _ in 0..4 {
+        io.send(
+            codec::Publish { qos: codec::QoS::AtMostOnce, packet_id: None, ..pkt_publish() }
+                .into(),
+            &codec,
+        )
+        .await
+        .unwrap();
+    }
+    sleep(Millis(100)).await;
+
+    // dispatcher stops reading once queue is full
+    assert_eq!(called.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_connect_options() -> std::io::Result<()> {
+    let packets = Arc::new(Mutex::new(Vec::new()));
+    let packets2 = packets.clone();
+
+    let srv = server::test_server(move || {
+        let packets = packets2.clone();
+        MqttServer::new(move |packet: Handshake| {
+            packets.lock().unwrap().push(packet.packet().clone());
+            Ready::Ok::<_, TestError>(packet.ack(St))
+        })
+        .publish(|p: Publish| Ready::Ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let opts: client::ConnectOptions = serde_json::from_str(
+        r#"{
+            "client_id": "user",
+            "keep_alive": 10,
+            "clean_start": true,
+            "session_expiry_interval": 60,
+            "username": "name",
+            "max_packet_size": 1024,
+            "last_will": {"topic": "will", "qos": "AtMostOnce", "delay_interval": 5}
+        }"#,
+    )
+    .unwrap();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).with_options(opts).connect().await.unwrap();
+    client.sink().close();
+
+    let pkt = packets.lock().unwrap()[0].clone();
+    assert_eq!(pkt.client_id, "user");
+    assert_eq!(pkt.keep_alive, 10);
+    assert!(pkt.clean_start);
+    assert_eq!(pkt.session_expiry_interval_secs, 60);
+    assert_eq!(pkt.username, Some(ByteString::from_static("name")));
+    assert_eq!(pkt.password, None);
+    assert_eq!(pkt.max_packet_size.map(|v| v.get()), Some(1024));
+    let will = pkt.last_will.unwrap();
+    assert_eq!(will.topic, "will");
+    assert_eq!(will.qos, QoS::AtMostOnce);
+    assert_eq!(will.will_delay_interval_sec, Some(5));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_strict_packet_ids() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .strict_packet_ids(true)
+            .publish(|p: Publish| async move {
+                sleep(Millis(50)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                Control::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    // re-delivery with DUP flag is not a violation
+    io.send(pkt_publish().into(), &codec).await.unwrap();
+    io.send(codec::Publish { dup: true, ..pkt_publish() }.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert_eq!(
+        pkt.0,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    // packet id reuse without DUP flag
+    let publish =
+        codec::Publish { packet_id: Some(NonZeroU16::new(2).unwrap()), ..pkt_publish() };
+    io.send(publish.clone().into(), &codec).await.unwrap();
+    io.send(publish.into(), &codec).await.unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt.0,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::ProtocolError,
+            ..
+        })
+    ));
+}
+
+#[ntex::test]
+async fn test_validate_payload_format() {
+    let formats = Arc::new(Mutex::new(Vec::new()));
+    let formats2 = formats.clone();
+
+    let srv = server::test_server(move || {
+        let formats = formats2.clone();
+        MqttServer::new(handshake)
+            .validate_payload_format(true)
+            .publish(move |p: Publish| {
+                formats.lock().unwrap().push(p.payload_format());
+                Ready::Ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                Control::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let utf8 = codec::PublishProperties { is_utf8_payload: true, ..Default::default() };
+    io.send(
+        codec::Publish {
+            payload: Bytes::from_static(b"text"),
+            properties: utf8.clone(),
+            ..pkt_publish()
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::PublishAck(_)));
+
+    // binary payload without indicator is not validated
+    io.send(
+        codec::Publish {
+            packet_id: Some(NonZeroU16::new(2).unwrap()),
+            payload: Bytes::from_static(b"\xff\xfe"),
+            ..pkt_publish()
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(pkt.0, codec::Packet::PublishAck(_)));
+    assert_eq!(*formats.lock().unwrap(), vec![Some(PayloadFormat::Utf8), None]);
+
+    io.send(
+        codec::Publish {
+            packet_id: Some(NonZeroU16::new(3).unwrap()),
+            payload: Bytes::from_static(b"\xff\xfe"),
+            properties: utf8,
+            ..pkt_publish()
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt.0,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::PayloadFormatInvalid,
+            ..
+        })
+    ));
+}
+
+#[ntex::test]
+async fn test_max_filters_per_subscribe() {
+    let subscribed = Arc::new(AtomicBool::new(false));
+    let subscribed2 = subscribed.clone();
+
+    let srv = server::test_server(move || {
+        let subscribed = subscribed2.clone();
+        MqttServer::new(handshake)
+            .max_filters_per_subscribe(2)
+            .control(move |msg| match msg {
+                Control::ProtocolError(msg) => Ready::Ok::<_, TestError>(msg.ack()),
+                Control::Subscribe(_) => {
+                    subscribed.store(true, Relaxed);
+                    Ready::Ok(msg.disconnect())
+                }
+                _ => Ready::Ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let codec = codec::Codec::default();
+    io.send(codec::Connect::default().client_id("user").into(), &codec).await.unwrap();
+    let _ = io.recv(&codec).await.unwrap().unwrap();
+
+    let filters = (0..3)
+        .map(|i| {
+            (ByteString::from(format!("topic{}", i)), codec::SubscriptionOptions::default())
+        })
+        .collect();
+    io.send(
+        codec::Subscribe {
+            id: None,
+            packet_id: NonZeroU16::new(1).unwrap(),
+            user_properties: Default::default(),
+            topic_filters: filters,
+        }
+        .into(),
+        &codec,
+    )
+    .await
+    .unwrap();
+
+    let pkt = io.recv(&codec).await.unwrap().unwrap();
+    assert!(matches!(
+        pkt.0,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::QuotaExceeded,
+            ..
+        })
+    ));
+    assert!(!subscribed.load(Relaxed));
+}